@@ -0,0 +1,413 @@
+//! The append-only file. Every write is logged as the command that
+//! reproduces it, with relative expiries made absolute, and the log is
+//! replayed at startup. BGREWRITEAOF compacts it into the shortest list of
+//! commands that rebuilds the current dataset.
+
+use bytes::{Bytes, BytesMut};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crate::{
+    aof_reader::read_command,
+    commands::Command,
+    config::AppendFsync,
+    db::{DataType, Db},
+    error::AofError,
+    rdb,
+    resp::{Protocol, RespValue},
+};
+
+/// Most elements a rewritten command adds at once, as in Redis.
+const ITEMS_PER_COMMAND: usize = 64;
+
+struct AofState {
+    /// Open while logging is enabled.
+    file: Option<File>,
+    /// Writes made while a rewrite runs, appended to the new file once the
+    /// dataset has been written out.
+    rewrite_buf: Option<BytesMut>,
+    /// Whether the file being rewritten should become the active log.
+    enable_after_rewrite: bool,
+    /// Whether there are writes the `everysec` policy has yet to flush.
+    unsynced: bool,
+    /// Replication offset up to which the `everysec` policy has flushed the
+    /// log.
+    fsynced_offset: u64,
+}
+
+/// The log shared by every connection.
+#[derive(Clone)]
+pub struct Aof {
+    state: Arc<Mutex<AofState>>,
+}
+
+impl Aof {
+    pub fn new() -> Aof {
+        Aof {
+            state: Arc::new(Mutex::new(AofState {
+                file: None,
+                rewrite_buf: None,
+                enable_after_rewrite: false,
+                unsynced: false,
+                fsynced_offset: 0,
+            })),
+        }
+    }
+
+    /// Logs a write that has just been applied, already encoded as a
+    /// command.
+    pub fn append(&self, buf: &[u8], fsync: AppendFsync) {
+        let mut lock = self.state.lock().unwrap();
+        if let Some(rewrite_buf) = &mut lock.rewrite_buf {
+            rewrite_buf.extend_from_slice(buf);
+        }
+
+        let Some(file) = &mut lock.file else {
+            return;
+        };
+        let result = file.write_all(buf).and_then(|()| match fsync {
+            AppendFsync::Always => file.sync_data(),
+            _ => Ok(()),
+        });
+        if let Err(e) = result {
+            eprintln!("Error writing to the AOF file: {}", e);
+        }
+        lock.unsynced = fsync == AppendFsync::EverySec;
+    }
+}
+
+/// Starts logging to the configured file, creating it when missing.
+pub fn open(db: &Db) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(aof_path(db))?;
+    db.aof().state.lock().unwrap().file = Some(file);
+    Ok(())
+}
+
+/// Starts or stops logging after `appendonly` was changed at runtime.
+/// Turning it on writes the current dataset out first, as the log would
+/// otherwise only hold the writes made from then on.
+pub fn apply_config(db: &Db) -> Result<(), AofError> {
+    {
+        let mut lock = db.aof().state.lock().unwrap();
+        if !db.config().appendonly() {
+            lock.file = None;
+            lock.enable_after_rewrite = false;
+            return Ok(());
+        }
+        if lock.file.is_some() {
+            return Ok(());
+        }
+        lock.enable_after_rewrite = true;
+    }
+
+    match rewrite(db) {
+        // The running rewrite picks up the request when it completes
+        Err(AofError::RewriteInProgress) => Ok(()),
+        result => result,
+    }
+}
+
+/// Rewrites the log on a separate thread from a snapshot of the dataset.
+/// Writes made meanwhile are captured and appended once the snapshot is on
+/// disk.
+pub fn rewrite(db: &Db) -> Result<(), AofError> {
+    let snapshot = {
+        // No write may slip in between taking the snapshot and starting to
+        // capture writes, or it would be lost or logged twice
+        let _guard = db.write_guard();
+        let mut lock = db.aof().state.lock().unwrap();
+        if lock.rewrite_buf.is_some() {
+            return Err(AofError::RewriteInProgress);
+        }
+        lock.rewrite_buf = Some(BytesMut::new());
+        drop(lock);
+        db.snapshot().0
+    };
+
+    let db = db.clone();
+    thread::spawn(move || match write_rewrite(&db, &snapshot.into_entries()) {
+        Ok(()) => println!("Background AOF rewrite finished successfully"),
+        Err(e) => {
+            eprintln!("Background AOF rewrite failed: {}", e);
+            db.aof().state.lock().unwrap().rewrite_buf = None;
+        }
+    });
+    Ok(())
+}
+
+fn write_rewrite(db: &Db, entries: &[rdb::Entry]) -> io::Result<()> {
+    let path = aof_path(db);
+    let temp = path.with_file_name(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(&rewrite_commands(entries))?;
+
+        // Hold the log while the captured writes are added and the new file
+        // swapped in, so that no write lands in between
+        let mut lock = db.aof().state.lock().unwrap();
+        let captured = lock.rewrite_buf.take().unwrap_or_default();
+        file.write_all(&captured)?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)?;
+
+        if lock.file.is_some() || lock.enable_after_rewrite {
+            lock.file = Some(file);
+            lock.enable_after_rewrite = false;
+        }
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// The commands that rebuild `entries` from an empty dataset.
+fn rewrite_commands(entries: &[rdb::Entry]) -> BytesMut {
+    let mut out = BytesMut::new();
+
+    for entry in entries {
+        let key = &entry.key;
+        match &entry.value {
+            DataType::String(value) => {
+                encode_command(&mut out, &[Bytes::from("SET"), key.clone(), value.clone()])
+            }
+            DataType::List(list) => encode_batched(
+                &mut out,
+                "RPUSH",
+                key,
+                list.iter().map(|item| [item.clone()]),
+            ),
+            DataType::Set(set) => encode_batched(
+                &mut out,
+                "SADD",
+                key,
+                set.iter().map(|member| [member.clone()]),
+            ),
+            DataType::Hash(hash) => encode_batched(
+                &mut out,
+                "HSET",
+                key,
+                hash.iter()
+                    .map(|(field, value)| [field.clone(), value.clone()]),
+            ),
+            DataType::ZSet(zset) => encode_batched(
+                &mut out,
+                "ZADD",
+                key,
+                zset.iter()
+                    .map(|(member, score)| [Bytes::from(score.to_string()), member.clone()]),
+            ),
+        }
+
+        if let Some(at) = entry.expire_at {
+            encode_command(
+                &mut out,
+                &[
+                    Bytes::from("PEXPIREAT"),
+                    key.clone(),
+                    Bytes::from(at.to_string()),
+                ],
+            );
+        }
+    }
+    out
+}
+
+/// Encodes `command key items...`, spreading large collections over
+/// several commands.
+fn encode_batched<const N: usize>(
+    out: &mut BytesMut,
+    command: &'static str,
+    key: &Bytes,
+    items: impl Iterator<Item = [Bytes; N]>,
+) {
+    let items: Vec<[Bytes; N]> = items.collect();
+    for batch in items.chunks(ITEMS_PER_COMMAND) {
+        let mut args = vec![Bytes::from(command), key.clone()];
+        args.extend(batch.iter().flatten().cloned());
+        encode_command(out, &args);
+    }
+}
+
+fn encode_command(out: &mut BytesMut, args: &[Bytes]) {
+    RespValue::Array(args.iter().cloned().map(RespValue::BulkString).collect())
+        .write_to(out, Protocol::Resp2);
+}
+
+/// Flushes the log to disk under the `everysec` policy. Called once a
+/// second by the server.
+pub fn cron(db: &Db) {
+    if db.config().appendfsync() != AppendFsync::EverySec {
+        return;
+    }
+
+    // Sync a second handle so writers aren't held up by the disk. Writes
+    // are logged before they are fed to replicas, so the offset read here
+    // covers nothing that isn't in the file yet
+    let (file, offset) = {
+        let mut lock = db.aof().state.lock().unwrap();
+        let offset = db.replication().offset();
+        if !lock.unsynced {
+            lock.fsynced_offset = offset;
+            return;
+        }
+        lock.unsynced = false;
+        (
+            lock.file.as_ref().and_then(|file| file.try_clone().ok()),
+            offset,
+        )
+    };
+    match file.map(|file| file.sync_data()) {
+        Some(Ok(())) => db.aof().state.lock().unwrap().fsynced_offset = offset,
+        Some(Err(e)) => eprintln!("Error syncing the AOF file: {}", e),
+        None => {}
+    }
+}
+
+/// The replication offset up to which every logged write is known to be on
+/// disk, or None while logging is off.
+pub fn fsynced_offset(db: &Db) -> Option<u64> {
+    let lock = db.aof().state.lock().unwrap();
+    lock.file.as_ref()?;
+    match db.config().appendfsync() {
+        // Every write was synced as it was logged
+        AppendFsync::Always if !lock.unsynced => Some(db.replication().offset()),
+        _ => Some(lock.fsynced_offset),
+    }
+}
+
+/// Replays the log into `db`. Returns false when there is no file.
+///
+/// A log that ends partway through a command, as left by a crash during a
+/// write, is loaded up to its last complete command and truncated there
+/// when `aof-load-truncated` is enabled.
+pub fn load(db: &Db) -> Result<bool, AofError> {
+    let path = aof_path(db);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+
+    db.set_loading(true);
+    let result = replay(db, &data);
+    db.set_loading(false);
+    match result {
+        Err(AofError::Truncated(offset)) if db.config().aof_load_truncated() => {
+            eprintln!("!!! Warning: short read while loading the AOF file !!!");
+            eprintln!("AOF loaded anyway because aof-load-truncated is enabled");
+            let file = OpenOptions::new().write(true).open(&path)?;
+            file.set_len(offset as u64)?;
+            println!(
+                "AOF truncated to the last valid command at offset {}",
+                offset
+            );
+            Ok(true)
+        }
+        result => result.map(|()| true),
+    }
+}
+
+fn replay(db: &Db, data: &[u8]) -> Result<(), AofError> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let (frame, next) = read_command(data, offset)?;
+        let cmd = Command::from_resp(frame).map_err(|e| AofError::BadCommand(offset, e))?;
+        cmd.execute_loaded(db);
+        offset = next;
+    }
+    Ok(())
+}
+
+fn aof_path(db: &Db) -> PathBuf {
+    Path::new(&db.config().dir()).join(db.config().appendfilename())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::unix_time_ms;
+    use std::collections::{HashMap, VecDeque};
+
+    #[test]
+    fn test_rewrite_commands_rebuild_dataset() {
+        let entries = vec![
+            rdb::Entry {
+                key: Bytes::from("l"),
+                value: DataType::List((0..100).map(|i| Bytes::from(i.to_string())).collect()),
+                expire_at: Some(unix_time_ms() + 100_000),
+            },
+            rdb::Entry {
+                key: Bytes::from("z"),
+                value: DataType::ZSet(HashMap::from([(Bytes::from("m"), 1.5)])),
+                expire_at: None,
+            },
+        ];
+
+        let db = Db::new();
+        replay(&db, &rewrite_commands(&entries)).unwrap();
+
+        let mut restored = db.snapshot().0.into_entries();
+        restored.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(restored[0].value, entries[0].value);
+        assert!(restored[0].expire_at.is_some());
+        assert_eq!(restored[1], entries[1]);
+    }
+
+    #[test]
+    fn test_replay_reports_offsets() {
+        let db = Db::new();
+        let log = b"*3\r\n$5\r\nRPUSH\r\n$1\r\nl\r\n$1\r\na\r\n*2\r\n$4\r\nLPOP\r\n$1";
+        assert!(matches!(replay(&db, log), Err(AofError::Truncated(29))));
+        assert_eq!(
+            db.get(b"l"),
+            Some(DataType::List(VecDeque::from([Bytes::from("a")])))
+        );
+
+        assert!(matches!(
+            replay(&db, b"GET x\r\n"),
+            Err(AofError::BadFormat(0))
+        ));
+        assert!(matches!(
+            replay(&db, b"*1\r\n$4\r\nNOPE\r\n"),
+            Err(AofError::BadCommand(0, _))
+        ));
+    }
+
+    #[test]
+    fn test_load_truncated_log() {
+        let dir = std::env::temp_dir().join(format!("redis-lite-aof-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nDEL";
+        fs::write(dir.join("appendonly.aof"), log).unwrap();
+
+        let db = Db::new();
+        db.config().set("dir", dir.to_str().unwrap()).unwrap();
+        db.config().set("aof-load-truncated", "no").unwrap();
+        assert!(matches!(load(&db), Err(AofError::Truncated(27))));
+
+        // Replayed writes already happened once, so they are neither
+        // propagated nor announced again
+        db.config().set("notify-keyspace-events", "KEA").unwrap();
+        let (mut sub, mut rx) = db.pubsub().subscriber();
+        sub.psubscribe(vec![Bytes::from("__key*__:*")]);
+        db.config().set("aof-load-truncated", "yes").unwrap();
+        assert!(load(&db).unwrap());
+        assert_eq!(db.get(b"k"), Some(DataType::String(Bytes::from("v"))));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(db.replication().offset(), 0);
+        assert_eq!(fs::read(dir.join("appendonly.aof")).unwrap(), &log[..27]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
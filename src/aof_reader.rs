@@ -0,0 +1,64 @@
+//! Reading commands back from an append-only file. Shared by the server
+//! and the `redis-lite-check-aof` tool.
+
+use std::io::Cursor;
+
+use crate::{
+    error::{AofError, ProtocolError},
+    resp::{RespValue, parse_resp},
+};
+
+/// Reads the command starting at `offset`, returning it along with the
+/// offset of the next one.
+pub fn read_command(data: &[u8], offset: usize) -> Result<(RespValue, usize), AofError> {
+    // Only multibulk commands are ever logged
+    if data.get(offset) != Some(&b'*') {
+        return Err(AofError::BadFormat(offset));
+    }
+
+    let mut cursor = Cursor::new(data);
+    cursor.set_position(offset as u64);
+    let frame = match parse_resp(&mut cursor) {
+        Ok(frame) => frame,
+        Err(ProtocolError::Incomplete) => return Err(AofError::Truncated(offset)),
+        Err(_) => return Err(AofError::BadFormat(offset)),
+    };
+
+    match &frame {
+        RespValue::Array(args)
+            if !args.is_empty()
+                && args
+                    .iter()
+                    .all(|arg| matches!(arg, RespValue::BulkString(_))) =>
+        {
+            Ok((frame, cursor.position() as usize))
+        }
+        _ => Err(AofError::BadFormat(offset)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_command_reports_offsets() {
+        let log = b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+        let (_, next) = read_command(log, 0).unwrap();
+        assert_eq!(next, 14);
+        assert_eq!(read_command(log, next).unwrap().1, log.len());
+
+        assert!(matches!(
+            read_command(&log[..log.len() - 3], next),
+            Err(AofError::Truncated(14))
+        ));
+        assert!(matches!(
+            read_command(b"+OK\r\n", 0),
+            Err(AofError::BadFormat(0))
+        ));
+        assert!(matches!(
+            read_command(b"*1\r\n:1\r\n", 0),
+            Err(AofError::BadFormat(0))
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+//! Validates an append-only file and, with `--fix`, truncates it to its
+//! last complete command.
+//!
+//!     redis-lite-check-aof [--fix] <file.aof>
+
+// The server's modules are shared as source; the tool needs only a part
+#[allow(dead_code)]
+#[path = "../aof_reader.rs"]
+mod aof_reader;
+#[allow(dead_code)]
+#[path = "../error.rs"]
+mod error;
+#[allow(dead_code)]
+#[path = "../resp.rs"]
+mod resp;
+
+use std::{fs, process::ExitCode};
+
+use aof_reader::read_command;
+use error::AofError;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (fix, path) = match args.as_slice() {
+        [path] => (false, path),
+        [flag, path] if flag == "--fix" => (true, path),
+        _ => {
+            eprintln!("Usage: redis-lite-check-aof [--fix] <file.aof>");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Cannot open file {}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let (valid_up_to, problem) = scan(&data);
+    println!(
+        "AOF analyzed: filename={}, size={}, ok_up_to={}, diff={}",
+        path,
+        data.len(),
+        valid_up_to,
+        data.len() - valid_up_to
+    );
+    let Some(problem) = problem else {
+        println!("AOF is valid");
+        return ExitCode::SUCCESS;
+    };
+
+    println!("AOF is not valid: {}", problem);
+    if !fix {
+        println!("Use the --fix option to truncate it to the last valid command");
+        return ExitCode::FAILURE;
+    }
+
+    let truncated = fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_len(valid_up_to as u64));
+    match truncated {
+        Ok(()) => {
+            println!("Successfully truncated AOF to {} bytes", valid_up_to);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to truncate AOF: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns the length of the valid prefix of `data` and, when that is not
+/// all of it, what is wrong with the rest.
+fn scan(data: &[u8]) -> (usize, Option<AofError>) {
+    let mut offset = 0;
+    while offset < data.len() {
+        match read_command(data, offset) {
+            Ok((_, next)) => offset = next,
+            Err(e) => return (offset, Some(e)),
+        }
+    }
+    (offset, None)
+}
@@ -0,0 +1,212 @@
+use bytes::Bytes;
+
+use crate::{
+    cluster,
+    command_table::{STALE, WRITE},
+    commands::Command,
+    db::Db,
+    error::CommandError,
+    info,
+    pubsub::{ClientId, Subscriber},
+    resp::{Protocol, RespValue},
+};
+
+/// State owned by a single connection.
+pub struct Client {
+    pub protocol: Protocol,
+    pub name: Option<String>,
+    /// The port a replica connecting through this connection listens on,
+    /// as announced with REPLCONF.
+    pub listening_port: Option<u16>,
+    /// Whether the last command was ASKING, letting the next one into a
+    /// cluster slot still being imported.
+    pub asking: bool,
+    subscriber: Subscriber,
+}
+
+impl Client {
+    pub fn new(subscriber: Subscriber) -> Client {
+        Client {
+            protocol: Protocol::Resp2,
+            name: None,
+            listening_port: None,
+            asking: false,
+            subscriber,
+        }
+    }
+
+    pub fn id(&self) -> ClientId {
+        self.subscriber.id()
+    }
+
+    /// Rejects `cmd` if this connection, or the server's replication state,
+    /// doesn't allow it right now.
+    pub fn check(&self, cmd: &Command, db: &Db) -> Result<(), CommandError> {
+        if self.subscribed() && !cmd.allowed_in_subscribed_mode() {
+            return Err(CommandError::Err(format!(
+                "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                cmd.name()
+            )));
+        }
+
+        let spec = cmd.spec();
+        let replication = db.replication();
+        if spec.has_flag(WRITE) && replication.is_replica() && db.config().replica_read_only() {
+            return Err(CommandError::ReadOnly);
+        }
+        if !spec.has_flag(STALE)
+            && replication.is_stale()
+            && !db.config().replica_serve_stale_data()
+        {
+            return Err(CommandError::MasterDown);
+        }
+        Ok(())
+    }
+
+    /// RESP3 can interleave pushes with replies, so only RESP2 connections
+    /// are restricted while subscribed.
+    fn subscribed(&self) -> bool {
+        self.protocol == Protocol::Resp2 && self.subscriber.count() > 0
+    }
+
+    /// Runs a command in the context of this connection, handling the
+    /// commands that depend on its protocol or subscription state.
+    pub fn dispatch(&mut self, cmd: Command, db: &Db) -> Vec<RespValue> {
+        if let Err(e) = self.check(&cmd, db) {
+            return vec![RespValue::from(e)];
+        }
+
+        let subscribed = self.subscribed();
+        match cmd {
+            Command::Hello(version, auth, name) => vec![
+                self.hello(version, auth, name, db)
+                    .unwrap_or_else(RespValue::from),
+            ],
+            Command::ClientId => vec![RespValue::Integer(self.id() as i64)],
+            Command::ClientGetName => vec![
+                self.name
+                    .clone()
+                    .map_or(RespValue::Null, |name| RespValue::BulkString(name.into())),
+            ],
+            Command::ClientSetName(name) => match client_name(name) {
+                Ok(name) => {
+                    self.name = name;
+                    vec![RespValue::SimpleString("OK".to_string())]
+                }
+                Err(e) => vec![RespValue::from(e)],
+            },
+            Command::ReplConf(options) => {
+                for (option, value) in options {
+                    if option.eq_ignore_ascii_case("listening-port") {
+                        let Ok(port) = value.parse() else {
+                            return vec![RespValue::from(CommandError::NotInteger)];
+                        };
+                        self.listening_port = Some(port);
+                    }
+                }
+                vec![RespValue::SimpleString("OK".to_string())]
+            }
+            Command::Asking => match cluster::asking(db) {
+                Ok(reply) => {
+                    self.asking = true;
+                    vec![reply]
+                }
+                Err(e) => vec![RespValue::from(e)],
+            },
+            Command::Subscribe(channels) => self.subscriber.subscribe(channels),
+            Command::Unsubscribe(channels) => self.subscriber.unsubscribe(channels),
+            Command::PSubscribe(patterns) => self.subscriber.psubscribe(patterns),
+            Command::PUnsubscribe(patterns) => self.subscriber.punsubscribe(patterns),
+            Command::SSubscribe(channels) => self.subscriber.ssubscribe(channels),
+            Command::SUnsubscribe(channels) => self.subscriber.sunsubscribe(channels),
+            Command::Ping if subscribed => vec![RespValue::Array(vec![
+                RespValue::BulkString(Bytes::from_static(b"pong")),
+                RespValue::BulkString(Bytes::new()),
+            ])],
+            cmd => vec![cmd.execute(db)],
+        }
+    }
+
+    fn hello(
+        &mut self,
+        version: Option<i64>,
+        auth: Option<(String, String)>,
+        name: Option<String>,
+        db: &Db,
+    ) -> Result<RespValue, CommandError> {
+        let protocol = match version {
+            None => self.protocol,
+            Some(2) => Protocol::Resp2,
+            Some(3) => Protocol::Resp3,
+            Some(_) => return Err(CommandError::NoProto),
+        };
+
+        // There is no ACL support, so only the passwordless default user exists
+        if let Some((user, _)) = auth
+            && user != "default"
+        {
+            return Err(CommandError::WrongPass);
+        }
+        let name = name.map(client_name).transpose()?;
+
+        self.protocol = protocol;
+        if let Some(name) = name {
+            self.name = name;
+        }
+
+        let proto = match protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        let field = |s: &str| RespValue::BulkString(Bytes::copy_from_slice(s.as_bytes()));
+        Ok(RespValue::Map(vec![
+            (field("server"), field("redis")),
+            (field("version"), field(crate::REDIS_VERSION)),
+            (field("proto"), RespValue::Integer(proto)),
+            (field("id"), RespValue::Integer(self.id() as i64)),
+            (field("mode"), field(info::server_mode(db))),
+            (
+                field("role"),
+                field(if db.replication().is_replica() {
+                    "replica"
+                } else {
+                    "master"
+                }),
+            ),
+            (field("modules"), RespValue::Array(vec![])),
+        ]))
+    }
+}
+
+/// Checks a name given with CLIENT SETNAME or HELLO SETNAME. An empty name
+/// clears the connection's name.
+fn client_name(name: String) -> Result<Option<String>, CommandError> {
+    if name.contains(|c: char| c == ' ' || c.is_control()) {
+        return Err(CommandError::Err(
+            "Client names cannot contain spaces, newlines or special characters.".to_string(),
+        ));
+    }
+    Ok((!name.is_empty()).then_some(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hello_setname_is_checked() {
+        let db = Db::new();
+        let (subscriber, _messages) = db.pubsub().subscriber();
+        let mut client = Client::new(subscriber);
+
+        // A bad name rejects the whole HELLO
+        let reply = client.dispatch(Command::Hello(Some(3), None, Some("a b".to_string())), &db);
+        assert!(matches!(&reply[..], [RespValue::SimpleError(_)]));
+        assert!(client.protocol == Protocol::Resp2);
+
+        client.dispatch(Command::ClientSetName("conn".to_string()), &db);
+        assert_eq!(client.name.as_deref(), Some("conn"));
+        client.dispatch(Command::Hello(None, None, Some(String::new())), &db);
+        assert_eq!(client.name, None);
+    }
+}
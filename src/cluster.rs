@@ -0,0 +1,2107 @@
+//! Redis Cluster. Keys are spread over 16384 hash slots, each served by one
+//! master. Nodes find each other and agree on who serves which slot by
+//! gossiping over the cluster bus, a second port on which they exchange
+//! PING and PONG messages, and clients asking a node for a key it doesn't
+//! serve are redirected with MOVED.
+//!
+//! Masters that stop answering are first suspected (PFAIL) by each node on
+//! its own, then marked as failed (FAIL) once a majority of masters report
+//! them. Their replicas then hold an election, and the one voted in by a
+//! majority takes over the slots with a new config epoch.
+
+use bytes::{Bytes, BytesMut};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::{self, Cursor},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+use crate::{
+    command_table::{self, ASKING, CommandSpec},
+    db::{Db, unix_time_ms},
+    error::{CommandError, ProtocolError},
+    replication::{self, random_id},
+    resp::{Protocol, RespValue, parse_resp},
+    slot::{SLOT_COUNT, key_hash_slot},
+};
+
+/// How often the bus checks its links and pings other nodes.
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long after the last PONG a node is pinged again, in milliseconds.
+const PING_INTERVAL_MS: u64 = 1000;
+
+/// How long a manual failover may take before it is given up.
+const MANUAL_FAILOVER_TIMEOUT_MS: u64 = 5000;
+
+/// How often clients whose writes are paused check again.
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The address every node listens on.
+const NODE_IP: &str = "127.0.0.1";
+
+const MYSELF: u8 = 1 << 0;
+const MASTER: u8 = 1 << 1;
+const REPLICA: u8 = 1 << 2;
+const PFAIL: u8 = 1 << 3;
+const FAIL: u8 = 1 << 4;
+const HANDSHAKE: u8 = 1 << 5;
+
+/// Message flags: the sender has paused writes for a manual failover.
+const PAUSED: u8 = 1 << 0;
+/// Message flags: vote even though the master hasn't failed.
+const FORCE_ACK: u8 = 1 << 1;
+
+/// Flags as they appear in CLUSTER NODES.
+const FLAG_NAMES: &[(u8, &str)] = &[
+    (MYSELF, "myself"),
+    (MASTER, "master"),
+    (REPLICA, "slave"),
+    (PFAIL, "fail?"),
+    (FAIL, "fail"),
+    (HANDSHAKE, "handshake"),
+];
+
+/// A node of the cluster, as known to this one.
+struct Node {
+    id: String,
+    ip: String,
+    port: u16,
+    /// Port of the node's cluster bus.
+    cport: u16,
+    flags: u8,
+    /// The master this node replicates, when it is a replica.
+    master: Option<String>,
+    /// The version of the node's claim on its slots.
+    config_epoch: u64,
+    /// Unix time in ms the oldest unanswered PING was sent at, 0 if none.
+    ping_sent: u64,
+    /// Unix time in ms the last PONG was received at.
+    pong_received: u64,
+    /// Unix time in ms the node was added at.
+    created: u64,
+    /// Masters that reported the node as failing, with when they last did.
+    fail_reports: HashMap<String, u64>,
+    /// Unix time in ms the node was marked as failed at.
+    fail_time: u64,
+    /// Unix time in ms this node last voted for one of the node's replicas.
+    voted_time: u64,
+    /// The node's replication offset, as of its last message.
+    repl_offset: u64,
+    /// Messages for the node, written by the task owning the connection to
+    /// it while that is open.
+    link: Option<mpsc::UnboundedSender<Bytes>>,
+}
+
+impl Node {
+    fn new(id: String, ip: String, port: u16, cport: u16, flags: u8) -> Node {
+        Node {
+            id,
+            ip,
+            port,
+            cport,
+            flags,
+            master: None,
+            config_epoch: 0,
+            ping_sent: 0,
+            pong_received: 0,
+            created: unix_time_ms() as u64,
+            fail_reports: HashMap::new(),
+            fail_time: 0,
+            voted_time: 0,
+            repl_offset: 0,
+            link: None,
+        }
+    }
+
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.link.as_ref().is_some_and(|link| !link.is_closed())
+    }
+}
+
+struct ClusterState {
+    myself: String,
+    current_epoch: u64,
+    nodes: HashMap<String, Node>,
+    /// The master serving each slot.
+    slots: Vec<Option<String>>,
+    /// Slots of this node being moved to another one, by target node.
+    migrating: BTreeMap<u16, String>,
+    /// Slots being moved to this node, by source node.
+    importing: BTreeMap<u16, String>,
+    /// Whether every slot is served by a master that isn't failing.
+    ok: bool,
+    /// The epoch this node last voted in.
+    last_vote_epoch: u64,
+    /// The election this replica holds to replace its master.
+    election: Option<Election>,
+    /// The manual failover this replica is going through.
+    manual_failover: Option<ManualFailover>,
+    /// Until when this master holds off writes for a replica taking over,
+    /// as a Unix time in ms, and that replica.
+    paused: Option<(u64, String)>,
+    /// Whether the configuration changed since it was last saved.
+    save_pending: bool,
+    messages_sent: u64,
+    messages_received: u64,
+}
+
+impl ClusterState {
+    fn myself(&self) -> &Node {
+        &self.nodes[&self.myself]
+    }
+
+    fn myself_mut(&mut self) -> &mut Node {
+        self.nodes.get_mut(&self.myself).unwrap()
+    }
+
+    /// The slots served by node `id`, as inclusive ranges.
+    fn slot_ranges(&self, id: &str) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for slot in 0..SLOT_COUNT {
+            if self.slots[slot as usize].as_deref() != Some(id) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    fn update_state(&mut self) {
+        self.ok = self.slots.iter().all(|owner| {
+            owner
+                .as_ref()
+                .and_then(|id| self.nodes.get(id))
+                .is_some_and(|node| !node.has(FAIL))
+        });
+    }
+
+    /// Sends `message` to node `id` if there is a connection to it.
+    fn send(&mut self, id: &str, message: &Message) {
+        let data = message.encode();
+        if let Some(link) = self.nodes.get(id).and_then(|node| node.link.as_ref())
+            && link.send(data).is_ok()
+        {
+            self.messages_sent += 1;
+        }
+    }
+
+    /// Sends `message` to every node this one is connected to.
+    fn broadcast(&mut self, message: &Message) {
+        let ids: Vec<String> = self
+            .nodes
+            .values()
+            .filter(|node| !node.has(MYSELF | HANDSHAKE))
+            .map(|node| node.id.clone())
+            .collect();
+        for id in ids {
+            self.send(&id, message);
+        }
+    }
+
+    /// Votes needed to fail a node over: a majority of the masters serving
+    /// slots.
+    fn quorum(&self) -> usize {
+        let masters: HashSet<&String> = self.slots.iter().flatten().collect();
+        masters.len() / 2 + 1
+    }
+
+    /// A message from this node, carrying its view of the other nodes.
+    /// `repl_offset` is this node's replication offset.
+    fn message(&self, kind: MessageKind, repl_offset: u64) -> Message {
+        let myself = self.myself();
+        Message {
+            kind,
+            sender: myself.id.clone(),
+            port: myself.port,
+            cport: myself.cport,
+            flags: myself.flags & !MYSELF,
+            master: myself.master.clone(),
+            config_epoch: myself.config_epoch,
+            current_epoch: self.current_epoch,
+            slots: self.slot_ranges(&myself.id),
+            repl_offset,
+            mflags: if self.paused.is_some() { PAUSED } else { 0 },
+            about: None,
+            gossip: self
+                .nodes
+                .values()
+                .filter(|node| !node.has(MYSELF | HANDSHAKE))
+                .map(|node| Gossip {
+                    id: node.id.clone(),
+                    ip: node.ip.clone(),
+                    port: node.port,
+                    cport: node.cport,
+                    flags: node.flags,
+                })
+                .collect(),
+        }
+    }
+
+    /// Starts meeting the node at `ip:port` unless that is already under
+    /// way.
+    fn start_handshake(&mut self, ip: &str, port: u16, cport: u16) {
+        let known = self
+            .nodes
+            .values()
+            .any(|node| node.has(HANDSHAKE) && node.ip == ip && node.port == port);
+        if !known {
+            let node = Node::new(random_id(), ip.to_string(), port, cport, HANDSHAKE);
+            self.nodes.insert(node.id.clone(), node);
+        }
+    }
+}
+
+/// Cluster state shared by every connection.
+#[derive(Clone)]
+pub struct Cluster {
+    state: Arc<Mutex<ClusterState>>,
+}
+
+impl Cluster {
+    pub fn new() -> Cluster {
+        let myself = Node::new(random_id(), NODE_IP.to_string(), 0, 0, MYSELF | MASTER);
+        Cluster {
+            state: Arc::new(Mutex::new(ClusterState {
+                myself: myself.id.clone(),
+                current_epoch: 0,
+                nodes: HashMap::from([(myself.id.clone(), myself)]),
+                slots: vec![None; SLOT_COUNT as usize],
+                migrating: BTreeMap::new(),
+                importing: BTreeMap::new(),
+                ok: false,
+                last_vote_epoch: 0,
+                election: None,
+                manual_failover: None,
+                paused: None,
+                save_pending: false,
+                messages_sent: 0,
+                messages_received: 0,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ClusterState> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// A replica's attempt at being voted in to replace its master.
+struct Election {
+    /// Unix time in ms to ask for votes at. Replicas further behind wait
+    /// longer, so the most up to date one usually wins.
+    start: u64,
+    /// The epoch votes were asked for in, 0 until they are.
+    epoch: u64,
+    votes: usize,
+}
+
+/// A failover asked for with CLUSTER FAILOVER.
+struct ManualFailover {
+    /// Unix time in ms the failover is given up at.
+    end: u64,
+    /// Whether the election can start, either straight away with FORCE or
+    /// once this replica caught up with its paused master.
+    ready: bool,
+    /// The offset the master paused writes at.
+    master_offset: Option<u64>,
+}
+
+/// Replication changes decided while the cluster state was locked.
+enum RoleChange {
+    Promote,
+    Follow(String, u16),
+}
+
+impl RoleChange {
+    fn apply(self, db: &Db) {
+        match self {
+            RoleChange::Promote => replication::promote(db),
+            RoleChange::Follow(host, port) => {
+                replication::follow(db, host, port);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MessageKind {
+    Ping,
+    Pong,
+    /// A PING that also asks the receiver to add the sender.
+    Meet,
+    /// Tells every node that `about` failed.
+    Fail,
+    /// A replica asking masters to vote for it replacing its master.
+    AuthRequest,
+    /// A master's vote.
+    AuthAck,
+    /// A replica asking its master to pause writes for a manual failover.
+    MfStart,
+}
+
+impl MessageKind {
+    fn name(self) -> &'static str {
+        match self {
+            MessageKind::Ping => "ping",
+            MessageKind::Pong => "pong",
+            MessageKind::Meet => "meet",
+            MessageKind::Fail => "fail",
+            MessageKind::AuthRequest => "auth-request",
+            MessageKind::AuthAck => "auth-ack",
+            MessageKind::MfStart => "mfstart",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<MessageKind> {
+        [
+            MessageKind::Ping,
+            MessageKind::Pong,
+            MessageKind::Meet,
+            MessageKind::Fail,
+            MessageKind::AuthRequest,
+            MessageKind::AuthAck,
+            MessageKind::MfStart,
+        ]
+        .into_iter()
+        .find(|kind| kind.name() == name)
+    }
+}
+
+/// What a node tells another about one of the nodes it knows.
+#[derive(Clone, Debug, PartialEq)]
+struct Gossip {
+    id: String,
+    ip: String,
+    port: u16,
+    cport: u16,
+    flags: u8,
+}
+
+/// A cluster bus message: the sender's own state followed by gossip about
+/// other nodes. Sent as a RESP array of bulk strings.
+#[derive(Clone, Debug, PartialEq)]
+struct Message {
+    kind: MessageKind,
+    sender: String,
+    port: u16,
+    cport: u16,
+    flags: u8,
+    master: Option<String>,
+    config_epoch: u64,
+    current_epoch: u64,
+    slots: Vec<(u16, u16)>,
+    repl_offset: u64,
+    mflags: u8,
+    /// The node a FAIL message is about.
+    about: Option<String>,
+    gossip: Vec<Gossip>,
+}
+
+/// Fields before the gossip entries in an encoded message.
+const HEADER_FIELDS: usize = 12;
+
+/// Fields each gossip entry takes up in an encoded message.
+const GOSSIP_FIELDS: usize = 5;
+
+impl Message {
+    fn encode(&self) -> Bytes {
+        let mut fields = vec![
+            self.kind.name().to_string(),
+            self.sender.clone(),
+            self.port.to_string(),
+            self.cport.to_string(),
+            self.flags.to_string(),
+            self.master.clone().unwrap_or_else(|| "-".to_string()),
+            self.config_epoch.to_string(),
+            self.current_epoch.to_string(),
+            format_ranges(&self.slots, ","),
+            self.repl_offset.to_string(),
+            self.mflags.to_string(),
+            self.about.clone().unwrap_or_else(|| "-".to_string()),
+        ];
+        for gossip in &self.gossip {
+            fields.extend([
+                gossip.id.clone(),
+                gossip.ip.clone(),
+                gossip.port.to_string(),
+                gossip.cport.to_string(),
+                gossip.flags.to_string(),
+            ]);
+        }
+
+        let mut buf = BytesMut::new();
+        RespValue::Array(
+            fields
+                .into_iter()
+                .map(|field| RespValue::BulkString(Bytes::from(field)))
+                .collect(),
+        )
+        .write_to(&mut buf, Protocol::Resp2);
+        buf.freeze()
+    }
+
+    fn decode(frame: RespValue) -> Option<Message> {
+        let RespValue::Array(items) = frame else {
+            return None;
+        };
+        let fields: Vec<String> = items
+            .into_iter()
+            .map(|item| match item {
+                RespValue::BulkString(s) => String::from_utf8(s.to_vec()).ok(),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        if fields.len() < HEADER_FIELDS
+            || !(fields.len() - HEADER_FIELDS).is_multiple_of(GOSSIP_FIELDS)
+        {
+            return None;
+        }
+
+        let kind = MessageKind::from_name(&fields[0])?;
+        let gossip = fields[HEADER_FIELDS..]
+            .chunks(GOSSIP_FIELDS)
+            .map(|entry| {
+                Some(Gossip {
+                    id: entry[0].clone(),
+                    ip: entry[1].clone(),
+                    port: entry[2].parse().ok()?,
+                    cport: entry[3].parse().ok()?,
+                    flags: entry[4].parse().ok()?,
+                })
+            })
+            .collect::<Option<_>>()?;
+
+        Some(Message {
+            kind,
+            sender: fields[1].clone(),
+            port: fields[2].parse().ok()?,
+            cport: fields[3].parse().ok()?,
+            flags: fields[4].parse().ok()?,
+            master: (fields[5] != "-").then(|| fields[5].clone()),
+            config_epoch: fields[6].parse().ok()?,
+            current_epoch: fields[7].parse().ok()?,
+            slots: parse_ranges(&fields[8], ',')?,
+            repl_offset: fields[9].parse().ok()?,
+            mflags: fields[10].parse().ok()?,
+            about: (fields[11] != "-").then(|| fields[11].clone()),
+            gossip,
+        })
+    }
+}
+
+/// Formats slot ranges as `0-5460`, with single slots on their own.
+fn format_ranges(ranges: &[(u16, u16)], separator: &str) -> String {
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+fn parse_ranges(s: &str, separator: char) -> Option<Vec<(u16, u16)>> {
+    s.split(separator)
+        .filter(|range| !range.is_empty())
+        .map(|range| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+            (start <= end && end < SLOT_COUNT).then_some((start, end))
+        })
+        .collect()
+}
+
+fn flags_to_string(flags: u8) -> String {
+    let names: Vec<&str> = FLAG_NAMES
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if names.is_empty() {
+        "noflags".to_string()
+    } else {
+        names.join(",")
+    }
+}
+
+fn parse_flags(s: &str) -> Option<u8> {
+    s.split(',')
+        .filter(|name| *name != "noflags")
+        .map(|name| {
+            FLAG_NAMES
+                .iter()
+                .find(|(_, flag_name)| *flag_name == name)
+                .map(|(flag, _)| *flag)
+        })
+        .try_fold(0, |flags, flag| Some(flags | flag?))
+}
+
+/// Applies a message received from node `msg.sender` at `ip`, returning
+/// the reply to send back, if any. `link_node` is the node whose
+/// connection the message arrived on, for replies to this node's messages.
+fn process(db: &Db, msg: Message, ip: IpAddr, link_node: Option<&str>) -> Option<Message> {
+    let (reply, change) = {
+        let mut lock = db.cluster().lock();
+        let offset = db.replication().offset();
+        let timeout = db.config().cluster_node_timeout();
+        process_locked(&mut lock, msg, ip, link_node, offset, timeout)
+    };
+    if let Some(change) = change {
+        change.apply(db);
+    }
+    reply
+}
+
+fn process_locked(
+    lock: &mut ClusterState,
+    msg: Message,
+    ip: IpAddr,
+    link_node: Option<&str>,
+    offset: u64,
+    timeout: u64,
+) -> (Option<Message>, Option<RoleChange>) {
+    lock.messages_received += 1;
+    let now = unix_time_ms() as u64;
+
+    if msg.current_epoch > lock.current_epoch {
+        lock.current_epoch = msg.current_epoch;
+        lock.save_pending = true;
+    }
+
+    // A node being met answers with its real id, which replaces the one it
+    // was given in the meantime
+    if msg.kind == MessageKind::Pong
+        && let Some(id) = link_node
+        && lock.nodes.get(id).is_some_and(|node| node.has(HANDSHAKE))
+    {
+        let mut node = lock.nodes.remove(id).unwrap();
+        if !lock.nodes.contains_key(&msg.sender) {
+            node.id = msg.sender.clone();
+            node.flags &= !HANDSHAKE;
+            lock.nodes.insert(node.id.clone(), node);
+            lock.save_pending = true;
+        }
+    }
+    if msg.kind == MessageKind::Meet && !lock.nodes.contains_key(&msg.sender) {
+        let node = Node::new(
+            msg.sender.clone(),
+            ip.to_string(),
+            msg.port,
+            msg.cport,
+            msg.flags & (MASTER | REPLICA),
+        );
+        lock.nodes.insert(node.id.clone(), node);
+        lock.save_pending = true;
+    }
+
+    // Only nodes already part of the cluster are listened to
+    let mut reply = matches!(msg.kind, MessageKind::Ping | MessageKind::Meet)
+        .then(|| lock.message(MessageKind::Pong, offset));
+    let Some(sender) = lock.nodes.get_mut(&msg.sender) else {
+        return (reply, None);
+    };
+
+    sender.ip = ip.to_string();
+    sender.port = msg.port;
+    sender.cport = msg.cport;
+    sender.flags = (sender.flags & !(MASTER | REPLICA)) | (msg.flags & (MASTER | REPLICA));
+    sender.master = msg.master.clone();
+    sender.config_epoch = msg.config_epoch;
+    sender.repl_offset = msg.repl_offset;
+    if msg.kind == MessageKind::Pong {
+        sender.pong_received = now;
+        sender.ping_sent = 0;
+        sender.flags &= !PFAIL;
+        clear_failure_if_needed(lock, &msg.sender, now, timeout);
+    }
+
+    let mut change = None;
+    if msg.flags & MASTER != 0 {
+        change = claim_slots(lock, &msg);
+        handle_epoch_collision(lock, &msg);
+    }
+
+    for gossip in &msg.gossip {
+        if gossip.id == lock.myself {
+            continue;
+        }
+        let Some(node) = lock.nodes.get_mut(&gossip.id) else {
+            if gossip.flags & HANDSHAKE == 0 {
+                lock.start_handshake(&gossip.ip, gossip.port, gossip.cport);
+            }
+            continue;
+        };
+        // Only masters get a say in whether a node failed
+        if msg.flags & MASTER == 0 {
+            continue;
+        }
+        if gossip.flags & (PFAIL | FAIL) != 0 {
+            node.fail_reports.insert(msg.sender.clone(), now);
+            mark_failing_if_needed(lock, &gossip.id, now, timeout, offset);
+        } else {
+            node.fail_reports.remove(&msg.sender);
+        }
+    }
+
+    match msg.kind {
+        MessageKind::Fail => {
+            if let Some(id) = &msg.about
+                && *id != lock.myself
+                && let Some(node) = lock.nodes.get_mut(id)
+                && !node.has(FAIL)
+            {
+                node.flags = (node.flags | FAIL) & !PFAIL;
+                node.fail_time = now;
+                lock.update_state();
+                lock.save_pending = true;
+            }
+        }
+        MessageKind::AuthRequest => reply = vote(lock, &msg, now, timeout, offset),
+        MessageKind::AuthAck => {
+            let serves_slots = lock.slots.contains(&Some(msg.sender.clone()));
+            if let Some(election) = &mut lock.election
+                && election.epoch != 0
+                && msg.current_epoch >= election.epoch
+                && serves_slots
+            {
+                election.votes += 1;
+            }
+        }
+        MessageKind::MfStart => {
+            if lock.myself().has(MASTER) && msg.master.as_ref() == Some(&lock.myself) {
+                lock.paused = Some((now + MANUAL_FAILOVER_TIMEOUT_MS, msg.sender.clone()));
+                reply = Some(lock.message(MessageKind::Ping, offset));
+            }
+        }
+        MessageKind::Ping | MessageKind::Pong | MessageKind::Meet => {}
+    }
+
+    // A master taking part in a manual failover tells how far it got
+    // before pausing
+    if msg.mflags & PAUSED != 0
+        && lock.myself().master.as_ref() == Some(&msg.sender)
+        && let Some(manual) = &mut lock.manual_failover
+        && manual.master_offset.is_none()
+    {
+        manual.master_offset = Some(msg.repl_offset);
+    }
+    (reply, change)
+}
+
+/// Gives the sender the slots it claims, unless they are held by a node
+/// whose claim is more recent. When that takes the last slots of this
+/// node's master, or of this node, it follows the sender instead.
+fn claim_slots(lock: &mut ClusterState, msg: &Message) -> Option<RoleChange> {
+    let myself = lock.myself();
+    let my_master = if myself.has(MASTER) {
+        Some(myself.id.clone())
+    } else {
+        myself.master.clone()
+    };
+
+    let mut changed = false;
+    let mut lost_by_my_master = false;
+    for &(start, end) in &msg.slots {
+        for slot in start..=end {
+            let owner = &lock.slots[slot as usize];
+            if owner.as_deref() == Some(msg.sender.as_str()) {
+                continue;
+            }
+            let owner_epoch = owner
+                .as_ref()
+                .and_then(|id| lock.nodes.get(id))
+                .map(|node| node.config_epoch);
+            if owner_epoch.is_none_or(|epoch| epoch < msg.config_epoch) {
+                lost_by_my_master |= owner.is_some() && *owner == my_master;
+                lock.slots[slot as usize] = Some(msg.sender.clone());
+                changed = true;
+            }
+        }
+    }
+    if changed {
+        lock.update_state();
+        lock.save_pending = true;
+    }
+
+    let my_master = my_master?;
+    if !lost_by_my_master || lock.slots.contains(&Some(my_master)) {
+        return None;
+    }
+    let sender = &lock.nodes[&msg.sender];
+    let change = RoleChange::Follow(sender.ip.clone(), sender.port);
+    println!(
+        "Configuration change detected. Reconfiguring myself as a replica of {}",
+        msg.sender
+    );
+    let myself = lock.myself_mut();
+    myself.flags = (myself.flags & !MASTER) | REPLICA;
+    myself.master = Some(msg.sender.clone());
+    lock.election = None;
+    lock.manual_failover = None;
+    lock.paused = None;
+    Some(change)
+}
+
+/// Two masters sharing a config epoch would make their slot claims
+/// ambiguous, so the one with the smaller id moves to a new epoch.
+fn handle_epoch_collision(lock: &mut ClusterState, msg: &Message) {
+    let myself = lock.myself();
+    if !myself.has(MASTER) || msg.config_epoch != myself.config_epoch || msg.sender <= myself.id {
+        return;
+    }
+    lock.current_epoch += 1;
+    let epoch = lock.current_epoch;
+    lock.myself_mut().config_epoch = epoch;
+    lock.save_pending = true;
+}
+
+/// Marks node `id` as failed once enough masters suspect it, and tells
+/// every other node.
+fn mark_failing_if_needed(lock: &mut ClusterState, id: &str, now: u64, timeout: u64, offset: u64) {
+    let Some(node) = lock.nodes.get(id) else {
+        return;
+    };
+    if !node.has(PFAIL) || node.has(FAIL) {
+        return;
+    }
+    let reports = node
+        .fail_reports
+        .values()
+        .filter(|&&at| now.saturating_sub(at) <= timeout * 2)
+        .count();
+    let myself = usize::from(lock.myself().has(MASTER));
+    if reports + myself < lock.quorum() {
+        return;
+    }
+
+    println!("Marking node {} as failing (quorum reached).", id);
+    let node = lock.nodes.get_mut(id).unwrap();
+    node.flags = (node.flags | FAIL) & !PFAIL;
+    node.fail_time = now;
+    lock.update_state();
+    lock.save_pending = true;
+
+    let mut message = lock.message(MessageKind::Fail, offset);
+    message.about = Some(id.to_string());
+    lock.broadcast(&message);
+}
+
+/// Lifts the FAIL flag of node `id` now that it answers again, unless it
+/// is a master whose slots may still be taken over.
+fn clear_failure_if_needed(lock: &mut ClusterState, id: &str, now: u64, timeout: u64) {
+    let serves_slots = lock.slots.contains(&Some(id.to_string()));
+    let node = lock.nodes.get_mut(id).unwrap();
+    if !node.has(FAIL) {
+        return;
+    }
+    if node.has(REPLICA) || !serves_slots || now.saturating_sub(node.fail_time) > timeout * 2 {
+        println!("Clear FAIL state for node {}: is reachable again.", id);
+        node.flags &= !FAIL;
+        lock.update_state();
+        lock.save_pending = true;
+    }
+}
+
+/// Answers a replica asking to replace its master, voting for it when its
+/// master failed (or a manual failover forces it), this node hasn't voted
+/// in the epoch yet, and no newer claim exists on the slots it wants.
+fn vote(
+    lock: &mut ClusterState,
+    msg: &Message,
+    now: u64,
+    timeout: u64,
+    offset: u64,
+) -> Option<Message> {
+    let myself = lock.myself();
+    if !myself.has(MASTER) || !lock.slots.contains(&Some(myself.id.clone())) {
+        return None;
+    }
+    if msg.current_epoch < lock.current_epoch || lock.last_vote_epoch == lock.current_epoch {
+        return None;
+    }
+    let master = lock.nodes.get(msg.master.as_ref()?)?;
+    if msg.flags & REPLICA == 0
+        || (!master.has(FAIL) && msg.mflags & FORCE_ACK == 0)
+        || now.saturating_sub(master.voted_time) < timeout * 2
+    {
+        return None;
+    }
+    for &(start, end) in &msg.slots {
+        for slot in start..=end {
+            let owner_epoch = lock.slots[slot as usize]
+                .as_ref()
+                .and_then(|id| lock.nodes.get(id))
+                .map_or(0, |node| node.config_epoch);
+            if owner_epoch > msg.config_epoch {
+                return None;
+            }
+        }
+    }
+
+    println!(
+        "Failover auth granted to {} for epoch {}",
+        msg.sender, lock.current_epoch
+    );
+    lock.last_vote_epoch = lock.current_epoch;
+    lock.nodes.get_mut(msg.master.as_ref()?)?.voted_time = now;
+    lock.save_pending = true;
+    Some(lock.message(MessageKind::AuthAck, offset))
+}
+
+/// Starts the cluster bus: loads or creates the cluster configuration,
+/// listens for other nodes and starts pinging the known ones.
+pub async fn start(db: &Db) -> io::Result<()> {
+    load_config(db)?;
+    let master = {
+        let mut lock = db.cluster().lock();
+        let myself = lock.myself_mut();
+        myself.port = db.config().port();
+        myself.cport = db.config().cluster_port();
+        lock.update_state();
+        println!("Node configuration loaded, I'm {}", lock.myself);
+        let myself = lock.myself();
+        myself
+            .master
+            .as_ref()
+            .and_then(|id| lock.nodes.get(id))
+            .map(|master| RoleChange::Follow(master.ip.clone(), master.port))
+    };
+    save_config(db)?;
+    // A replica picks up where it left off
+    if let Some(change) = master {
+        change.apply(db);
+    }
+
+    let listener = TcpListener::bind((NODE_IP, db.config().cluster_port())).await?;
+    let bus_db = db.clone();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(serve_bus(socket, bus_db.clone()));
+        }
+    });
+
+    let cron_db = db.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CRON_INTERVAL);
+        loop {
+            interval.tick().await;
+            cron(&cron_db);
+        }
+    });
+    Ok(())
+}
+
+/// Opens connections to nodes that lack one, pings nodes that haven't been
+/// heard from lately, suspects those that don't answer, fails this
+/// replica's master over when needed and saves the configuration when it
+/// changed.
+fn cron(db: &Db) {
+    let now = unix_time_ms() as u64;
+    let timeout = db.config().cluster_node_timeout();
+    let handshake_timeout = timeout.max(1000);
+    let offset = db.replication().offset();
+    let mut lock = db.cluster().lock();
+
+    // Nodes that never answered being met are given up on
+    lock.nodes.retain(|_, node| {
+        !node.has(HANDSHAKE) || now.saturating_sub(node.created) < handshake_timeout
+    });
+
+    let ids: Vec<String> = lock
+        .nodes
+        .values()
+        .filter(|node| !node.has(MYSELF))
+        .map(|node| node.id.clone())
+        .collect();
+    for id in ids {
+        let node = lock.nodes.get_mut(&id).unwrap();
+        if node.ping_sent != 0
+            && now.saturating_sub(node.ping_sent) > timeout
+            && !node.has(HANDSHAKE | PFAIL | FAIL)
+        {
+            println!("*** NODE {} possibly failing", id);
+            node.flags |= PFAIL;
+            mark_failing_if_needed(&mut lock, &id, now, timeout, offset);
+        }
+
+        let node = lock.nodes.get_mut(&id).unwrap();
+        let kind = if node.has(HANDSHAKE) {
+            MessageKind::Meet
+        } else {
+            MessageKind::Ping
+        };
+        if !node.is_connected() {
+            let (tx, rx) = mpsc::unbounded_channel();
+            node.link = Some(tx);
+            tokio::spawn(run_link(
+                db.clone(),
+                id.clone(),
+                (node.ip.clone(), node.cport),
+                rx,
+            ));
+        } else if node.ping_sent != 0 || now.saturating_sub(node.pong_received) < PING_INTERVAL_MS {
+            continue;
+        }
+
+        let node = lock.nodes.get_mut(&id).unwrap();
+        if node.ping_sent == 0 {
+            node.ping_sent = now;
+        }
+        let message = lock.message(kind, offset);
+        lock.send(&id, &message);
+    }
+
+    // The replica taking over is kept informed of the offset writes
+    // stopped at
+    if let Some((until, replica)) = lock.paused.clone() {
+        if now >= until {
+            println!("Manual failover timed out.");
+            lock.paused = None;
+        } else {
+            let message = lock.message(MessageKind::Ping, offset);
+            lock.send(&replica, &message);
+        }
+    }
+
+    let change = failover_cron(&mut lock, now, timeout, offset);
+    let save = std::mem::take(&mut lock.save_pending);
+    drop(lock);
+    if let Some(change) = change {
+        change.apply(db);
+    }
+    if save && let Err(e) = save_config(db) {
+        eprintln!("Could not save the cluster configuration: {}", e);
+    }
+}
+
+/// Runs this replica's election once its master failed or a manual
+/// failover is ready, returning the change to make when it won.
+fn failover_cron(
+    lock: &mut ClusterState,
+    now: u64,
+    timeout: u64,
+    offset: u64,
+) -> Option<RoleChange> {
+    let myself = lock.myself();
+    if !myself.has(REPLICA) {
+        return None;
+    }
+    let master_id = myself.master.clone()?;
+
+    if let Some(manual) = &mut lock.manual_failover {
+        if now >= manual.end {
+            println!("Manual failover timed out.");
+            lock.manual_failover = None;
+            lock.election = None;
+        } else if !manual.ready && manual.master_offset.is_some_and(|at| offset >= at) {
+            println!("All master replication stream processed, manual failover can start.");
+            manual.ready = true;
+        }
+    }
+    let manual = lock.manual_failover.as_ref().is_some_and(|m| m.ready);
+    let master = lock.nodes.get(&master_id)?;
+    let master_epoch = master.config_epoch;
+    let master_slots = lock.slot_ranges(&master_id);
+    if !(master.has(FAIL) || manual) || master_slots.is_empty() {
+        lock.election = None;
+        return None;
+    }
+
+    // An election that didn't get enough votes is retried after a while
+    let auth_timeout = (timeout * 2).max(2000);
+    if lock
+        .election
+        .as_ref()
+        .is_none_or(|e| e.epoch != 0 && now.saturating_sub(e.start) > auth_timeout * 2)
+    {
+        let delay = if manual {
+            0
+        } else {
+            // Replicas that got further down the replication stream go
+            // first
+            let rank = lock
+                .nodes
+                .values()
+                .filter(|node| node.master.as_ref() == Some(&master_id) && !node.has(MYSELF))
+                .filter(|node| node.repl_offset > offset)
+                .count() as u64;
+            let jitter = u64::from_str_radix(&random_id()[..4], 16).unwrap_or(0) % 500;
+            500 + jitter + rank * 1000
+        };
+        println!(
+            "Start of election delayed for {} milliseconds (offset {}).",
+            delay, offset
+        );
+        lock.election = Some(Election {
+            start: now + delay,
+            epoch: 0,
+            votes: 0,
+        });
+        return None;
+    }
+
+    let election = lock.election.as_ref().unwrap();
+    if now < election.start || now.saturating_sub(election.start) > auth_timeout {
+        return None;
+    }
+    if election.epoch == 0 {
+        lock.current_epoch += 1;
+        lock.save_pending = true;
+        let epoch = lock.current_epoch;
+        lock.election.as_mut().unwrap().epoch = epoch;
+        println!("Starting a failover election for epoch {}.", epoch);
+
+        let mut request = lock.message(MessageKind::AuthRequest, offset);
+        request.slots = master_slots;
+        request.config_epoch = master_epoch;
+        if manual {
+            request.mflags |= FORCE_ACK;
+        }
+        lock.broadcast(&request);
+        return None;
+    }
+    if election.votes < lock.quorum() {
+        return None;
+    }
+
+    let epoch = election.epoch;
+    println!("Failover election won: I'm the new master.");
+    Some(promote_myself(lock, epoch, offset))
+}
+
+/// Turns this replica into a master taking over its master's slots with
+/// config epoch `epoch`, and tells every other node.
+fn promote_myself(lock: &mut ClusterState, epoch: u64, offset: u64) -> RoleChange {
+    lock.election = None;
+    lock.manual_failover = None;
+    let myself = lock.myself_mut();
+    let master = myself.master.take();
+    myself.flags = (myself.flags & !REPLICA) | MASTER;
+    myself.config_epoch = epoch;
+    println!("configEpoch set to {} after successful failover", epoch);
+
+    let id = lock.myself.clone();
+    for owner in lock.slots.iter_mut() {
+        if owner.is_some() && *owner == master {
+            *owner = Some(id.clone());
+        }
+    }
+    lock.update_state();
+    lock.save_pending = true;
+    let pong = lock.message(MessageKind::Pong, offset);
+    lock.broadcast(&pong);
+    RoleChange::Promote
+}
+
+/// Owns the connection to node `id`: writes the messages queued for it and
+/// applies its replies, until the connection fails.
+async fn run_link(
+    db: Db,
+    id: String,
+    addr: (String, u16),
+    mut messages: mpsc::UnboundedReceiver<Bytes>,
+) {
+    let Ok(mut socket) = TcpStream::connect((addr.0.as_str(), addr.1)).await else {
+        return;
+    };
+    let Ok(peer) = socket.peer_addr() else {
+        return;
+    };
+
+    let mut input = BytesMut::with_capacity(4096);
+    loop {
+        tokio::select! {
+            message = messages.recv() => match message {
+                Some(data) => {
+                    if socket.write_all(&data).await.is_err() {
+                        return;
+                    }
+                }
+                None => return,
+            },
+            read = socket.read_buf(&mut input) => {
+                if !matches!(read, Ok(n) if n > 0) {
+                    return;
+                }
+                while let Some(msg) = take_message(&mut input) {
+                    let Some(msg) = msg else {
+                        return;
+                    };
+                    process(&db, msg, peer.ip(), Some(&id));
+                }
+            }
+        }
+    }
+}
+
+/// Serves a connection another node opened to this one's bus, answering
+/// its PINGs.
+async fn serve_bus(mut socket: TcpStream, db: Db) {
+    let Ok(peer) = socket.peer_addr() else {
+        return;
+    };
+    let mut input = BytesMut::with_capacity(4096);
+    loop {
+        if !matches!(socket.read_buf(&mut input).await, Ok(n) if n > 0) {
+            return;
+        }
+        while let Some(msg) = take_message(&mut input) {
+            let Some(msg) = msg else {
+                return;
+            };
+            if let Some(reply) = process(&db, msg, peer.ip(), None) {
+                db.cluster().lock().messages_sent += 1;
+                if socket.write_all(&reply.encode()).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Takes the next message out of `input`: None when it is incomplete,
+/// Some(None) when it is malformed.
+fn take_message(input: &mut BytesMut) -> Option<Option<Message>> {
+    let mut cursor = Cursor::new(&input[..]);
+    let frame = match parse_resp(&mut cursor) {
+        Ok(frame) => frame,
+        Err(ProtocolError::Incomplete) => return None,
+        Err(_) => return Some(None),
+    };
+    let len = cursor.position() as usize;
+    let _ = input.split_to(len);
+    Some(Message::decode(frame))
+}
+
+fn config_path(db: &Db) -> PathBuf {
+    Path::new(&db.config().dir()).join(db.config().cluster_config_file())
+}
+
+/// Writes the cluster configuration in the CLUSTER NODES format, followed
+/// by the epochs.
+fn save_config(db: &Db) -> io::Result<()> {
+    let contents = {
+        let lock = db.cluster().lock();
+        let mut contents = nodes_description(&lock, |node| !node.has(HANDSHAKE));
+        contents.push_str(&format!(
+            "vars currentEpoch {} lastVoteEpoch {}\n",
+            lock.current_epoch, lock.last_vote_epoch
+        ));
+        contents
+    };
+
+    let path = config_path(db);
+    let temp = path.with_file_name(format!("temp-{}.nodes", std::process::id()));
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, &path)
+}
+
+/// Loads the configuration saved by a previous run, keeping this node's
+/// identity, the nodes it knew and their slots.
+fn load_config(db: &Db) -> io::Result<()> {
+    let contents = match fs::read_to_string(config_path(db)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupted cluster config file");
+
+    let mut lock = db.cluster().lock();
+    let mut myself = None;
+    let mut nodes = HashMap::new();
+    let mut slots = vec![None; SLOT_COUNT as usize];
+    let mut migrating = BTreeMap::new();
+    let mut importing = BTreeMap::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields[0] == "vars" {
+            for pair in fields[1..].chunks(2) {
+                match pair {
+                    ["currentEpoch", value] => {
+                        lock.current_epoch = value.parse().map_err(|_| corrupt())?;
+                    }
+                    ["lastVoteEpoch", value] => {
+                        lock.last_vote_epoch = value.parse().map_err(|_| corrupt())?;
+                    }
+                    _ => {}
+                }
+            }
+            continue;
+        }
+        if fields.len() < 8 {
+            return Err(corrupt());
+        }
+
+        let (addr, cport) = fields[1].split_once('@').ok_or_else(corrupt)?;
+        let (ip, port) = addr.rsplit_once(':').ok_or_else(corrupt)?;
+        let mut node = Node::new(
+            fields[0].to_string(),
+            ip.to_string(),
+            port.parse().map_err(|_| corrupt())?,
+            cport.parse().map_err(|_| corrupt())?,
+            parse_flags(fields[2]).ok_or_else(corrupt)?,
+        );
+        node.master = (fields[3] != "-").then(|| fields[3].to_string());
+        node.config_epoch = fields[6].parse().map_err(|_| corrupt())?;
+        let (transfers, ranges): (Vec<&str>, Vec<&str>) =
+            fields[8..].iter().partition(|field| field.starts_with('['));
+        for &(start, end) in &parse_ranges(&ranges.join(" "), ' ').ok_or_else(corrupt)? {
+            for slot in start..=end {
+                slots[slot as usize] = Some(node.id.clone());
+            }
+        }
+        for transfer in transfers {
+            let transfer = transfer
+                .strip_prefix('[')
+                .and_then(|t| t.strip_suffix(']'))
+                .ok_or_else(corrupt)?;
+            if let Some((slot, target)) = transfer.split_once("->-") {
+                migrating.insert(slot.parse().map_err(|_| corrupt())?, target.to_string());
+            } else if let Some((slot, source)) = transfer.split_once("-<-") {
+                importing.insert(slot.parse().map_err(|_| corrupt())?, source.to_string());
+            } else {
+                return Err(corrupt());
+            }
+        }
+        if node.has(MYSELF) {
+            myself = Some(node.id.clone());
+        }
+        nodes.insert(node.id.clone(), node);
+    }
+
+    lock.myself = myself.ok_or_else(corrupt)?;
+    lock.nodes = nodes;
+    lock.slots = slots;
+    lock.migrating = migrating;
+    lock.importing = importing;
+    Ok(())
+}
+
+/// One CLUSTER NODES line per node accepted by `filter`.
+fn nodes_description(lock: &ClusterState, filter: impl Fn(&Node) -> bool) -> String {
+    let mut nodes: Vec<&Node> = lock.nodes.values().filter(|node| filter(node)).collect();
+    nodes.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let mut out = String::new();
+    for node in nodes {
+        let link_state = if node.has(MYSELF) || node.is_connected() {
+            "connected"
+        } else {
+            "disconnected"
+        };
+        out.push_str(&format!(
+            "{} {}@{} {} {} {} {} {} {}",
+            node.id,
+            node.addr(),
+            node.cport,
+            flags_to_string(node.flags),
+            node.master.as_deref().unwrap_or("-"),
+            node.ping_sent,
+            node.pong_received,
+            node.config_epoch,
+            link_state
+        ));
+        let ranges = lock.slot_ranges(&node.id);
+        if !ranges.is_empty() {
+            out.push(' ');
+            out.push_str(&format_ranges(&ranges, " "));
+        }
+        if node.has(MYSELF) {
+            for (slot, target) in &lock.migrating {
+                out.push_str(&format!(" [{}->-{}]", slot, target));
+            }
+            for (slot, source) in &lock.importing {
+                out.push_str(&format!(" [{}-<-{}]", slot, source));
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Checks that this node serves the keys of the command in `frame`,
+/// redirecting the client to the node that does otherwise. `asking` is
+/// whether the client sent ASKING just before, allowing it into a slot
+/// still being imported.
+pub fn check_route(
+    db: &Db,
+    spec: &CommandSpec,
+    args: &[RespValue],
+    asking: bool,
+) -> Result<(), CommandError> {
+    // MIGRATE only ever moves keys this node holds, reporting the others
+    // as missing
+    if spec.name == "migrate" {
+        return Ok(());
+    }
+
+    let mut slot = None;
+    let mut keys = Vec::new();
+    for key in spec.keys_of(args) {
+        let RespValue::BulkString(key) = key else {
+            continue;
+        };
+        let key_slot = key_hash_slot(key);
+        if slot.is_some_and(|slot| slot != key_slot) {
+            return Err(CommandError::CrossSlot);
+        }
+        slot = Some(key_slot);
+        keys.push(key);
+    }
+    let Some(slot) = slot else {
+        return Ok(());
+    };
+
+    let (owner, migrating_to, importing) = {
+        let lock = db.cluster().lock();
+        if !lock.ok && db.config().cluster_require_full_coverage() {
+            return Err(CommandError::ClusterDown("The cluster is down"));
+        }
+        let Some(owner) = &lock.slots[slot as usize] else {
+            return Err(CommandError::ClusterDown("Hash slot not served"));
+        };
+        let owner = (*owner != lock.myself).then(|| lock.nodes[owner].addr());
+        let migrating_to = lock
+            .migrating
+            .get(&slot)
+            .and_then(|id| lock.nodes.get(id))
+            .map(Node::addr);
+        (owner, migrating_to, lock.importing.contains_key(&slot))
+    };
+    let missing = keys.iter().filter(|key| !db.exists(key)).count();
+
+    match owner {
+        // Keys already moved away are looked up on the target
+        None => match migrating_to {
+            Some(target) if missing > 0 => Err(CommandError::Ask(slot, target)),
+            _ => Ok(()),
+        },
+        Some(_) if importing && (asking || spec.has_flag(ASKING)) => {
+            // Only part of the keys may have arrived yet
+            if keys.len() > 1 && missing > 0 {
+                Err(CommandError::TryAgain)
+            } else {
+                Ok(())
+            }
+        }
+        Some(owner) => Err(CommandError::Moved(slot, owner)),
+    }
+}
+
+fn check_enabled(db: &Db) -> Result<(), CommandError> {
+    if db.config().cluster_enabled() {
+        Ok(())
+    } else {
+        Err(CommandError::Err(
+            "This instance has cluster support disabled".to_string(),
+        ))
+    }
+}
+
+/// CLUSTER MEET: starts a handshake with the node at `ip:port`.
+pub fn meet(db: &Db, ip: &str, port: u16, cport: Option<u16>) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    let Ok(ip) = ip.parse::<IpAddr>() else {
+        return Err(CommandError::Err(format!(
+            "Invalid node address specified: {}:{}",
+            ip, port
+        )));
+    };
+    let cport = cport.unwrap_or(port.wrapping_add(10000));
+    db.cluster()
+        .lock()
+        .start_handshake(&ip.to_string(), port, cport);
+    Ok(RespValue::SimpleString("OK".to_string()))
+}
+
+pub fn myid(db: &Db) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    Ok(RespValue::BulkString(Bytes::from(
+        db.cluster().lock().myself.clone(),
+    )))
+}
+
+pub fn nodes(db: &Db) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    let lock = db.cluster().lock();
+    Ok(RespValue::Verbatim(
+        "txt".to_string(),
+        nodes_description(&lock, |_| true),
+    ))
+}
+
+/// CLUSTER SLOTS: each range of slots with the master serving it followed
+/// by its replicas.
+pub fn slots(db: &Db) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    let lock = db.cluster().lock();
+    let describe = |node: &Node| {
+        RespValue::Array(vec![
+            RespValue::BulkString(Bytes::from(node.ip.clone())),
+            RespValue::Integer(node.port as i64),
+            RespValue::BulkString(Bytes::from(node.id.clone())),
+            RespValue::Map(vec![]),
+        ])
+    };
+
+    let mut masters: Vec<&Node> = lock
+        .nodes
+        .values()
+        .filter(|node| node.has(MASTER))
+        .collect();
+    masters.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut ranges = Vec::new();
+    for master in masters {
+        for (start, end) in lock.slot_ranges(&master.id) {
+            let mut entry = vec![
+                RespValue::Integer(start as i64),
+                RespValue::Integer(end as i64),
+                describe(master),
+            ];
+            entry.extend(
+                replicas_of(&lock, &master.id)
+                    .into_iter()
+                    .filter(|replica| !replica.has(FAIL))
+                    .map(describe),
+            );
+            ranges.push((start, RespValue::Array(entry)));
+        }
+    }
+    ranges.sort_by_key(|(start, _)| *start);
+    Ok(RespValue::Array(
+        ranges.into_iter().map(|(_, entry)| entry).collect(),
+    ))
+}
+
+fn replicas_of<'a>(lock: &'a ClusterState, master: &str) -> Vec<&'a Node> {
+    let mut replicas: Vec<&Node> = lock
+        .nodes
+        .values()
+        .filter(|node| node.has(REPLICA) && node.master.as_deref() == Some(master))
+        .collect();
+    replicas.sort_by(|a, b| a.id.cmp(&b.id));
+    replicas
+}
+
+/// CLUSTER SHARDS: each master with its slots and its replicas.
+pub fn shards(db: &Db) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    let lock = db.cluster().lock();
+    let bulk = |s: &str| RespValue::BulkString(Bytes::copy_from_slice(s.as_bytes()));
+    let describe = |node: &Node| {
+        let offset = if node.has(MYSELF) {
+            db.replication().offset() as i64
+        } else {
+            0
+        };
+        RespValue::Map(vec![
+            (bulk("id"), bulk(&node.id)),
+            (bulk("port"), RespValue::Integer(node.port as i64)),
+            (bulk("ip"), bulk(&node.ip)),
+            (bulk("endpoint"), bulk(&node.ip)),
+            (
+                bulk("role"),
+                bulk(if node.has(MASTER) {
+                    "master"
+                } else {
+                    "replica"
+                }),
+            ),
+            (bulk("replication-offset"), RespValue::Integer(offset)),
+            (
+                bulk("health"),
+                bulk(if node.has(FAIL) { "fail" } else { "online" }),
+            ),
+        ])
+    };
+
+    let mut masters: Vec<&Node> = lock
+        .nodes
+        .values()
+        .filter(|node| node.has(MASTER) && !node.has(HANDSHAKE))
+        .collect();
+    masters.sort_by(|a, b| a.id.cmp(&b.id));
+    let shards = masters
+        .into_iter()
+        .map(|master| {
+            let slots = lock
+                .slot_ranges(&master.id)
+                .into_iter()
+                .flat_map(|(start, end)| {
+                    [
+                        RespValue::Integer(start as i64),
+                        RespValue::Integer(end as i64),
+                    ]
+                })
+                .collect();
+            let mut nodes = vec![describe(master)];
+            nodes.extend(replicas_of(&lock, &master.id).into_iter().map(describe));
+            RespValue::Map(vec![
+                (bulk("slots"), RespValue::Array(slots)),
+                (bulk("nodes"), RespValue::Array(nodes)),
+            ])
+        })
+        .collect();
+    Ok(RespValue::Array(shards))
+}
+
+pub fn info(db: &Db) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    let lock = db.cluster().lock();
+    let node_of = |owner: &Option<String>| owner.as_ref().and_then(|id| lock.nodes.get(id));
+    let assigned = lock.slots.iter().filter(|owner| owner.is_some()).count();
+    let pfail = lock
+        .slots
+        .iter()
+        .filter(|owner| node_of(owner).is_some_and(|node| node.has(PFAIL)))
+        .count();
+    let fail = lock
+        .slots
+        .iter()
+        .filter(|owner| node_of(owner).is_some_and(|node| node.has(FAIL)))
+        .count();
+    let size = lock
+        .nodes
+        .values()
+        .filter(|node| node.has(MASTER) && lock.slots.contains(&Some(node.id.clone())))
+        .count();
+
+    let fields = [
+        format!("cluster_state:{}", if lock.ok { "ok" } else { "fail" }),
+        format!("cluster_slots_assigned:{}", assigned),
+        format!("cluster_slots_ok:{}", assigned - pfail - fail),
+        format!("cluster_slots_pfail:{}", pfail),
+        format!("cluster_slots_fail:{}", fail),
+        format!("cluster_known_nodes:{}", lock.nodes.len()),
+        format!("cluster_size:{}", size),
+        format!("cluster_current_epoch:{}", lock.current_epoch),
+        format!("cluster_my_epoch:{}", lock.myself().config_epoch),
+        format!("cluster_stats_messages_sent:{}", lock.messages_sent),
+        format!("cluster_stats_messages_received:{}", lock.messages_received),
+    ];
+    Ok(RespValue::Verbatim(
+        "txt".to_string(),
+        fields
+            .iter()
+            .map(|field| format!("{}\r\n", field))
+            .collect(),
+    ))
+}
+
+pub fn keyslot(db: &Db, key: &[u8]) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    Ok(RespValue::Integer(key_hash_slot(key) as i64))
+}
+
+/// CLUSTER ADDSLOTS: makes this node serve slots no node serves yet.
+pub fn add_slots(db: &Db, slots: &[u16]) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    let mut lock = db.cluster().lock();
+    for (i, &slot) in slots.iter().enumerate() {
+        if lock.slots[slot as usize].is_some() {
+            return Err(CommandError::Err(format!("Slot {} is already busy", slot)));
+        }
+        if slots[..i].contains(&slot) {
+            return Err(CommandError::Err(format!(
+                "Slot {} specified multiple times",
+                slot
+            )));
+        }
+    }
+
+    let myself = lock.myself.clone();
+    for &slot in slots {
+        lock.slots[slot as usize] = Some(myself.clone());
+    }
+    lock.update_state();
+    lock.save_pending = true;
+    Ok(RespValue::SimpleString("OK".to_string()))
+}
+
+/// A CLUSTER SETSLOT change to the state of a slot.
+#[derive(Debug)]
+pub enum SetSlot {
+    /// Start moving the slot to the given node.
+    Migrating(String),
+    /// Start moving the slot here from the given node.
+    Importing(String),
+    /// Cancel moving the slot.
+    Stable,
+    /// Give the slot to the given node, ending its move.
+    Node(String),
+}
+
+/// CLUSTER SETSLOT: the steps of moving a slot from one node to another.
+pub fn set_slot(db: &Db, slot: u16, action: SetSlot) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    let mut lock = db.cluster().lock();
+    let lock = &mut *lock;
+    if lock.myself().has(REPLICA) {
+        return Err(CommandError::Err(
+            "Please use SETSLOT only with masters.".to_string(),
+        ));
+    }
+    let is_mine = lock.slots[slot as usize].as_ref() == Some(&lock.myself);
+    let master = |lock: &ClusterState, id: &str| match lock.nodes.get(id) {
+        None => Err(CommandError::Err(format!("I don't know about node {}", id))),
+        Some(node) if !node.has(MASTER) => {
+            Err(CommandError::Err("Target node is not a master".to_string()))
+        }
+        Some(_) => Ok(()),
+    };
+
+    match action {
+        SetSlot::Migrating(id) => {
+            if !is_mine {
+                return Err(CommandError::Err(format!(
+                    "I'm not the owner of hash slot {}",
+                    slot
+                )));
+            }
+            master(lock, &id)?;
+            lock.migrating.insert(slot, id);
+        }
+        SetSlot::Importing(id) => {
+            if is_mine {
+                return Err(CommandError::Err(format!(
+                    "I'm already the owner of hash slot {}",
+                    slot
+                )));
+            }
+            master(lock, &id)?;
+            lock.importing.insert(slot, id);
+        }
+        SetSlot::Stable => {
+            lock.migrating.remove(&slot);
+            lock.importing.remove(&slot);
+        }
+        SetSlot::Node(id) => {
+            if !lock.nodes.contains_key(&id) {
+                return Err(CommandError::Err(format!("Unknown node {}", id)));
+            }
+            master(lock, &id)?;
+            if is_mine && id != lock.myself && db.count_keys_in_slot(slot) > 0 {
+                return Err(CommandError::Err(format!(
+                    "Can't assign hashslot {} to a different node while I still hold keys for this hash slot.",
+                    slot
+                )));
+            }
+            lock.migrating.remove(&slot);
+            // Taking over an imported slot needs a newer claim than the
+            // previous owner's for the rest of the cluster to accept it
+            if id == lock.myself && lock.importing.remove(&slot).is_some() {
+                lock.current_epoch += 1;
+                let epoch = lock.current_epoch;
+                lock.myself_mut().config_epoch = epoch;
+            }
+            lock.slots[slot as usize] = Some(id);
+            lock.update_state();
+        }
+    }
+    lock.save_pending = true;
+    Ok(RespValue::SimpleString("OK".to_string()))
+}
+
+pub fn get_keys_in_slot(db: &Db, slot: u16, count: usize) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    Ok(RespValue::Array(
+        db.keys_in_slot(slot, count)
+            .into_iter()
+            .map(RespValue::BulkString)
+            .collect(),
+    ))
+}
+
+pub fn count_keys_in_slot(db: &Db, slot: u16) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    Ok(RespValue::Integer(db.count_keys_in_slot(slot) as i64))
+}
+
+/// ASKING: lets the client's next command into a slot being imported.
+pub fn asking(db: &Db) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    Ok(RespValue::SimpleString("OK".to_string()))
+}
+
+/// How a CLUSTER FAILOVER goes about replacing the master.
+#[derive(Debug)]
+pub enum Failover {
+    /// Pause the master's writes and take over once caught up with it.
+    Default,
+    /// Hold an election without involving the master.
+    Force,
+    /// Take over without an election.
+    Takeover,
+}
+
+/// CLUSTER FAILOVER: has this replica replace its master.
+pub fn failover(db: &Db, mode: Failover) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    let now = unix_time_ms() as u64;
+    let offset = db.replication().offset();
+    let change = {
+        let mut lock = db.cluster().lock();
+        let myself = lock.myself();
+        if !myself.has(REPLICA) {
+            return Err(CommandError::Err(
+                "You should send CLUSTER FAILOVER to a replica".to_string(),
+            ));
+        }
+        let Some(master) = myself.master.as_ref().and_then(|id| lock.nodes.get(id)) else {
+            return Err(CommandError::Err(
+                "I'm a replica but my master is unknown to me".to_string(),
+            ));
+        };
+        if matches!(mode, Failover::Default) && master.has(PFAIL | FAIL) {
+            return Err(CommandError::Err(
+                "Master is down or failed, please use CLUSTER FAILOVER FORCE".to_string(),
+            ));
+        }
+        let master = master.id.clone();
+
+        lock.election = None;
+        lock.manual_failover = Some(ManualFailover {
+            end: now + MANUAL_FAILOVER_TIMEOUT_MS,
+            ready: matches!(mode, Failover::Force),
+            master_offset: None,
+        });
+        match mode {
+            Failover::Takeover => {
+                // No agreement is sought, so the new epoch must be one no
+                // other node can have taken
+                lock.current_epoch += 1;
+                let epoch = lock.current_epoch;
+                println!("Taking over the master (user request).");
+                Some(promote_myself(&mut lock, epoch, offset))
+            }
+            Failover::Force => {
+                println!("Forced failover user request accepted.");
+                None
+            }
+            Failover::Default => {
+                println!("Manual failover user request accepted.");
+                let message = lock.message(MessageKind::MfStart, offset);
+                lock.send(&master, &message);
+                None
+            }
+        }
+    };
+    if let Some(change) = change {
+        change.apply(db);
+    }
+    Ok(RespValue::SimpleString("OK".to_string()))
+}
+
+/// CLUSTER REPLICATE: makes this node a replica of master `id`.
+pub fn replicate(db: &Db, id: &str) -> Result<RespValue, CommandError> {
+    check_enabled(db)?;
+    let change = {
+        let mut lock = db.cluster().lock();
+        let Some(master) = lock.nodes.get(id) else {
+            return Err(CommandError::Err(format!("Unknown node {}", id)));
+        };
+        if master.has(MYSELF) {
+            return Err(CommandError::Err("Can't replicate myself".to_string()));
+        }
+        if !master.has(MASTER) {
+            return Err(CommandError::Err(
+                "I can only replicate a master, not a replica.".to_string(),
+            ));
+        }
+        let change = RoleChange::Follow(master.ip.clone(), master.port);
+        if lock.myself().has(MASTER)
+            && (lock.slots.contains(&Some(lock.myself.clone())) || db.key_count() != 0)
+        {
+            return Err(CommandError::Err(
+                "To set a master the node must be empty and without assigned slots.".to_string(),
+            ));
+        }
+
+        let myself = lock.myself_mut();
+        myself.flags = (myself.flags & !MASTER) | REPLICA;
+        myself.master = Some(id.to_string());
+        lock.election = None;
+        lock.manual_failover = None;
+        lock.save_pending = true;
+        change
+    };
+    change.apply(db);
+    Ok(RespValue::SimpleString("OK".to_string()))
+}
+
+/// Holds off a write while this master pauses writes for one of its
+/// replicas to take over.
+pub async fn wait_while_paused(db: &Db, spec: &CommandSpec) {
+    if !spec.has_flag(command_table::WRITE) {
+        return;
+    }
+    while db.cluster().lock().paused.is_some() {
+        tokio::time::sleep(PAUSE_POLL_INTERVAL).await;
+    }
+}
+
+/// The `cluster` section of INFO.
+pub fn info_section(db: &Db) -> String {
+    format!(
+        "cluster_enabled:{}\r\n",
+        db.config().cluster_enabled() as u8
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Command;
+
+    fn message(kind: MessageKind, sender: &str, epoch: u64, slots: Vec<(u16, u16)>) -> Message {
+        Message {
+            kind,
+            sender: sender.to_string(),
+            port: 7001,
+            cport: 17001,
+            flags: MASTER,
+            master: None,
+            config_epoch: epoch,
+            current_epoch: epoch,
+            slots,
+            repl_offset: 0,
+            mflags: 0,
+            about: None,
+            gossip: vec![Gossip {
+                id: "c".repeat(40),
+                ip: NODE_IP.to_string(),
+                port: 7002,
+                cport: 17002,
+                flags: MASTER,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let mut msg = message(MessageKind::Meet, &"a".repeat(40), 3, vec![(0, 5), (9, 9)]);
+        msg.repl_offset = 42;
+        msg.mflags = PAUSED | FORCE_ACK;
+        msg.about = Some("b".repeat(40));
+        let data = msg.encode();
+        let mut input = BytesMut::from(&data[..]);
+        assert_eq!(take_message(&mut input), Some(Some(msg)));
+        assert!(input.is_empty());
+
+        let mut partial = BytesMut::from(&data[..data.len() - 1]);
+        assert_eq!(take_message(&mut partial), None);
+        assert_eq!(flags_to_string(MYSELF | MASTER), "myself,master");
+        assert_eq!(parse_flags("myself,master"), Some(MYSELF | MASTER));
+        assert_eq!(parse_ranges("0-5 9", ' '), Some(vec![(0, 5), (9, 9)]));
+        assert_eq!(parse_ranges("5-0", ' '), None);
+    }
+
+    #[test]
+    fn test_meet_learns_slots_and_gossip() {
+        let db = Db::new();
+        let ip: IpAddr = NODE_IP.parse().unwrap();
+        let sender = "b".repeat(40);
+
+        // Nodes that haven't met this one are answered but ignored
+        let ping = message(MessageKind::Ping, &sender, 1, vec![(0, 99)]);
+        assert!(process(&db, ping, ip, None).is_some());
+        assert_eq!(db.cluster().lock().nodes.len(), 1);
+
+        let meet = message(MessageKind::Meet, &sender, 1, vec![(0, 99)]);
+        let reply = process(&db, meet, ip, None).unwrap();
+        assert_eq!(reply.kind, MessageKind::Pong);
+
+        let lock = db.cluster().lock();
+        assert_eq!(lock.slots[50].as_deref(), Some(sender.as_str()));
+        assert_eq!(lock.slots[100], None);
+        assert_eq!(lock.current_epoch, 1);
+        // One node added by the MEET, one being met after the gossip
+        assert_eq!(lock.nodes.len(), 3);
+        assert!(
+            lock.nodes
+                .values()
+                .any(|node| node.has(HANDSHAKE) && node.port == 7002)
+        );
+    }
+
+    #[test]
+    fn test_newer_claims_win_slots() {
+        let db = Db::new();
+        let ip: IpAddr = NODE_IP.parse().unwrap();
+        let (old, new) = ("b".repeat(40), "c".repeat(40));
+        process(
+            &db,
+            message(MessageKind::Meet, &old, 2, vec![(0, 9)]),
+            ip,
+            None,
+        );
+        process(
+            &db,
+            message(MessageKind::Meet, &new, 1, vec![(5, 5)]),
+            ip,
+            None,
+        );
+        assert_eq!(db.cluster().lock().slots[5].as_deref(), Some(old.as_str()));
+
+        process(
+            &db,
+            message(MessageKind::Ping, &new, 3, vec![(5, 5)]),
+            ip,
+            None,
+        );
+        assert_eq!(db.cluster().lock().slots[5].as_deref(), Some(new.as_str()));
+    }
+
+    #[test]
+    fn test_failing_master_is_voted_out() {
+        let db = Db::new();
+        let ip: IpAddr = NODE_IP.parse().unwrap();
+        let (failing, reporter, replica) = ("b".repeat(40), "c".repeat(40), "d".repeat(40));
+        db.config().set("cluster-enabled", "yes").unwrap();
+        let mine: Vec<u16> = (0..SLOT_COUNT)
+            .filter(|slot| !matches!(slot, 1 | 2))
+            .collect();
+        add_slots(&db, &mine).unwrap();
+        process(
+            &db,
+            message(MessageKind::Meet, &failing, 1, vec![(1, 1)]),
+            ip,
+            None,
+        );
+        process(
+            &db,
+            message(MessageKind::Meet, &reporter, 2, vec![(2, 2)]),
+            ip,
+            None,
+        );
+        db.cluster().lock().nodes.get_mut(&failing).unwrap().flags |= PFAIL;
+
+        // This node and one other master make a majority of three
+        let mut report = message(MessageKind::Ping, &reporter, 2, vec![(2, 2)]);
+        report.gossip[0] = Gossip {
+            id: failing.clone(),
+            ip: NODE_IP.to_string(),
+            port: 7001,
+            cport: 17001,
+            flags: MASTER | PFAIL,
+        };
+        process(&db, report, ip, None);
+        {
+            let lock = db.cluster().lock();
+            assert!(lock.nodes[&failing].has(FAIL));
+            assert!(!lock.ok);
+        }
+
+        // One vote per epoch
+        let mut request = message(MessageKind::Meet, &replica, 1, vec![(1, 1)]);
+        request.flags = REPLICA;
+        request.master = Some(failing.clone());
+        request.current_epoch = 3;
+        process(&db, request.clone(), ip, None);
+        request.kind = MessageKind::AuthRequest;
+        let reply = process(&db, request.clone(), ip, None).unwrap();
+        assert_eq!(reply.kind, MessageKind::AuthAck);
+        assert_eq!(db.cluster().lock().last_vote_epoch, 3);
+        assert_eq!(process(&db, request, ip, None), None);
+
+        // The winner's claim moves the slot over
+        let promoted = message(MessageKind::Pong, &replica, 3, vec![(1, 1)]);
+        process(&db, promoted, ip, None);
+        let lock = db.cluster().lock();
+        assert_eq!(lock.slots[1].as_deref(), Some(replica.as_str()));
+        assert!(lock.ok);
+    }
+
+    /// Runs [`check_route`] on a call to the command in `args`.
+    fn route(db: &Db, args: &[&str], asking: bool) -> Result<(), CommandError> {
+        let frame = RespValue::Array(
+            args.iter()
+                .map(|arg| RespValue::BulkString(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        let (spec, args) = Command::resolve(frame).unwrap();
+        check_route(db, spec, &args, asking)
+    }
+
+    #[test]
+    fn test_slot_migration_redirects() {
+        let ip: IpAddr = NODE_IP.parse().unwrap();
+        let other = "b".repeat(40);
+        let slot = key_hash_slot(b"foo");
+        let target = || format!("{}:7001", NODE_IP);
+
+        // The source serves keys it still has and sends the rest on
+        let source = Db::new();
+        source.config().set("cluster-enabled", "yes").unwrap();
+        process(
+            &source,
+            message(MessageKind::Meet, &other, 0, vec![]),
+            ip,
+            None,
+        );
+        let all: Vec<u16> = (0..SLOT_COUNT).collect();
+        add_slots(&source, &all).unwrap();
+        source.set(Bytes::from("foo"), Bytes::from("v"), None);
+        set_slot(&source, slot, SetSlot::Migrating(other.clone())).unwrap();
+
+        assert_eq!(route(&source, &["GET", "foo"], false), Ok(()));
+        assert_eq!(
+            route(&source, &["DEL", "foo", "{foo}x"], false),
+            Err(CommandError::Ask(slot, target()))
+        );
+        assert!(set_slot(&source, slot, SetSlot::Node(other.clone())).is_err());
+
+        // The target only serves clients that were sent there with ASK
+        let dest = Db::new();
+        dest.config().set("cluster-enabled", "yes").unwrap();
+        process(
+            &dest,
+            message(MessageKind::Meet, &other, 1, vec![(0, SLOT_COUNT - 1)]),
+            ip,
+            None,
+        );
+        set_slot(&dest, slot, SetSlot::Importing(other.clone())).unwrap();
+        let get = &["GET", "foo"];
+        assert_eq!(
+            route(&dest, get, false),
+            Err(CommandError::Moved(slot, target()))
+        );
+        assert_eq!(route(&dest, get, true), Ok(()));
+        assert_eq!(
+            route(&dest, &["DEL", "foo", "{foo}x"], true),
+            Err(CommandError::TryAgain)
+        );
+
+        let myself = dest.cluster().lock().myself.clone();
+        set_slot(&dest, slot, SetSlot::Node(myself)).unwrap();
+        assert_eq!(route(&dest, get, false), Ok(()));
+        let lock = dest.cluster().lock();
+        assert!(lock.importing.is_empty());
+        assert_eq!(lock.myself().config_epoch, 2);
+    }
+}
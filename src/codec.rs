@@ -0,0 +1,556 @@
+use bytes::{Buf, BytesMut};
+use std::io::{self, Cursor};
+use thiserror::Error;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    error::ProtocolError,
+    resp::{MAX_NESTING_DEPTH, Protocol, RespValue, parse_resp, split_args},
+};
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Protocol error: {0}")]
+    Protocol(#[from] ProtocolError),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum AggregateKind {
+    Array,
+    Set,
+    Push,
+    Map,
+    Attribute,
+}
+
+/// A value whose header has been consumed but whose body is still arriving.
+#[derive(Debug)]
+enum Pending {
+    Aggregate {
+        kind: AggregateKind,
+        remaining: usize,
+        items: Vec<RespValue>,
+    },
+    /// Attribute entries waiting for the reply they annotate.
+    Attribute(Vec<(RespValue, RespValue)>),
+    Bulk {
+        len: usize,
+        verbatim: bool,
+    },
+}
+
+/// Bounds applied to untrusted input, so a malicious length header cannot
+/// make the server allocate or buffer without limit.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Longest accepted bulk string (`proto-max-bulk-len`).
+    pub max_bulk_len: usize,
+    /// Most elements accepted in a single aggregate.
+    pub max_multibulk_len: usize,
+    /// Deepest accepted nesting of aggregates.
+    pub max_depth: usize,
+    /// Longest accepted inline command or header line.
+    pub max_inline_len: usize,
+    /// Most data buffered for one incomplete frame
+    /// (`client-query-buffer-limit`).
+    pub max_query_buffer: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_multibulk_len: i32::MAX as usize,
+            max_depth: MAX_NESTING_DEPTH,
+            max_inline_len: 64 * 1024,
+            max_query_buffer: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// Incremental RESP codec. Fully parsed tokens are split off the read
+/// buffer as they complete (bulk payloads without copying), and partially
+/// received aggregates are kept on a stack, so a frame arriving over many
+/// reads is never rescanned from its start.
+#[derive(Debug, Default)]
+pub struct RespCodec {
+    protocol: Protocol,
+    limits: Limits,
+    stack: Vec<Pending>,
+    /// Bytes of the current, unterminated line already searched for `\n`.
+    scanned: usize,
+    /// Bytes already consumed by the frame currently being decoded.
+    consumed: usize,
+}
+
+impl RespCodec {
+    pub fn new() -> RespCodec {
+        RespCodec::default()
+    }
+
+    /// Sets the protocol replies are encoded with.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Splits the next `\n`-terminated line off `buf`, without its line
+    /// ending. Returns `None` when the line is still incomplete.
+    fn take_line(
+        &mut self,
+        buf: &mut BytesMut,
+        require_cr: bool,
+    ) -> Result<Option<BytesMut>, CodecError> {
+        let Some(offset) = buf[self.scanned..].iter().position(|&b| b == b'\n') else {
+            self.scanned = buf.len();
+            if self.scanned > self.limits.max_inline_len {
+                let inline =
+                    self.stack.is_empty() && !buf.first().is_some_and(|&b| is_type_byte(b));
+                return Err(if inline {
+                    ProtocolError::InlineTooBig
+                } else {
+                    ProtocolError::CountTooBig
+                }
+                .into());
+            }
+            return Ok(None);
+        };
+
+        let end = self.scanned + offset;
+        self.scanned = 0;
+
+        self.consumed += end + 1;
+        let mut line = buf.split_to(end + 1);
+        line.truncate(end);
+        if line.last() == Some(&b'\r') {
+            line.truncate(end - 1);
+        } else if require_cr {
+            return Err(ProtocolError::ExpectedCrlf.into());
+        }
+        Ok(Some(line))
+    }
+
+    /// Reads the next token: either a complete scalar value, or the header
+    /// of a value whose body follows (which is pushed onto the stack).
+    fn next_token(&mut self, buf: &mut BytesMut) -> Result<Option<Option<RespValue>>, CodecError> {
+        let Some(&type_byte) = buf.first() else {
+            return Ok(None);
+        };
+
+        if self.stack.is_empty() && !is_type_byte(type_byte) {
+            let Some(line) = self.take_line(buf, false)? else {
+                return Ok(None);
+            };
+            let args = split_args(&line)?;
+            let items = args.into_iter().map(RespValue::BulkString).collect();
+            return Ok(Some(Some(RespValue::Array(items))));
+        }
+        // Inside an aggregate every element must be typed, which also rules
+        // out empty lines
+        if !is_type_byte(type_byte) {
+            return Err(ProtocolError::UnknownType(type_byte).into());
+        }
+
+        let Some(line) = self.take_line(buf, true)? else {
+            return Ok(None);
+        };
+        let header = &line[1..];
+
+        let kind = match type_byte {
+            b'$' | b'=' => {
+                let Some(len) = parse_length(header, self.limits.max_bulk_len)
+                    .ok_or(ProtocolError::InvalidBulkLength)?
+                else {
+                    return Ok(Some(Some(RespValue::Null)));
+                };
+                self.stack.push(Pending::Bulk {
+                    len,
+                    verbatim: type_byte == b'=',
+                });
+                return Ok(Some(None));
+            }
+            b'*' => AggregateKind::Array,
+            b'~' => AggregateKind::Set,
+            b'>' => AggregateKind::Push,
+            b'%' => AggregateKind::Map,
+            b'|' => AggregateKind::Attribute,
+            _ => {
+                // Scalars are a single complete line, so defer to parse_resp
+                let mut framed = line.to_vec();
+                framed.extend_from_slice(b"\r\n");
+                let value = parse_resp(&mut Cursor::new(&framed[..]))?;
+                return Ok(Some(Some(value)));
+            }
+        };
+
+        let Some(len) = parse_length(header, self.limits.max_multibulk_len)
+            .ok_or(ProtocolError::InvalidMultibulkLength)?
+        else {
+            return Ok(Some(Some(RespValue::Null)));
+        };
+        if len > 0 && self.stack.len() >= self.limits.max_depth {
+            return Err(ProtocolError::NestingTooDeep.into());
+        }
+        let remaining = match kind {
+            AggregateKind::Map | AggregateKind::Attribute => len * 2,
+            _ => len,
+        };
+        self.stack.push(Pending::Aggregate {
+            kind,
+            remaining,
+            items: Vec::new(),
+        });
+        Ok(Some(None))
+    }
+
+    /// Attaches a completed value to the innermost pending aggregate,
+    /// returning it once it is no longer nested in anything.
+    fn complete(&mut self, mut value: RespValue) -> Option<RespValue> {
+        loop {
+            match self.stack.last_mut() {
+                None => return Some(value),
+                Some(Pending::Attribute(_)) => {
+                    let Some(Pending::Attribute(entries)) = self.stack.pop() else {
+                        unreachable!();
+                    };
+                    value = RespValue::Attribute(entries, Box::new(value));
+                }
+                Some(Pending::Aggregate {
+                    remaining, items, ..
+                }) => {
+                    items.push(value);
+                    *remaining -= 1;
+                    if *remaining > 0 {
+                        return None;
+                    }
+                    let Some(Pending::Aggregate { kind, items, .. }) = self.stack.pop() else {
+                        unreachable!();
+                    };
+                    match self.finish(kind, items) {
+                        Some(finished) => value = finished,
+                        None => return None,
+                    }
+                }
+                Some(Pending::Bulk { .. }) => unreachable!("bulk bodies are read before nesting"),
+            }
+        }
+    }
+
+    /// Decodes as much of the next frame as `buf` holds.
+    fn decode_frame(&mut self, buf: &mut BytesMut) -> Result<Option<RespValue>, CodecError> {
+        loop {
+            let value = if let Some(&Pending::Bulk { len, verbatim }) = self.stack.last() {
+                if buf.len() < len + 2 {
+                    return Ok(None);
+                }
+                self.consumed += len + 2;
+                let data = buf.split_to(len).freeze();
+                if &buf[..2] != b"\r\n" {
+                    return Err(ProtocolError::ExpectedCrlf.into());
+                }
+                buf.advance(2);
+                self.stack.pop();
+
+                if verbatim {
+                    let text = String::from_utf8_lossy(&data);
+                    match text.split_once(':') {
+                        Some((format, text)) if format.len() == 3 => {
+                            RespValue::Verbatim(format.to_string(), text.to_string())
+                        }
+                        _ => return Err(ProtocolError::Invalid("verbatim string").into()),
+                    }
+                } else {
+                    RespValue::BulkString(data)
+                }
+            } else {
+                match self.next_token(buf)? {
+                    None => return Ok(None),
+                    Some(Some(value)) => value,
+                    Some(None) => match self.stack.last() {
+                        Some(&Pending::Aggregate {
+                            kind, remaining: 0, ..
+                        }) => {
+                            self.stack.pop();
+                            match self.finish(kind, Vec::new()) {
+                                Some(value) => value,
+                                None => continue,
+                            }
+                        }
+                        _ => continue,
+                    },
+                }
+            };
+
+            if let Some(frame) = self.complete(value) {
+                return Ok(Some(frame));
+            }
+        }
+    }
+
+    /// Builds the value of a completed aggregate. An attribute map instead
+    /// becomes pending until the reply it annotates arrives, in which case
+    /// `None` is returned.
+    fn finish(&mut self, kind: AggregateKind, items: Vec<RespValue>) -> Option<RespValue> {
+        Some(match kind {
+            AggregateKind::Array => RespValue::Array(items),
+            AggregateKind::Set => RespValue::Set(items),
+            AggregateKind::Push => RespValue::Push(items),
+            AggregateKind::Map => RespValue::Map(pairs(items)),
+            AggregateKind::Attribute => {
+                self.stack.push(Pending::Attribute(pairs(items)));
+                return None;
+            }
+        })
+    }
+}
+
+fn pairs(items: Vec<RespValue>) -> Vec<(RespValue, RespValue)> {
+    let mut entries = Vec::with_capacity(items.len() / 2);
+    let mut iter = items.into_iter();
+    while let (Some(key), Some(value)) = (iter.next(), iter.next()) {
+        entries.push((key, value));
+    }
+    entries
+}
+
+fn is_type_byte(b: u8) -> bool {
+    matches!(
+        b,
+        b'+' | b'-'
+            | b':'
+            | b'$'
+            | b'*'
+            | b'_'
+            | b'#'
+            | b','
+            | b'('
+            | b'='
+            | b'%'
+            | b'~'
+            | b'>'
+            | b'|'
+    )
+}
+
+/// Parses an aggregate or bulk length of at most `max`, where `-1` denotes
+/// a null. Returns `None` for anything else.
+fn parse_length(header: &[u8], max: usize) -> Option<Option<usize>> {
+    let len = std::str::from_utf8(header).ok()?.parse::<i64>().ok()?;
+
+    match len {
+        -1 => Some(None),
+        len if len < 0 || len as u64 > max as u64 => None,
+        len => Some(Some(len as usize)),
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = RespValue;
+    type Error = CodecError;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<RespValue>, CodecError> {
+        match self.decode_frame(buf)? {
+            Some(frame) => {
+                self.consumed = 0;
+                Ok(Some(frame))
+            }
+            None if self.consumed + buf.len() > self.limits.max_query_buffer => {
+                Err(ProtocolError::QueryBufferLimit.into())
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<RespValue> for RespCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: RespValue, dst: &mut BytesMut) -> Result<(), CodecError> {
+        item.write_to(dst, self.protocol);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn bulk(s: &str) -> RespValue {
+        RespValue::BulkString(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    #[test]
+    fn test_decode_pipeline() {
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::from(&b"*1\r\n$4\r\nPING\r\n*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n"[..]);
+
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespValue::Array(vec![bulk("PING")]))
+        );
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespValue::Array(vec![bulk("ECHO"), bulk("hi")]))
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_byte_at_a_time() {
+        let input = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n%1\r\n+a\r\n:1\r\n";
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::new();
+        let mut frames = Vec::new();
+
+        for &b in input.iter() {
+            buf.extend_from_slice(&[b]);
+            if let Some(frame) = codec.decode(&mut buf).unwrap() {
+                frames.push(frame);
+            }
+        }
+
+        assert_eq!(
+            frames,
+            vec![RespValue::Array(vec![
+                bulk("SET"),
+                bulk("k"),
+                RespValue::Map(vec![(
+                    RespValue::SimpleString("a".to_string()),
+                    RespValue::Integer(1)
+                )]),
+            ])]
+        );
+    }
+
+    #[test]
+    fn test_decode_inline_and_nested() {
+        let mut codec = RespCodec::new();
+        let mut buf =
+            BytesMut::from(&b"SET k \"a b\"\r\n*2\r\n*0\r\n|1\r\n+ttl\r\n:3\r\n$-1\r\n"[..]);
+
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespValue::Array(vec![bulk("SET"), bulk("k"), bulk("a b")]))
+        );
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespValue::Array(vec![
+                RespValue::Array(vec![]),
+                RespValue::Attribute(
+                    vec![(
+                        RespValue::SimpleString("ttl".to_string()),
+                        RespValue::Integer(3)
+                    )],
+                    Box::new(RespValue::Null)
+                ),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_bulk_ending() {
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::from(&b"$2\r\nabcd\r\n"[..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(CodecError::Protocol(_))
+        ));
+    }
+
+    fn decode_error(codec: &mut RespCodec, input: &[u8]) -> ProtocolError {
+        match codec.decode(&mut BytesMut::from(input)) {
+            Err(CodecError::Protocol(e)) => e,
+            other => panic!("expected a protocol error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut codec = RespCodec::new();
+        codec.set_limits(Limits {
+            max_bulk_len: 8,
+            max_multibulk_len: 4,
+            max_depth: 2,
+            max_inline_len: 16,
+            max_query_buffer: 64,
+        });
+
+        assert_eq!(
+            decode_error(&mut codec, b"$9\r\n"),
+            ProtocolError::InvalidBulkLength
+        );
+        assert_eq!(
+            decode_error(&mut codec, b"$-2\r\n"),
+            ProtocolError::InvalidBulkLength
+        );
+        assert_eq!(
+            decode_error(&mut RespCodec::new(), b"*-5\r\n"),
+            ProtocolError::InvalidMultibulkLength
+        );
+        assert_eq!(
+            decode_error(&mut codec, b"*5\r\n"),
+            ProtocolError::InvalidMultibulkLength
+        );
+        assert_eq!(
+            decode_error(&mut codec, b"*1\r\n*1\r\n*1\r\n"),
+            ProtocolError::NestingTooDeep
+        );
+        assert_eq!(
+            decode_error(&mut RespCodec::new(), b"*1\r\n\r\n"),
+            ProtocolError::UnknownType(b'\r')
+        );
+        assert_eq!(
+            decode_error(&mut RespCodec::new(), b"*2\r\n$1\r\na\r\n\n"),
+            ProtocolError::UnknownType(b'\n')
+        );
+        assert_eq!(
+            decode_error(&mut RespCodec::new(), b"*1\r\nget\r\n"),
+            ProtocolError::UnknownType(b'g')
+        );
+
+        let mut codec = RespCodec::new();
+        codec.set_limits(Limits {
+            max_inline_len: 16,
+            max_query_buffer: 64,
+            ..Limits::default()
+        });
+        assert_eq!(
+            decode_error(&mut codec, &[b'a'; 17]),
+            ProtocolError::InlineTooBig
+        );
+
+        // Whole arguments count towards the limit once split off the buffer
+        let mut codec = RespCodec::new();
+        codec.set_limits(Limits {
+            max_query_buffer: 64,
+            ..Limits::default()
+        });
+        let mut buf = BytesMut::from(&b"*3\r\n$30\r\n"[..]);
+        buf.extend_from_slice(&[b'x'; 30]);
+        buf.extend_from_slice(b"\r\n$30\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&[b'x'; 20]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(CodecError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_encode_uses_protocol() {
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::new();
+
+        codec.encode(RespValue::Boolean(true), &mut buf).unwrap();
+        codec.set_protocol(Protocol::Resp3);
+        codec.encode(RespValue::Boolean(true), &mut buf).unwrap();
+        assert_eq!(&buf[..], b":1\r\n#t\r\n");
+    }
+}
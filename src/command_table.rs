@@ -0,0 +1,418 @@
+//! Static description of every supported command. The table in
+//! `commands.rs` drives dispatch and arity checks, and is what COMMAND
+//! reports to clients that introspect the server.
+
+use bytes::Bytes;
+
+use crate::{
+    commands::{COMMANDS, Command},
+    error::CommandError,
+    resp::RespValue,
+};
+
+pub const WRITE: u32 = 1 << 0;
+pub const READONLY: u32 = 1 << 1;
+pub const DENYOOM: u32 = 1 << 2;
+pub const ADMIN: u32 = 1 << 3;
+pub const PUBSUB: u32 = 1 << 4;
+pub const NOSCRIPT: u32 = 1 << 5;
+pub const BLOCKING: u32 = 1 << 6;
+pub const LOADING: u32 = 1 << 7;
+pub const STALE: u32 = 1 << 8;
+pub const FAST: u32 = 1 << 9;
+pub const NO_AUTH: u32 = 1 << 10;
+pub const ASKING: u32 = 1 << 11;
+
+const FLAG_NAMES: &[(u32, &str)] = &[
+    (WRITE, "write"),
+    (READONLY, "readonly"),
+    (DENYOOM, "denyoom"),
+    (ADMIN, "admin"),
+    (PUBSUB, "pubsub"),
+    (NOSCRIPT, "noscript"),
+    (BLOCKING, "blocking"),
+    (LOADING, "loading"),
+    (STALE, "stale"),
+    (FAST, "fast"),
+    (NO_AUTH, "no_auth"),
+    (ASKING, "asking"),
+];
+
+type Parser = fn(&[RespValue]) -> Result<Command, CommandError>;
+
+/// A command, or a subcommand of a container command such as CONFIG.
+pub struct CommandSpec {
+    /// Lowercase name; subcommands are named `container|subcommand`.
+    pub name: &'static str,
+    /// Argument count including the name. Negative means "at least".
+    pub arity: i64,
+    pub flags: u32,
+    /// Position of the first key, or 0 when the command takes none.
+    pub first_key: i64,
+    /// Position of the last key; negative counts from the end.
+    pub last_key: i64,
+    pub key_step: i64,
+    /// ACL categories besides those implied by the flags.
+    pub categories: &'static [&'static str],
+    pub group: &'static str,
+    pub since: &'static str,
+    pub summary: &'static str,
+    pub parse: Parser,
+    pub subcommands: &'static [CommandSpec],
+}
+
+impl CommandSpec {
+    pub const fn new(name: &'static str, arity: i64, flags: u32, parse: Parser) -> CommandSpec {
+        CommandSpec {
+            name,
+            arity,
+            flags,
+            first_key: 0,
+            last_key: 0,
+            key_step: 0,
+            categories: &[],
+            group: "",
+            since: "",
+            summary: "",
+            parse,
+            subcommands: &[],
+        }
+    }
+
+    pub const fn keys(self, first_key: i64, last_key: i64, key_step: i64) -> CommandSpec {
+        CommandSpec {
+            first_key,
+            last_key,
+            key_step,
+            ..self
+        }
+    }
+
+    pub const fn acl(self, categories: &'static [&'static str]) -> CommandSpec {
+        CommandSpec { categories, ..self }
+    }
+
+    pub const fn docs(
+        self,
+        group: &'static str,
+        since: &'static str,
+        summary: &'static str,
+    ) -> CommandSpec {
+        CommandSpec {
+            group,
+            since,
+            summary,
+            ..self
+        }
+    }
+
+    pub const fn subcommands(self, subcommands: &'static [CommandSpec]) -> CommandSpec {
+        CommandSpec {
+            subcommands,
+            ..self
+        }
+    }
+
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn arity_matches(&self, argc: usize) -> bool {
+        let argc = argc as i64;
+        if self.arity >= 0 {
+            argc == self.arity
+        } else {
+            argc >= -self.arity
+        }
+    }
+
+    /// Every ACL category the command belongs to, including the ones Redis
+    /// derives from its flags.
+    fn acl_categories(&self) -> Vec<&'static str> {
+        let mut categories = Vec::new();
+        if self.has_flag(WRITE) {
+            categories.push("write");
+        }
+        if self.has_flag(READONLY) {
+            categories.push("read");
+        }
+        if self.has_flag(ADMIN) {
+            categories.extend(["admin", "dangerous"]);
+        }
+        if self.has_flag(PUBSUB) {
+            categories.push("pubsub");
+        }
+        categories.extend(self.categories);
+        categories.push(if self.has_flag(FAST) { "fast" } else { "slow" });
+        categories
+    }
+
+    /// The entry COMMAND INFO reports for this command.
+    pub fn info(&self) -> RespValue {
+        let text = |s: &str| RespValue::SimpleString(s.to_string());
+
+        let flags = FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| self.has_flag(*flag))
+            .map(|(_, name)| text(name))
+            .collect();
+        let categories = self
+            .acl_categories()
+            .into_iter()
+            .map(|category| text(&format!("@{}", category)))
+            .collect();
+
+        RespValue::Array(vec![
+            RespValue::BulkString(Bytes::from_static(self.name.as_bytes())),
+            RespValue::Integer(self.arity),
+            RespValue::Set(flags),
+            RespValue::Integer(self.first_key),
+            RespValue::Integer(self.last_key),
+            RespValue::Integer(self.key_step),
+            RespValue::Set(categories),
+            RespValue::Array(Vec::new()),
+            RespValue::Array(self.key_specs()),
+            RespValue::Array(self.subcommands.iter().map(CommandSpec::info).collect()),
+        ])
+    }
+
+    /// Describes the key positions in the Redis 7 key specification format.
+    fn key_specs(&self) -> Vec<RespValue> {
+        if self.first_key == 0 {
+            return Vec::new();
+        }
+
+        let field = |s: &str| RespValue::BulkString(Bytes::copy_from_slice(s.as_bytes()));
+        // The last key is given relative to the first one
+        let last_key = if self.last_key < 0 {
+            self.last_key
+        } else {
+            self.last_key - self.first_key
+        };
+        let access = if self.has_flag(WRITE) { "RW" } else { "RO" };
+
+        vec![RespValue::Map(vec![
+            (
+                field("flags"),
+                RespValue::Set(vec![RespValue::SimpleString(access.to_string())]),
+            ),
+            (
+                field("begin_search"),
+                RespValue::Map(vec![
+                    (field("type"), field("index")),
+                    (
+                        field("spec"),
+                        RespValue::Map(vec![(field("index"), RespValue::Integer(self.first_key))]),
+                    ),
+                ]),
+            ),
+            (
+                field("find_keys"),
+                RespValue::Map(vec![
+                    (field("type"), field("range")),
+                    (
+                        field("spec"),
+                        RespValue::Map(vec![
+                            (field("lastkey"), RespValue::Integer(last_key)),
+                            (field("keystep"), RespValue::Integer(self.key_step)),
+                            (field("limit"), RespValue::Integer(0)),
+                        ]),
+                    ),
+                ]),
+            ),
+        ])]
+    }
+
+    /// The entry COMMAND DOCS reports for this command.
+    pub fn docs_entry(&self) -> RespValue {
+        let field = |s: &str| RespValue::BulkString(Bytes::copy_from_slice(s.as_bytes()));
+
+        let mut entries = vec![
+            (field("summary"), field(self.summary)),
+            (field("since"), field(self.since)),
+            (field("group"), field(self.group)),
+        ];
+        if !self.subcommands.is_empty() {
+            let subcommands = self
+                .subcommands
+                .iter()
+                .map(|sub| (field(sub.name), sub.docs_entry()))
+                .collect();
+            entries.push((field("subcommands"), RespValue::Map(subcommands)));
+        }
+        RespValue::Map(entries)
+    }
+
+    /// Extracts the keys from a call of this command.
+    pub fn keys_of<'a>(&self, args: &'a [RespValue]) -> Vec<&'a RespValue> {
+        if self.first_key == 0 {
+            return Vec::new();
+        }
+
+        let last = if self.last_key < 0 {
+            args.len() as i64 + self.last_key
+        } else {
+            self.last_key.min(args.len() as i64 - 1)
+        };
+        (self.first_key..=last)
+            .step_by(self.key_step.max(1) as usize)
+            .filter_map(|i| args.get(i as usize))
+            .collect()
+    }
+}
+
+/// Looks up the table entry for the command in `args` (resolving the
+/// subcommand of container commands) and checks its arity.
+pub fn lookup(args: &[RespValue]) -> Result<&'static CommandSpec, CommandError> {
+    let name = arg_name(&args[0]);
+    let Some(spec) = find(COMMANDS, &name) else {
+        return Err(unknown_command(args));
+    };
+
+    if spec.subcommands.is_empty() {
+        if !spec.arity_matches(args.len()) {
+            return Err(CommandError::WrongArity(spec.name));
+        }
+        return Ok(spec);
+    }
+
+    // Containers such as COMMAND may also run without a subcommand
+    let Some(subcommand) = args.get(1).map(arg_name) else {
+        return if spec.arity_matches(args.len()) {
+            Ok(spec)
+        } else {
+            Err(CommandError::WrongArity(spec.name))
+        };
+    };
+    let Some(sub) = spec.subcommands.iter().find(|sub| {
+        sub.name
+            .split_once('|')
+            .is_some_and(|(_, s)| s == subcommand)
+    }) else {
+        return Err(CommandError::UnknownSubcommand(subcommand, spec.name));
+    };
+    if !sub.arity_matches(args.len()) {
+        return Err(CommandError::WrongArity(sub.name));
+    }
+    Ok(sub)
+}
+
+/// Finds the top-level command named `name` (case-insensitively).
+pub fn find(table: &'static [CommandSpec], name: &str) -> Option<&'static CommandSpec> {
+    let name = name.to_lowercase();
+    table.iter().find(|spec| spec.name == name)
+}
+
+/// Finds a command, or a subcommand given as `container|subcommand`.
+pub fn find_by_full_name(name: &str) -> Option<&'static CommandSpec> {
+    match name.split_once('|') {
+        Some((container, _)) => find(COMMANDS, container)?
+            .subcommands
+            .iter()
+            .find(|sub| sub.name == name),
+        None => find(COMMANDS, name),
+    }
+}
+
+fn arg_name(value: &RespValue) -> String {
+    match value {
+        RespValue::BulkString(s) => String::from_utf8_lossy(s).to_lowercase(),
+        RespValue::SimpleString(s) => s.to_lowercase(),
+        _ => String::new(),
+    }
+}
+
+/// Builds the error for an unrecognised command, quoting the start of its
+/// arguments the way Redis does.
+pub fn unknown_command(args: &[RespValue]) -> CommandError {
+    let arg = |value: &RespValue| match value {
+        RespValue::BulkString(s) => String::from_utf8_lossy(s).into_owned(),
+        RespValue::SimpleString(s) => s.clone(),
+        _ => String::new(),
+    };
+
+    let mut quoted = String::new();
+    for value in &args[1..] {
+        if quoted.len() >= 128 {
+            break;
+        }
+        quoted.push_str(&format!("'{}' ", arg(value)));
+    }
+    CommandError::UnknownCommand(arg(&args[0]), quoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<RespValue> {
+        args.iter()
+            .map(|s| RespValue::BulkString(Bytes::copy_from_slice(s.as_bytes())))
+            .collect()
+    }
+
+    #[test]
+    fn test_lookup_checks_arity() {
+        assert_eq!(lookup(&args(&["get", "k"])).unwrap().name, "get");
+        assert_eq!(
+            lookup(&args(&["GET"])).err(),
+            Some(CommandError::WrongArity("get"))
+        );
+        assert_eq!(
+            lookup(&args(&["config", "get"])).err(),
+            Some(CommandError::WrongArity("config|get"))
+        );
+        assert_eq!(
+            lookup(&args(&["config", "nope"])).err(),
+            Some(CommandError::UnknownSubcommand(
+                "nope".to_string(),
+                "config"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_commands_know_their_spec() {
+        for call in [
+            &["set", "k", "v"][..],
+            &["config", "get", "port"],
+            &["command", "count"],
+            &["command"],
+            &["pubsub", "numpat"],
+        ] {
+            let cmd = Command::from_resp(RespValue::Array(args(call))).unwrap();
+            assert!(std::ptr::eq(cmd.spec(), lookup(&args(call)).unwrap()));
+        }
+        assert!(find_by_full_name("config|nope").is_none());
+    }
+
+    #[test]
+    fn test_keys_of() {
+        let spec = find(COMMANDS, "del").unwrap();
+        let call = args(&["del", "a", "b", "c"]);
+        assert_eq!(spec.keys_of(&call), vec![&call[1], &call[2], &call[3]]);
+
+        let spec = find(COMMANDS, "hset").unwrap();
+        let call = args(&["hset", "h", "f", "v"]);
+        assert_eq!(spec.keys_of(&call), vec![&call[1]]);
+
+        let spec = find(COMMANDS, "ping").unwrap();
+        assert!(spec.keys_of(&args(&["ping"])).is_empty());
+    }
+
+    #[test]
+    fn test_info_derives_categories() {
+        let RespValue::Array(info) = find(COMMANDS, "get").unwrap().info() else {
+            panic!("expected an array");
+        };
+        assert_eq!(info[1], RespValue::Integer(2));
+        assert_eq!(
+            info[6],
+            RespValue::Set(vec![
+                RespValue::SimpleString("@read".to_string()),
+                RespValue::SimpleString("@string".to_string()),
+                RespValue::SimpleString("@fast".to_string()),
+            ])
+        );
+    }
+}
@@ -1,92 +1,1114 @@
+use bytes::Bytes;
 use std::time::{Duration, Instant};
 
 use crate::{
-    db::{DataType, Db},
+    aof,
+    cluster::{self, Failover, SetSlot},
+    command_table::{
+        self, ADMIN, ASKING, CommandSpec, DENYOOM, FAST, LOADING, NO_AUTH, NOSCRIPT, PUBSUB,
+        READONLY, STALE, WRITE,
+    },
+    config::Config,
+    db::{DataType, Db, unix_ms_to_instant, unix_time_ms},
+    error::{CommandError, RdbError},
+    info,
+    migrate::Migration,
+    persistence, rdb, replication,
     resp::RespValue,
+    sentinel,
+    slot::SLOT_COUNT,
 };
 
 #[derive(Debug)]
 pub enum Command {
     Ping,
-    Echo(String),
-    Set(String, String, Option<Duration>),
-    Get(String),
-    RPush(String, Vec<String>),
-    LPush(String, Vec<String>),
-    LRange(String, i64, i64),
-    LLen(String),
-    LPop(String, Option<usize>),
+    Echo(Bytes),
+    Set(Bytes, Bytes, Option<Duration>),
+    Get(Bytes),
+    Del(Vec<Bytes>),
+    Expire(Bytes, i64),
+    /// Expire at an absolute Unix time in milliseconds.
+    PExpireAt(Bytes, i64),
+    RPush(Bytes, Vec<Bytes>),
+    LPush(Bytes, Vec<Bytes>),
+    LRange(Bytes, i64, i64),
+    LLen(Bytes),
+    LPop(Bytes, Option<usize>),
+    HSet(Bytes, Vec<(Bytes, Bytes)>),
+    HGet(Bytes, Bytes),
+    HGetAll(Bytes),
+    SAdd(Bytes, Vec<Bytes>),
+    SMembers(Bytes),
+    ZAdd(Bytes, Vec<(f64, Bytes)>),
+    ZScore(Bytes, Bytes),
+    Subscribe(Vec<Bytes>),
+    Unsubscribe(Vec<Bytes>),
+    PSubscribe(Vec<Bytes>),
+    PUnsubscribe(Vec<Bytes>),
+    SSubscribe(Vec<Bytes>),
+    SUnsubscribe(Vec<Bytes>),
+    Publish(Bytes, Bytes),
+    SPublish(Bytes, Bytes),
+    PubSubChannels(Option<Bytes>),
+    PubSubNumSub(Vec<Bytes>),
+    PubSubNumPat,
+    PubSubShardChannels(Option<Bytes>),
+    PubSubShardNumSub(Vec<Bytes>),
+    Hello(Option<i64>, Option<(String, String)>, Option<String>),
+    ClientId,
+    ClientGetName,
+    ClientSetName(String),
+    ConfigGet(Vec<String>),
+    ConfigSet(Vec<(String, String)>),
+    ConfigRewrite,
+    ConfigResetStat,
+    Save,
+    BgSave,
+    LastSave,
+    BgRewriteAof,
+    /// Replicate the given master, or stop replicating when `None`.
+    ReplicaOf(Option<(String, u16)>),
+    /// Options a replica sets on its connection to the master.
+    ReplConf(Vec<(String, String)>),
+    Role,
+    /// The INFO sections asked for, all of them when empty.
+    Info(Vec<String>),
+    PSync(String, i64),
+    /// Block until this many replicas acknowledge the writes made so far.
+    Wait(i64, Duration),
+    /// Block until the writes made so far are flushed locally and on this
+    /// many replicas.
+    WaitAof(i64, i64, Duration),
+    /// Meet the node at the given address, with its bus port if not the
+    /// default.
+    ClusterMeet(String, u16, Option<u16>),
+    ClusterMyId,
+    ClusterNodes,
+    ClusterSlots,
+    ClusterShards,
+    ClusterInfo,
+    ClusterKeySlot(Bytes),
+    ClusterAddSlots(Vec<u16>),
+    ClusterSetSlot(u16, SetSlot),
+    ClusterGetKeysInSlot(u16, usize),
+    ClusterCountKeysInSlot(u16),
+    ClusterFailover(Failover),
+    ClusterReplicate(String),
+    Asking,
+    Migrate(Migration),
+    Dump(Bytes),
+    /// Store a serialized value, expiring at the given Unix time in
+    /// milliseconds, replacing any existing key when set.
+    Restore(Bytes, Option<i64>, Bytes, bool),
+    /// Store a serialized value, expiring at the given Unix time in
+    /// milliseconds, replacing any existing key when set.
+    RestoreAsking(Bytes, Option<i64>, Bytes, bool),
+    SentinelMasters,
+    SentinelMaster(String),
+    SentinelReplicas(String),
+    SentinelSentinels(String),
+    SentinelGetMasterAddr(String),
+    /// Master address, epoch and the run id of the sentinel asking for a
+    /// vote, or `*`.
+    SentinelIsMasterDown(String, u16, u64, String),
+    SentinelMonitor(String, String, u16, usize),
+    SentinelRemove(String),
+    SentinelSet(String, Vec<(String, String)>),
+    SentinelMyId,
+    SentinelFailover(String),
+    ListCommands,
+    CountCommands,
+    InfoCommands(Vec<String>),
+    DocsCommands(Vec<String>),
+    GetKeys(Vec<RespValue>),
+    /// Runs a cached script. There is no scripting, so no script is ever
+    /// cached.
+    EvalSha,
+    Quit,
 }
 
-const WRONG_TYPE_ERR: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+/// Every supported command. Arity, flags and key positions follow Redis.
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec::new("ping", -1, FAST, parse_ping)
+        .acl(&["connection"])
+        .docs(
+            "connection",
+            "1.0.0",
+            "Returns the server's liveliness response.",
+        ),
+    CommandSpec::new("echo", 2, FAST, parse_echo)
+        .acl(&["connection"])
+        .docs("connection", "1.0.0", "Returns the given string."),
+    CommandSpec::new("set", -3, WRITE | DENYOOM, parse_set)
+        .keys(1, 1, 1)
+        .acl(&["string"])
+        .docs(
+            "string",
+            "1.0.0",
+            "Sets the string value of a key, ignoring its type.",
+        ),
+    CommandSpec::new("get", 2, READONLY | FAST, parse_get)
+        .keys(1, 1, 1)
+        .acl(&["string"])
+        .docs("string", "1.0.0", "Returns the string value of a key."),
+    CommandSpec::new("del", -2, WRITE, parse_del)
+        .keys(1, -1, 1)
+        .acl(&["keyspace"])
+        .docs("generic", "1.0.0", "Deletes one or more keys."),
+    CommandSpec::new("expire", 3, WRITE | FAST, parse_expire)
+        .keys(1, 1, 1)
+        .acl(&["keyspace"])
+        .docs(
+            "generic",
+            "1.0.0",
+            "Sets the expiration time of a key in seconds.",
+        ),
+    CommandSpec::new("pexpireat", 3, WRITE | FAST, parse_pexpireat)
+        .keys(1, 1, 1)
+        .acl(&["keyspace"])
+        .docs(
+            "generic",
+            "2.6.0",
+            "Sets the expiration time of a key to a Unix milliseconds timestamp.",
+        ),
+    CommandSpec::new("rpush", -3, WRITE | DENYOOM | FAST, parse_rpush)
+        .keys(1, 1, 1)
+        .acl(&["list"])
+        .docs("list", "1.0.0", "Appends one or more elements to a list."),
+    CommandSpec::new("lpush", -3, WRITE | DENYOOM | FAST, parse_lpush)
+        .keys(1, 1, 1)
+        .acl(&["list"])
+        .docs("list", "1.0.0", "Prepends one or more elements to a list."),
+    CommandSpec::new("lrange", 4, READONLY, parse_range)
+        .keys(1, 1, 1)
+        .acl(&["list"])
+        .docs("list", "1.0.0", "Returns a range of elements from a list."),
+    CommandSpec::new("llen", 2, READONLY | FAST, parse_llen)
+        .keys(1, 1, 1)
+        .acl(&["list"])
+        .docs("list", "1.0.0", "Returns the length of a list."),
+    CommandSpec::new("lpop", -2, WRITE | FAST, parse_lpop)
+        .keys(1, 1, 1)
+        .acl(&["list"])
+        .docs(
+            "list",
+            "1.0.0",
+            "Returns the first elements in a list after removing them.",
+        ),
+    CommandSpec::new("hset", -4, WRITE | DENYOOM | FAST, parse_hset)
+        .keys(1, 1, 1)
+        .acl(&["hash"])
+        .docs(
+            "hash",
+            "2.0.0",
+            "Creates or modifies the value of a field in a hash.",
+        ),
+    CommandSpec::new("hget", 3, READONLY | FAST, parse_hget)
+        .keys(1, 1, 1)
+        .acl(&["hash"])
+        .docs("hash", "2.0.0", "Returns the value of a field in a hash."),
+    CommandSpec::new("hgetall", 2, READONLY, parse_hgetall)
+        .keys(1, 1, 1)
+        .acl(&["hash"])
+        .docs("hash", "2.0.0", "Returns all fields and values in a hash."),
+    CommandSpec::new("sadd", -3, WRITE | DENYOOM | FAST, parse_sadd)
+        .keys(1, 1, 1)
+        .acl(&["set"])
+        .docs("set", "1.0.0", "Adds one or more members to a set."),
+    CommandSpec::new("smembers", 2, READONLY, parse_smembers)
+        .keys(1, 1, 1)
+        .acl(&["set"])
+        .docs("set", "1.0.0", "Returns all members of a set."),
+    CommandSpec::new("zadd", -4, WRITE | DENYOOM | FAST, parse_zadd)
+        .keys(1, 1, 1)
+        .acl(&["sortedset"])
+        .docs(
+            "sorted-set",
+            "1.2.0",
+            "Adds one or more members to a sorted set.",
+        ),
+    CommandSpec::new("zscore", 3, READONLY | FAST, parse_zscore)
+        .keys(1, 1, 1)
+        .acl(&["sortedset"])
+        .docs(
+            "sorted-set",
+            "1.2.0",
+            "Returns the score of a member in a sorted set.",
+        ),
+    CommandSpec::new(
+        "subscribe",
+        -2,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        parse_subscribe,
+    )
+    .docs(
+        "pubsub",
+        "2.0.0",
+        "Listens for messages published to channels.",
+    ),
+    CommandSpec::new(
+        "unsubscribe",
+        -1,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        parse_unsubscribe,
+    )
+    .docs(
+        "pubsub",
+        "2.0.0",
+        "Stops listening to messages posted to channels.",
+    ),
+    CommandSpec::new(
+        "psubscribe",
+        -2,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        parse_psubscribe,
+    )
+    .docs(
+        "pubsub",
+        "2.0.0",
+        "Listens for messages published to channels that match patterns.",
+    ),
+    CommandSpec::new(
+        "punsubscribe",
+        -1,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        parse_punsubscribe,
+    )
+    .docs(
+        "pubsub",
+        "2.0.0",
+        "Stops listening to messages published to channels that match patterns.",
+    ),
+    CommandSpec::new(
+        "ssubscribe",
+        -2,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        parse_ssubscribe,
+    )
+    .keys(1, -1, 1)
+    .docs(
+        "pubsub",
+        "7.0.0",
+        "Listens for messages published to shard channels.",
+    ),
+    CommandSpec::new(
+        "sunsubscribe",
+        -1,
+        PUBSUB | NOSCRIPT | LOADING | STALE,
+        parse_sunsubscribe,
+    )
+    .keys(1, -1, 1)
+    .docs(
+        "pubsub",
+        "7.0.0",
+        "Stops listening to messages posted to shard channels.",
+    ),
+    CommandSpec::new("publish", 3, PUBSUB | LOADING | STALE | FAST, parse_publish).docs(
+        "pubsub",
+        "2.0.0",
+        "Posts a message to a channel.",
+    ),
+    CommandSpec::new(
+        "spublish",
+        3,
+        PUBSUB | LOADING | STALE | FAST,
+        parse_spublish,
+    )
+    .keys(1, 1, 1)
+    .docs("pubsub", "7.0.0", "Post a message to a shard channel."),
+    CommandSpec::new("pubsub", -2, 0, parse_container)
+        .docs("pubsub", "2.8.0", "A container for Pub/Sub commands.")
+        .subcommands(&[
+            CommandSpec::new(
+                "pubsub|channels",
+                -2,
+                PUBSUB | LOADING | STALE,
+                parse_pubsub_channels,
+            )
+            .docs("pubsub", "2.8.0", "Returns the active channels."),
+            CommandSpec::new(
+                "pubsub|numsub",
+                -2,
+                PUBSUB | LOADING | STALE,
+                parse_pubsub_numsub,
+            )
+            .docs(
+                "pubsub",
+                "2.8.0",
+                "Returns a count of subscribers to channels.",
+            ),
+            CommandSpec::new(
+                "pubsub|numpat",
+                2,
+                PUBSUB | LOADING | STALE,
+                parse_pubsub_numpat,
+            )
+            .docs(
+                "pubsub",
+                "2.8.0",
+                "Returns a count of unique pattern subscriptions.",
+            ),
+            CommandSpec::new(
+                "pubsub|shardchannels",
+                -2,
+                PUBSUB | LOADING | STALE,
+                parse_pubsub_shardchannels,
+            )
+            .docs("pubsub", "7.0.0", "Returns the active shard channels."),
+            CommandSpec::new(
+                "pubsub|shardnumsub",
+                -2,
+                PUBSUB | LOADING | STALE,
+                parse_pubsub_shardnumsub,
+            )
+            .docs(
+                "pubsub",
+                "7.0.0",
+                "Returns the count of subscribers of shard channels.",
+            ),
+        ]),
+    CommandSpec::new(
+        "hello",
+        -1,
+        NOSCRIPT | LOADING | STALE | FAST | NO_AUTH,
+        parse_hello,
+    )
+    .acl(&["connection"])
+    .docs("connection", "6.0.0", "Handshakes with the Redis server."),
+    CommandSpec::new("client", -2, 0, parse_container)
+        .docs(
+            "connection",
+            "2.4.0",
+            "A container for client connection commands.",
+        )
+        .subcommands(&[
+            CommandSpec::new("client|id", 2, NOSCRIPT | LOADING | STALE, parse_client_id)
+                .acl(&["connection"])
+                .docs(
+                    "connection",
+                    "5.0.0",
+                    "Returns the unique client ID of the connection.",
+                ),
+            CommandSpec::new(
+                "client|getname",
+                2,
+                NOSCRIPT | LOADING | STALE,
+                parse_client_getname,
+            )
+            .acl(&["connection"])
+            .docs("connection", "2.6.9", "Returns the name of the connection."),
+            CommandSpec::new(
+                "client|setname",
+                3,
+                NOSCRIPT | LOADING | STALE,
+                parse_client_setname,
+            )
+            .acl(&["connection"])
+            .docs("connection", "2.6.9", "Sets the connection name."),
+        ]),
+    CommandSpec::new("config", -2, 0, parse_container)
+        .docs(
+            "server",
+            "2.0.0",
+            "A container for server configuration commands.",
+        )
+        .subcommands(&[
+            CommandSpec::new(
+                "config|get",
+                -3,
+                ADMIN | NOSCRIPT | LOADING | STALE,
+                parse_config_get,
+            )
+            .docs(
+                "server",
+                "2.0.0",
+                "Returns the effective values of configuration parameters.",
+            ),
+            CommandSpec::new(
+                "config|set",
+                -4,
+                ADMIN | NOSCRIPT | LOADING | STALE,
+                parse_config_set,
+            )
+            .docs(
+                "server",
+                "2.0.0",
+                "Sets configuration parameters in-flight.",
+            ),
+            CommandSpec::new(
+                "config|rewrite",
+                2,
+                ADMIN | NOSCRIPT | LOADING | STALE,
+                parse_config_rewrite,
+            )
+            .docs(
+                "server",
+                "2.8.0",
+                "Persists the effective configuration to file.",
+            ),
+            CommandSpec::new(
+                "config|resetstat",
+                2,
+                ADMIN | NOSCRIPT | LOADING | STALE,
+                parse_config_resetstat,
+            )
+            .docs("server", "2.0.0", "Resets the server's statistics."),
+        ]),
+    CommandSpec::new("save", 1, ADMIN | NOSCRIPT, parse_save).docs(
+        "server",
+        "1.0.0",
+        "Synchronously saves the database(s) to disk.",
+    ),
+    CommandSpec::new("bgsave", -1, ADMIN | NOSCRIPT, parse_bgsave).docs(
+        "server",
+        "1.0.0",
+        "Asynchronously saves the database(s) to disk.",
+    ),
+    CommandSpec::new("bgrewriteaof", 1, ADMIN | NOSCRIPT, parse_bgrewriteaof).docs(
+        "server",
+        "1.0.0",
+        "Asynchronously rewrites the append-only file to disk.",
+    ),
+    CommandSpec::new("lastsave", 1, LOADING | STALE | FAST, parse_lastsave)
+        .acl(&["admin", "dangerous"])
+        .docs(
+            "server",
+            "1.0.0",
+            "Returns the Unix timestamp of the last successful save to disk.",
+        ),
+    CommandSpec::new("replicaof", 3, ADMIN | NOSCRIPT | STALE, parse_replicaof).docs(
+        "server",
+        "5.0.0",
+        "Configures a server as replica of another, or promotes it to a master.",
+    ),
+    CommandSpec::new(
+        "replconf",
+        -1,
+        ADMIN | NOSCRIPT | LOADING | STALE,
+        parse_replconf,
+    )
+    .docs(
+        "server",
+        "3.0.0",
+        "An internal command for configuring the replication stream.",
+    ),
+    CommandSpec::new("psync", -3, ADMIN | NOSCRIPT, parse_psync).docs(
+        "server",
+        "2.8.0",
+        "An internal command used in replication.",
+    ),
+    CommandSpec::new("role", 1, NOSCRIPT | LOADING | STALE | FAST, parse_role)
+        .acl(&["admin", "dangerous"])
+        .docs(
+            "server",
+            "2.8.12",
+            "Returns the replication role.",
+        ),
+    CommandSpec::new("info", -1, LOADING | STALE, parse_info)
+        .acl(&["dangerous"])
+        .docs(
+            "server",
+            "1.0.0",
+            "Returns information and statistics about the server.",
+        ),
+    CommandSpec::new("wait", 3, 0, parse_wait)
+        .acl(&["connection"])
+        .docs(
+            "generic",
+            "3.0.0",
+            "Blocks until the asynchronous replication of all preceding write commands sent by the connection is completed.",
+        ),
+    CommandSpec::new("waitaof", 4, NOSCRIPT, parse_waitaof)
+        .acl(&["connection"])
+        .docs(
+            "generic",
+            "7.2.0",
+            "Blocks until all of the preceding write commands sent by the connection are written to the append-only file of the master and/or replicas.",
+        ),
+    CommandSpec::new("cluster", -2, 0, parse_container)
+        .docs("cluster", "3.0.0", "A container for Redis Cluster commands.")
+        .subcommands(&[
+            CommandSpec::new(
+                "cluster|meet",
+                -4,
+                ADMIN | NOSCRIPT | STALE,
+                parse_cluster_meet,
+            )
+            .docs(
+                "cluster",
+                "3.0.0",
+                "Forces a node to handshake with another node.",
+            ),
+            CommandSpec::new("cluster|myid", 2, STALE, parse_cluster_myid)
+                .docs("cluster", "3.0.0", "Returns the ID of a node."),
+            CommandSpec::new("cluster|nodes", 2, LOADING | STALE, parse_cluster_nodes).docs(
+                "cluster",
+                "3.0.0",
+                "Returns the cluster configuration for a node.",
+            ),
+            CommandSpec::new("cluster|slots", 2, LOADING | STALE, parse_cluster_slots).docs(
+                "cluster",
+                "3.0.0",
+                "Returns the mapping of cluster slots to nodes.",
+            ),
+            CommandSpec::new("cluster|shards", 2, LOADING | STALE, parse_cluster_shards).docs(
+                "cluster",
+                "7.0.0",
+                "Returns the mapping of cluster slots to shards.",
+            ),
+            CommandSpec::new("cluster|info", 2, LOADING | STALE, parse_cluster_info).docs(
+                "cluster",
+                "3.0.0",
+                "Returns information about the state of a node.",
+            ),
+            CommandSpec::new("cluster|keyslot", 3, STALE, parse_cluster_keyslot)
+                .docs("cluster", "3.0.0", "Returns the hash slot for a key."),
+            CommandSpec::new(
+                "cluster|setslot",
+                -4,
+                ADMIN | NOSCRIPT | STALE,
+                parse_cluster_setslot,
+            )
+            .docs("cluster", "3.0.0", "Binds a hash slot to a node."),
+            CommandSpec::new(
+                "cluster|getkeysinslot",
+                4,
+                STALE,
+                parse_cluster_getkeysinslot,
+            )
+            .docs("cluster", "3.0.0", "Returns the key names in a hash slot."),
+            CommandSpec::new(
+                "cluster|countkeysinslot",
+                3,
+                STALE,
+                parse_cluster_countkeysinslot,
+            )
+            .docs(
+                "cluster",
+                "3.0.0",
+                "Returns the number of keys in a hash slot.",
+            ),
+            CommandSpec::new(
+                "cluster|addslots",
+                -3,
+                ADMIN | NOSCRIPT | STALE,
+                parse_cluster_addslots,
+            )
+            .docs(
+                "cluster",
+                "3.0.0",
+                "Assigns new hash slots to a node.",
+            ),
+            CommandSpec::new(
+                "cluster|failover",
+                -2,
+                ADMIN | NOSCRIPT | STALE,
+                parse_cluster_failover,
+            )
+            .docs(
+                "cluster",
+                "3.0.0",
+                "Forces a replica to perform a manual failover of its master.",
+            ),
+            CommandSpec::new(
+                "cluster|replicate",
+                3,
+                ADMIN | NOSCRIPT | STALE,
+                parse_cluster_replicate,
+            )
+            .docs(
+                "cluster",
+                "3.0.0",
+                "Configure a node as replica of a master node.",
+            ),
+        ]),
+    CommandSpec::new("asking", 1, FAST, parse_asking)
+        .acl(&["connection"])
+        .docs(
+            "cluster",
+            "3.0.0",
+            "Signals that a cluster client is following an -ASK redirect.",
+        ),
+    CommandSpec::new("migrate", -6, WRITE, parse_migrate)
+        .keys(3, 3, 1)
+        .acl(&["keyspace", "dangerous"])
+        .docs(
+            "generic",
+            "2.6.0",
+            "Atomically transfers a key from one Redis instance to another.",
+        ),
+    CommandSpec::new("dump", 2, READONLY, parse_dump)
+        .keys(1, 1, 1)
+        .acl(&["keyspace"])
+        .docs(
+            "generic",
+            "2.6.0",
+            "Returns a serialized representation of the value stored at a key.",
+        ),
+    CommandSpec::new("restore", -4, WRITE | DENYOOM, parse_restore)
+        .keys(1, 1, 1)
+        .acl(&["keyspace", "dangerous"])
+        .docs(
+            "generic",
+            "2.6.0",
+            "Creates a key from the serialized representation of a value.",
+        ),
+    CommandSpec::new(
+        "restore-asking",
+        -4,
+        WRITE | DENYOOM | ASKING,
+        parse_restore_asking,
+    )
+    .keys(1, 1, 1)
+    .acl(&["keyspace", "dangerous"])
+    .docs(
+        "server",
+        "3.0.0",
+        "An internal command for migrating keys in a cluster.",
+    ),
+    CommandSpec::new("sentinel", -2, 0, parse_container)
+        .docs("sentinel", "2.8.4", "A container for Redis Sentinel commands.")
+        .subcommands(&[
+            CommandSpec::new("sentinel|masters", 2, 0, parse_sentinel_masters).docs(
+                "sentinel",
+                "2.8.4",
+                "Returns a list of monitored masters.",
+            ),
+            CommandSpec::new("sentinel|master", 3, 0, parse_sentinel_master).docs(
+                "sentinel",
+                "2.8.4",
+                "Returns the state of a master.",
+            ),
+            CommandSpec::new("sentinel|replicas", 3, 0, parse_sentinel_replicas).docs(
+                "sentinel",
+                "5.0.0",
+                "Returns a list of the monitored replicas.",
+            ),
+            CommandSpec::new("sentinel|slaves", 3, 0, parse_sentinel_replicas).docs(
+                "sentinel",
+                "2.8.0",
+                "Returns a list of the monitored replicas.",
+            ),
+            CommandSpec::new("sentinel|sentinels", 3, 0, parse_sentinel_sentinels).docs(
+                "sentinel",
+                "2.8.4",
+                "Returns a list of Sentinel instances.",
+            ),
+            CommandSpec::new(
+                "sentinel|get-master-addr-by-name",
+                3,
+                0,
+                parse_sentinel_get_master_addr,
+            )
+            .docs(
+                "sentinel",
+                "2.8.4",
+                "Returns the port and address of a master instance.",
+            ),
+            CommandSpec::new(
+                "sentinel|is-master-down-by-addr",
+                6,
+                0,
+                parse_sentinel_is_master_down,
+            )
+            .docs(
+                "sentinel",
+                "2.8.4",
+                "Determines whether a master instance is down.",
+            ),
+            CommandSpec::new("sentinel|monitor", 6, ADMIN, parse_sentinel_monitor).docs(
+                "sentinel",
+                "2.8.4",
+                "Starts monitoring.",
+            ),
+            CommandSpec::new("sentinel|remove", 3, ADMIN, parse_sentinel_remove).docs(
+                "sentinel",
+                "2.8.4",
+                "Stops monitoring.",
+            ),
+            CommandSpec::new("sentinel|set", -5, ADMIN, parse_sentinel_set).docs(
+                "sentinel",
+                "2.8.4",
+                "Changes the configuration of a monitored master.",
+            ),
+            CommandSpec::new("sentinel|myid", 2, 0, parse_sentinel_myid).docs(
+                "sentinel",
+                "6.2.0",
+                "Returns the Sentinel instance ID.",
+            ),
+            CommandSpec::new("sentinel|failover", 3, ADMIN, parse_sentinel_failover).docs(
+                "sentinel",
+                "2.8.4",
+                "Forces a Sentinel failover.",
+            ),
+        ]),
+    CommandSpec::new("command", -1, LOADING | STALE, parse_command)
+        .acl(&["connection"])
+        .docs(
+            "server",
+            "2.8.13",
+            "Returns detailed information about all commands.",
+        )
+        .subcommands(&[
+            CommandSpec::new("command|count", 2, LOADING | STALE, parse_command_count)
+                .acl(&["connection"])
+                .docs("server", "2.8.13", "Returns a count of commands."),
+            CommandSpec::new("command|info", -2, LOADING | STALE, parse_command_info)
+                .acl(&["connection"])
+                .docs(
+                    "server",
+                    "2.8.13",
+                    "Returns information about one, multiple or all commands.",
+                ),
+            CommandSpec::new("command|docs", -2, LOADING | STALE, parse_command_docs)
+                .acl(&["connection"])
+                .docs(
+                    "server",
+                    "7.0.0",
+                    "Returns documentary information about one, multiple or all commands.",
+                ),
+            CommandSpec::new(
+                "command|getkeys",
+                -3,
+                LOADING | STALE,
+                parse_command_getkeys,
+            )
+            .acl(&["connection"])
+            .docs(
+                "server",
+                "2.8.13",
+                "Extracts the key names from an arbitrary command.",
+            ),
+        ]),
+    CommandSpec::new("evalsha", -3, NOSCRIPT | STALE, parse_evalsha)
+        .acl(&["scripting"])
+        .docs(
+            "scripting",
+            "2.6.0",
+            "Executes a server-side Lua script by SHA1 digest.",
+        ),
+    CommandSpec::new(
+        "quit",
+        -1,
+        NOSCRIPT | LOADING | STALE | FAST | NO_AUTH,
+        parse_quit,
+    )
+    .acl(&["connection"])
+    .docs("connection", "1.0.0", "Closes the connection."),
+];
 
 impl Command {
-    pub fn from_resp(value: RespValue) -> Result<Command, String> {
+    pub fn from_resp(value: RespValue) -> Result<Command, CommandError> {
+        let (spec, args) = Command::resolve(value)?;
+        Command::parse(spec, &args)
+    }
+
+    /// Splits `value` into a command's arguments and looks the command up
+    /// in the table, once for every check that needs it and the parse.
+    pub fn resolve(
+        value: RespValue,
+    ) -> Result<(&'static CommandSpec, Vec<RespValue>), CommandError> {
         let args = match value {
             RespValue::Array(a) => a,
-            _ => return Err("Command must be an Array".to_string()),
+            _ => {
+                return Err(CommandError::Err(
+                    "Protocol error: expected an array".to_string(),
+                ));
+            }
         };
 
         if args.is_empty() {
-            return Err("Command cannot be empty".to_string());
+            return Err(CommandError::Err(
+                "Protocol error: empty command".to_string(),
+            ));
         }
 
-        let command_name = match &args[0] {
-            RespValue::SimpleString(s) | RespValue::BulkString(s) => s.to_uppercase(),
-            _ => return Err("Command name must be a string".to_string()),
-        };
+        let spec = command_table::lookup(&args)?;
+        Ok((spec, args))
+    }
+
+    /// Parses `args`, a call to the command `spec` describes.
+    pub fn parse(spec: &CommandSpec, args: &[RespValue]) -> Result<Command, CommandError> {
+        (spec.parse)(args)
+    }
 
-        match command_name.as_str() {
-            "PING" => Ok(Command::Ping),
-            "ECHO" => parse_echo(&args),
-            "SET" => parse_set(&args),
-            "GET" => parse_get(&args),
-            "RPUSH" => parse_rpush(&args),
-            "LPUSH" => parse_lpush(&args),
-            "LRANGE" => parse_range(&args),
-            "LLEN" => parse_llen(&args),
-            "LPOP" => parse_lpop(&args),
-            _ => Err(format!("Unknown command: {}", command_name)),
+    /// The command's full name in the command table, `container|subcommand`
+    /// for subcommands.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Ping => "ping",
+            Command::Echo(_) => "echo",
+            Command::Set(..) => "set",
+            Command::Get(_) => "get",
+            Command::Del(_) => "del",
+            Command::Expire(..) => "expire",
+            Command::PExpireAt(..) => "pexpireat",
+            Command::RPush(..) => "rpush",
+            Command::LPush(..) => "lpush",
+            Command::LRange(..) => "lrange",
+            Command::LLen(_) => "llen",
+            Command::LPop(..) => "lpop",
+            Command::HSet(..) => "hset",
+            Command::HGet(..) => "hget",
+            Command::HGetAll(_) => "hgetall",
+            Command::SAdd(..) => "sadd",
+            Command::SMembers(_) => "smembers",
+            Command::ZAdd(..) => "zadd",
+            Command::ZScore(..) => "zscore",
+            Command::Subscribe(_) => "subscribe",
+            Command::Unsubscribe(_) => "unsubscribe",
+            Command::PSubscribe(_) => "psubscribe",
+            Command::PUnsubscribe(_) => "punsubscribe",
+            Command::SSubscribe(_) => "ssubscribe",
+            Command::SUnsubscribe(_) => "sunsubscribe",
+            Command::Publish(..) => "publish",
+            Command::SPublish(..) => "spublish",
+            Command::PubSubChannels(_) => "pubsub|channels",
+            Command::PubSubNumSub(_) => "pubsub|numsub",
+            Command::PubSubNumPat => "pubsub|numpat",
+            Command::PubSubShardChannels(_) => "pubsub|shardchannels",
+            Command::PubSubShardNumSub(_) => "pubsub|shardnumsub",
+            Command::Hello(..) => "hello",
+            Command::ClientId => "client|id",
+            Command::ClientGetName => "client|getname",
+            Command::ClientSetName(_) => "client|setname",
+            Command::ConfigGet(_) => "config|get",
+            Command::ConfigSet(_) => "config|set",
+            Command::ConfigRewrite => "config|rewrite",
+            Command::ConfigResetStat => "config|resetstat",
+            Command::Save => "save",
+            Command::BgSave => "bgsave",
+            Command::LastSave => "lastsave",
+            Command::BgRewriteAof => "bgrewriteaof",
+            Command::ReplicaOf(_) => "replicaof",
+            Command::ReplConf(_) => "replconf",
+            Command::Role => "role",
+            Command::Info(_) => "info",
+            Command::PSync(..) => "psync",
+            Command::Wait(..) => "wait",
+            Command::WaitAof(..) => "waitaof",
+            Command::ClusterMeet(..) => "cluster|meet",
+            Command::ClusterMyId => "cluster|myid",
+            Command::ClusterNodes => "cluster|nodes",
+            Command::ClusterSlots => "cluster|slots",
+            Command::ClusterShards => "cluster|shards",
+            Command::ClusterInfo => "cluster|info",
+            Command::ClusterKeySlot(_) => "cluster|keyslot",
+            Command::ClusterAddSlots(_) => "cluster|addslots",
+            Command::ClusterSetSlot(..) => "cluster|setslot",
+            Command::ClusterGetKeysInSlot(..) => "cluster|getkeysinslot",
+            Command::ClusterCountKeysInSlot(_) => "cluster|countkeysinslot",
+            Command::ClusterFailover(_) => "cluster|failover",
+            Command::ClusterReplicate(_) => "cluster|replicate",
+            Command::Asking => "asking",
+            Command::Migrate(_) => "migrate",
+            Command::Dump(_) => "dump",
+            Command::Restore(..) => "restore",
+            Command::RestoreAsking(..) => "restore-asking",
+            Command::SentinelMasters => "sentinel|masters",
+            Command::SentinelMaster(_) => "sentinel|master",
+            Command::SentinelReplicas(_) => "sentinel|replicas",
+            Command::SentinelSentinels(_) => "sentinel|sentinels",
+            Command::SentinelGetMasterAddr(_) => "sentinel|get-master-addr-by-name",
+            Command::SentinelIsMasterDown(..) => "sentinel|is-master-down-by-addr",
+            Command::SentinelMonitor(..) => "sentinel|monitor",
+            Command::SentinelRemove(_) => "sentinel|remove",
+            Command::SentinelSet(..) => "sentinel|set",
+            Command::SentinelMyId => "sentinel|myid",
+            Command::SentinelFailover(_) => "sentinel|failover",
+            Command::ListCommands => "command",
+            Command::CountCommands => "command|count",
+            Command::InfoCommands(_) => "command|info",
+            Command::DocsCommands(_) => "command|docs",
+            Command::GetKeys(_) => "command|getkeys",
+            Command::EvalSha => "evalsha",
+            Command::Quit => "quit",
         }
     }
 
+    pub fn spec(&self) -> &'static CommandSpec {
+        command_table::find_by_full_name(self.name()).expect("every command is in the table")
+    }
+
+    /// Whether the command may run while the connection is in subscribed mode.
+    pub fn allowed_in_subscribed_mode(&self) -> bool {
+        matches!(
+            self,
+            Command::Subscribe(_)
+                | Command::Unsubscribe(_)
+                | Command::PSubscribe(_)
+                | Command::PUnsubscribe(_)
+                | Command::SSubscribe(_)
+                | Command::SUnsubscribe(_)
+                | Command::Ping
+                | Command::Quit
+        )
+    }
+
     pub fn execute(self, db: &Db) -> RespValue {
-        match self {
+        let Some(args) = self.propagated_args() else {
+            return self.run(db).unwrap_or_else(RespValue::from);
+        };
+
+        let _guard = db.write_guard();
+        match self.run(db) {
+            Ok(reply) => {
+                db.propagate(&args);
+                reply
+            }
+            Err(e) => RespValue::from(e),
+        }
+    }
+
+    /// Applies a command received from this server's master. Its bytes in
+    /// the master's stream, `raw`, are passed on to this server's own
+    /// replicas unchanged, so that offsets agree along the chain.
+    pub fn execute_replicated(self, db: &Db, raw: Bytes) {
+        let _guard = db.write_guard();
+        let args = self.propagated_args();
+        if self.run(db).is_ok()
+            && let Some(args) = args
+        {
+            db.propagate(&args);
+        }
+        db.replication().feed(raw);
+    }
+
+    /// Applies a command read back from this server's own append-only
+    /// file. It was propagated when first run, so it isn't again.
+    pub fn execute_loaded(self, db: &Db) {
+        let _guard = db.write_guard();
+        let _ = self.run(db);
+    }
+
+    /// The command to log for a write, with relative expiries replaced by
+    /// absolute ones so that replaying it later has the same effect.
+    fn propagated_args(&self) -> Option<Vec<Bytes>> {
+        let command = |name: &'static str, key: &Bytes| vec![Bytes::from(name), key.clone()];
+        let expire_at = |key: &Bytes, ms: i64| {
+            let mut args = command("PEXPIREAT", key);
+            args.push(Bytes::from(ms.to_string()));
+            args
+        };
+
+        Some(match self {
+            Command::Set(key, value, duration) => {
+                let mut args = command("SET", key);
+                args.push(value.clone());
+                if let Some(duration) = duration {
+                    let ms = i64::try_from(duration.as_millis()).unwrap_or(i64::MAX);
+                    let at = unix_time_ms().saturating_add(ms);
+                    args.extend([Bytes::from("PXAT"), Bytes::from(at.to_string())]);
+                }
+                args
+            }
+            // Expiry is sent as an absolute time so that replaying the
+            // command later restores the same deadline
+            Command::Restore(key, expire_at, payload, replace)
+            | Command::RestoreAsking(key, expire_at, payload, replace) => {
+                let name = match self {
+                    Command::Restore(..) => "RESTORE",
+                    _ => "RESTORE-ASKING",
+                };
+                let mut args = command(name, key);
+                args.extend([
+                    Bytes::from(expire_at.unwrap_or(0).to_string()),
+                    payload.clone(),
+                    Bytes::from("ABSTTL"),
+                ]);
+                if *replace {
+                    args.push(Bytes::from("REPLACE"));
+                }
+                args
+            }
+            Command::Del(keys) => {
+                let mut args = vec![Bytes::from("DEL")];
+                args.extend(keys.iter().cloned());
+                args
+            }
+            Command::Expire(key, seconds) => expire_at(
+                key,
+                unix_time_ms().saturating_add(seconds.saturating_mul(1000)),
+            ),
+            Command::PExpireAt(key, ms) => expire_at(key, *ms),
+            Command::RPush(key, values) => {
+                let mut args = command("RPUSH", key);
+                args.extend(values.iter().cloned());
+                args
+            }
+            Command::LPush(key, values) => {
+                let mut args = command("LPUSH", key);
+                args.extend(values.iter().cloned());
+                args
+            }
+            Command::LPop(key, count) => {
+                let mut args = command("LPOP", key);
+                args.extend(count.map(|count| Bytes::from(count.to_string())));
+                args
+            }
+            Command::HSet(key, fields) => {
+                let mut args = command("HSET", key);
+                for (field, value) in fields {
+                    args.extend([field.clone(), value.clone()]);
+                }
+                args
+            }
+            Command::SAdd(key, members) => {
+                let mut args = command("SADD", key);
+                args.extend(members.iter().cloned());
+                args
+            }
+            Command::ZAdd(key, members) => {
+                let mut args = command("ZADD", key);
+                for (score, member) in members {
+                    args.extend([Bytes::from(score.to_string()), member.clone()]);
+                }
+                args
+            }
+            _ => return None,
+        })
+    }
+
+    fn run(self, db: &Db) -> Result<RespValue, CommandError> {
+        Ok(match self {
             Command::Ping => RespValue::SimpleString("PONG".to_string()),
             Command::Echo(msg) => RespValue::BulkString(msg.clone()),
             Command::Set(key, value, duration) => {
-                let expiry = duration.map(|d| Instant::now() + d);
+                let expiry = match duration {
+                    Some(d) => Some(
+                        Instant::now()
+                            .checked_add(d)
+                            .ok_or_else(|| invalid_expire_time("set"))?,
+                    ),
+                    None => None,
+                };
                 db.set(key, value, expiry);
                 RespValue::SimpleString("OK".to_string())
             }
             Command::Get(key) => match db.get(&key) {
                 Some(DataType::String(s)) => RespValue::BulkString(s),
                 None => RespValue::Null,
-                _ => RespValue::SimpleError(WRONG_TYPE_ERR.to_string()),
+                _ => return Err(CommandError::WrongType),
             },
-            Command::RPush(key, value) => {
-                let curr_len = db.rpush(key, value);
-                handle_push(curr_len)
-            }
-            Command::LPush(key, value) => {
-                let curr_len = db.lpush(key, value);
-                handle_push(curr_len)
-            }
-            Command::LRange(key, start, end) => match db.lrange(key, start, end) {
-                Ok(items) => {
-                    let resp_items = items.into_iter().map(RespValue::BulkString).collect();
-                    RespValue::Array(resp_items)
-                }
-                Err(_) => RespValue::SimpleError(WRONG_TYPE_ERR.to_string()),
-            },
-            Command::LLen(key) => match db.llen(key) {
-                Ok(len) => RespValue::Integer(len as i64),
-                Err(_) => RespValue::SimpleError(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                ),
-            },
-            Command::LPop(key, count) => match db.lpop(&key, count) {
-                Ok(Some(items)) => {
+            Command::Del(keys) => RespValue::Integer(db.del(&keys) as i64),
+            Command::Expire(key, seconds) => {
+                let at =
+                    expiry_from_seconds(seconds).ok_or_else(|| invalid_expire_time("expire"))?;
+                RespValue::Integer(db.expire(&key, at) as i64)
+            }
+            Command::PExpireAt(key, at) => {
+                let at = unix_ms_to_instant(at).ok_or_else(|| invalid_expire_time("pexpireat"))?;
+                RespValue::Integer(db.expire(&key, at) as i64)
+            }
+            Command::RPush(key, value) => RespValue::Integer(db.rpush(key, value)? as i64),
+            Command::LPush(key, value) => RespValue::Integer(db.lpush(key, value)? as i64),
+            Command::LRange(key, start, end) => {
+                let items = db.lrange(&key, start, end)?;
+                RespValue::Array(items.into_iter().map(RespValue::BulkString).collect())
+            }
+            Command::LLen(key) => RespValue::Integer(db.llen(&key)? as i64),
+            Command::LPop(key, count) => match db.lpop(&key, count)? {
+                Some(items) => {
                     if count.is_none() {
                         RespValue::BulkString(items[0].clone())
                     } else {
@@ -94,152 +1116,1115 @@ impl Command {
                         RespValue::Array(resp_items)
                     }
                 }
-                Ok(None) => RespValue::Null,
-                Err(_) => RespValue::SimpleError(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+                None => RespValue::Null,
+            },
+            Command::HSet(key, fields) => RespValue::Integer(db.hset(key, fields)? as i64),
+            Command::HGet(key, field) => match db.get(&key) {
+                Some(DataType::Hash(hash)) => hash
+                    .get(&field)
+                    .map_or(RespValue::Null, |v| RespValue::BulkString(v.clone())),
+                None => RespValue::Null,
+                _ => return Err(CommandError::WrongType),
+            },
+            Command::HGetAll(key) => match db.get(&key) {
+                Some(DataType::Hash(hash)) => RespValue::Map(
+                    hash.into_iter()
+                        .map(|(f, v)| (RespValue::BulkString(f), RespValue::BulkString(v)))
+                        .collect(),
                 ),
+                None => RespValue::Map(Vec::new()),
+                _ => return Err(CommandError::WrongType),
             },
-        }
+            Command::SAdd(key, members) => RespValue::Integer(db.sadd(key, members)? as i64),
+            Command::SMembers(key) => match db.get(&key) {
+                Some(DataType::Set(set)) => {
+                    RespValue::Set(set.into_iter().map(RespValue::BulkString).collect())
+                }
+                None => RespValue::Set(Vec::new()),
+                _ => return Err(CommandError::WrongType),
+            },
+            Command::ZAdd(key, members) => RespValue::Integer(db.zadd(key, members)? as i64),
+            Command::ZScore(key, member) => match db.get(&key) {
+                Some(DataType::ZSet(zset)) => zset
+                    .get(&member)
+                    .map_or(RespValue::Null, |score| RespValue::Double(*score)),
+                None => RespValue::Null,
+                _ => return Err(CommandError::WrongType),
+            },
+            Command::Publish(channel, message) => {
+                let receivers = db.pubsub().publish(channel, message);
+                RespValue::Integer(receivers as i64)
+            }
+            Command::PubSubChannels(pattern) => {
+                let channels = db.pubsub().channels(pattern.as_deref());
+                RespValue::Array(channels.into_iter().map(RespValue::BulkString).collect())
+            }
+            Command::PubSubNumSub(channels) => {
+                let mut items = Vec::with_capacity(channels.len() * 2);
+                for channel in channels {
+                    let count = db.pubsub().numsub(&channel);
+                    items.push(RespValue::BulkString(channel));
+                    items.push(RespValue::Integer(count as i64));
+                }
+                RespValue::Array(items)
+            }
+            Command::PubSubNumPat => RespValue::Integer(db.pubsub().numpat() as i64),
+            Command::SPublish(channel, message) => {
+                let receivers = db.pubsub().spublish(channel, message);
+                RespValue::Integer(receivers as i64)
+            }
+            Command::PubSubShardChannels(pattern) => {
+                let channels = db.pubsub().shard_channels(pattern.as_deref());
+                RespValue::Array(channels.into_iter().map(RespValue::BulkString).collect())
+            }
+            Command::PubSubShardNumSub(channels) => {
+                let mut items = Vec::with_capacity(channels.len() * 2);
+                for channel in channels {
+                    let count = db.pubsub().shard_numsub(&channel);
+                    items.push(RespValue::BulkString(channel));
+                    items.push(RespValue::Integer(count as i64));
+                }
+                RespValue::Array(items)
+            }
+            Command::ConfigGet(patterns) => {
+                let mut items = Vec::new();
+                for pattern in patterns {
+                    for (name, value) in db.config().get(&pattern) {
+                        items.push(RespValue::BulkString(name.into()));
+                        items.push(RespValue::BulkString(value.into()));
+                    }
+                }
+                RespValue::Array(items)
+            }
+            Command::ConfigSet(pairs) => {
+                let failed = |name: &str, e: String| {
+                    CommandError::Err(format!(
+                        "CONFIG SET failed (possibly related to argument '{}') - {}",
+                        name, e
+                    ))
+                };
+                if let Some((name, _)) = pairs.iter().find(|(name, _)| Config::is_immutable(name)) {
+                    return Err(failed(name, "can't set immutable config".to_string()));
+                }
+
+                // Either every pair is set or none is, even when putting a
+                // new value into effect fails
+                let previous: Vec<(String, String)> = pairs
+                    .iter()
+                    .flat_map(|(name, _)| db.config().get(name))
+                    .collect();
+                db.config()
+                    .set_all(&pairs)
+                    .map_err(|(name, e)| failed(&name, e))?;
+                if pairs
+                    .iter()
+                    .any(|(name, _)| name.eq_ignore_ascii_case("appendonly"))
+                    && let Err(e) = aof::apply_config(db)
+                {
+                    let _ = db.config().set_all(&previous);
+                    return Err(failed("appendonly", e.to_string()));
+                }
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::ConfigRewrite => {
+                db.config()
+                    .rewrite()
+                    .map_err(|e| CommandError::Err(e.to_string()))?;
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::ConfigResetStat => {
+                db.stats().reset();
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::Save => {
+                persistence::save(db).map_err(|e| CommandError::Err(e.to_string()))?;
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::BgSave => {
+                persistence::bgsave(db).map_err(|e| CommandError::Err(e.to_string()))?;
+                RespValue::SimpleString("Background saving started".to_string())
+            }
+            Command::LastSave => RespValue::Integer(db.persistence().lastsave() as i64),
+            Command::BgRewriteAof => {
+                aof::rewrite(db).map_err(|e| CommandError::Err(e.to_string()))?;
+                RespValue::SimpleString("Background append only file rewriting started".to_string())
+            }
+            Command::ReplicaOf(_) if db.config().cluster_enabled() => {
+                return Err(CommandError::Err(
+                    "REPLICAOF not allowed in cluster mode.".to_string(),
+                ));
+            }
+            Command::ReplicaOf(None) => {
+                replication::promote(db);
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::ReplicaOf(Some((host, port))) => {
+                if replication::follow(db, host, port) {
+                    RespValue::SimpleString("OK".to_string())
+                } else {
+                    RespValue::SimpleString("OK Already connected to specified master".to_string())
+                }
+            }
+            Command::Role if db.sentinel().is_enabled() => sentinel::role(db),
+            Command::Role => replication::role(db),
+            Command::Info(sections) => {
+                RespValue::Verbatim("txt".to_string(), info::info(db, &sections))
+            }
+            Command::ClusterMeet(ip, port, cport) => cluster::meet(db, &ip, port, cport)?,
+            Command::ClusterMyId => cluster::myid(db)?,
+            Command::ClusterNodes => cluster::nodes(db)?,
+            Command::ClusterSlots => cluster::slots(db)?,
+            Command::ClusterShards => cluster::shards(db)?,
+            Command::ClusterInfo => cluster::info(db)?,
+            Command::ClusterKeySlot(key) => cluster::keyslot(db, &key)?,
+            Command::ClusterAddSlots(slots) => cluster::add_slots(db, &slots)?,
+            Command::ClusterSetSlot(slot, action) => cluster::set_slot(db, slot, action)?,
+            Command::ClusterGetKeysInSlot(slot, count) => {
+                cluster::get_keys_in_slot(db, slot, count)?
+            }
+            Command::ClusterCountKeysInSlot(slot) => cluster::count_keys_in_slot(db, slot)?,
+            Command::ClusterFailover(mode) => cluster::failover(db, mode)?,
+            Command::ClusterReplicate(id) => cluster::replicate(db, &id)?,
+            Command::Dump(key) => match db.get(&key) {
+                Some(value) => RespValue::BulkString(Bytes::from(rdb::dump_value(&value))),
+                None => RespValue::Null,
+            },
+            Command::Restore(key, expire_at, payload, replace)
+            | Command::RestoreAsking(key, expire_at, payload, replace) => {
+                restore(db, key, expire_at, &payload, replace)?
+            }
+            Command::SentinelMasters => sentinel::masters(db)?,
+            Command::SentinelMaster(name) => sentinel::master(db, &name)?,
+            Command::SentinelReplicas(name) => sentinel::replicas(db, &name)?,
+            Command::SentinelSentinels(name) => sentinel::sentinels(db, &name)?,
+            Command::SentinelGetMasterAddr(name) => sentinel::get_master_addr(db, &name)?,
+            Command::SentinelIsMasterDown(ip, port, epoch, run_id) => {
+                sentinel::is_master_down_by_addr(db, &ip, port, epoch, &run_id)?
+            }
+            Command::SentinelMonitor(name, ip, port, quorum) => {
+                sentinel::monitor(db, name, ip, port, quorum)?
+            }
+            Command::SentinelRemove(name) => sentinel::remove(db, &name)?,
+            Command::SentinelSet(name, options) => sentinel::set(db, &name, &options)?,
+            Command::SentinelMyId => sentinel::myid(db)?,
+            Command::SentinelFailover(name) => sentinel::failover(db, &name)?,
+            Command::ListCommands => {
+                RespValue::Array(COMMANDS.iter().map(CommandSpec::info).collect())
+            }
+            Command::CountCommands => RespValue::Integer(COMMANDS.len() as i64),
+            Command::InfoCommands(names) if names.is_empty() => {
+                RespValue::Array(COMMANDS.iter().map(CommandSpec::info).collect())
+            }
+            Command::InfoCommands(names) => RespValue::Array(
+                names
+                    .iter()
+                    .map(|name| {
+                        command_table::find(COMMANDS, name)
+                            .map_or(RespValue::Null, CommandSpec::info)
+                    })
+                    .collect(),
+            ),
+            Command::DocsCommands(names) => {
+                let specs: Vec<&CommandSpec> = if names.is_empty() {
+                    COMMANDS.iter().collect()
+                } else {
+                    names
+                        .iter()
+                        .filter_map(|name| command_table::find(COMMANDS, name))
+                        .collect()
+                };
+                RespValue::Map(
+                    specs
+                        .into_iter()
+                        .map(|spec| {
+                            (
+                                RespValue::BulkString(Bytes::from_static(spec.name.as_bytes())),
+                                spec.docs_entry(),
+                            )
+                        })
+                        .collect(),
+                )
+            }
+            Command::GetKeys(args) => {
+                let spec = match command_table::lookup(&args) {
+                    Ok(spec) => spec,
+                    Err(CommandError::WrongArity(_)) => {
+                        return Err(CommandError::Err(
+                            "Invalid number of arguments specified for command".to_string(),
+                        ));
+                    }
+                    Err(_) => {
+                        return Err(CommandError::Err("Invalid command specified".to_string()));
+                    }
+                };
+                let keys = spec.keys_of(&args);
+                if keys.is_empty() {
+                    return Err(CommandError::Err(
+                        "The command has no key arguments".to_string(),
+                    ));
+                }
+                RespValue::Array(keys.into_iter().cloned().collect())
+            }
+            Command::EvalSha => return Err(CommandError::NoScript),
+            Command::Quit => RespValue::SimpleString("OK".to_string()),
+            // Per-connection commands are handled by the connection's client
+            Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PSubscribe(_)
+            | Command::PUnsubscribe(_)
+            | Command::SSubscribe(_)
+            | Command::SUnsubscribe(_)
+            | Command::Hello(..)
+            | Command::ClientId
+            | Command::ClientGetName
+            | Command::ClientSetName(_)
+            | Command::ReplConf(_)
+            | Command::PSync(..)
+            | Command::Wait(..)
+            | Command::WaitAof(..)
+            | Command::Asking
+            | Command::Migrate(_) => {
+                return Err(CommandError::Err(
+                    "command requires a connection".to_string(),
+                ));
+            }
+        })
     }
 }
 
-fn handle_push(len: usize) -> RespValue {
-    if len == 0 {
-        RespValue::SimpleError(WRONG_TYPE_ERR.to_string())
-    } else {
-        RespValue::Integer(len as i64)
+/// Converts a relative EXPIRE argument to a deadline; non-positive values
+/// yield a deadline that has already passed. As in Redis, the deadline must
+/// fit in milliseconds since the Unix epoch.
+fn expiry_from_seconds(seconds: i64) -> Option<Instant> {
+    let now = Instant::now();
+    if seconds <= 0 {
+        return Some(now);
     }
+    seconds.checked_mul(1000)?.checked_add(unix_time_ms())?;
+    now.checked_add(Duration::from_secs(seconds as u64))
 }
 
-fn parse_echo(args: &[RespValue]) -> Result<Command, String> {
-    match args.get(1) {
-        Some(RespValue::BulkString(s)) => Ok(Command::Echo(s.clone())),
-        _ => Err("ERR wrong number of arguments for 'echo' command".to_string()),
-    }
+fn invalid_expire_time(command: &str) -> CommandError {
+    CommandError::Err(format!("invalid expire time in '{}' command", command))
+}
+
+fn parse_ping(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Ping)
 }
 
-fn parse_set(args: &[RespValue]) -> Result<Command, String> {
-    if args.len() < 3 {
-        return Err("ERR wrong number of arguments for 'set' command".to_string());
+fn parse_quit(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Quit)
+}
+
+fn parse_evalsha(args: &[RespValue]) -> Result<Command, CommandError> {
+    let numkeys = parse_int(&args[2])?;
+    if numkeys < 0 {
+        return Err(CommandError::Err(
+            "Number of keys can't be negative".to_string(),
+        ));
     }
+    if numkeys as usize > args.len() - 3 {
+        return Err(CommandError::Err(
+            "Number of keys can't be greater than number of args".to_string(),
+        ));
+    }
+    Ok(Command::EvalSha)
+}
 
+fn parse_echo(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Echo(get_bulk_string_value(&args[1])?))
+}
+
+fn parse_set(args: &[RespValue]) -> Result<Command, CommandError> {
     let key = get_bulk_string_value(&args[1])?;
     let value = get_bulk_string_value(&args[2])?;
 
     let mut duration: Option<Duration> = None;
 
     if args.len() > 3 {
-        match &args[3] {
-            RespValue::BulkString(s) if s.to_lowercase() == "px" => match args.get(4) {
-                Some(RespValue::BulkString(ms_str)) => {
-                    let ms = ms_str
-                        .parse::<u64>()
-                        .map_err(|_| "ERR value is not an integer")?;
-                    duration = Some(Duration::from_millis(ms));
-                }
-                _ => return Err("ERR syntax error".to_string()),
-            },
-            _ => return Err("ERR syntax error".to_string()),
+        let option = get_bulk_string_value(&args[3])?.to_ascii_lowercase();
+        let Some(arg) = args.get(4).filter(|_| args.len() == 5) else {
+            return Err(CommandError::Syntax);
+        };
+        // An expiry must lie ahead, even when given as an absolute time
+        let value = parse_int(arg)?;
+        if value <= 0 {
+            return Err(invalid_expire_time("set"));
         }
+
+        // Absolute times are stored relative to now, like every other expiry
+        let now = unix_time_ms();
+        let ms = match option.as_slice() {
+            b"ex" => value.checked_mul(1000),
+            b"px" => Some(value),
+            b"exat" => value.checked_mul(1000).map(|at| (at - now).max(0)),
+            b"pxat" => Some((value - now).max(0)),
+            _ => return Err(CommandError::Syntax),
+        };
+        // The deadline must fit both as a Unix time and as an `Instant`
+        let d = ms
+            .filter(|&ms| now.checked_add(ms).is_some())
+            .map(|ms| Duration::from_millis(ms as u64))
+            .filter(|&d| Instant::now().checked_add(d).is_some())
+            .ok_or_else(|| invalid_expire_time("set"))?;
+        duration = Some(d);
     }
 
     Ok(Command::Set(key, value, duration))
 }
 
-fn parse_get(args: &[RespValue]) -> Result<Command, String> {
-    if args.len() != 2 {
-        return Err("ERR wrong number of arguments for 'get' command".to_string());
-    }
+fn parse_get(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Get(get_bulk_string_value(&args[1])?))
+}
 
-    let key = get_bulk_string_value(&args[1]);
+fn parse_del(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Del(get_bulk_strings(&args[1..])?))
+}
 
-    Ok(Command::Get(key?))
+fn parse_expire(args: &[RespValue]) -> Result<Command, CommandError> {
+    let key = get_bulk_string_value(&args[1])?;
+    let seconds = parse_int(&args[2])?;
+    Ok(Command::Expire(key, seconds))
 }
 
-fn parse_rpush(args: &[RespValue]) -> Result<Command, String> {
-    let (key, values) = parse_push_command(args, "rpush")?;
-    Ok(Command::RPush(key, values))
+fn parse_pexpireat(args: &[RespValue]) -> Result<Command, CommandError> {
+    let key = get_bulk_string_value(&args[1])?;
+    let at = parse_int(&args[2])?;
+    Ok(Command::PExpireAt(key, at))
 }
 
-fn parse_lpush(args: &[RespValue]) -> Result<Command, String> {
-    let (key, values) = parse_push_command(args, "lpush")?;
-    Ok(Command::LPush(key, values))
+fn parse_rpush(args: &[RespValue]) -> Result<Command, CommandError> {
+    let key = get_bulk_string_value(&args[1])?;
+    Ok(Command::RPush(key, get_bulk_strings(&args[2..])?))
 }
 
-fn parse_push_command(args: &[RespValue], cmd_name: &str) -> Result<(String, Vec<String>), String> {
-    if args.len() < 3 {
-        return Err(format!(
-            "ERR wrong number of arguments for '{}' command",
-            cmd_name
+fn parse_lpush(args: &[RespValue]) -> Result<Command, CommandError> {
+    let key = get_bulk_string_value(&args[1])?;
+    Ok(Command::LPush(key, get_bulk_strings(&args[2..])?))
+}
+
+fn parse_hset(args: &[RespValue]) -> Result<Command, CommandError> {
+    if !args.len().is_multiple_of(2) {
+        return Err(CommandError::WrongArity("hset"));
+    }
+
+    let key = get_bulk_string_value(&args[1])?;
+    let mut fields = Vec::with_capacity((args.len() - 2) / 2);
+    for pair in args[2..].chunks(2) {
+        fields.push((
+            get_bulk_string_value(&pair[0])?,
+            get_bulk_string_value(&pair[1])?,
         ));
     }
+    Ok(Command::HSet(key, fields))
+}
 
+fn parse_hget(args: &[RespValue]) -> Result<Command, CommandError> {
     let key = get_bulk_string_value(&args[1])?;
+    let field = get_bulk_string_value(&args[2])?;
+    Ok(Command::HGet(key, field))
+}
+
+fn parse_hgetall(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::HGetAll(get_bulk_string_value(&args[1])?))
+}
 
-    // Use iterator to collect all values from index 2 onwards
-    let values: Result<Vec<String>, String> = args[2..].iter().map(get_bulk_string_value).collect();
+fn parse_sadd(args: &[RespValue]) -> Result<Command, CommandError> {
+    let key = get_bulk_string_value(&args[1])?;
+    Ok(Command::SAdd(key, get_bulk_strings(&args[2..])?))
+}
 
-    Ok((key, values?))
+fn parse_smembers(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::SMembers(get_bulk_string_value(&args[1])?))
 }
 
-fn parse_range(args: &[RespValue]) -> Result<Command, String> {
-    // LRANGE key start stop
-    if args.len() != 4 {
-        return Err("ERR wrong number of arguments for 'lrange' command".to_string());
+fn parse_zadd(args: &[RespValue]) -> Result<Command, CommandError> {
+    if !args.len().is_multiple_of(2) {
+        return Err(CommandError::WrongArity("zadd"));
     }
-    let key = match &args[1] {
-        RespValue::BulkString(s) => s.clone(),
-        _ => return Err("ERR key must be a bulk string".to_string()),
-    };
 
+    let key = get_bulk_string_value(&args[1])?;
+    let mut members = Vec::with_capacity((args.len() - 2) / 2);
+    for pair in args[2..].chunks(2) {
+        let score = get_string_value(&pair[0])?
+            .parse::<f64>()
+            .ok()
+            .filter(|score| !score.is_nan())
+            .ok_or(CommandError::NotFloat)?;
+        members.push((score, get_bulk_string_value(&pair[1])?));
+    }
+    Ok(Command::ZAdd(key, members))
+}
+
+fn parse_zscore(args: &[RespValue]) -> Result<Command, CommandError> {
+    let key = get_bulk_string_value(&args[1])?;
+    let member = get_bulk_string_value(&args[2])?;
+    Ok(Command::ZScore(key, member))
+}
+
+fn parse_range(args: &[RespValue]) -> Result<Command, CommandError> {
+    // LRANGE key start stop
+    let key = get_bulk_string_value(&args[1])?;
     let start = parse_int(&args[2])?;
     let end = parse_int(&args[3])?;
 
     Ok(Command::LRange(key, start, end))
 }
 
-fn get_bulk_string_value(arg: &RespValue) -> Result<String, String> {
+fn get_bulk_string_value(arg: &RespValue) -> Result<Bytes, CommandError> {
     Ok(match arg {
         RespValue::BulkString(s) => s.clone(),
-        _ => return Err("ERR value must be bulk string".to_string()),
+        _ => return Err(CommandError::Err("value must be bulk string".to_string())),
     })
 }
 
-fn parse_llen(args: &[RespValue]) -> Result<Command, String> {
-    if args.len() != 2 {
-        return Err("ERR wrong number of arguments for 'llen' command".to_string());
-    }
+fn get_bulk_strings(args: &[RespValue]) -> Result<Vec<Bytes>, CommandError> {
+    args.iter().map(get_bulk_string_value).collect()
+}
 
-    let key = get_bulk_string_value(&args[1])?;
-    Ok(Command::LLen(key))
+/// Reads a textual argument such as a subcommand, option or config value.
+fn get_string_value(arg: &RespValue) -> Result<String, CommandError> {
+    let bytes = get_bulk_string_value(arg)?;
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| CommandError::Err("value must be valid UTF-8".to_string()))
 }
 
-fn parse_lpop(args: &[RespValue]) -> Result<Command, String> {
-    let num_args = args.len();
+fn parse_llen(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::LLen(get_bulk_string_value(&args[1])?))
+}
 
-    if num_args < 2 || num_args > 4 {
-        return Err("ERR wrong number of arguments for 'lpop' command".to_string());
+fn parse_lpop(args: &[RespValue]) -> Result<Command, CommandError> {
+    if args.len() > 3 {
+        return Err(CommandError::WrongArity("lpop"));
     }
     let key = get_bulk_string_value(&args[1])?;
-    let count = if num_args > 2 {
-        let n = parse_int(&args[2])?;
-        if n < 0 {
-            return Err("Must be positive value".to_string());
+    let count = match args.get(2) {
+        Some(arg) => {
+            let n = parse_int(arg)?;
+            if n < 0 {
+                return Err(CommandError::Err(
+                    "value is out of range, must be positive".to_string(),
+                ));
+            }
+            Some(n as usize)
         }
-        Some(n as usize)
-    } else {
-        None
+        None => None,
     };
 
     Ok(Command::LPop(key, count))
 }
 
-fn parse_int(arg: &RespValue) -> Result<i64, String> {
+fn parse_subscribe(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Subscribe(get_bulk_strings(&args[1..])?))
+}
+
+fn parse_unsubscribe(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Unsubscribe(get_bulk_strings(&args[1..])?))
+}
+
+fn parse_psubscribe(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::PSubscribe(get_bulk_strings(&args[1..])?))
+}
+
+fn parse_punsubscribe(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::PUnsubscribe(get_bulk_strings(&args[1..])?))
+}
+
+fn parse_ssubscribe(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::SSubscribe(get_bulk_strings(&args[1..])?))
+}
+
+fn parse_sunsubscribe(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::SUnsubscribe(get_bulk_strings(&args[1..])?))
+}
+
+fn parse_publish(args: &[RespValue]) -> Result<Command, CommandError> {
+    let channel = get_bulk_string_value(&args[1])?;
+    let message = get_bulk_string_value(&args[2])?;
+    Ok(Command::Publish(channel, message))
+}
+
+fn parse_spublish(args: &[RespValue]) -> Result<Command, CommandError> {
+    let channel = get_bulk_string_value(&args[1])?;
+    let message = get_bulk_string_value(&args[2])?;
+    Ok(Command::SPublish(channel, message))
+}
+
+/// Container commands always dispatch to one of their subcommands, so this
+/// only runs when a container is given no subcommand, which its arity
+/// already rejects.
+fn parse_container(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Err(CommandError::Syntax)
+}
+
+fn parse_pubsub_channels(args: &[RespValue]) -> Result<Command, CommandError> {
+    if args.len() > 3 {
+        return Err(CommandError::WrongArity("pubsub|channels"));
+    }
+    let pattern = args.get(2).map(get_bulk_string_value).transpose()?;
+    Ok(Command::PubSubChannels(pattern))
+}
+
+fn parse_pubsub_numsub(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::PubSubNumSub(get_bulk_strings(&args[2..])?))
+}
+
+fn parse_pubsub_numpat(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::PubSubNumPat)
+}
+
+fn parse_pubsub_shardchannels(args: &[RespValue]) -> Result<Command, CommandError> {
+    if args.len() > 3 {
+        return Err(CommandError::WrongArity("pubsub|shardchannels"));
+    }
+    let pattern = args.get(2).map(get_bulk_string_value).transpose()?;
+    Ok(Command::PubSubShardChannels(pattern))
+}
+
+fn parse_pubsub_shardnumsub(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::PubSubShardNumSub(get_bulk_strings(&args[2..])?))
+}
+
+fn parse_hello(args: &[RespValue]) -> Result<Command, CommandError> {
+    let Some(version) = args.get(1) else {
+        return Ok(Command::Hello(None, None, None));
+    };
+
+    let version = parse_int(version).map_err(|_| {
+        CommandError::Err("Protocol version is not an integer or out of range".to_string())
+    })?;
+
+    let mut auth = None;
+    let mut name = None;
+    let mut i = 2;
+    while i < args.len() {
+        let option = get_string_value(&args[i])?.to_uppercase();
+        match option.as_str() {
+            "AUTH" if i + 2 < args.len() => {
+                auth = Some((
+                    get_string_value(&args[i + 1])?,
+                    get_string_value(&args[i + 2])?,
+                ));
+                i += 3;
+            }
+            "SETNAME" if i + 1 < args.len() => {
+                name = Some(get_string_value(&args[i + 1])?);
+                i += 2;
+            }
+            _ => {
+                return Err(CommandError::Err(format!(
+                    "Syntax error in HELLO option '{}'",
+                    option
+                )));
+            }
+        }
+    }
+
+    Ok(Command::Hello(Some(version), auth, name))
+}
+
+fn parse_client_id(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ClientId)
+}
+
+fn parse_client_getname(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ClientGetName)
+}
+
+fn parse_client_setname(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ClientSetName(get_string_value(&args[2])?))
+}
+
+fn parse_config_get(args: &[RespValue]) -> Result<Command, CommandError> {
+    let patterns: Result<Vec<String>, CommandError> =
+        args[2..].iter().map(get_string_value).collect();
+    Ok(Command::ConfigGet(patterns?))
+}
+
+fn parse_config_set(args: &[RespValue]) -> Result<Command, CommandError> {
+    if !args.len().is_multiple_of(2) {
+        return Err(CommandError::WrongArity("config|set"));
+    }
+
+    let mut pairs = Vec::new();
+    for pair in args[2..].chunks(2) {
+        pairs.push((get_string_value(&pair[0])?, get_string_value(&pair[1])?));
+    }
+    Ok(Command::ConfigSet(pairs))
+}
+
+fn parse_config_rewrite(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ConfigRewrite)
+}
+
+fn parse_config_resetstat(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ConfigResetStat)
+}
+
+fn parse_save(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Save)
+}
+
+fn parse_bgsave(args: &[RespValue]) -> Result<Command, CommandError> {
+    // SCHEDULE only matters while another child is busy, which never
+    // happens here, so it simply starts the save
+    match args.get(1).map(get_string_value).transpose()? {
+        None => Ok(Command::BgSave),
+        Some(option) if option.eq_ignore_ascii_case("schedule") && args.len() == 2 => {
+            Ok(Command::BgSave)
+        }
+        Some(_) => Err(CommandError::Syntax),
+    }
+}
+
+fn parse_bgrewriteaof(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::BgRewriteAof)
+}
+
+fn parse_replicaof(args: &[RespValue]) -> Result<Command, CommandError> {
+    let host = get_string_value(&args[1])?;
+    let port = get_string_value(&args[2])?;
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        return Ok(Command::ReplicaOf(None));
+    }
+
+    let port = u16::try_from(parse_int(&args[2])?).map_err(|_| CommandError::NotInteger)?;
+    Ok(Command::ReplicaOf(Some((host, port))))
+}
+
+fn parse_replconf(args: &[RespValue]) -> Result<Command, CommandError> {
+    // Options come in pairs
+    if args.len().is_multiple_of(2) {
+        return Err(CommandError::Syntax);
+    }
+    let options = args[1..]
+        .chunks(2)
+        .map(|pair| Ok((get_string_value(&pair[0])?, get_string_value(&pair[1])?)))
+        .collect::<Result<_, CommandError>>()?;
+    Ok(Command::ReplConf(options))
+}
+
+fn parse_role(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Role)
+}
+
+fn parse_info(args: &[RespValue]) -> Result<Command, CommandError> {
+    let sections = args[1..]
+        .iter()
+        .map(|arg| get_string_value(arg).map(|section| section.to_lowercase()))
+        .collect::<Result<_, _>>()?;
+    Ok(Command::Info(sections))
+}
+
+fn parse_psync(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::PSync(
+        get_string_value(&args[1])?,
+        parse_int(&args[2])?,
+    ))
+}
+
+fn parse_wait(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Wait(
+        parse_int(&args[1])?,
+        parse_timeout(&args[2])?,
+    ))
+}
+
+fn parse_waitaof(args: &[RespValue]) -> Result<Command, CommandError> {
+    let numlocal = parse_int(&args[1])?;
+    let numreplicas = parse_int(&args[2])?;
+    if numlocal < 0 || numreplicas < 0 {
+        return Err(CommandError::Err(
+            "value is out of range, must be positive".to_string(),
+        ));
+    }
+    Ok(Command::WaitAof(
+        numlocal,
+        numreplicas,
+        parse_timeout(&args[3])?,
+    ))
+}
+
+/// A timeout given in milliseconds.
+fn parse_timeout(arg: &RespValue) -> Result<Duration, CommandError> {
+    let ms = parse_int(arg)?;
+    if ms < 0 {
+        return Err(CommandError::Err("timeout is negative".to_string()));
+    }
+    Ok(Duration::from_millis(ms as u64))
+}
+
+fn parse_cluster_meet(args: &[RespValue]) -> Result<Command, CommandError> {
+    if args.len() > 5 {
+        return Err(CommandError::WrongArity("cluster|meet"));
+    }
+    let ip = get_string_value(&args[2])?;
+    let port = parse_int(&args[3])
+        .ok()
+        .and_then(|port| u16::try_from(port).ok())
+        .ok_or_else(|| {
+            CommandError::Err(format!(
+                "Invalid base port specified: {}",
+                get_string_value(&args[3]).unwrap_or_default()
+            ))
+        })?;
+    let cport = args
+        .get(4)
+        .map(|arg| {
+            parse_int(arg)
+                .ok()
+                .and_then(|cport| u16::try_from(cport).ok())
+                .ok_or_else(|| CommandError::Err("Invalid bus port specified".to_string()))
+        })
+        .transpose()?;
+    Ok(Command::ClusterMeet(ip, port, cport))
+}
+
+fn parse_cluster_myid(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ClusterMyId)
+}
+
+fn parse_cluster_nodes(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ClusterNodes)
+}
+
+fn parse_cluster_slots(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ClusterSlots)
+}
+
+fn parse_cluster_shards(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ClusterShards)
+}
+
+fn parse_cluster_info(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ClusterInfo)
+}
+
+fn parse_cluster_keyslot(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ClusterKeySlot(get_bulk_string_value(&args[2])?))
+}
+
+fn parse_cluster_addslots(args: &[RespValue]) -> Result<Command, CommandError> {
+    let slots = args[2..].iter().map(parse_slot).collect::<Result<_, _>>()?;
+    Ok(Command::ClusterAddSlots(slots))
+}
+
+fn parse_slot(arg: &RespValue) -> Result<u16, CommandError> {
+    parse_int(arg)
+        .ok()
+        .and_then(|slot| u16::try_from(slot).ok())
+        .filter(|&slot| slot < SLOT_COUNT)
+        .ok_or_else(|| CommandError::Err("Invalid or out of range slot".to_string()))
+}
+
+fn parse_cluster_setslot(args: &[RespValue]) -> Result<Command, CommandError> {
+    let slot = parse_slot(&args[2])?;
+    let action = get_string_value(&args[3])?.to_lowercase();
+    let node = || match args.get(4) {
+        Some(node) if args.len() == 5 => get_string_value(node),
+        _ => Err(CommandError::Syntax),
+    };
+    let action = match action.as_str() {
+        "migrating" => SetSlot::Migrating(node()?),
+        "importing" => SetSlot::Importing(node()?),
+        "node" => SetSlot::Node(node()?),
+        "stable" if args.len() == 4 => SetSlot::Stable,
+        _ => {
+            return Err(CommandError::Err(
+                "Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"
+                    .to_string(),
+            ));
+        }
+    };
+    Ok(Command::ClusterSetSlot(slot, action))
+}
+
+fn parse_cluster_getkeysinslot(args: &[RespValue]) -> Result<Command, CommandError> {
+    let slot = parse_slot(&args[2])?;
+    let count = usize::try_from(parse_int(&args[3])?)
+        .map_err(|_| CommandError::Err("Invalid number of keys".to_string()))?;
+    Ok(Command::ClusterGetKeysInSlot(slot, count))
+}
+
+fn parse_cluster_countkeysinslot(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ClusterCountKeysInSlot(parse_slot(&args[2])?))
+}
+
+fn parse_cluster_failover(args: &[RespValue]) -> Result<Command, CommandError> {
+    if args.len() > 3 {
+        return Err(CommandError::Syntax);
+    }
+    let mode = match args.get(2).map(get_string_value).transpose()? {
+        None => Failover::Default,
+        Some(mode) if mode.eq_ignore_ascii_case("force") => Failover::Force,
+        Some(mode) if mode.eq_ignore_ascii_case("takeover") => Failover::Takeover,
+        Some(_) => return Err(CommandError::Syntax),
+    };
+    Ok(Command::ClusterFailover(mode))
+}
+
+fn parse_cluster_replicate(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ClusterReplicate(get_string_value(&args[2])?))
+}
+
+fn parse_asking(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Asking)
+}
+
+/// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE]
+/// [AUTH password | AUTH2 username password] [KEYS key ...]
+fn parse_migrate(args: &[RespValue]) -> Result<Command, CommandError> {
+    let host = get_string_value(&args[1])?;
+    let port = u16::try_from(parse_int(&args[2])?).map_err(|_| CommandError::NotInteger)?;
+    let db = parse_int(&args[4])?;
+    let timeout = Duration::from_millis(parse_int(&args[5])?.max(0) as u64);
+
+    let mut migration = Migration {
+        host,
+        port,
+        keys: vec![get_bulk_string_value(&args[3])?],
+        db,
+        timeout,
+        copy: false,
+        replace: false,
+        auth: None,
+    };
+    let mut i = 6;
+    while i < args.len() {
+        let option = get_string_value(&args[i])?.to_lowercase();
+        match option.as_str() {
+            "copy" => migration.copy = true,
+            "replace" => migration.replace = true,
+            "auth" if i + 1 < args.len() => {
+                migration.auth = Some(vec![get_bulk_string_value(&args[i + 1])?]);
+                i += 1;
+            }
+            "auth2" if i + 2 < args.len() => {
+                migration.auth = Some(get_bulk_strings(&args[i + 1..i + 3])?);
+                i += 2;
+            }
+            "keys" => {
+                if !migration.keys[0].is_empty() {
+                    return Err(CommandError::Err(
+                        "When using MIGRATE KEYS option, the key argument must be set to the empty string"
+                            .to_string(),
+                    ));
+                }
+                migration.keys = get_bulk_strings(&args[i + 1..])?;
+                break;
+            }
+            _ => return Err(CommandError::Syntax),
+        }
+        i += 1;
+    }
+    Ok(Command::Migrate(migration))
+}
+
+fn parse_dump(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::Dump(get_bulk_string_value(&args[1])?))
+}
+
+fn parse_restore(args: &[RespValue]) -> Result<Command, CommandError> {
+    let (key, expire_at, payload, replace) = parse_restore_args(args)?;
+    Ok(Command::Restore(key, expire_at, payload, replace))
+}
+
+fn parse_restore_asking(args: &[RespValue]) -> Result<Command, CommandError> {
+    let (key, expire_at, payload, replace) = parse_restore_args(args)?;
+    Ok(Command::RestoreAsking(key, expire_at, payload, replace))
+}
+
+/// RESTORE key ttl serialized-value [REPLACE] [ABSTTL] [IDLETIME seconds]
+/// [FREQ frequency]
+///
+/// Keys carry no eviction metadata here, so IDLETIME and FREQ are checked
+/// and then ignored.
+fn parse_restore_args(
+    args: &[RespValue],
+) -> Result<(Bytes, Option<i64>, Bytes, bool), CommandError> {
+    let key = get_bulk_string_value(&args[1])?;
+    let ttl = parse_int(&args[2])?;
+    let payload = get_bulk_string_value(&args[3])?;
+
+    let mut replace = false;
+    let mut absttl = false;
+    let mut idletime = false;
+    let mut freq = false;
+    let mut i = 4;
+    while i < args.len() {
+        let has_value = i + 1 < args.len();
+        match get_string_value(&args[i])?.to_lowercase().as_str() {
+            "replace" => replace = true,
+            "absttl" => absttl = true,
+            "idletime" if has_value && !freq => {
+                i += 1;
+                if parse_int(&args[i])? < 0 {
+                    return Err(CommandError::Err(
+                        "Invalid IDLETIME value, must be >= 0".to_string(),
+                    ));
+                }
+                idletime = true;
+            }
+            "freq" if has_value && !idletime => {
+                i += 1;
+                if !(0..=255).contains(&parse_int(&args[i])?) {
+                    return Err(CommandError::Err(
+                        "Invalid FREQ value, must be >= 0 and <= 255".to_string(),
+                    ));
+                }
+                freq = true;
+            }
+            _ => return Err(CommandError::Syntax),
+        }
+        i += 1;
+    }
+    if ttl < 0 {
+        return Err(CommandError::Err(
+            "Invalid TTL value, must be >= 0".to_string(),
+        ));
+    }
+
+    let expire_at = match ttl {
+        0 => None,
+        ttl if absttl => Some(ttl),
+        ttl => Some(unix_time_ms().saturating_add(ttl)),
+    };
+    Ok((key, expire_at, payload, replace))
+}
+
+fn parse_sentinel_masters(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::SentinelMasters)
+}
+
+fn parse_sentinel_master(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::SentinelMaster(get_string_value(&args[2])?))
+}
+
+fn parse_sentinel_replicas(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::SentinelReplicas(get_string_value(&args[2])?))
+}
+
+fn parse_sentinel_sentinels(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::SentinelSentinels(get_string_value(&args[2])?))
+}
+
+fn parse_sentinel_get_master_addr(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::SentinelGetMasterAddr(get_string_value(&args[2])?))
+}
+
+fn parse_sentinel_is_master_down(args: &[RespValue]) -> Result<Command, CommandError> {
+    let ip = get_string_value(&args[2])?;
+    let port = parse_port(&args[3])?;
+    let epoch = u64::try_from(parse_int(&args[4])?).map_err(|_| CommandError::NotInteger)?;
+    let run_id = get_string_value(&args[5])?;
+    Ok(Command::SentinelIsMasterDown(ip, port, epoch, run_id))
+}
+
+fn parse_sentinel_monitor(args: &[RespValue]) -> Result<Command, CommandError> {
+    let name = get_string_value(&args[2])?;
+    let ip = get_string_value(&args[3])?;
+    let port = parse_port(&args[4])?;
+    let quorum = usize::try_from(parse_int(&args[5])?).unwrap_or(0);
+    Ok(Command::SentinelMonitor(name, ip, port, quorum))
+}
+
+fn parse_port(arg: &RespValue) -> Result<u16, CommandError> {
+    parse_int(arg)
+        .ok()
+        .and_then(|port| u16::try_from(port).ok())
+        .filter(|&port| port != 0)
+        .ok_or_else(|| CommandError::Err("Invalid port number".to_string()))
+}
+
+fn parse_sentinel_remove(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::SentinelRemove(get_string_value(&args[2])?))
+}
+
+fn parse_sentinel_set(args: &[RespValue]) -> Result<Command, CommandError> {
+    // Options come in pairs
+    if args.len().is_multiple_of(2) {
+        return Err(CommandError::WrongArity("sentinel|set"));
+    }
+    let options = args[3..]
+        .chunks(2)
+        .map(|pair| Ok((get_string_value(&pair[0])?, get_string_value(&pair[1])?)))
+        .collect::<Result<_, CommandError>>()?;
+    Ok(Command::SentinelSet(get_string_value(&args[2])?, options))
+}
+
+fn parse_sentinel_myid(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::SentinelMyId)
+}
+
+fn parse_sentinel_failover(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::SentinelFailover(get_string_value(&args[2])?))
+}
+
+fn parse_lastsave(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::LastSave)
+}
+
+fn parse_command(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::ListCommands)
+}
+
+fn parse_command_count(_args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::CountCommands)
+}
+
+fn parse_command_info(args: &[RespValue]) -> Result<Command, CommandError> {
+    let names: Result<Vec<String>, CommandError> = args[2..].iter().map(get_string_value).collect();
+    Ok(Command::InfoCommands(names?))
+}
+
+fn parse_command_docs(args: &[RespValue]) -> Result<Command, CommandError> {
+    let names: Result<Vec<String>, CommandError> = args[2..].iter().map(get_string_value).collect();
+    Ok(Command::DocsCommands(names?))
+}
+
+fn parse_command_getkeys(args: &[RespValue]) -> Result<Command, CommandError> {
+    Ok(Command::GetKeys(args[2..].to_vec()))
+}
+
+/// Stores a value serialized by DUMP under `key`.
+fn restore(
+    db: &Db,
+    key: Bytes,
+    expire_at: Option<i64>,
+    payload: &[u8],
+    replace: bool,
+) -> Result<RespValue, CommandError> {
+    let expiry = match expire_at {
+        Some(at) => Some(unix_ms_to_instant(at).ok_or_else(|| invalid_expire_time("restore"))?),
+        None => None,
+    };
+    if !replace && db.exists(&key) {
+        return Err(CommandError::BusyKey);
+    }
+    let max_len = db.config().proto_max_bulk_len();
+    let value = rdb::restore_value(payload, max_len).map_err(|e| match e {
+        RdbError::BadChecksum | RdbError::UnsupportedVersion(_) => {
+            CommandError::Err("DUMP payload version or checksum are wrong".to_string())
+        }
+        _ => CommandError::Err("Bad data format".to_string()),
+    })?;
+
+    // A key already past its expiry is simply not created
+    if expire_at.is_some_and(|at| at <= unix_time_ms()) {
+        db.del(&[key]);
+    } else {
+        db.restore(key, value, expiry);
+    }
+    Ok(RespValue::SimpleString("OK".to_string()))
+}
+
+fn parse_int(arg: &RespValue) -> Result<i64, CommandError> {
     match arg {
-        RespValue::BulkString(s) => s
-            .parse::<i64>()
-            .map_err(|_| "ERR value is not an integer".to_string()),
-        _ => Err("ERR value is not an integer".to_string()),
+        RespValue::BulkString(s) => std::str::from_utf8(s)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or(CommandError::NotInteger),
+        _ => Err(CommandError::NotInteger),
     }
 }
 
@@ -251,7 +2236,7 @@ mod tests {
     fn make_resp_command(args: Vec<&str>) -> RespValue {
         let items = args
             .into_iter()
-            .map(|s| RespValue::BulkString(s.to_string()))
+            .map(|s| RespValue::BulkString(Bytes::copy_from_slice(s.as_bytes())))
             .collect();
         RespValue::Array(items)
     }
@@ -298,22 +2283,22 @@ mod tests {
     #[test]
     fn test_execute_set_get() {
         let db = Db::new();
-        let set_cmd = Command::Set("key".to_string(), "val".to_string(), None);
+        let set_cmd = Command::Set(Bytes::from("key"), Bytes::from("val"), None);
         let resp = set_cmd.execute(&db);
         assert_eq!(resp, RespValue::SimpleString("OK".to_string()));
 
-        let get_cmd = Command::Get("key".to_string());
+        let get_cmd = Command::Get(Bytes::from("key"));
         let resp = get_cmd.execute(&db);
-        assert_eq!(resp, RespValue::BulkString("val".to_string()));
+        assert_eq!(resp, RespValue::BulkString(Bytes::from("val")));
     }
 
     #[test]
     fn test_execute_rpush_wrong_type() {
         let db = Db::new();
-        let set_cmd = Command::Set("mykey".to_string(), "hello".to_string(), None);
+        let set_cmd = Command::Set(Bytes::from("mykey"), Bytes::from("hello"), None);
         set_cmd.execute(&db);
 
-        let rpush_cmd = Command::RPush("mykey".to_string(), vec!["hello".to_string()]);
+        let rpush_cmd = Command::RPush(Bytes::from("mykey"), vec![Bytes::from("hello")]);
         let resp = rpush_cmd.execute(&db);
 
         match resp {
@@ -321,4 +2306,217 @@ mod tests {
             _ => panic!("Expected SimpleError for WRONGTYPE"),
         }
     }
+
+    #[test]
+    fn test_execute_publish_counts_receivers() {
+        let db = Db::new();
+        let (mut sub, _rx) = db.pubsub().subscriber();
+        sub.subscribe(vec![Bytes::from("news")]);
+
+        let publish = make_resp_command(vec!["PUBLISH", "news", "hi"]);
+        let resp = Command::from_resp(publish).unwrap().execute(&db);
+        assert_eq!(resp, RespValue::Integer(1));
+
+        let numsub = make_resp_command(vec!["PUBSUB", "NUMSUB", "news", "other"]);
+        let resp = Command::from_resp(numsub).unwrap().execute(&db);
+        assert_eq!(
+            resp,
+            RespValue::Array(vec![
+                RespValue::BulkString(Bytes::from("news")),
+                RespValue::Integer(1),
+                RespValue::BulkString(Bytes::from("other")),
+                RespValue::Integer(0),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_execute_native_resp3_replies() {
+        let db = Db::new();
+        Command::HSet(Bytes::from("h"), vec![(Bytes::from("f"), Bytes::from("v"))]).execute(&db);
+        Command::ZAdd(Bytes::from("z"), vec![(1.5, Bytes::from("m"))]).execute(&db);
+
+        let resp = Command::HGetAll(Bytes::from("h")).execute(&db);
+        assert_eq!(
+            resp,
+            RespValue::Map(vec![(
+                RespValue::BulkString(Bytes::from("f")),
+                RespValue::BulkString(Bytes::from("v")),
+            )])
+        );
+
+        let resp = Command::ZScore(Bytes::from("z"), Bytes::from("m")).execute(&db);
+        assert_eq!(resp, RespValue::Double(1.5));
+    }
+
+    #[test]
+    fn test_execute_set_get_binary_value() {
+        let db = Db::new();
+        let value = Bytes::from_static(&[0x00, 0xff, 0xfe, b'\r', b'\n']);
+        Command::Set(Bytes::from_static(&[0xc3, 0x28]), value.clone(), None).execute(&db);
+
+        let resp = Command::Get(Bytes::from_static(&[0xc3, 0x28])).execute(&db);
+        assert_eq!(resp, RespValue::BulkString(value));
+    }
+
+    #[test]
+    fn test_execute_expire_out_of_range() {
+        let db = Db::new();
+        Command::Set(Bytes::from("k"), Bytes::from("v"), None).execute(&db);
+        assert_eq!(
+            Command::Expire(Bytes::from("k"), i64::MAX).execute(&db),
+            RespValue::from(invalid_expire_time("expire"))
+        );
+        assert_eq!(
+            Command::Set(Bytes::from("a"), Bytes::from("b"), None).execute(&db),
+            RespValue::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            Command::Get(Bytes::from("k")).execute(&db),
+            RespValue::BulkString(Bytes::from("v"))
+        );
+
+        // SET accepts no expiry that would remove the key straight away
+        for option in ["EX", "PX", "EXAT", "PXAT"] {
+            for value in ["0", "-1"] {
+                let set = make_resp_command(vec!["SET", "k", "w", option, value]);
+                assert_eq!(
+                    Command::from_resp(set).unwrap_err(),
+                    invalid_expire_time("set")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_execute_dump_restore() {
+        let db = Db::new();
+        Command::RPush(Bytes::from("l"), vec![Bytes::from("a"), Bytes::from("b")]).execute(&db);
+        let RespValue::BulkString(payload) = Command::Dump(Bytes::from("l")).execute(&db) else {
+            panic!("Expected the serialized value");
+        };
+        assert_eq!(
+            Command::Dump(Bytes::from("missing")).execute(&db),
+            RespValue::Null
+        );
+
+        let restore = |args: Vec<&[u8]>| {
+            let items = args
+                .into_iter()
+                .map(|s| RespValue::BulkString(Bytes::copy_from_slice(s)))
+                .collect();
+            Command::from_resp(RespValue::Array(items)).map(|cmd| cmd.execute(&db))
+        };
+        assert_eq!(
+            restore(vec![b"RESTORE", b"l", b"0", &payload]).unwrap(),
+            RespValue::from(CommandError::BusyKey)
+        );
+        assert_eq!(
+            restore(vec![b"RESTORE", b"l2", b"0", &payload, b"IDLETIME", b"10"]).unwrap(),
+            RespValue::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            Command::LRange(Bytes::from("l2"), 0, -1).execute(&db),
+            RespValue::Array(vec![
+                RespValue::BulkString(Bytes::from("a")),
+                RespValue::BulkString(Bytes::from("b")),
+            ])
+        );
+
+        let mut corrupt = payload.to_vec();
+        *corrupt.last_mut().unwrap() ^= 0xff;
+        assert_eq!(
+            restore(vec![b"RESTORE", b"l", b"0", &corrupt, b"REPLACE"]).unwrap(),
+            RespValue::SimpleError("ERR DUMP payload version or checksum are wrong".to_string())
+        );
+        assert_eq!(
+            restore(vec![b"RESTORE", b"l", b"0", &payload, b"FREQ", b"256"]).unwrap_err(),
+            CommandError::Err("Invalid FREQ value, must be >= 0 and <= 255".to_string())
+        );
+        assert_eq!(
+            restore(vec![
+                b"RESTORE",
+                b"l",
+                b"0",
+                &payload,
+                b"IDLETIME",
+                b"1",
+                b"FREQ",
+                b"1"
+            ])
+            .unwrap_err(),
+            CommandError::Syntax
+        );
+    }
+
+    #[test]
+    fn test_evalsha_finds_no_script() {
+        let db = Db::new();
+        let call = make_resp_command(vec!["EVALSHA", "ffffffff", "1", "k"]);
+        assert_eq!(
+            Command::from_resp(call).unwrap().execute(&db),
+            RespValue::from(CommandError::NoScript)
+        );
+        let call = make_resp_command(vec!["EVALSHA", "ffffffff", "2", "k"]);
+        assert_eq!(
+            Command::from_resp(call).unwrap_err().to_string(),
+            "ERR Number of keys can't be greater than number of args"
+        );
+    }
+
+    #[test]
+    fn test_config_set_is_all_or_nothing() {
+        let db = Db::new();
+        let call = make_resp_command(vec![
+            "CONFIG",
+            "SET",
+            "dbfilename",
+            "other.rdb",
+            "save",
+            "bad",
+        ]);
+        assert_eq!(
+            Command::from_resp(call).unwrap().execute(&db),
+            RespValue::SimpleError(
+                "ERR CONFIG SET failed (possibly related to argument 'save') - Invalid save parameters"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            db.config().get("dbfilename"),
+            vec![("dbfilename".to_string(), "dump.rdb".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let unknown = make_resp_command(vec!["FOO", "a", "b"]);
+        assert_eq!(
+            Command::from_resp(unknown).unwrap_err().to_string(),
+            "ERR unknown command 'FOO', with args beginning with: 'a' 'b' "
+        );
+
+        let arity = make_resp_command(vec!["GET"]);
+        assert_eq!(
+            Command::from_resp(arity).unwrap_err(),
+            CommandError::WrongArity("get")
+        );
+
+        let far = make_resp_command(vec!["SET", "k", "v", "EX", "9223372036854775"]);
+        assert_eq!(
+            Command::from_resp(far).unwrap_err(),
+            invalid_expire_time("set")
+        );
+        let max = make_resp_command(vec!["SET", "k", "v", "PX", "9223372036854775807"]);
+        assert_eq!(
+            Command::from_resp(max).unwrap_err(),
+            invalid_expire_time("set")
+        );
+
+        let not_integer = make_resp_command(vec!["EXPIRE", "k", "soon"]);
+        assert_eq!(
+            Command::from_resp(not_integer).unwrap_err(),
+            CommandError::NotInteger
+        );
+    }
 }
@@ -0,0 +1,758 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::{error::ConfigError, glob::glob_match, notify, resp::split_args};
+
+#[derive(Clone)]
+struct ConfigState {
+    /// The config file the server was started with, if any.
+    file: Option<PathBuf>,
+    port: u16,
+    bind: Vec<String>,
+    notify_keyspace_events: u32,
+    proto_max_bulk_len: usize,
+    client_query_buffer_limit: usize,
+    dir: String,
+    dbfilename: String,
+    save: Vec<SaveRule>,
+    appendonly: bool,
+    appendfilename: String,
+    appendfsync: AppendFsync,
+    aof_load_truncated: bool,
+    repl_backlog_size: usize,
+    replica_read_only: bool,
+    replica_serve_stale_data: bool,
+    cluster_enabled: bool,
+    cluster_config_file: String,
+    cluster_port: u16,
+    cluster_node_timeout: u64,
+    cluster_require_full_coverage: bool,
+}
+
+impl ConfigState {
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let name = name.to_lowercase();
+
+        match name.as_str() {
+            "port" => {
+                self.port = value
+                    .parse()
+                    .map_err(|_| "argument couldn't be parsed into an integer".to_string())?;
+            }
+            "bind" => {
+                let addresses: Vec<String> = value.split_whitespace().map(String::from).collect();
+                if addresses.is_empty() {
+                    return Err("bind needs at least one address".to_string());
+                }
+                self.bind = addresses;
+            }
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events = notify::parse_flags(value)?;
+            }
+            "proto-max-bulk-len" => {
+                self.proto_max_bulk_len = parse_proto_limit(value)?;
+            }
+            "client-query-buffer-limit" => {
+                self.client_query_buffer_limit = parse_proto_limit(value)?;
+            }
+            "dir" => {
+                if !Path::new(value).is_dir() {
+                    return Err("No such file or directory".to_string());
+                }
+                self.dir = value.to_string();
+            }
+            "dbfilename" => {
+                if value.is_empty() || value.contains('/') {
+                    return Err("dbfilename can't be a path, just a filename".to_string());
+                }
+                self.dbfilename = value.to_string();
+            }
+            "save" => {
+                self.save = parse_save_rules(value).ok_or("Invalid save parameters")?;
+            }
+            "appendonly" => self.appendonly = parse_bool(value)?,
+            "aof-load-truncated" => self.aof_load_truncated = parse_bool(value)?,
+            "replica-read-only" => self.replica_read_only = parse_bool(value)?,
+            "replica-serve-stale-data" => self.replica_serve_stale_data = parse_bool(value)?,
+            "cluster-enabled" => self.cluster_enabled = parse_bool(value)?,
+            "cluster-require-full-coverage" => {
+                self.cluster_require_full_coverage = parse_bool(value)?
+            }
+            "cluster-config-file" => {
+                if value.is_empty() || value.contains('/') {
+                    return Err("cluster-config-file can't be a path, just a filename".to_string());
+                }
+                self.cluster_config_file = value.to_string();
+            }
+            "cluster-port" => {
+                self.cluster_port = value
+                    .parse()
+                    .map_err(|_| "argument couldn't be parsed into an integer".to_string())?;
+            }
+            "cluster-node-timeout" => {
+                self.cluster_node_timeout = value
+                    .parse()
+                    .map_err(|_| "argument couldn't be parsed into an integer".to_string())?;
+            }
+            "appendfilename" => {
+                if value.is_empty() || value.contains('/') {
+                    return Err("appendfilename can't be a path, just a filename".to_string());
+                }
+                self.appendfilename = value.to_string();
+            }
+            "repl-backlog-size" => {
+                self.repl_backlog_size = match parse_memory(value) {
+                    Some(size) if size >= MIN_REPL_BACKLOG_SIZE => size,
+                    Some(_) => {
+                        return Err(format!(
+                            "argument must be between {} and {} inclusive",
+                            MIN_REPL_BACKLOG_SIZE,
+                            i64::MAX
+                        ));
+                    }
+                    None => return Err("argument must be a memory value".to_string()),
+                };
+            }
+            "appendfsync" => {
+                self.appendfsync = match value.to_lowercase().as_str() {
+                    "always" => AppendFsync::Always,
+                    "everysec" => AppendFsync::EverySec,
+                    "no" => AppendFsync::No,
+                    _ => {
+                        return Err(
+                            "argument(s) must be one of the following: always, everysec, no"
+                                .to_string(),
+                        );
+                    }
+                };
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Snapshot after `seconds` have passed if at least `changes` were made.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SaveRule {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// When the append-only file is flushed to disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AppendFsync {
+    /// After every write.
+    Always,
+    /// Once a second, from the background.
+    EverySec,
+    /// Whenever the operating system decides to.
+    No,
+}
+
+/// Runtime-tunable server parameters, shared by every connection.
+#[derive(Clone)]
+pub struct Config {
+    state: Arc<RwLock<ConfigState>>,
+}
+
+const PARAMETERS: &[&str] = &[
+    "port",
+    "bind",
+    "notify-keyspace-events",
+    "proto-max-bulk-len",
+    "client-query-buffer-limit",
+    "dir",
+    "dbfilename",
+    "save",
+    "appendonly",
+    "appendfilename",
+    "appendfsync",
+    "aof-load-truncated",
+    "repl-backlog-size",
+    "replica-read-only",
+    "replica-serve-stale-data",
+    "cluster-enabled",
+    "cluster-config-file",
+    "cluster-port",
+    "cluster-node-timeout",
+    "cluster-require-full-coverage",
+];
+
+/// Parameters that can only be given at startup.
+const IMMUTABLE: &[&str] = &[
+    "port",
+    "bind",
+    "cluster-enabled",
+    "cluster-config-file",
+    "cluster-port",
+];
+
+/// Parameters whose value is a list of arguments rather than a single one.
+const MULTI_ARG: &[&str] = &["bind", "save"];
+
+/// Marks the lines CONFIG REWRITE appends to a config file.
+const REWRITE_SIGNATURE: &str = "# Generated by CONFIG REWRITE";
+
+/// Smallest accepted value for the protocol size limits, as in Redis.
+const MIN_PROTO_LIMIT: usize = 1024 * 1024;
+
+/// Smallest accepted replication backlog, as in Redis.
+const MIN_REPL_BACKLOG_SIZE: usize = 16 * 1024;
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            state: Arc::new(RwLock::new(ConfigState {
+                file: None,
+                port: 6379,
+                bind: vec!["127.0.0.1".to_string()],
+                notify_keyspace_events: 0,
+                proto_max_bulk_len: 512 * 1024 * 1024,
+                client_query_buffer_limit: 1024 * 1024 * 1024,
+                dir: ".".to_string(),
+                dbfilename: "dump.rdb".to_string(),
+                save: parse_save_rules("3600 1 300 100 60 10000").unwrap(),
+                appendonly: false,
+                appendfilename: "appendonly.aof".to_string(),
+                appendfsync: AppendFsync::EverySec,
+                aof_load_truncated: true,
+                repl_backlog_size: 1024 * 1024,
+                replica_read_only: true,
+                replica_serve_stale_data: true,
+                cluster_enabled: false,
+                cluster_config_file: "nodes.conf".to_string(),
+                cluster_port: 0,
+                cluster_node_timeout: 15000,
+                cluster_require_full_coverage: true,
+            })),
+        }
+    }
+
+    pub fn file(&self) -> Option<PathBuf> {
+        self.state.read().unwrap().file.clone()
+    }
+
+    /// Remembers the config file the server was started with, for CONFIG
+    /// REWRITE.
+    pub fn set_file(&self, path: PathBuf) {
+        self.state.write().unwrap().file = Some(path);
+    }
+
+    /// TCP port the server listens on. Only read at startup.
+    pub fn port(&self) -> u16 {
+        self.state.read().unwrap().port
+    }
+
+    /// Addresses the server listens on. Only read at startup.
+    pub fn bind(&self) -> Vec<String> {
+        self.state.read().unwrap().bind.clone()
+    }
+
+    pub fn notify_keyspace_events(&self) -> u32 {
+        self.state.read().unwrap().notify_keyspace_events
+    }
+
+    /// Largest bulk string a client may send.
+    pub fn proto_max_bulk_len(&self) -> usize {
+        self.state.read().unwrap().proto_max_bulk_len
+    }
+
+    /// Largest amount of data a client may have buffered for a single,
+    /// still incomplete request.
+    pub fn client_query_buffer_limit(&self) -> usize {
+        self.state.read().unwrap().client_query_buffer_limit
+    }
+
+    /// Directory the snapshot file is kept in.
+    pub fn dir(&self) -> String {
+        self.state.read().unwrap().dir.clone()
+    }
+
+    pub fn dbfilename(&self) -> String {
+        self.state.read().unwrap().dbfilename.clone()
+    }
+
+    pub fn save_rules(&self) -> Vec<SaveRule> {
+        self.state.read().unwrap().save.clone()
+    }
+
+    pub fn appendonly(&self) -> bool {
+        self.state.read().unwrap().appendonly
+    }
+
+    pub fn appendfilename(&self) -> String {
+        self.state.read().unwrap().appendfilename.clone()
+    }
+
+    pub fn appendfsync(&self) -> AppendFsync {
+        self.state.read().unwrap().appendfsync
+    }
+
+    /// Whether an append-only file cut short by a crash is loaded anyway.
+    pub fn aof_load_truncated(&self) -> bool {
+        self.state.read().unwrap().aof_load_truncated
+    }
+
+    /// How much of the recent replication stream is kept for replicas that
+    /// reconnect.
+    pub fn repl_backlog_size(&self) -> usize {
+        self.state.read().unwrap().repl_backlog_size
+    }
+
+    /// Whether a replica rejects writes from its own clients.
+    pub fn replica_read_only(&self) -> bool {
+        self.state.read().unwrap().replica_read_only
+    }
+
+    /// Whether a replica answers queries while its link to the master is
+    /// down, with data that may be out of date.
+    pub fn replica_serve_stale_data(&self) -> bool {
+        self.state.read().unwrap().replica_serve_stale_data
+    }
+
+    pub fn cluster_enabled(&self) -> bool {
+        self.state.read().unwrap().cluster_enabled
+    }
+
+    /// File the cluster configuration is kept in, inside `dir`.
+    pub fn cluster_config_file(&self) -> String {
+        self.state.read().unwrap().cluster_config_file.clone()
+    }
+
+    /// Port of the cluster bus, the client port plus 10000 unless set.
+    pub fn cluster_port(&self) -> u16 {
+        let lock = self.state.read().unwrap();
+        match lock.cluster_port {
+            0 => lock.port.wrapping_add(10000),
+            port => port,
+        }
+    }
+
+    /// Milliseconds a node may be unreachable before it is considered
+    /// failing.
+    pub fn cluster_node_timeout(&self) -> u64 {
+        self.state.read().unwrap().cluster_node_timeout
+    }
+
+    /// Whether queries are refused while some slots have no working master.
+    pub fn cluster_require_full_coverage(&self) -> bool {
+        self.state.read().unwrap().cluster_require_full_coverage
+    }
+
+    /// Whether `name` can only be given at startup.
+    pub fn is_immutable(name: &str) -> bool {
+        IMMUTABLE.contains(&name.to_lowercase().as_str())
+    }
+
+    pub fn is_parameter(name: &str) -> bool {
+        PARAMETERS.contains(&name.to_lowercase().as_str())
+    }
+
+    /// Returns every parameter whose name matches the glob `pattern`, paired
+    /// with its current value.
+    pub fn get(&self, pattern: &str) -> Vec<(String, String)> {
+        let pattern = pattern.to_lowercase();
+        PARAMETERS
+            .iter()
+            .filter(|name| glob_match(pattern.as_bytes(), name.as_bytes()))
+            .filter_map(|name| self.value(name).map(|value| (name.to_string(), value)))
+            .collect()
+    }
+
+    pub fn set(&self, name: &str, value: &str) -> Result<(), String> {
+        self.state.write().unwrap().set(name, value)
+    }
+
+    /// Sets every pair in `pairs`, or none of them when one can't be set,
+    /// in which case its name is returned along with the reason.
+    pub fn set_all(&self, pairs: &[(String, String)]) -> Result<(), (String, String)> {
+        let mut lock = self.state.write().unwrap();
+        let mut updated = lock.clone();
+        for (name, value) in pairs {
+            updated.set(name, value).map_err(|e| (name.clone(), e))?;
+        }
+        *lock = updated;
+        Ok(())
+    }
+
+    fn value(&self, name: &str) -> Option<String> {
+        let lock = self.state.read().unwrap();
+        match name {
+            "port" => Some(lock.port.to_string()),
+            "bind" => Some(lock.bind.join(" ")),
+            "notify-keyspace-events" => Some(notify::flags_to_string(lock.notify_keyspace_events)),
+            "proto-max-bulk-len" => Some(lock.proto_max_bulk_len.to_string()),
+            "client-query-buffer-limit" => Some(lock.client_query_buffer_limit.to_string()),
+            "dir" => Some(lock.dir.clone()),
+            "dbfilename" => Some(lock.dbfilename.clone()),
+            "save" => Some(
+                lock.save
+                    .iter()
+                    .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            "appendonly" => Some(yes_no(lock.appendonly)),
+            "appendfilename" => Some(lock.appendfilename.clone()),
+            "aof-load-truncated" => Some(yes_no(lock.aof_load_truncated)),
+            "repl-backlog-size" => Some(lock.repl_backlog_size.to_string()),
+            "replica-read-only" => Some(yes_no(lock.replica_read_only)),
+            "replica-serve-stale-data" => Some(yes_no(lock.replica_serve_stale_data)),
+            "cluster-enabled" => Some(yes_no(lock.cluster_enabled)),
+            "cluster-config-file" => Some(lock.cluster_config_file.clone()),
+            "cluster-port" => Some(lock.cluster_port.to_string()),
+            "cluster-node-timeout" => Some(lock.cluster_node_timeout.to_string()),
+            "cluster-require-full-coverage" => Some(yes_no(lock.cluster_require_full_coverage)),
+            "appendfsync" => Some(
+                match lock.appendfsync {
+                    AppendFsync::Always => "always",
+                    AppendFsync::EverySec => "everysec",
+                    AppendFsync::No => "no",
+                }
+                .to_string(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// The config file line setting `name` to its current value.
+    fn line(&self, name: &str) -> String {
+        let value = self.value(name).unwrap_or_default();
+        let args = if MULTI_ARG.contains(&name) && !value.is_empty() {
+            value
+                .split_whitespace()
+                .map(quote)
+                .collect::<Vec<_>>()
+                .join(" ")
+        } else {
+            quote(&value)
+        };
+        format!("{} {}", name, args)
+    }
+
+    /// Updates the config file the server was started with to hold the
+    /// current value of every parameter. Lines setting a parameter are
+    /// replaced in place and parameters missing from the file are appended
+    /// unless at their default, so comments and other directives survive.
+    pub fn rewrite(&self) -> Result<(), ConfigError> {
+        let path = self.file().ok_or(ConfigError::NoFile)?;
+        let old = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(ConfigError::Rewrite(e)),
+        };
+
+        // Only the first line for a parameter is kept, as it now holds
+        // the whole value
+        let mut lines = Vec::new();
+        let mut written = HashSet::new();
+        for line in old.lines() {
+            match directive_name(line) {
+                Some(name) if Config::is_parameter(&name) => {
+                    if written.insert(name.clone()) {
+                        lines.push(self.line(&name));
+                    }
+                }
+                _ => lines.push(line.to_string()),
+            }
+        }
+
+        let defaults = Config::new();
+        let mut signed = lines.iter().any(|line| line == REWRITE_SIGNATURE);
+        for name in PARAMETERS {
+            if written.contains(*name) || self.value(name) == defaults.value(name) {
+                continue;
+            }
+            if !signed {
+                lines.push(REWRITE_SIGNATURE.to_string());
+                signed = true;
+            }
+            lines.push(self.line(name));
+        }
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        write_file(&path, &text).map_err(ConfigError::Rewrite)
+    }
+}
+
+/// A line of a config file: a parameter name followed by its arguments.
+pub struct Directive {
+    /// Line number, counting from 1.
+    pub line: usize,
+    pub text: String,
+    /// The lowercased name.
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+/// Splits the text of a config file into its directives, skipping blank
+/// lines and comments. Arguments are quoted as in inline commands.
+pub fn parse(text: &str) -> Result<Vec<Directive>, ConfigError> {
+    let mut directives = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let args = split_args(line.as_bytes()).map_err(|_| {
+            ConfigError::BadLine(
+                i + 1,
+                line.to_string(),
+                "Unbalanced quotes in configuration line".to_string(),
+            )
+        })?;
+        let mut args = args
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg).into_owned());
+        let Some(name) = args.next() else {
+            continue;
+        };
+        directives.push(Directive {
+            line: i + 1,
+            text: line.to_string(),
+            name: name.to_lowercase(),
+            args: args.collect(),
+        });
+    }
+    Ok(directives)
+}
+
+/// Formats `value` as a single config file argument, in double quotes with
+/// escapes when it's empty or holds whitespace, quotes or unprintable
+/// characters.
+pub fn quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !matches!(b, b'"' | b'\'' | b'\\'));
+    if plain {
+        return value.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    for b in value.bytes() {
+        match b {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            b' ' => quoted.push(' '),
+            b if b.is_ascii_graphic() => quoted.push(b as char),
+            b => quoted.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The lowercased name a config file line sets, if it isn't a comment.
+fn directive_name(line: &str) -> Option<String> {
+    let line = line.trim();
+    if line.starts_with('#') {
+        return None;
+    }
+    let args = split_args(line.as_bytes()).ok()?;
+    let name = args.first()?;
+    Some(String::from_utf8_lossy(name).to_lowercase())
+}
+
+/// Writes the file to a temporary one first and renames it into place, so
+/// that a crash never leaves it half written.
+fn write_file(path: &Path, text: &str) -> io::Result<()> {
+    let temp = path.with_file_name(format!("temp-{}.conf", std::process::id()));
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Parses a memory amount such as `1048576`, `512mb` or `1g`. As in Redis,
+/// `k`, `m` and `g` are powers of 1000 while `kb`, `mb` and `gb` are powers
+/// of 1024.
+pub fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.to_lowercase();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (digits, unit) = value.split_at(split);
+
+    let multiplier: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+/// Parses `save` rules given as `<seconds> <changes>` pairs. An empty
+/// value disables automatic snapshots.
+fn parse_save_rules(value: &str) -> Option<Vec<SaveRule>> {
+    let numbers: Option<Vec<u64>> = value.split_whitespace().map(|n| n.parse().ok()).collect();
+    let numbers = numbers?;
+    if !numbers.len().is_multiple_of(2) {
+        return None;
+    }
+    Some(
+        numbers
+            .chunks(2)
+            .map(|pair| SaveRule {
+                seconds: pair[0],
+                changes: pair[1],
+            })
+            .collect(),
+    )
+}
+
+fn parse_proto_limit(value: &str) -> Result<usize, String> {
+    match parse_memory(value) {
+        Some(limit) if limit >= MIN_PROTO_LIMIT => Ok(limit),
+        Some(_) => Err(format!(
+            "argument must be between {} and {} inclusive",
+            MIN_PROTO_LIMIT,
+            i64::MAX
+        )),
+        None => Err("argument must be a memory value".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("1048576"), Some(1048576));
+        assert_eq!(parse_memory("512mb"), Some(512 * 1024 * 1024));
+        assert_eq!(parse_memory("1G"), Some(1_000_000_000));
+        assert_eq!(parse_memory("10kb"), Some(10240));
+        assert_eq!(parse_memory("mb"), None);
+        assert_eq!(parse_memory("5tb"), None);
+    }
+
+    #[test]
+    fn test_proto_limits() {
+        let config = Config::new();
+        config.set("proto-max-bulk-len", "2mb").unwrap();
+        assert_eq!(config.proto_max_bulk_len(), 2 * 1024 * 1024);
+        assert!(config.set("client-query-buffer-limit", "1k").is_err());
+        assert_eq!(
+            config.get("proto-*"),
+            vec![("proto-max-bulk-len".to_string(), "2097152".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_save_rules() {
+        let config = Config::new();
+        assert_eq!(config.save_rules().len(), 3);
+
+        config.set("save", "900 1").unwrap();
+        assert_eq!(
+            config.save_rules(),
+            vec![SaveRule {
+                seconds: 900,
+                changes: 1
+            }]
+        );
+        assert!(config.set("save", "900").is_err());
+        assert!(config.set("save", "a 1").is_err());
+
+        config.set("save", "").unwrap();
+        assert!(config.save_rules().is_empty());
+        assert_eq!(
+            config.get("save"),
+            vec![("save".to_string(), String::new())]
+        );
+    }
+
+    #[test]
+    fn test_parse_and_quote() {
+        let text = "# comment\n\nPort 7000\ndir \"/tmp/a b\"\nsave 900 1\n";
+        let directives = parse(text).unwrap();
+        assert_eq!(directives.len(), 3);
+        assert_eq!(directives[0].line, 3);
+        assert_eq!(directives[0].name, "port");
+        assert_eq!(directives[1].args, vec!["/tmp/a b"]);
+        assert_eq!(directives[2].args, vec!["900", "1"]);
+        assert!(matches!(
+            parse("dir \"unterminated"),
+            Err(ConfigError::BadLine(1, _, _))
+        ));
+
+        for value in ["plain", "", "a b", "quote\"s", "back\\slash\n"] {
+            let line = format!("x {}", quote(value));
+            assert_eq!(parse(&line).unwrap()[0].args, vec![value]);
+        }
+    }
+
+    #[test]
+    fn test_rewrite_preserves_comments() {
+        let dir = std::env::temp_dir().join(format!("redis-lite-conf-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("redis.conf");
+        fs::write(
+            &path,
+            "# Server settings\nport 7000\nsave 900 1\nsave 300 10\nunknown-directive 1\n",
+        )
+        .unwrap();
+
+        let config = Config::new();
+        assert!(matches!(config.rewrite(), Err(ConfigError::NoFile)));
+        config.set_file(path.clone());
+        config.set("port", "7000").unwrap();
+        config.set("save", "60 5").unwrap();
+        config.set("appendonly", "yes").unwrap();
+        config.rewrite().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# Server settings\nport 7000\nsave 60 5\nunknown-directive 1\n\
+             # Generated by CONFIG REWRITE\nappendonly yes\n"
+        );
+
+        // Rewriting again changes lines in place without signing twice
+        config.set("appendonly", "no").unwrap();
+        config.rewrite().unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# Server settings\nport 7000\nsave 60 5\nunknown-directive 1\n\
+             # Generated by CONFIG REWRITE\nappendonly no\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,93 +1,581 @@
+use bytes::{Bytes, BytesMut};
 use std::{
     collections::{HashMap, HashSet, VecDeque},
-    sync::{Arc, Mutex},
-    time::Instant,
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::oneshot;
+
+use crate::{
+    aof::Aof,
+    cluster::Cluster,
+    config::Config,
+    error::{DbError, RdbError},
+    notify,
+    persistence::Persistence,
+    pubsub::PubSub,
+    rdb,
+    replication::Replication,
+    resp::{Protocol, RespValue},
+    sentinel::Sentinel,
+    slot::key_hash_slot,
+    stats::Stats,
+};
+
+/// Only a single logical database is supported, so notifications always
+/// refer to database 0.
+const DB_INDEX: usize = 0;
+
+/// Rough memory held by a key's table entry, besides its key and value:
+/// the key and value handles, the expiry and the table slot.
+const ENTRY_OVERHEAD: usize = 112;
+
+/// Rough memory held by each element of a collection besides its data,
+/// the size of a `Bytes` handle.
+const ELEMENT_OVERHEAD: usize = 32;
 
 struct DbState {
-    kv: HashMap<String, (DataType, Option<Instant>)>,
-    waiting: HashMap<String, VecDeque<oneshot::Sender<String>>>,
+    /// Values are shared with snapshots still being written out, and copied
+    /// on the first change made to them meanwhile.
+    kv: HashMap<Bytes, (Arc<DataType>, Option<Instant>)>,
+    /// Changes since the last successful save.
+    dirty: u64,
+    /// Estimated memory held by `kv`, kept up to date as it changes so that
+    /// INFO needn't walk every key.
+    used_memory: usize,
+    /// Keys with an expiry set.
+    expires: usize,
+    /// The sum of those expiries, each in milliseconds after `epoch`.
+    expiry_sum: u128,
+    epoch: Instant,
 }
 
-#[derive(Clone, Debug)]
+impl DbState {
+    fn new() -> DbState {
+        DbState {
+            kv: HashMap::new(),
+            dirty: 0,
+            used_memory: 0,
+            expires: 0,
+            expiry_sum: 0,
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Stores `key`, replacing any existing value.
+    fn insert(&mut self, key: Bytes, value: DataType, expiry: Option<Instant>) {
+        self.used_memory += entry_size(&key, &value);
+        self.count_expiry(expiry, true);
+        if let Some((old, old_expiry)) = self.kv.insert(key.clone(), (Arc::new(value), expiry)) {
+            self.used_memory -= entry_size(&key, &old);
+            self.count_expiry(old_expiry, false);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Arc<DataType>> {
+        let (value, expiry) = self.kv.remove(key)?;
+        self.used_memory -= entry_size(key, &value);
+        self.count_expiry(expiry, false);
+        Some(value)
+    }
+
+    /// The collection at `key`, created empty with `empty` if missing.
+    fn get_or_insert(&mut self, key: &Bytes, empty: fn() -> DataType) -> &mut DataType {
+        if !self.kv.contains_key(key) {
+            self.insert(key.clone(), empty(), None);
+        }
+        Arc::make_mut(&mut self.kv.get_mut(key).expect("key was just inserted").0)
+    }
+
+    fn set_expiry(&mut self, key: &[u8], at: Instant) {
+        let Some((_, expiry)) = self.kv.get_mut(key) else {
+            return;
+        };
+        let old = expiry.replace(at);
+        self.count_expiry(old, false);
+        self.count_expiry(Some(at), true);
+    }
+
+    /// Adds `expiry`, if there is one, to the expiry totals, or takes it
+    /// away from them.
+    fn count_expiry(&mut self, expiry: Option<Instant>, added: bool) {
+        let Some(at) = expiry else {
+            return;
+        };
+        let ms = at.saturating_duration_since(self.epoch).as_millis();
+        if added {
+            self.expires += 1;
+            self.expiry_sum += ms;
+        } else {
+            self.expires -= 1;
+            self.expiry_sum -= ms;
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum DataType {
-    String(String),
-    List(VecDeque<String>),
-    Set(HashSet<String>),
-    Hash(HashMap<String, String>),
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Set(HashSet<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+    /// Sorted set members mapped to their scores.
+    ZSet(HashMap<Bytes, f64>),
+}
+
+impl DataType {
+    /// An estimate of the memory the value holds.
+    fn estimated_size(&self) -> usize {
+        match self {
+            DataType::String(s) => s.len(),
+            DataType::List(items) => items.iter().map(element_size).sum(),
+            DataType::Set(members) => members.iter().map(element_size).sum(),
+            DataType::Hash(fields) => fields
+                .iter()
+                .map(|(f, v)| 2 * ELEMENT_OVERHEAD + f.len() + v.len())
+                .sum(),
+            DataType::ZSet(members) => members
+                .keys()
+                .map(|m| element_size(m) + size_of::<f64>())
+                .sum(),
+        }
+    }
+}
+
+/// An estimate of the memory a key and its value hold.
+fn entry_size(key: &[u8], value: &DataType) -> usize {
+    ENTRY_OVERHEAD + key.len() + value.estimated_size()
+}
+
+/// An estimate of the memory a list or set element holds.
+fn element_size(element: &Bytes) -> usize {
+    ELEMENT_OVERHEAD + element.len()
+}
+
+/// The dataset as it was when [`Db::snapshot`] was called.
+#[derive(Default)]
+pub struct Snapshot(Vec<(Bytes, Arc<DataType>, Option<i64>)>);
+
+impl Snapshot {
+    /// The keys as they are stored on disk. Values changed since the
+    /// snapshot was taken are copied here, the rest moved.
+    pub fn into_entries(self) -> Vec<rdb::Entry> {
+        self.0
+            .into_iter()
+            .map(|(key, value, expire_at)| rdb::Entry {
+                key,
+                value: Arc::unwrap_or_clone(value),
+                expire_at,
+            })
+            .collect()
+    }
 }
 
 #[derive(Clone)]
 pub struct Db {
     state: Arc<Mutex<DbState>>,
+    pubsub: PubSub,
+    config: Config,
+    persistence: Persistence,
+    aof: Aof,
+    replication: Replication,
+    cluster: Cluster,
+    sentinel: Sentinel,
+    stats: Stats,
+    /// Serializes writes with their propagation, so the log records them in
+    /// the order they were applied.
+    write_lock: Arc<Mutex<()>>,
+    /// Set while the append-only file is replayed, whose writes already
+    /// happened once and so fire no notifications.
+    loading: Arc<AtomicBool>,
 }
 
 impl Db {
     pub fn new() -> Db {
+        let config = Config::new();
         Db {
-            state: Arc::new(Mutex::new(DbState { kv: HashMap::new() })),
+            state: Arc::new(Mutex::new(DbState::new())),
+            pubsub: PubSub::new(),
+            replication: Replication::new(config.clone()),
+            cluster: Cluster::new(),
+            sentinel: Sentinel::new(),
+            stats: Stats::new(),
+            config,
+            persistence: Persistence::new(),
+            aof: Aof::new(),
+            write_lock: Arc::new(Mutex::new(())),
+            loading: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub fn get(&self, key: &str) -> Option<DataType> {
+    pub fn pubsub(&self) -> &PubSub {
+        &self.pubsub
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn persistence(&self) -> &Persistence {
+        &self.persistence
+    }
+
+    pub fn aof(&self) -> &Aof {
+        &self.aof
+    }
+
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
+
+    pub fn cluster(&self) -> &Cluster {
+        &self.cluster
+    }
+
+    pub fn sentinel(&self) -> &Sentinel {
+        &self.sentinel
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Held while a write is applied and propagated. The lock guards no
+    /// data, so a command that panicked while holding it leaves nothing
+    /// inconsistent behind and later writes carry on.
+    pub fn write_guard(&self) -> MutexGuard<'_, ()> {
+        self.write_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Marks the start or end of replaying the append-only file.
+    pub fn set_loading(&self, loading: bool) {
+        self.loading.store(loading, Ordering::Relaxed);
+    }
+
+    /// Records a write that was just applied, given as the command that
+    /// reproduces it.
+    pub fn propagate(&self, args: &[Bytes]) {
+        let mut buf = BytesMut::new();
+        RespValue::Array(args.iter().cloned().map(RespValue::BulkString).collect())
+            .write_to(&mut buf, Protocol::Resp2);
+        let data = buf.freeze();
+
+        self.aof.append(&data, self.config.appendfsync());
+        self.replication.propagate(data);
+    }
+
+    /// Number of changes since the last successful save.
+    pub fn dirty(&self) -> u64 {
+        self.state.lock().unwrap().dirty
+    }
+
+    /// Takes a snapshot of every live key, along with the change count it
+    /// includes. Values are shared rather than copied, so the lock is only
+    /// held for as long as it takes to walk the keys.
+    pub fn snapshot(&self) -> (Snapshot, u64) {
+        let lock = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let entries = lock
+            .kv
+            .iter()
+            .filter(|(_, (_, expiry))| expiry.is_none_or(|at| at > now))
+            .map(|(key, (value, expiry))| {
+                (key.clone(), value.clone(), expiry.map(instant_to_unix_ms))
+            })
+            .collect();
+        (Snapshot(entries), lock.dirty)
+    }
+
+    /// Records that the first `dirty` changes are now on disk.
+    pub fn mark_saved(&self, dirty: u64) {
         let mut lock = self.state.lock().unwrap();
+        lock.dirty = lock.dirty.saturating_sub(dirty);
+    }
 
-        if let Some((_val, Some(expiry))) = lock.kv.get(key) {
-            if Instant::now() > *expiry {
-                lock.kv.remove(key);
-                return None;
+    /// Replaces the dataset with keys loaded from disk, dropping those
+    /// that expired in the meantime. Nothing is replaced if a key's expiry
+    /// is out of range.
+    pub fn load(&self, entries: Vec<rdb::Entry>) -> Result<(), RdbError> {
+        let mut loaded = DbState::new();
+        for entry in entries {
+            if entry.expire_at.is_some_and(|ms| ms <= unix_time_ms()) {
+                continue;
             }
+            let expiry = match entry.expire_at {
+                Some(ms) => Some(unix_ms_to_instant(ms).ok_or(RdbError::Corrupt("expire time"))?),
+                None => None,
+            };
+            loaded.insert(entry.key, entry.value, expiry);
         }
+        let mut lock = self.state.lock().unwrap();
+        loaded.dirty = lock.dirty;
+        *lock = loaded;
+        Ok(())
+    }
 
-        lock.kv.get(key).map(|(val, _)| val.clone())
+    /// Whether `key` exists, without counting as a lookup.
+    pub fn exists(&self, key: &[u8]) -> bool {
+        let mut lock = self.state.lock().unwrap();
+        !self.expire_if_needed(&mut lock, key) && lock.kv.contains_key(key)
     }
 
-    pub fn set(&self, key: String, value: String, expiry: Option<Instant>) {
+    /// The value and expiry of `key`, for copying it elsewhere.
+    pub fn entry(&self, key: &[u8]) -> Option<rdb::Entry> {
         let mut lock = self.state.lock().unwrap();
-        let data = DataType::String(value);
-        lock.kv.insert(key, (data, expiry));
+        if self.expire_if_needed(&mut lock, key) {
+            return None;
+        }
+        let (value, expiry) = lock.kv.get(key)?;
+        Some(rdb::Entry {
+            key: Bytes::copy_from_slice(key),
+            value: DataType::clone(value),
+            expire_at: expiry.map(instant_to_unix_ms),
+        })
+    }
+
+    /// Stores a key copied from elsewhere, replacing any existing value.
+    pub fn restore(&self, key: Bytes, value: DataType, expiry: Option<Instant>) {
+        let mut lock = self.state.lock().unwrap();
+        lock.insert(key.clone(), value, expiry);
+        lock.dirty += 1;
+        self.notify(notify::GENERIC, "restore", &key);
+    }
+
+    /// Up to `count` keys that hash to `slot`.
+    pub fn keys_in_slot(&self, slot: u16, count: usize) -> Vec<Bytes> {
+        let lock = self.state.lock().unwrap();
+        let now = Instant::now();
+        lock.kv
+            .iter()
+            .filter(|(key, (_, expiry))| {
+                key_hash_slot(key) == slot && expiry.is_none_or(|at| at > now)
+            })
+            .map(|(key, _)| key.clone())
+            .take(count)
+            .collect()
+    }
+
+    pub fn count_keys_in_slot(&self, slot: u16) -> usize {
+        self.keys_in_slot(slot, usize::MAX).len()
+    }
+
+    /// The number of keys, as in Redis including those past their expiry
+    /// that are yet to be removed.
+    pub fn key_count(&self) -> usize {
+        self.state.lock().unwrap().kv.len()
+    }
+
+    /// Keys with an expiry set, and the average time in ms they have left.
+    /// Keys past their expiry that are yet to be removed count too, which
+    /// brings the average down.
+    pub fn expires_info(&self) -> (usize, u64) {
+        let lock = self.state.lock().unwrap();
+        if lock.expires == 0 {
+            return (0, 0);
+        }
+        let average = lock.expiry_sum / lock.expires as u128;
+        let elapsed = lock.epoch.elapsed().as_millis();
+        (lock.expires, average.saturating_sub(elapsed) as u64)
+    }
+
+    /// An estimate of the memory the dataset holds.
+    pub fn used_memory(&self) -> usize {
+        self.state.lock().unwrap().used_memory
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<DataType> {
+        let mut lock = self.state.lock().unwrap();
+
+        self.expire_if_needed(&mut lock, key);
+
+        let value = lock.kv.get(key).map(|(val, _)| DataType::clone(val));
+        self.stats.keyspace_lookup(value.is_some());
+        if value.is_none() {
+            self.notify(notify::KEY_MISS, "keymiss", key);
+        }
+        value
     }
 
-    pub fn rpush(&self, key: String, values: Vec<String>) -> usize {
-        self.modify_list(key, |list| {
+    pub fn set(&self, key: Bytes, value: Bytes, expiry: Option<Instant>) {
+        let mut lock = self.state.lock().unwrap();
+        lock.insert(key.clone(), DataType::String(value), expiry);
+        lock.dirty += 1;
+
+        self.notify(notify::STRING, "set", &key);
+        if expiry.is_some() {
+            self.notify(notify::GENERIC, "expire", &key);
+        }
+    }
+
+    /// Removes `keys`, returning how many of them existed.
+    pub fn del(&self, keys: &[Bytes]) -> usize {
+        let mut lock = self.state.lock().unwrap();
+        let mut removed = 0;
+
+        for key in keys {
+            if self.expire_if_needed(&mut lock, key) {
+                continue;
+            }
+            if lock.remove(key).is_some() {
+                removed += 1;
+                lock.dirty += 1;
+                self.notify(notify::GENERIC, "del", key);
+            }
+        }
+        removed
+    }
+
+    /// Sets the expiry of `key`, deleting it straight away when `at` is
+    /// already in the past. Returns false when the key does not exist.
+    pub fn expire(&self, key: &[u8], at: Instant) -> bool {
+        let mut lock = self.state.lock().unwrap();
+
+        if self.expire_if_needed(&mut lock, key) || !lock.kv.contains_key(key) {
+            return false;
+        }
+
+        lock.dirty += 1;
+        if at <= Instant::now() {
+            lock.remove(key);
+            self.notify(notify::GENERIC, "del", key);
+        } else {
+            lock.set_expiry(key, at);
+            self.notify(notify::GENERIC, "expire", key);
+        }
+        true
+    }
+
+    pub fn rpush(&self, key: Bytes, values: Vec<Bytes>) -> Result<usize, DbError> {
+        let size = values.iter().map(element_size).sum();
+        self.modify_list(key, "rpush", size, |list| {
             list.extend(values);
         })
     }
 
-    pub fn lpush(&self, key: String, values: Vec<String>) -> usize {
-        self.modify_list(key, |list| {
+    pub fn lpush(&self, key: Bytes, values: Vec<Bytes>) -> Result<usize, DbError> {
+        let size = values.iter().map(element_size).sum();
+        self.modify_list(key, "lpush", size, |list| {
             for value in values {
                 list.push_front(value);
             }
         })
     }
 
-    fn modify_list<F>(&self, key: String, op: F) -> usize
+    /// Applies `op` to the list at `key`, which adds elements of `size`
+    /// bytes in total.
+    fn modify_list<F>(&self, key: Bytes, event: &str, size: usize, op: F) -> Result<usize, DbError>
     where
-        F: FnOnce(&mut VecDeque<String>),
+        F: FnOnce(&mut VecDeque<Bytes>),
     {
         let mut lock = self.state.lock().unwrap();
+        self.expire_if_needed(&mut lock, &key);
+
+        let DataType::List(list) = lock.get_or_insert(&key, || DataType::List(VecDeque::new()))
+        else {
+            return Err(DbError::WrongType);
+        };
+        op(list);
+        let len = list.len();
+        lock.used_memory += size;
+        lock.dirty += 1;
+
+        self.notify(notify::LIST, event, &key);
+        Ok(len)
+    }
 
-        let entry = lock
-            .kv
-            .entry(key)
-            .or_insert((DataType::List(VecDeque::new()), None));
+    /// Sets `fields` on the hash at `key`, returning how many were new.
+    pub fn hset(&self, key: Bytes, fields: Vec<(Bytes, Bytes)>) -> Result<usize, DbError> {
+        let mut lock = self.state.lock().unwrap();
+        self.expire_if_needed(&mut lock, &key);
+
+        let DataType::Hash(hash) = lock.get_or_insert(&key, || DataType::Hash(HashMap::new()))
+        else {
+            return Err(DbError::WrongType);
+        };
+
+        let mut added = 0;
+        let (mut grown, mut shrunk) = (0, 0);
+        for (field, value) in fields {
+            let field_size = 2 * ELEMENT_OVERHEAD + field.len();
+            grown += field_size + value.len();
+            match hash.insert(field, value) {
+                // Only the value was replaced
+                Some(old) => shrunk += field_size + old.len(),
+                None => added += 1,
+            }
+        }
+        lock.used_memory = lock.used_memory + grown - shrunk;
+        lock.dirty += 1;
+
+        self.notify(notify::HASH, "hset", &key);
+        Ok(added)
+    }
 
-        match &mut entry.0 {
-            DataType::List(list) => {
-                op(list);
-                list.len()
+    /// Adds `members` to the set at `key`, returning how many were new.
+    pub fn sadd(&self, key: Bytes, members: Vec<Bytes>) -> Result<usize, DbError> {
+        let mut lock = self.state.lock().unwrap();
+        self.expire_if_needed(&mut lock, &key);
+
+        let DataType::Set(set) = lock.get_or_insert(&key, || DataType::Set(HashSet::new())) else {
+            return Err(DbError::WrongType);
+        };
+
+        let mut added = 0;
+        let mut grown = 0;
+        for member in members {
+            let size = element_size(&member);
+            if set.insert(member) {
+                added += 1;
+                grown += size;
             }
-            _ => 0,
         }
+        lock.used_memory += grown;
+
+        if added > 0 {
+            lock.dirty += 1;
+            self.notify(notify::SET, "sadd", &key);
+        }
+        Ok(added)
     }
 
-    pub fn lrange(&self, key: String, start: i64, end: i64) -> Result<Vec<String>, ()> {
-        let lock = self.state.lock().unwrap();
+    /// Adds or updates `members` in the sorted set at `key`, returning how
+    /// many were new.
+    pub fn zadd(&self, key: Bytes, members: Vec<(f64, Bytes)>) -> Result<usize, DbError> {
+        let mut lock = self.state.lock().unwrap();
+        self.expire_if_needed(&mut lock, &key);
+
+        let DataType::ZSet(zset) = lock.get_or_insert(&key, || DataType::ZSet(HashMap::new()))
+        else {
+            return Err(DbError::WrongType);
+        };
+
+        let mut added = 0;
+        let mut grown = 0;
+        for (score, member) in members {
+            let size = element_size(&member) + size_of::<f64>();
+            if zset.insert(member, score).is_none() {
+                added += 1;
+                grown += size;
+            }
+        }
+        lock.used_memory += grown;
+        lock.dirty += 1;
+
+        self.notify(notify::ZSET, "zadd", &key);
+        Ok(added)
+    }
+
+    pub fn lrange(&self, key: &[u8], start: i64, end: i64) -> Result<Vec<Bytes>, DbError> {
+        let mut lock = self.state.lock().unwrap();
+        self.expire_if_needed(&mut lock, key);
 
-        match lock.kv.get(&key) {
-            Some((DataType::List(list), _expiry)) => {
+        match lock.kv.get(key).map(|(value, _)| &**value) {
+            Some(DataType::List(list)) => {
                 let len = list.len() as i64;
                 if len == 0 {
                     return Ok(Vec::new());
@@ -106,46 +594,40 @@ impl Db {
                 if start_idx >= end_idx || start_idx >= len {
                     return Ok(Vec::new());
                 }
-                let result: Vec<String> = list
+                let result: Vec<Bytes> = list
                     .range(start_idx as usize..=end_idx as usize)
                     .cloned()
                     .collect();
                 Ok(result)
             }
-            Some(_) => Err(()),
+            Some(_) => Err(DbError::WrongType),
             None => Ok(Vec::new()),
         }
     }
 
-    pub fn llen(&self, key: String) -> Result<usize, ()> {
+    pub fn llen(&self, key: &[u8]) -> Result<usize, DbError> {
         let mut lock = self.state.lock().unwrap();
 
-        if let Some((_, Some(expiry))) = lock.kv.get(&key) {
-            if std::time::Instant::now() > *expiry {
-                lock.kv.remove(&key);
-                return Ok(0);
-            }
+        if self.expire_if_needed(&mut lock, key) {
+            return Ok(0);
         }
 
-        match lock.kv.get(&key) {
-            Some((DataType::List(list), _)) => Ok(list.len()),
+        match lock.kv.get(key).map(|(value, _)| &**value) {
+            Some(DataType::List(list)) => Ok(list.len()),
             None => Ok(0),
-            Some(_) => Err(()),
+            Some(_) => Err(DbError::WrongType),
         }
     }
 
-    pub fn lpop(&self, key: &str, count: Option<usize>) -> Result<Option<Vec<String>>, ()> {
+    pub fn lpop(&self, key: &[u8], count: Option<usize>) -> Result<Option<Vec<Bytes>>, DbError> {
         let mut lock = self.state.lock().unwrap();
 
-        if let Some((_, Some(expiry))) = lock.kv.get(key) {
-            if std::time::Instant::now() > *expiry {
-                lock.kv.remove(key);
-                return Ok(None);
-            }
+        if self.expire_if_needed(&mut lock, key) {
+            return Ok(None);
         }
 
-        match lock.kv.get_mut(key) {
-            Some((DataType::List(list), _)) => {
+        match lock.kv.get_mut(key).map(|(value, _)| Arc::make_mut(value)) {
+            Some(DataType::List(list)) => {
                 let needed = count.unwrap_or(1);
                 let actual = std::cmp::min(list.len(), needed);
 
@@ -153,31 +635,130 @@ impl Db {
                     return Ok(None);
                 }
 
-                let items: Vec<String> = list.drain(0..actual).collect();
-                if list.is_empty() {
-                    lock.kv.remove(key);
+                let items: Vec<Bytes> = list.drain(0..actual).collect();
+                let emptied = list.is_empty();
+                lock.used_memory -= items.iter().map(element_size).sum::<usize>();
+                if emptied {
+                    lock.remove(key);
+                }
+                lock.dirty += 1;
+
+                self.notify(notify::LIST, "lpop", key);
+                if emptied {
+                    self.notify(notify::GENERIC, "del", key);
                 }
 
                 Ok(Some(items))
             }
-            Some(_) => Err(()),
+            Some(_) => Err(DbError::WrongType),
             None => Ok(None),
         }
     }
+
+    /// Lazily removes `key` if its expiry has passed, firing the `expired`
+    /// event. Returns true when the key was removed.
+    fn expire_if_needed(&self, state: &mut DbState, key: &[u8]) -> bool {
+        match state.kv.get(key) {
+            Some((_, Some(expiry))) if Instant::now() > *expiry => {
+                state.remove(key);
+                state.dirty += 1;
+                self.notify(notify::EXPIRED, "expired", key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Publishes keyspace and keyevent notifications for `event` on `key`
+    /// when its class is enabled by `notify-keyspace-events`.
+    fn notify(&self, class: u32, event: &str, key: &[u8]) {
+        let flags = self.config.notify_keyspace_events();
+        if flags & class == 0 || self.loading.load(Ordering::Relaxed) {
+            return;
+        }
+
+        if flags & notify::KEYSPACE != 0 {
+            let mut channel = format!("__keyspace@{}__:", DB_INDEX).into_bytes();
+            channel.extend_from_slice(key);
+            self.pubsub.publish(
+                Bytes::from(channel),
+                Bytes::copy_from_slice(event.as_bytes()),
+            );
+        }
+        if flags & notify::KEYEVENT != 0 {
+            let channel = format!("__keyevent@{}__:{}", DB_INDEX, event);
+            self.pubsub
+                .publish(Bytes::from(channel), Bytes::copy_from_slice(key));
+        }
+    }
+}
+
+/// Current time in milliseconds since the Unix epoch.
+pub fn unix_time_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+/// Converts a deadline to milliseconds since the Unix epoch.
+pub fn instant_to_unix_ms(at: Instant) -> i64 {
+    let now = Instant::now();
+    let ms = |d: Duration| i64::try_from(d.as_millis()).unwrap_or(i64::MAX);
+    if at >= now {
+        unix_time_ms().saturating_add(ms(at - now))
+    } else {
+        unix_time_ms().saturating_sub(ms(now - at))
+    }
+}
+
+/// Converts milliseconds since the Unix epoch to a deadline. Times in the
+/// past map to now, so keys given them expire straight away. `None` when
+/// the time is too far in the future to represent.
+pub fn unix_ms_to_instant(ms: i64) -> Option<Instant> {
+    let now = Instant::now();
+    match ms.checked_sub(unix_time_ms()).map(u64::try_from) {
+        Some(Ok(remaining)) => now.checked_add(Duration::from_millis(remaining)),
+        // Either in the past or so far back the difference overflows
+        _ => Some(now),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::resp::RespValue;
     use std::thread;
     use std::time::Duration;
 
+    #[test]
+    fn test_write_guard_survives_a_panic() {
+        let db = Db::new();
+        let writer = db.clone();
+        let result = thread::spawn(move || {
+            let _guard = writer.write_guard();
+            panic!("command failed");
+        })
+        .join();
+        assert!(result.is_err());
+
+        let _guard = db.write_guard();
+        db.set(Bytes::from("a"), Bytes::from("b"), None);
+    }
+
+    #[test]
+    fn test_unix_ms_to_instant_extremes() {
+        let before = Instant::now();
+        assert!(unix_ms_to_instant(i64::MIN).is_some_and(|at| at >= before));
+        assert!(unix_ms_to_instant(i64::MAX).is_none_or(|at| at > before));
+        assert!(instant_to_unix_ms(before) <= unix_time_ms());
+    }
+
     #[test]
     fn test_set_and_get_string() {
         let db = Db::new();
-        db.set("foo".to_string(), "bar".to_string(), None);
+        db.set(Bytes::from("foo"), Bytes::from("bar"), None);
 
-        let result = db.get("foo");
+        let result = db.get(b"foo");
         match result {
             Some(DataType::String(s)) => assert_eq!(s, "bar"),
             _ => panic!("Expected String 'bar'"),
@@ -189,30 +770,195 @@ mod tests {
         let db = Db::new();
         let expiry = Instant::now() + Duration::from_millis(50);
 
-        db.set("temp".to_string(), "val".to_string(), Some(expiry));
+        db.set(Bytes::from("temp"), Bytes::from("val"), Some(expiry));
 
-        assert!(db.get("temp").is_some());
+        assert!(db.get(b"temp").is_some());
 
         thread::sleep(Duration::from_millis(60));
 
-        assert!(db.get("temp").is_none());
+        assert!(db.get(b"temp").is_none());
     }
 
     #[test]
     fn test_rpush_list() {
         let db = Db::new();
 
-        let len1 = db.rpush("mylist".to_string(), vec!["a".to_string()]);
-        assert_eq!(len1, 1);
+        let len1 = db.rpush(Bytes::from("mylist"), vec![Bytes::from("a")]);
+        assert_eq!(len1, Ok(1));
 
-        let len2 = db.rpush("mylist".to_string(), vec!["b".to_string(), "c".to_string()]);
-        assert_eq!(len2, 3);
+        let len2 = db.rpush(
+            Bytes::from("mylist"),
+            vec![Bytes::from("b"), Bytes::from("c")],
+        );
+        assert_eq!(len2, Ok(3));
 
-        match db.get("mylist") {
+        match db.get(b"mylist") {
             Some(DataType::List(vec)) => {
-                assert_eq!(vec, vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+                assert_eq!(
+                    vec,
+                    vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")]
+                );
             }
             _ => panic!("Expected List"),
         }
     }
+
+    #[test]
+    fn test_keyspace_notifications() {
+        let db = Db::new();
+        db.config().set("notify-keyspace-events", "KEA").unwrap();
+
+        let (mut sub, mut rx) = db.pubsub().subscriber();
+        sub.psubscribe(vec![Bytes::from("__key*__:*")]);
+
+        db.set(Bytes::from("foo"), Bytes::from("bar"), None);
+        db.del(&[Bytes::from("foo")]);
+
+        let events: Vec<RespValue> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        let channels: Vec<&RespValue> = events
+            .iter()
+            .map(|event| match event {
+                RespValue::Push(items) => &items[2],
+                _ => panic!("Expected pmessage"),
+            })
+            .collect();
+
+        assert_eq!(
+            channels,
+            vec![
+                &RespValue::BulkString(Bytes::from("__keyspace@0__:foo")),
+                &RespValue::BulkString(Bytes::from("__keyevent@0__:set")),
+                &RespValue::BulkString(Bytes::from("__keyspace@0__:foo")),
+                &RespValue::BulkString(Bytes::from("__keyevent@0__:del")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lazy_expiry_fires_expired_event() {
+        let db = Db::new();
+        db.config().set("notify-keyspace-events", "Ex").unwrap();
+
+        let (mut sub, mut rx) = db.pubsub().subscriber();
+        sub.subscribe(vec![Bytes::from("__keyevent@0__:expired")]);
+
+        let expiry = Instant::now() + Duration::from_millis(10);
+        db.set(Bytes::from("temp"), Bytes::from("val"), Some(expiry));
+        thread::sleep(Duration::from_millis(20));
+        assert!(db.get(b"temp").is_none());
+
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            RespValue::Push(vec![
+                RespValue::BulkString(Bytes::from("message")),
+                RespValue::BulkString(Bytes::from("__keyevent@0__:expired")),
+                RespValue::BulkString(Bytes::from("temp")),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_collection_types_reject_wrong_type() {
+        let db = Db::new();
+        db.set(Bytes::from("str"), Bytes::from("val"), None);
+
+        assert!(
+            db.hset(
+                Bytes::from("str"),
+                vec![(Bytes::from("f"), Bytes::from("v"))]
+            )
+            .is_err()
+        );
+        assert!(db.sadd(Bytes::from("str"), vec![Bytes::from("m")]).is_err());
+        assert_eq!(
+            db.rpush(Bytes::from("str"), vec![Bytes::from("a")]),
+            Err(DbError::WrongType)
+        );
+        assert!(
+            db.zadd(Bytes::from("str"), vec![(1.0, Bytes::from("m"))])
+                .is_err()
+        );
+
+        assert_eq!(
+            db.sadd(Bytes::from("set"), vec![Bytes::from("a"), Bytes::from("a")]),
+            Ok(1)
+        );
+        assert_eq!(
+            db.zadd(Bytes::from("zset"), vec![(1.0, Bytes::from("a"))]),
+            Ok(1)
+        );
+        assert_eq!(
+            db.zadd(Bytes::from("zset"), vec![(2.0, Bytes::from("a"))]),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn test_snapshot_unaffected_by_later_writes() {
+        let db = Db::new();
+        db.rpush(Bytes::from("l"), vec![Bytes::from("a")]).unwrap();
+        let (snapshot, _) = db.snapshot();
+
+        db.rpush(Bytes::from("l"), vec![Bytes::from("b")]).unwrap();
+        db.lpop(b"l", Some(2)).unwrap();
+        assert_eq!(
+            snapshot.into_entries()[0].value,
+            DataType::List(VecDeque::from([Bytes::from("a")]))
+        );
+        assert_eq!(db.llen(b"l"), Ok(0));
+    }
+
+    #[test]
+    fn test_memory_and_expiries_kept_up_to_date() {
+        // The running totals must match what walking the keyspace gives
+        fn check(db: &Db) {
+            let lock = db.state.lock().unwrap();
+            let used: usize = lock
+                .kv
+                .iter()
+                .map(|(key, (value, _))| entry_size(key, value))
+                .sum();
+            assert_eq!(lock.used_memory, used);
+            let expires = lock.kv.values().filter(|(_, at)| at.is_some()).count();
+            assert_eq!(lock.expires, expires);
+        }
+
+        let db = Db::new();
+        let later = Instant::now() + Duration::from_secs(100);
+        db.set(Bytes::from("s"), Bytes::from("short"), None);
+        db.set(Bytes::from("s"), Bytes::from("a longer value"), Some(later));
+        db.rpush(Bytes::from("l"), vec![Bytes::from("a"), Bytes::from("bc")])
+            .unwrap();
+        db.lpush(Bytes::from("l"), vec![Bytes::from("def")])
+            .unwrap();
+        db.hset(Bytes::from("h"), vec![(Bytes::from("f"), Bytes::from("1"))])
+            .unwrap();
+        db.hset(
+            Bytes::from("h"),
+            vec![(Bytes::from("f"), Bytes::from("123"))],
+        )
+        .unwrap();
+        db.sadd(Bytes::from("t"), vec![Bytes::from("x"), Bytes::from("x")])
+            .unwrap();
+        db.zadd(Bytes::from("z"), vec![(1.0, Bytes::from("m"))])
+            .unwrap();
+        db.expire(b"h", later + Duration::from_secs(100));
+        check(&db);
+        let (expires, avg_ttl) = db.expires_info();
+        assert_eq!(expires, 2);
+        assert!(avg_ttl > 100_000 && avg_ttl <= 150_000);
+
+        db.lpop(b"l", Some(1)).unwrap();
+        check(&db);
+        db.lpop(b"l", Some(5)).unwrap();
+        db.del(&[Bytes::from("s"), Bytes::from("t")]);
+        db.restore(Bytes::from("z"), DataType::String(Bytes::from("v")), None);
+        check(&db);
+        assert_eq!(db.expires_info().0, 1);
+
+        let (snapshot, _) = db.snapshot();
+        db.load(snapshot.into_entries()).unwrap();
+        check(&db);
+        assert_eq!(db.key_count(), 2);
+    }
 }
@@ -0,0 +1,238 @@
+//! Error types. Command and database errors render as the error line sent to
+//! clients, starting with the Redis error prefix clients match on.
+
+use thiserror::Error;
+
+use crate::resp::RespValue;
+
+/// Malformed or oversized input on the wire. Apart from
+/// [`ProtocolError::Incomplete`], these leave the stream unusable.
+#[derive(Debug, Error, PartialEq)]
+pub enum ProtocolError {
+    /// More data is needed before a whole value can be parsed.
+    #[error("incomplete value")]
+    Incomplete,
+    #[error("invalid bulk length")]
+    InvalidBulkLength,
+    #[error("invalid multibulk length")]
+    InvalidMultibulkLength,
+    #[error("nesting too deep")]
+    NestingTooDeep,
+    #[error("too big inline request")]
+    InlineTooBig,
+    #[error("too big count string")]
+    CountTooBig,
+    #[error("query buffer limit exceeded")]
+    QueryBufferLimit,
+    #[error("unbalanced quotes in request")]
+    UnbalancedQuotes,
+    #[error("expected CRLF line ending")]
+    ExpectedCrlf,
+    #[error("unknown RESP type '{}'", *.0 as char)]
+    UnknownType(u8),
+    /// A value of the named type that could not be parsed.
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+}
+
+/// A command that failed. Rendered with its Redis error prefix.
+#[derive(Debug, Error, PartialEq)]
+pub enum CommandError {
+    #[error("ERR {0}")]
+    Err(String),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(&'static str),
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+    #[error("ERR unknown command '{0}', with args beginning with: {1}")]
+    UnknownCommand(String, String),
+    #[error("ERR unknown subcommand '{0}'. Try {} HELP.", .1.to_uppercase())]
+    UnknownSubcommand(String, &'static str),
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+    #[error("WRONGPASS invalid username-password pair or user is disabled.")]
+    WrongPass,
+    #[error("READONLY You can't write against a read only replica.")]
+    ReadOnly,
+    #[error("MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.")]
+    MasterDown,
+    /// The slot is served by the node at the given address.
+    #[error("MOVED {0} {1}")]
+    Moved(u16, String),
+    /// The slot is being migrated to the node at the given address, which
+    /// should be asked for this key once.
+    #[error("ASK {0} {1}")]
+    Ask(u16, String),
+    #[error("TRYAGAIN Multiple keys request during rehashing of slot")]
+    TryAgain,
+    #[error("CROSSSLOT Keys in request don't hash to the same slot")]
+    CrossSlot,
+    #[error("CLUSTERDOWN {0}")]
+    ClusterDown(&'static str),
+    #[error("NOSCRIPT No matching script. Please use EVAL.")]
+    NoScript,
+    /// A script holds up the server. Scripts can't run here, so nothing
+    /// reports this yet.
+    #[allow(dead_code)]
+    #[error(
+        "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSCRIPT."
+    )]
+    Busy,
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+    #[error("IOERR {0}")]
+    IoErr(String),
+    #[error("INPROG Failover already in progress")]
+    InProgress,
+    #[error("NOGOODSLAVE No suitable replica to promote")]
+    NoGoodReplica,
+}
+
+/// A keyspace operation that could not be applied.
+#[derive(Debug, Error, PartialEq)]
+pub enum DbError {
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+}
+
+/// A snapshot that could not be written or loaded.
+#[derive(Debug, Error)]
+pub enum RdbError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("wrong signature trying to load DB from file")]
+    BadSignature,
+    #[error("can't handle RDB format version {0}")]
+    UnsupportedVersion(u32),
+    #[error("unexpected end of file")]
+    UnexpectedEof,
+    #[error("unknown RDB value type {0}")]
+    UnsupportedType(u8),
+    #[error("unsupported RDB opcode {0:#04x}")]
+    UnsupportedOpcode(u8),
+    #[error("invalid {0} encoding")]
+    Corrupt(&'static str),
+    #[error("wrong RDB checksum")]
+    BadChecksum,
+    #[error("Background save already in progress")]
+    SaveInProgress,
+}
+
+/// An append-only file that could not be written or replayed.
+#[derive(Debug, Error)]
+pub enum AofError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The file ends in the middle of a command.
+    #[error("unexpected end of file at offset {0}")]
+    Truncated(usize),
+    #[error("bad file format reading the append only file at offset {0}")]
+    BadFormat(usize),
+    #[error("{1} reading the append only file at offset {0}")]
+    BadCommand(usize, CommandError),
+    #[error("Background append only file rewriting already in progress")]
+    RewriteInProgress,
+}
+
+/// A replication link to a master that broke down.
+#[derive(Debug, Error)]
+pub enum ReplicationError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Protocol error: {0}")]
+    Protocol(#[from] ProtocolError),
+    #[error("error loading the snapshot from the master: {0}")]
+    Rdb(#[from] RdbError),
+    #[error("unexpected reply from master: {0}")]
+    UnexpectedReply(String),
+    #[error("connection closed by master")]
+    Closed,
+}
+
+/// An exchange between a sentinel and an instance it watches that failed.
+#[derive(Debug, Error)]
+pub enum SentinelError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Protocol error: {0}")]
+    Protocol(#[from] ProtocolError),
+    #[error("timed out")]
+    Timeout,
+    #[error("connection closed")]
+    Closed,
+}
+
+/// A configuration that could not be loaded at startup or rewritten.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Fatal error, can't open config file '{0}': {1}")]
+    Open(String, std::io::Error),
+    #[error("Unrecognized option '{0}'")]
+    UnknownOption(String),
+    /// A line, with its number, that could not be applied. Command line
+    /// options count as lines following the file's.
+    #[error(
+        "\n*** FATAL CONFIG FILE ERROR ***\nReading the configuration file, at line {0}\n>>> '{1}'\n{2}"
+    )]
+    BadLine(usize, String, String),
+    #[error("The server is running without a config file")]
+    NoFile,
+    #[error("Rewriting config file: {0}")]
+    Rewrite(std::io::Error),
+}
+
+impl From<DbError> for CommandError {
+    fn from(e: DbError) -> Self {
+        match e {
+            DbError::WrongType => CommandError::WrongType,
+        }
+    }
+}
+
+impl From<CommandError> for RespValue {
+    fn from(e: CommandError) -> Self {
+        RespValue::SimpleError(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_render_with_prefix() {
+        assert_eq!(
+            RespValue::from(CommandError::from(DbError::WrongType)),
+            RespValue::SimpleError(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string()
+            )
+        );
+        assert_eq!(
+            CommandError::WrongArity("get").to_string(),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        assert_eq!(
+            CommandError::UnknownSubcommand("foo".to_string(), "client").to_string(),
+            "ERR unknown subcommand 'foo'. Try CLIENT HELP."
+        );
+        assert_eq!(
+            CommandError::NoScript.to_string(),
+            "NOSCRIPT No matching script. Please use EVAL."
+        );
+        assert!(
+            CommandError::Busy
+                .to_string()
+                .starts_with("BUSY Redis is busy")
+        );
+        assert_eq!(
+            ProtocolError::UnknownType(b'!').to_string(),
+            "unknown RESP type '!'"
+        );
+    }
+}
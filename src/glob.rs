@@ -0,0 +1,127 @@
+/// Matches `string` against a Redis-style glob `pattern`.
+///
+/// Supports `*`, `?`, `[...]` character classes (with `^` negation and `a-z`
+/// ranges) and `\` escapes, following the semantics of Redis' `stringmatchlen`.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let mut p = 0;
+    let mut s = 0;
+
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                // Collapse consecutive stars, a trailing star matches everything
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+                for start in s..=string.len() {
+                    if glob_match(&pattern[p + 1..], &string[start..]) {
+                        return true;
+                    }
+                }
+                return false;
+            }
+            b'?' => {
+                if s >= string.len() {
+                    return false;
+                }
+                s += 1;
+            }
+            b'[' => {
+                if s >= string.len() {
+                    return false;
+                }
+                let (matched, next) = match_class(pattern, p + 1, string[s]);
+                if !matched {
+                    return false;
+                }
+                p = next;
+                s += 1;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+                if s >= string.len() || pattern[p] != string[s] {
+                    return false;
+                }
+                s += 1;
+            }
+            c => {
+                if s >= string.len() || c != string[s] {
+                    return false;
+                }
+                s += 1;
+            }
+        }
+        p += 1;
+    }
+
+    s == string.len()
+}
+
+/// Matches `c` against the class starting at `p` (just past the `[`). Returns
+/// whether it matched and the index of the closing `]` (or the last byte of
+/// the pattern when the class is unterminated).
+fn match_class(pattern: &[u8], mut p: usize, c: u8) -> (bool, usize) {
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            p += 1;
+            if pattern[p] == c {
+                matched = true;
+            }
+        } else if pattern[p] == b']' {
+            break;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' {
+            let (mut start, mut end) = (pattern[p], pattern[p + 2]);
+            if start > end {
+                std::mem::swap(&mut start, &mut end);
+            }
+            if (start..=end).contains(&c) {
+                matched = true;
+            }
+            p += 2;
+        } else if pattern[p] == c {
+            matched = true;
+        }
+        p += 1;
+    }
+
+    if p >= pattern.len() {
+        p = pattern.len() - 1;
+    }
+
+    (matched != negate, p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_wildcards() {
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"orders.*", b"orders.eu"));
+        assert!(!glob_match(b"orders.*", b"invoices.eu"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"a*b*c", b"axxbyyc"));
+    }
+
+    #[test]
+    fn test_glob_classes_and_escapes() {
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"h\\*llo", b"h*llo"));
+        assert!(!glob_match(b"h\\*llo", b"hello"));
+    }
+}
@@ -0,0 +1,229 @@
+//! INFO, the server's report on itself, split into named sections.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{cluster, db::Db, replication, sentinel, stats};
+
+struct Section {
+    name: &'static str,
+    heading: &'static str,
+    /// Whether the section is reported when none are asked for.
+    default: bool,
+    /// Produces the section's `field:value` lines.
+    fields: fn(&Db) -> String,
+}
+
+const SECTIONS: &[Section] = &[
+    Section {
+        name: "server",
+        heading: "Server",
+        default: true,
+        fields: server,
+    },
+    Section {
+        name: "clients",
+        heading: "Clients",
+        default: true,
+        fields: stats::clients_section,
+    },
+    Section {
+        name: "memory",
+        heading: "Memory",
+        default: true,
+        fields: memory,
+    },
+    Section {
+        name: "stats",
+        heading: "Stats",
+        default: true,
+        fields: stats::info_section,
+    },
+    Section {
+        name: "replication",
+        heading: "Replication",
+        default: true,
+        fields: replication::info,
+    },
+    Section {
+        name: "cluster",
+        heading: "Cluster",
+        default: true,
+        fields: cluster::info_section,
+    },
+    Section {
+        name: "sentinel",
+        heading: "Sentinel",
+        default: true,
+        fields: sentinel::info_section,
+    },
+    Section {
+        name: "keyspace",
+        heading: "Keyspace",
+        default: true,
+        fields: keyspace,
+    },
+    Section {
+        name: "commandstats",
+        heading: "Commandstats",
+        default: false,
+        fields: stats::commandstats_section,
+    },
+];
+
+/// Renders the requested sections: the default ones when none are named or
+/// `default` is, and every one for `all` or `everything`. Sections with
+/// nothing to report in this mode are left out.
+pub fn info(db: &Db, sections: &[String]) -> String {
+    let requested = |name: &str| sections.iter().any(|section| section == name);
+    let default = sections.is_empty() || requested("default");
+    let all = requested("all") || requested("everything");
+
+    SECTIONS
+        .iter()
+        .filter(|section| all || (default && section.default) || requested(section.name))
+        .map(|section| (section.heading, (section.fields)(db)))
+        .filter(|(_, fields)| !fields.is_empty())
+        .map(|(heading, fields)| format!("# {}\r\n{}", heading, fields))
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// How the server is running, as reported by INFO and HELLO.
+pub fn server_mode(db: &Db) -> &'static str {
+    if db.config().cluster_enabled() {
+        "cluster"
+    } else if db.sentinel().is_enabled() {
+        "sentinel"
+    } else {
+        "standalone"
+    }
+}
+
+fn server(db: &Db) -> String {
+    let now_usec = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros());
+    let uptime = db.stats().uptime().as_secs();
+    let config_file = db
+        .config()
+        .file()
+        .map(|path| path.display().to_string())
+        .unwrap_or_default();
+
+    [
+        format!("redis_version:{}", crate::REDIS_VERSION),
+        format!("redis_mode:{}", server_mode(db)),
+        format!("os:{} {}", std::env::consts::OS, std::env::consts::ARCH),
+        format!("arch_bits:{}", usize::BITS),
+        format!("process_id:{}", std::process::id()),
+        format!("run_id:{}", db.stats().run_id()),
+        format!("tcp_port:{}", db.config().port()),
+        format!("server_time_usec:{}", now_usec),
+        format!("uptime_in_seconds:{}", uptime),
+        format!("uptime_in_days:{}", uptime / 86400),
+        format!("config_file:{}", config_file),
+    ]
+    .iter()
+    .map(|line| format!("{}\r\n", line))
+    .collect()
+}
+
+/// Memory figures are estimates worked out from the dataset, as there's no
+/// allocator to ask.
+fn memory(db: &Db) -> String {
+    let used = db.used_memory();
+    [
+        format!("used_memory:{}", used),
+        format!("used_memory_human:{}", bytes_to_human(used)),
+    ]
+    .iter()
+    .map(|line| format!("{}\r\n", line))
+    .collect()
+}
+
+/// Only database 0 exists, and as in Redis it isn't listed while empty.
+fn keyspace(db: &Db) -> String {
+    let keys = db.key_count();
+    if keys == 0 {
+        return String::new();
+    }
+    let (expires, avg_ttl) = db.expires_info();
+    format!(
+        "db0:keys={},expires={},avg_ttl={}\r\n",
+        keys, expires, avg_ttl
+    )
+}
+
+/// Formats a byte count the way Redis does, such as `1.50M`.
+fn bytes_to_human(bytes: usize) -> String {
+    const UNITS: &[(f64, &str)] = &[
+        (1024.0 * 1024.0 * 1024.0 * 1024.0, "T"),
+        (1024.0 * 1024.0 * 1024.0, "G"),
+        (1024.0 * 1024.0, "M"),
+        (1024.0, "K"),
+    ];
+    let value = bytes as f64;
+    UNITS
+        .iter()
+        .find(|(size, _)| value >= *size)
+        .map_or(format!("{}B", bytes), |(size, unit)| {
+            format!("{:.2}{}", value / size, unit)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_info_sections() {
+        let db = Db::new();
+        let report = info(&db, &[]);
+        assert!(report.starts_with("# Server\r\nredis_version:"));
+        assert!(report.contains("\r\n# Replication\r\nrole:master\r\n"));
+        assert!(report.contains("connected_slaves:0\r\n"));
+        assert!(report.ends_with("# Cluster\r\ncluster_enabled:0\r\n"));
+        assert!(report.contains(&info(&db, &["replication".to_string()])));
+        assert_eq!(info(&db, &["nope".to_string()]), "");
+
+        assert!(report.contains("\r\nredis_mode:standalone\r\n"));
+        db.sentinel().enable();
+        assert_eq!(server_mode(&db), "sentinel");
+        assert!(info(&db, &[]).ends_with(
+            "# Sentinel\r\nsentinel_masters:0\r\nsentinel_tilt:0\r\nsentinel_running_scripts:0\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_keyspace_and_commandstats() {
+        let db = Db::new();
+        db.set(Bytes::from("a"), Bytes::from("1"), None);
+        let expiry = Instant::now() + Duration::from_secs(100);
+        db.set(Bytes::from("b"), Bytes::from("2"), Some(expiry));
+        db.get(b"a");
+        db.get(b"missing");
+        db.stats()
+            .command_processed("get", Duration::from_micros(10));
+
+        let keyspace = info(&db, &["keyspace".to_string()]);
+        assert!(keyspace.starts_with("# Keyspace\r\ndb0:keys=2,expires=1,avg_ttl="));
+        let stats = info(&db, &["stats".to_string()]);
+        assert!(stats.contains("keyspace_hits:1\r\nkeyspace_misses:1\r\n"));
+
+        // Command stats are only reported when asked for
+        assert!(!info(&db, &[]).contains("# Commandstats"));
+        assert!(
+            info(&db, &["all".to_string()])
+                .ends_with("# Commandstats\r\ncmdstat_get:calls=1,usec=10,usec_per_call=10.00\r\n")
+        );
+    }
+
+    #[test]
+    fn test_bytes_to_human() {
+        assert_eq!(bytes_to_human(512), "512B");
+        assert_eq!(bytes_to_human(1536), "1.50K");
+        assert_eq!(bytes_to_human(3 * 1024 * 1024), "3.00M");
+    }
+}
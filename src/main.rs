@@ -1,26 +1,135 @@
-use std::io::Cursor;
+use bytes::BytesMut;
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Decoder, Encoder};
 
+mod aof;
+mod aof_reader;
+mod client;
+mod cluster;
+mod codec;
+mod command_table;
 mod commands;
+mod config;
+mod crc64;
 mod db;
+mod error;
+mod glob;
+mod info;
+mod migrate;
+mod notify;
+mod persistence;
+mod pubsub;
+mod rdb;
+mod replication;
 mod resp;
+mod sentinel;
+mod slot;
+mod stats;
+use client::Client;
+use codec::{CodecError, Limits, RespCodec};
 use commands::Command;
+use config::Config;
 use db::Db;
-use resp::parse_resp;
+use error::{CommandError, ConfigError};
 
 use crate::resp::RespValue;
 
+/// Redis version reported to clients, chosen for client library compatibility.
+pub const REDIS_VERSION: &str = "7.2.0";
+
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
+    let db = Db::new();
+    let replicaof = match load_config(&db, std::env::args().skip(1)) {
+        Ok(replicaof) => replicaof,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    println!("Redis-lite listening on 6379");
+    let port = db.config().port();
+    let mut listeners = Vec::new();
+    for address in db.config().bind() {
+        // A leading `-` marks an address that may not be available
+        let optional = address.starts_with('-');
+        let address = address.trim_start_matches('-');
+        let host = match address {
+            "*" => "0.0.0.0",
+            "::*" => "::",
+            host => host,
+        };
+        match TcpListener::bind((host, port)).await {
+            Ok(listener) => listeners.push(listener),
+            Err(_) if optional => {}
+            Err(e) => {
+                eprintln!(
+                    "Could not create server TCP listening socket {}:{}: {}",
+                    address, port, e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+    println!("Redis-lite listening on {}", port);
 
-    let db = Db::new();
+    // Sentinels hold no data of their own
+    if db.sentinel().is_enabled() {
+        sentinel::start(&db);
+    } else if let Err(e) = load_data(&db) {
+        eprintln!("Fatal error loading the DB: {}. Exiting.", e);
+        std::process::exit(1);
+    }
+
+    if db.config().cluster_enabled()
+        && let Err(e) = cluster::start(&db).await
+    {
+        eprintln!("Fatal error starting the cluster bus: {}. Exiting.", e);
+        std::process::exit(1);
+    }
 
+    if let Some((host, port)) = replicaof {
+        replication::follow(&db, host, port);
+    }
+
+    let cron_db = db.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let db = cron_db.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                persistence::cron(&db);
+                aof::cron(&db);
+            })
+            .await;
+        }
+    });
+
+    for listener in listeners {
+        tokio::spawn(accept(listener, db.clone()));
+    }
+    // The listeners serve clients until the process exits
+    std::future::pending::<()>().await;
+}
+
+/// Serves every connection made to `listener`. Failing to accept one, such
+/// as when out of file descriptors, only pauses accepting for a moment.
+async fn accept(listener: TcpListener, db: Db) {
     loop {
-        let (socket, _) = listener.accept().await.unwrap();
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                eprintln!("Accepting client connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
         let db = db.clone();
         tokio::spawn(async move {
             process_socket(socket, db).await;
@@ -28,47 +137,286 @@ async fn main() {
     }
 }
 
-async fn process_socket(mut socket: TcpStream, db: Db) {
-    let mut buffer = Vec::with_capacity(1024);
-    let mut temp_buffer = [0; 1024];
+/// Runs one of the commands that wait on other servers.
+async fn run_waiting(db: &Db, cmd: Command) -> Result<RespValue, CommandError> {
+    match cmd {
+        Command::Wait(numreplicas, timeout) => replication::wait(db, numreplicas, timeout).await,
+        Command::WaitAof(numlocal, numreplicas, timeout) => {
+            replication::wait_aof(db, numlocal, numreplicas, timeout).await
+        }
+        Command::Migrate(migration) => migrate::migrate(db, migration).await,
+        cmd => Ok(cmd.execute(db)),
+    }
+}
 
+/// Runs `command`, which holds up this connection, while reading whatever
+/// the client sends in the meantime into `input` for later. Returns `None`,
+/// abandoning the command, once the client disconnects or sends more than
+/// its query buffer may hold.
+async fn while_connected<T>(
+    socket: &mut TcpStream,
+    input: &mut BytesMut,
+    db: &Db,
+    command: impl Future<Output = T>,
+) -> Option<T> {
+    tokio::pin!(command);
     loop {
-        let read_result = socket.read(&mut temp_buffer).await;
-        match read_result {
-            Ok(0) => return,
-            Ok(n) => {
-                buffer.extend_from_slice(&temp_buffer[0..n]);
+        tokio::select! {
+            result = &mut command => return Some(result),
+            read = socket.read_buf(input) => {
+                if !matches!(read, Ok(n) if n > 0)
+                    || input.len() > db.config().client_query_buffer_limit()
+                {
+                    return None;
+                }
             }
-            Err(e) => {
-                eprintln!("Error reading from socket: {:?}", e);
-                return;
+        }
+    }
+}
+
+/// Loads the configuration as Redis does: an optional config file, then
+/// `--<parameter> <value>...` options that override it, each read as one
+/// more line of the file. Besides the parameters in [`Config`], `sentinel`
+/// and `replicaof <host> <port>` are understood. Returns the master to
+/// replicate, if any.
+fn load_config(
+    db: &Db,
+    args: impl Iterator<Item = String>,
+) -> Result<Option<(String, u16)>, ConfigError> {
+    let mut args = args.peekable();
+    let mut text = String::new();
+    if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+        text = fs::read_to_string(&path).map_err(|e| ConfigError::Open(path.clone(), e))?;
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        db.config()
+            .set_file(fs::canonicalize(&path).unwrap_or_else(|_| path.into()));
+    }
+
+    let mut options: Vec<String> = Vec::new();
+    for arg in args {
+        match (arg.strip_prefix("--"), options.last_mut()) {
+            (Some(name), _) => options.push(name.to_string()),
+            (None, Some(line)) => {
+                line.push(' ');
+                line.push_str(&config::quote(&arg));
             }
+            (None, None) => return Err(ConfigError::UnknownOption(arg)),
         }
+    }
+    text.push_str(&options.join("\n"));
 
-        loop {
-            let mut cursor = Cursor::new(&buffer[..]);
+    let mut replicaof = None;
+    let mut port_given = false;
+    let mut save: Option<String> = None;
+    for directive in config::parse(&text)? {
+        let value = directive.args.join(" ");
+        let result = match directive.name.as_str() {
+            "sentinel" if directive.args.is_empty() => {
+                db.sentinel().enable();
+                Ok(())
+            }
+            "replicaof" | "slaveof" => {
+                parse_replicaof(&directive.args).map(|master| replicaof = Some(master))
+            }
+            // Each `save` line adds its rules to those before it
+            "save" => {
+                let rules = match save.take() {
+                    Some(rules) => format!("{} {}", rules, value),
+                    None => value,
+                };
+                let result = db.config().set("save", &rules);
+                save = Some(rules);
+                result
+            }
+            name if Config::is_parameter(name) => {
+                port_given |= name == "port";
+                db.config().set(name, &value)
+            }
+            _ => Err("Bad directive or wrong number of arguments".to_string()),
+        };
+        result.map_err(|reason| ConfigError::BadLine(directive.line, directive.text, reason))?;
+    }
+
+    if db.sentinel().is_enabled() && !port_given {
+        let _ = db.config().set("port", &sentinel::DEFAULT_PORT.to_string());
+    }
+    Ok(replicaof)
+}
+
+/// `replicaof <host> <port>`, also accepted as a single `"<host> <port>"`
+/// argument.
+fn parse_replicaof(args: &[String]) -> Result<(String, u16), String> {
+    let args: Vec<&str> = args.iter().flat_map(|arg| arg.split_whitespace()).collect();
+    let [host, port] = args[..] else {
+        return Err("wrong number of arguments".to_string());
+    };
+    let port = port
+        .parse()
+        .map_err(|_| "Invalid master port".to_string())?;
+    Ok((host.to_string(), port))
+}
+
+/// Restores the dataset from disk. The append-only file wins when enabled,
+/// since it holds every write rather than the last snapshot.
+fn load_data(db: &Db) -> Result<(), Box<dyn std::error::Error>> {
+    if db.config().appendonly() {
+        if aof::load(db)? {
+            println!("DB loaded from append only file");
+        }
+        aof::open(db)?;
+    } else if persistence::load(db)? {
+        println!("DB loaded from disk");
+    }
+    Ok(())
+}
 
-            match parse_resp(&mut cursor) {
-                Ok(value) => {
-                    let command_result = Command::from_resp(value);
+async fn process_socket(mut socket: TcpStream, db: Db) {
+    let mut input = BytesMut::with_capacity(4096);
+    let mut output = BytesMut::with_capacity(4096);
+    let mut codec = RespCodec::new();
+    let (subscriber, mut messages) = db.pubsub().subscriber();
+    let mut client = Client::new(subscriber);
+    let connected = db.stats().client_connected();
 
-                    let response = match command_result {
-                        Ok(cmd) => cmd.execute(&db),
-                        Err(err) => RespValue::SimpleError(err),
-                    };
+    loop {
+        // Answer every complete frame already buffered, then flush the whole
+        // batch of replies with a single write
+        let mut quit = false;
+        let mut psync = None;
+        codec.set_limits(Limits {
+            max_bulk_len: db.config().proto_max_bulk_len(),
+            max_query_buffer: db.config().client_query_buffer_limit(),
+            ..Limits::default()
+        });
+        loop {
+            let frame = match codec.decode(&mut input) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    // The stream can't be resynchronised, so report the error
+                    // and drop the client
+                    eprintln!("Closing client: {}", e);
+                    if let CodecError::Protocol(_) = e {
+                        let error = RespValue::from(CommandError::Err(e.to_string()));
+                        let _ = codec.encode(error, &mut output);
+                    }
+                    quit = true;
+                    break;
+                }
+            };
 
-                    socket.write_all(&response.serialize()).await.unwrap();
+            // Empty inline lines and `*0` arrays are skipped without a reply
+            if matches!(&frame, RespValue::Array(args) if args.is_empty()) {
+                continue;
+            }
 
-                    let len = cursor.position() as usize;
-                    buffer.drain(0..len);
+            // ASKING only lasts one command
+            let asking = std::mem::take(&mut client.asking);
+            let (spec, args) = match Command::resolve(frame) {
+                Ok(resolved) => resolved,
+                Err(err) => {
+                    let _ = codec.encode(RespValue::from(err), &mut output);
+                    continue;
                 }
-                Err(e) if e == "Incomplete" || e == "EOF" => {
+            };
+
+            if let Err(err) = sentinel::check_command(&db, spec, &args) {
+                let _ = codec.encode(RespValue::from(err), &mut output);
+                continue;
+            }
+
+            // Keys served by another node are redirected before anything
+            // else looks at the command
+            if db.config().cluster_enabled() {
+                cluster::wait_while_paused(&db, spec).await;
+            }
+            if db.config().cluster_enabled()
+                && let Err(err) = cluster::check_route(&db, spec, &args, asking)
+            {
+                let _ = codec.encode(RespValue::from(err), &mut output);
+                continue;
+            }
+
+            let command = Command::parse(spec, &args);
+            let name = command.as_ref().ok().map(Command::name);
+            let start = Instant::now();
+            let responses = match command {
+                // The connection turns into a replication link once the
+                // replies before it have been sent
+                Ok(Command::PSync(replid, offset)) => {
+                    db.stats().command_processed("psync", start.elapsed());
+                    psync = Some((replid, offset));
                     break;
                 }
+                // Waiting only holds up this connection, and is given up
+                // if the client goes away
+                Ok(cmd @ (Command::Wait(..) | Command::WaitAof(..) | Command::Migrate(_))) => {
+                    match client.check(&cmd, &db) {
+                        Err(err) => vec![RespValue::from(err)],
+                        Ok(()) => {
+                            let _blocked = matches!(cmd, Command::Wait(..) | Command::WaitAof(..))
+                                .then(|| db.stats().client_blocked());
+                            let run = run_waiting(&db, cmd);
+                            match while_connected(&mut socket, &mut input, &db, run).await {
+                                Some(reply) => vec![reply.unwrap_or_else(RespValue::from)],
+                                None => return,
+                            }
+                        }
+                    }
+                }
+                Ok(cmd) => {
+                    quit = matches!(cmd, Command::Quit);
+                    client.dispatch(cmd, &db)
+                }
+                Err(err) => vec![RespValue::from(err)],
+            };
+            if let Some(name) = name {
+                db.stats().command_processed(name, start.elapsed());
+            }
+
+            // HELLO switches protocol from its own reply onwards
+            codec.set_protocol(client.protocol);
+            for response in responses {
+                let _ = codec.encode(response, &mut output);
+            }
+
+            if quit {
+                break;
+            }
+        }
+
+        if !output.is_empty() {
+            if socket.write_all(&output).await.is_err() {
+                return;
+            }
+            output.clear();
+        }
+        if quit {
+            return;
+        }
+        if let Some((replid, offset)) = psync {
+            // Replicas aren't counted among the clients
+            drop(connected);
+            replication::serve_replica(socket, db, replid, offset, client.listening_port).await;
+            return;
+        }
+
+        tokio::select! {
+            read_result = socket.read_buf(&mut input) => match read_result {
+                Ok(0) => return,
+                Ok(_) => {}
                 Err(e) => {
-                    eprintln!("Protocol Error: {}", e);
+                    eprintln!("Error reading from socket: {:?}", e);
                     return;
                 }
+            },
+            Some(message) = messages.recv() => {
+                let _ = codec.encode(message, &mut output);
+                while let Ok(message) = messages.try_recv() {
+                    let _ = codec.encode(message, &mut output);
+                }
             }
         }
     }
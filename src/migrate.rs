@@ -0,0 +1,274 @@
+//! MIGRATE: moving keys to another instance. Each key is serialized as by
+//! DUMP and sent with RESTORE-ASKING, then deleted here once the target
+//! has stored it. Writes are only held off while the keys are read and
+//! while they are deleted, not while waiting on the target, so a key that
+//! changed in between is kept here and reported instead.
+
+use bytes::{Bytes, BytesMut};
+use std::{
+    io::{self, Cursor, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::{
+    db::{Db, unix_time_ms},
+    error::{CommandError, ProtocolError},
+    rdb,
+    resp::{Protocol, RespValue, parse_resp},
+};
+
+/// Used when MIGRATE is given a timeout of 0.
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// A parsed MIGRATE call.
+#[derive(Debug)]
+pub struct Migration {
+    pub host: String,
+    pub port: u16,
+    pub keys: Vec<Bytes>,
+    /// Database on the target, selected first unless it is 0.
+    pub db: i64,
+    pub timeout: Duration,
+    /// Keep the keys here as well.
+    pub copy: bool,
+    /// Overwrite keys already on the target.
+    pub replace: bool,
+    /// Arguments of the AUTH command to send the target first.
+    pub auth: Option<Vec<Bytes>>,
+}
+
+/// Runs MIGRATE off the async runtime, since it waits on the target.
+pub async fn migrate(db: &Db, migration: Migration) -> Result<RespValue, CommandError> {
+    let db = db.clone();
+    tokio::task::spawn_blocking(move || transfer(&db, migration))
+        .await
+        .unwrap_or_else(|e| Err(CommandError::Err(e.to_string())))
+}
+
+fn transfer(db: &Db, migration: Migration) -> Result<RespValue, CommandError> {
+    if !migration.copy && db.replication().is_replica() && db.config().replica_read_only() {
+        return Err(CommandError::ReadOnly);
+    }
+
+    let entries: Vec<rdb::Entry> = {
+        let _guard = db.write_guard();
+        migration
+            .keys
+            .iter()
+            .filter_map(|key| db.entry(key))
+            .collect()
+    };
+    if entries.is_empty() {
+        return Ok(RespValue::SimpleString("NOKEY".to_string()));
+    }
+
+    let mut requests = Vec::new();
+    if let Some(auth) = &migration.auth {
+        let mut args = vec![Bytes::from("AUTH")];
+        args.extend(auth.iter().cloned());
+        requests.push(args);
+    }
+    if migration.db != 0 {
+        requests.push(vec![
+            Bytes::from("SELECT"),
+            Bytes::from(migration.db.to_string()),
+        ]);
+    }
+    let preamble = requests.len();
+    let now = unix_time_ms();
+    for entry in &entries {
+        let ttl = entry.expire_at.map_or(0, |at| (at - now).max(1));
+        let mut args = vec![
+            Bytes::from("RESTORE-ASKING"),
+            entry.key.clone(),
+            Bytes::from(ttl.to_string()),
+            Bytes::from(rdb::dump_value(&entry.value)),
+        ];
+        if migration.replace {
+            args.push(Bytes::from("REPLACE"));
+        }
+        requests.push(args);
+    }
+
+    let timeout = if migration.timeout.is_zero() {
+        DEFAULT_TIMEOUT
+    } else {
+        migration.timeout
+    };
+    let replies = exchange(&migration.host, migration.port, timeout, &requests)?;
+
+    for reply in &replies[..preamble] {
+        if let RespValue::SimpleError(e) = reply {
+            return Err(target_error(e));
+        }
+    }
+    // The first failure is reported, but keys the target took are still
+    // removed here
+    let mut error = None;
+    let mut moved = Vec::new();
+    for (entry, reply) in entries.iter().zip(&replies[preamble..]) {
+        match reply {
+            RespValue::SimpleError(e) => {
+                error.get_or_insert_with(|| target_error(e));
+            }
+            _ => moved.push(entry),
+        }
+    }
+
+    if !migration.copy && !moved.is_empty() {
+        // Keys written to since they were read stay, as the target now holds
+        // an older copy
+        let _guard = db.write_guard();
+        let (moved, changed): (Vec<_>, Vec<_>) =
+            moved.into_iter().partition(|entry| unchanged(db, entry));
+        if let Some(entry) = changed.first() {
+            error.get_or_insert_with(|| {
+                CommandError::Err(format!(
+                    "Key '{}' was modified during MIGRATE and was kept",
+                    String::from_utf8_lossy(&entry.key)
+                ))
+            });
+        }
+        if !moved.is_empty() {
+            let moved: Vec<Bytes> = moved.into_iter().map(|entry| entry.key.clone()).collect();
+            db.del(&moved);
+            let mut args = vec![Bytes::from("DEL")];
+            args.extend(moved);
+            db.propagate(&args);
+        }
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(RespValue::SimpleString("OK".to_string())),
+    }
+}
+
+/// Whether `entry` still holds what was sent to the target. Expiry times
+/// are allowed to differ by a millisecond, since each read converts the
+/// deadline to wall-clock time anew.
+fn unchanged(db: &Db, entry: &rdb::Entry) -> bool {
+    db.entry(&entry.key).is_some_and(|current| {
+        current.value == entry.value
+            && match (current.expire_at, entry.expire_at) {
+                (Some(a), Some(b)) => a.abs_diff(b) <= 1,
+                (a, b) => a == b,
+            }
+    })
+}
+
+fn target_error(message: &str) -> CommandError {
+    CommandError::Err(format!("Target instance replied with error: {}", message))
+}
+
+/// Sends `requests` to the target in one go and reads a reply to each.
+fn exchange(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+    requests: &[Vec<Bytes>],
+) -> Result<Vec<RespValue>, CommandError> {
+    let ioerr = |what: &str| CommandError::IoErr(format!("error or timeout {}", what));
+    let mut stream = (host, port)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .and_then(|addr| TcpStream::connect_timeout(&addr, timeout).ok())
+        .ok_or_else(|| ioerr("connecting to the client"))?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .map_err(|_| ioerr("connecting to the client"))?;
+
+    let mut buf = BytesMut::new();
+    for args in requests {
+        RespValue::Array(args.iter().cloned().map(RespValue::BulkString).collect())
+            .write_to(&mut buf, Protocol::Resp2);
+    }
+    stream
+        .write_all(&buf)
+        .map_err(|_| ioerr("writing to target instance"))?;
+
+    let mut input = Vec::new();
+    let mut replies = Vec::with_capacity(requests.len());
+    while replies.len() < requests.len() {
+        match read_reply(&mut stream, &mut input) {
+            Ok(reply) => replies.push(reply),
+            Err(_) => return Err(ioerr("reading to target instance")),
+        }
+    }
+    Ok(replies)
+}
+
+/// Reads the next reply, keeping whatever follows it in `input`.
+fn read_reply(stream: &mut TcpStream, input: &mut Vec<u8>) -> io::Result<RespValue> {
+    loop {
+        let mut cursor = Cursor::new(&input[..]);
+        match parse_resp(&mut cursor) {
+            Ok(reply) => {
+                let len = cursor.position() as usize;
+                input.drain(..len);
+                return Ok(reply);
+            }
+            Err(ProtocolError::Incomplete) => {}
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+
+        let mut chunk = [0; 4096];
+        match stream.read(&mut chunk)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => input.extend_from_slice(&chunk[..n]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    fn migration(port: u16, timeout: Duration) -> Migration {
+        Migration {
+            host: "127.0.0.1".to_string(),
+            port,
+            keys: vec![Bytes::from("k")],
+            db: 0,
+            timeout,
+            copy: false,
+            replace: false,
+            auth: None,
+        }
+    }
+
+    #[test]
+    fn test_unreachable_target() {
+        let db = Db::new();
+        db.set(Bytes::from("k"), Bytes::from("v"), None);
+
+        // Nothing listens on a port just given back
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let result = transfer(&db, migration(port, Duration::from_millis(100)));
+        assert!(matches!(result, Err(CommandError::IoErr(_))));
+        assert!(db.entry(b"k").is_some());
+
+        // A target that never answers doesn't hold off writes meanwhile
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = {
+            let db = db.clone();
+            thread::spawn(move || transfer(&db, migration(port, Duration::from_millis(500))))
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(db.write_guard());
+        assert!(!handle.is_finished());
+        assert!(matches!(
+            handle.join().unwrap(),
+            Err(CommandError::IoErr(_))
+        ));
+        assert!(db.entry(b"k").is_some());
+    }
+}
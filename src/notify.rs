@@ -0,0 +1,98 @@
+//! Keyspace event classes, as configured through `notify-keyspace-events`.
+
+pub const KEYSPACE: u32 = 1 << 0; // K
+pub const KEYEVENT: u32 = 1 << 1; // E
+pub const GENERIC: u32 = 1 << 2; // g
+pub const STRING: u32 = 1 << 3; // $
+pub const LIST: u32 = 1 << 4; // l
+pub const SET: u32 = 1 << 5; // s
+pub const HASH: u32 = 1 << 6; // h
+pub const ZSET: u32 = 1 << 7; // z
+pub const EXPIRED: u32 = 1 << 8; // x
+pub const EVICTED: u32 = 1 << 9; // e
+pub const STREAM: u32 = 1 << 10; // t
+pub const KEY_MISS: u32 = 1 << 11; // m
+pub const NEW: u32 = 1 << 12; // n
+
+/// Every class included by the `A` alias.
+pub const ALL: u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM;
+
+/// Parses a flag string such as `KEA` or `Kx$`, rejecting unknown characters.
+pub fn parse_flags(flags: &str) -> Result<u32, String> {
+    let mut parsed = 0;
+    for c in flags.chars() {
+        parsed |= match c {
+            'A' => ALL,
+            'g' => GENERIC,
+            '$' => STRING,
+            'l' => LIST,
+            's' => SET,
+            'h' => HASH,
+            'z' => ZSET,
+            'x' => EXPIRED,
+            'e' => EVICTED,
+            'K' => KEYSPACE,
+            'E' => KEYEVENT,
+            't' => STREAM,
+            'm' => KEY_MISS,
+            'n' => NEW,
+            _ => return Err(format!("Invalid event class character '{}'", c)),
+        };
+    }
+    Ok(parsed)
+}
+
+/// Renders flags back to their canonical string form, using `A` when every
+/// class it covers is enabled.
+pub fn flags_to_string(flags: u32) -> String {
+    let mut out = String::new();
+
+    if flags & ALL == ALL {
+        out.push('A');
+    } else {
+        for (class, c) in [
+            (GENERIC, 'g'),
+            (STRING, '$'),
+            (LIST, 'l'),
+            (SET, 's'),
+            (HASH, 'h'),
+            (ZSET, 'z'),
+            (EXPIRED, 'x'),
+            (EVICTED, 'e'),
+            (STREAM, 't'),
+        ] {
+            if flags & class != 0 {
+                out.push(c);
+            }
+        }
+    }
+
+    for (class, c) in [
+        (KEYSPACE, 'K'),
+        (KEYEVENT, 'E'),
+        (KEY_MISS, 'm'),
+        (NEW, 'n'),
+    ] {
+        if flags & class != 0 {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_round_trip() {
+        let flags = parse_flags("KEA").unwrap();
+        assert_eq!(flags & ALL, ALL);
+        assert_eq!(flags_to_string(flags), "AKE");
+
+        let flags = parse_flags("Kx$").unwrap();
+        assert_eq!(flags_to_string(flags), "$xK");
+
+        assert!(parse_flags("Q").is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! Writing snapshots to disk, in the foreground (SAVE) or on a background
+//! thread (BGSAVE and the `save` rules), and loading them at startup.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{db::Db, error::RdbError, rdb};
+
+/// How long to wait before retrying after a failed background save.
+const RETRY_DELAY_SECS: u64 = 5;
+
+struct SaveState {
+    /// Unix time of the last successful save.
+    lastsave: u64,
+    /// Unix time of the last save attempt.
+    last_attempt: u64,
+    last_save_ok: bool,
+    in_progress: bool,
+}
+
+/// Snapshot bookkeeping shared by every connection.
+#[derive(Clone)]
+pub struct Persistence {
+    state: Arc<Mutex<SaveState>>,
+}
+
+impl Persistence {
+    pub fn new() -> Persistence {
+        let now = unix_time();
+        Persistence {
+            state: Arc::new(Mutex::new(SaveState {
+                lastsave: now,
+                last_attempt: now,
+                last_save_ok: true,
+                in_progress: false,
+            })),
+        }
+    }
+
+    /// Unix time of the last successful save, or of startup.
+    pub fn lastsave(&self) -> u64 {
+        self.state.lock().unwrap().lastsave
+    }
+
+    /// Marks a save as started, failing when one is already running.
+    fn begin(&self) -> Result<(), RdbError> {
+        let mut lock = self.state.lock().unwrap();
+        if lock.in_progress {
+            return Err(RdbError::SaveInProgress);
+        }
+        lock.in_progress = true;
+        lock.last_attempt = unix_time();
+        Ok(())
+    }
+
+    fn finish(&self, ok: bool) {
+        let mut lock = self.state.lock().unwrap();
+        lock.in_progress = false;
+        lock.last_save_ok = ok;
+        if ok {
+            lock.lastsave = unix_time();
+        }
+    }
+}
+
+/// Writes a snapshot of `db`, blocking the caller until it is on disk.
+pub fn save(db: &Db) -> Result<(), RdbError> {
+    db.persistence().begin()?;
+    let (snapshot, dirty) = db.snapshot();
+    let result = write_snapshot(db, &snapshot.into_entries());
+    finish(db, dirty, &result);
+    result.map_err(RdbError::from)
+}
+
+/// Takes a snapshot of the dataset and writes it out on a separate thread,
+/// so clients are only held up while the snapshot is taken.
+pub fn bgsave(db: &Db) -> Result<(), RdbError> {
+    db.persistence().begin()?;
+    let (snapshot, dirty) = db.snapshot();
+
+    let db = db.clone();
+    thread::spawn(move || {
+        let result = write_snapshot(&db, &snapshot.into_entries());
+        finish(&db, dirty, &result);
+    });
+    Ok(())
+}
+
+fn finish(db: &Db, dirty: u64, result: &io::Result<()>) {
+    match result {
+        Ok(()) => {
+            db.mark_saved(dirty);
+            println!("DB saved on disk");
+        }
+        Err(e) => eprintln!("Failed saving the DB: {}", e),
+    }
+    db.persistence().finish(result.is_ok());
+}
+
+/// Starts a background save when one of the `save` rules is satisfied.
+/// Called periodically by the server.
+pub fn cron(db: &Db) {
+    let dirty = db.dirty();
+    if dirty == 0 {
+        return;
+    }
+
+    let now = unix_time();
+    let (since_save, may_retry) = {
+        let lock = db.persistence().state.lock().unwrap();
+        if lock.in_progress {
+            return;
+        }
+        (
+            now.saturating_sub(lock.lastsave),
+            lock.last_save_ok || now.saturating_sub(lock.last_attempt) >= RETRY_DELAY_SECS,
+        )
+    };
+
+    let due = db
+        .config()
+        .save_rules()
+        .into_iter()
+        .find(|rule| dirty >= rule.changes && since_save >= rule.seconds);
+    if let Some(rule) = due
+        && may_retry
+    {
+        println!(
+            "{} changes in {} seconds. Saving...",
+            rule.changes, rule.seconds
+        );
+        let _ = bgsave(db);
+    }
+}
+
+/// Loads the snapshot file into `db`. Returns false when there is no file.
+pub fn load(db: &Db) -> Result<bool, RdbError> {
+    let data = match fs::read(snapshot_path(db)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    db.load(rdb::decode(&data, db.config().proto_max_bulk_len())?)?;
+    Ok(true)
+}
+
+fn snapshot_path(db: &Db) -> PathBuf {
+    Path::new(&db.config().dir()).join(db.config().dbfilename())
+}
+
+/// Writes the snapshot to a temporary file first and renames it into place,
+/// so a crash mid-write never leaves a truncated snapshot behind.
+fn write_snapshot(db: &Db, entries: &[rdb::Entry]) -> io::Result<()> {
+    let path = snapshot_path(db);
+    let temp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+
+    let result = (|| {
+        let mut file = File::create(&temp)?;
+        file.write_all(&rdb::encode(entries))?;
+        file.sync_all()?;
+        fs::rename(&temp, &path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DataType;
+    use bytes::Bytes;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("redis-lite-rdb-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let db = Db::new();
+        db.config().set("dir", dir.to_str().unwrap()).unwrap();
+        db.set(Bytes::from("k"), Bytes::from("v"), None);
+        let expiry = Instant::now() + Duration::from_secs(100);
+        db.set(Bytes::from("t"), Bytes::from("v"), Some(expiry));
+        assert_eq!(db.dirty(), 2);
+
+        save(&db).unwrap();
+        assert_eq!(db.dirty(), 0);
+
+        let restored = Db::new();
+        restored.config().set("dir", dir.to_str().unwrap()).unwrap();
+        assert!(load(&restored).unwrap());
+        assert_eq!(restored.get(b"k"), Some(DataType::String(Bytes::from("v"))));
+        assert!(restored.get(b"t").is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
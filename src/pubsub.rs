@@ -0,0 +1,525 @@
+use bytes::Bytes;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::{glob::glob_match, resp::RespValue, slot::key_hash_slot};
+
+pub type ClientId = u64;
+
+type Subscribers = HashMap<ClientId, UnboundedSender<RespValue>>;
+
+struct PubSubState {
+    next_id: ClientId,
+    channels: HashMap<Bytes, Subscribers>,
+    patterns: PatternIndex,
+    /// Shard channels grouped by the hash slot of their name, kept apart
+    /// from regular channels.
+    shard_channels: HashMap<u16, HashMap<Bytes, Subscribers>>,
+}
+
+/// Pattern subscriptions bucketed by their literal prefix (everything before
+/// the first glob metacharacter). Publishing only has to glob-match the
+/// patterns whose prefix is a prefix of the channel, which costs one lookup
+/// per channel byte instead of one match per subscribed pattern.
+#[derive(Default)]
+struct PatternIndex {
+    by_prefix: HashMap<Vec<u8>, HashMap<Bytes, Subscribers>>,
+    count: usize,
+}
+
+impl PatternIndex {
+    fn literal_prefix(pattern: &[u8]) -> &[u8] {
+        let end = pattern
+            .iter()
+            .position(|b| matches!(b, b'*' | b'?' | b'[' | b'\\'))
+            .unwrap_or(pattern.len());
+        &pattern[..end]
+    }
+
+    fn insert(&mut self, pattern: &Bytes, id: ClientId, tx: &UnboundedSender<RespValue>) {
+        let bucket = self
+            .by_prefix
+            .entry(Self::literal_prefix(pattern).to_vec())
+            .or_default();
+        let subscribers = bucket.entry(pattern.clone()).or_insert_with(|| {
+            self.count += 1;
+            HashMap::new()
+        });
+        subscribers.insert(id, tx.clone());
+    }
+
+    fn remove(&mut self, pattern: &[u8], id: ClientId) {
+        let prefix = Self::literal_prefix(pattern);
+        let Some(bucket) = self.by_prefix.get_mut(prefix) else {
+            return;
+        };
+        if let Some(subscribers) = bucket.get_mut(pattern) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                bucket.remove(pattern);
+                self.count -= 1;
+            }
+        }
+        if bucket.is_empty() {
+            self.by_prefix.remove(prefix);
+        }
+    }
+
+    /// Yields every subscribed pattern matching `channel` with its subscribers.
+    fn matches<'a>(
+        &'a self,
+        channel: &'a [u8],
+    ) -> impl Iterator<Item = (&'a Bytes, &'a Subscribers)> {
+        (0..=channel.len())
+            .filter_map(|end| self.by_prefix.get(&channel[..end]))
+            .flat_map(|bucket| bucket.iter())
+            .filter(move |(pattern, _)| glob_match(pattern, channel))
+    }
+}
+
+/// Registry of channel subscribers shared by every connection.
+#[derive(Clone)]
+pub struct PubSub {
+    state: Arc<Mutex<PubSubState>>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub {
+            state: Arc::new(Mutex::new(PubSubState {
+                next_id: 0,
+                channels: HashMap::new(),
+                patterns: PatternIndex::default(),
+                shard_channels: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Creates the per-connection subscriber handle along with the receiving
+    /// end of its outbound message queue.
+    pub fn subscriber(&self) -> (Subscriber, UnboundedReceiver<RespValue>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut lock = self.state.lock().unwrap();
+        lock.next_id += 1;
+
+        let subscriber = Subscriber {
+            id: lock.next_id,
+            tx,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            shard_channels: HashSet::new(),
+            pubsub: self.clone(),
+        };
+        (subscriber, rx)
+    }
+
+    /// Delivers `message` to every subscriber of `channel` and of any pattern
+    /// matching it, returning the number of deliveries made.
+    pub fn publish(&self, channel: Bytes, message: Bytes) -> usize {
+        let lock = self.state.lock().unwrap();
+        let mut receivers = 0;
+
+        if let Some(subscribers) = lock.channels.get(&channel) {
+            let payload = RespValue::Push(vec![
+                RespValue::BulkString(Bytes::from_static(b"message")),
+                RespValue::BulkString(channel.clone()),
+                RespValue::BulkString(message.clone()),
+            ]);
+            receivers += deliver(subscribers, &payload);
+        }
+
+        for (pattern, subscribers) in lock.patterns.matches(&channel) {
+            let payload = RespValue::Push(vec![
+                RespValue::BulkString(Bytes::from_static(b"pmessage")),
+                RespValue::BulkString(pattern.clone()),
+                RespValue::BulkString(channel.clone()),
+                RespValue::BulkString(message.clone()),
+            ]);
+            receivers += deliver(subscribers, &payload);
+        }
+
+        receivers
+    }
+
+    pub fn channels(&self, pattern: Option<&[u8]>) -> Vec<Bytes> {
+        let lock = self.state.lock().unwrap();
+        lock.channels
+            .keys()
+            .filter(|ch| pattern.is_none_or(|p| glob_match(p, ch)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn numsub(&self, channel: &[u8]) -> usize {
+        let lock = self.state.lock().unwrap();
+        lock.channels.get(channel).map_or(0, |subs| subs.len())
+    }
+
+    /// Number of unique patterns subscribed to by any client.
+    pub fn numpat(&self) -> usize {
+        self.state.lock().unwrap().patterns.count
+    }
+
+    /// Delivers `message` to the subscribers of the shard channel, returning
+    /// the number of clients that received it.
+    pub fn spublish(&self, channel: Bytes, message: Bytes) -> usize {
+        let lock = self.state.lock().unwrap();
+        let slot = key_hash_slot(&channel);
+
+        let Some(subscribers) = lock
+            .shard_channels
+            .get(&slot)
+            .and_then(|channels| channels.get(&channel))
+        else {
+            return 0;
+        };
+
+        let payload = RespValue::Push(vec![
+            RespValue::BulkString(Bytes::from_static(b"smessage")),
+            RespValue::BulkString(channel),
+            RespValue::BulkString(message),
+        ]);
+        deliver(subscribers, &payload)
+    }
+
+    pub fn shard_channels(&self, pattern: Option<&[u8]>) -> Vec<Bytes> {
+        let lock = self.state.lock().unwrap();
+        lock.shard_channels
+            .values()
+            .flat_map(|channels| channels.keys())
+            .filter(|ch| pattern.is_none_or(|p| glob_match(p, ch)))
+            .cloned()
+            .collect()
+    }
+
+    pub fn shard_numsub(&self, channel: &[u8]) -> usize {
+        let lock = self.state.lock().unwrap();
+        let slot = key_hash_slot(channel);
+        lock.shard_channels
+            .get(&slot)
+            .and_then(|channels| channels.get(channel))
+            .map_or(0, |subs| subs.len())
+    }
+
+    fn add(&self, channel: &Bytes, id: ClientId, tx: &UnboundedSender<RespValue>) {
+        let mut lock = self.state.lock().unwrap();
+        lock.channels
+            .entry(channel.clone())
+            .or_default()
+            .insert(id, tx.clone());
+    }
+
+    fn remove(&self, channel: &[u8], id: ClientId) {
+        let mut lock = self.state.lock().unwrap();
+        if let Some(subscribers) = lock.channels.get_mut(channel) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                lock.channels.remove(channel);
+            }
+        }
+    }
+
+    fn add_pattern(&self, pattern: &Bytes, id: ClientId, tx: &UnboundedSender<RespValue>) {
+        let mut lock = self.state.lock().unwrap();
+        lock.patterns.insert(pattern, id, tx);
+    }
+
+    fn remove_pattern(&self, pattern: &[u8], id: ClientId) {
+        let mut lock = self.state.lock().unwrap();
+        lock.patterns.remove(pattern, id);
+    }
+
+    fn add_shard(&self, channel: &Bytes, id: ClientId, tx: &UnboundedSender<RespValue>) {
+        let mut lock = self.state.lock().unwrap();
+        lock.shard_channels
+            .entry(key_hash_slot(channel))
+            .or_default()
+            .entry(channel.clone())
+            .or_default()
+            .insert(id, tx.clone());
+    }
+
+    fn remove_shard(&self, channel: &[u8], id: ClientId) {
+        let mut lock = self.state.lock().unwrap();
+        let slot = key_hash_slot(channel);
+        let Some(channels) = lock.shard_channels.get_mut(&slot) else {
+            return;
+        };
+        if let Some(subscribers) = channels.get_mut(channel) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                channels.remove(channel);
+            }
+        }
+        if channels.is_empty() {
+            lock.shard_channels.remove(&slot);
+        }
+    }
+}
+
+fn deliver(subscribers: &Subscribers, payload: &RespValue) -> usize {
+    subscribers
+        .values()
+        .filter(|tx| tx.send(payload.clone()).is_ok())
+        .count()
+}
+
+/// Per-connection subscription state. Dropping it removes the connection
+/// from every channel, pattern and shard channel it was subscribed to.
+pub struct Subscriber {
+    id: ClientId,
+    tx: UnboundedSender<RespValue>,
+    channels: HashSet<Bytes>,
+    patterns: HashSet<Bytes>,
+    shard_channels: HashSet<Bytes>,
+    pubsub: PubSub,
+}
+
+impl Subscriber {
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+
+    /// Number of active subscriptions; a non-zero count puts the connection
+    /// in subscribed mode.
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.shard_channels.len()
+    }
+
+    pub fn subscribe(&mut self, channels: Vec<Bytes>) -> Vec<RespValue> {
+        channels
+            .into_iter()
+            .map(|channel| {
+                if self.channels.insert(channel.clone()) {
+                    self.pubsub.add(&channel, self.id, &self.tx);
+                }
+                self.confirmation("subscribe", Some(channel), self.pubsub_count())
+            })
+            .collect()
+    }
+
+    /// Unsubscribes from `channels`, or from every channel when empty.
+    pub fn unsubscribe(&mut self, channels: Vec<Bytes>) -> Vec<RespValue> {
+        let channels = if channels.is_empty() {
+            self.channels.iter().cloned().collect()
+        } else {
+            channels
+        };
+
+        if channels.is_empty() {
+            return vec![self.confirmation("unsubscribe", None, self.pubsub_count())];
+        }
+
+        channels
+            .into_iter()
+            .map(|channel| {
+                if self.channels.remove(&channel) {
+                    self.pubsub.remove(&channel, self.id);
+                }
+                self.confirmation("unsubscribe", Some(channel), self.pubsub_count())
+            })
+            .collect()
+    }
+
+    pub fn psubscribe(&mut self, patterns: Vec<Bytes>) -> Vec<RespValue> {
+        patterns
+            .into_iter()
+            .map(|pattern| {
+                if self.patterns.insert(pattern.clone()) {
+                    self.pubsub.add_pattern(&pattern, self.id, &self.tx);
+                }
+                self.confirmation("psubscribe", Some(pattern), self.pubsub_count())
+            })
+            .collect()
+    }
+
+    /// Unsubscribes from `patterns`, or from every pattern when empty.
+    pub fn punsubscribe(&mut self, patterns: Vec<Bytes>) -> Vec<RespValue> {
+        let patterns = if patterns.is_empty() {
+            self.patterns.iter().cloned().collect()
+        } else {
+            patterns
+        };
+
+        if patterns.is_empty() {
+            return vec![self.confirmation("punsubscribe", None, self.pubsub_count())];
+        }
+
+        patterns
+            .into_iter()
+            .map(|pattern| {
+                if self.patterns.remove(&pattern) {
+                    self.pubsub.remove_pattern(&pattern, self.id);
+                }
+                self.confirmation("punsubscribe", Some(pattern), self.pubsub_count())
+            })
+            .collect()
+    }
+
+    pub fn ssubscribe(&mut self, channels: Vec<Bytes>) -> Vec<RespValue> {
+        channels
+            .into_iter()
+            .map(|channel| {
+                if self.shard_channels.insert(channel.clone()) {
+                    self.pubsub.add_shard(&channel, self.id, &self.tx);
+                }
+                self.confirmation("ssubscribe", Some(channel), self.shard_channels.len())
+            })
+            .collect()
+    }
+
+    /// Unsubscribes from shard `channels`, or from every shard channel when
+    /// empty.
+    pub fn sunsubscribe(&mut self, channels: Vec<Bytes>) -> Vec<RespValue> {
+        let channels = if channels.is_empty() {
+            self.shard_channels.iter().cloned().collect()
+        } else {
+            channels
+        };
+
+        if channels.is_empty() {
+            return vec![self.confirmation("sunsubscribe", None, 0)];
+        }
+
+        channels
+            .into_iter()
+            .map(|channel| {
+                if self.shard_channels.remove(&channel) {
+                    self.pubsub.remove_shard(&channel, self.id);
+                }
+                self.confirmation("sunsubscribe", Some(channel), self.shard_channels.len())
+            })
+            .collect()
+    }
+
+    /// Channel and pattern subscriptions, which is what the (un)subscribe
+    /// confirmations report; shard subscriptions are counted separately.
+    fn pubsub_count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    fn confirmation(&self, kind: &str, channel: Option<Bytes>, count: usize) -> RespValue {
+        RespValue::Push(vec![
+            RespValue::BulkString(Bytes::copy_from_slice(kind.as_bytes())),
+            channel.map_or(RespValue::Null, RespValue::BulkString),
+            RespValue::Integer(count as i64),
+        ])
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for channel in &self.channels {
+            self.pubsub.remove(channel, self.id);
+        }
+        for pattern in &self.patterns {
+            self.pubsub.remove_pattern(pattern, self.id);
+        }
+        for channel in &self.shard_channels {
+            self.pubsub.remove_shard(channel, self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_reaches_subscribers() {
+        let pubsub = PubSub::new();
+        let (mut sub, mut rx) = pubsub.subscriber();
+        sub.subscribe(vec![Bytes::from("news")]);
+
+        assert_eq!(pubsub.publish(Bytes::from("news"), Bytes::from("hello")), 1);
+        assert_eq!(
+            pubsub.publish(Bytes::from("other"), Bytes::from("hello")),
+            0
+        );
+
+        let expected = RespValue::Push(vec![
+            RespValue::BulkString(Bytes::from("message")),
+            RespValue::BulkString(Bytes::from("news")),
+            RespValue::BulkString(Bytes::from("hello")),
+        ]);
+        assert_eq!(rx.try_recv().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_unsubscribe_and_drop_cleanup() {
+        let pubsub = PubSub::new();
+        let (mut sub, _rx) = pubsub.subscriber();
+        sub.subscribe(vec![Bytes::from("a"), Bytes::from("b")]);
+        assert_eq!(pubsub.numsub(b"a"), 1);
+
+        let replies = sub.unsubscribe(vec![Bytes::from("a")]);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(sub.count(), 1);
+        assert_eq!(pubsub.numsub(b"a"), 0);
+
+        drop(sub);
+        assert!(pubsub.channels(None).is_empty());
+    }
+
+    #[test]
+    fn test_pattern_subscriptions() {
+        let pubsub = PubSub::new();
+        let (mut sub, mut rx) = pubsub.subscriber();
+        sub.psubscribe(vec![Bytes::from("orders.*"), Bytes::from("*")]);
+        sub.psubscribe(vec![Bytes::from("orders.*")]);
+        assert_eq!(pubsub.numpat(), 2);
+
+        assert_eq!(
+            pubsub.publish(Bytes::from("orders.eu"), Bytes::from("1")),
+            2
+        );
+        assert_eq!(pubsub.publish(Bytes::from("invoices"), Bytes::from("1")), 1);
+
+        let received = [rx.try_recv().unwrap(), rx.try_recv().unwrap()];
+        assert!(received.contains(&RespValue::Push(vec![
+            RespValue::BulkString(Bytes::from("pmessage")),
+            RespValue::BulkString(Bytes::from("orders.*")),
+            RespValue::BulkString(Bytes::from("orders.eu")),
+            RespValue::BulkString(Bytes::from("1")),
+        ])));
+
+        sub.punsubscribe(vec![]);
+        assert_eq!(pubsub.numpat(), 0);
+        assert_eq!(
+            pubsub.publish(Bytes::from("orders.eu"), Bytes::from("1")),
+            0
+        );
+    }
+
+    #[test]
+    fn test_shard_channels_are_independent() {
+        let pubsub = PubSub::new();
+        let (mut sub, mut rx) = pubsub.subscriber();
+        sub.ssubscribe(vec![Bytes::from("{user1}.events")]);
+        sub.subscribe(vec![Bytes::from("{user1}.events")]);
+
+        assert_eq!(
+            pubsub.spublish(Bytes::from("{user1}.events"), Bytes::from("hi")),
+            1
+        );
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            RespValue::Push(vec![
+                RespValue::BulkString(Bytes::from("smessage")),
+                RespValue::BulkString(Bytes::from("{user1}.events")),
+                RespValue::BulkString(Bytes::from("hi")),
+            ])
+        );
+        assert!(rx.try_recv().is_err());
+
+        assert_eq!(pubsub.shard_numsub(b"{user1}.events"), 1);
+        assert_eq!(pubsub.shard_channels(Some(b"{user1}*")).len(), 1);
+
+        sub.sunsubscribe(vec![]);
+        assert_eq!(pubsub.shard_numsub(b"{user1}.events"), 0);
+        assert_eq!(pubsub.numsub(b"{user1}.events"), 1);
+    }
+}
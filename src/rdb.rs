@@ -0,0 +1,839 @@
+//! The RDB snapshot format. Snapshots are written with the plain (non
+//! listpack) encodings every Redis version can load, and files written by
+//! Redis itself can be read back, including its compact listpack, ziplist
+//! and intset encodings and LZF-compressed strings.
+
+use bytes::Bytes;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{crc64::crc64, db::DataType, error::RdbError};
+
+/// Version written to new files. Redis 5.0 and later can load it.
+const RDB_VERSION: u32 = 9;
+/// Newest version that can be loaded (Redis 7.2).
+const MAX_RDB_VERSION: u32 = 11;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+const OPCODE_FUNCTION2: u8 = 0xf5;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+/// Special string encodings, flagged by the top two bits of a length.
+const ENC_INT8: u64 = 0;
+const ENC_INT16: u64 = 1;
+const ENC_INT32: u64 = 2;
+const ENC_LZF: u64 = 3;
+
+/// Quicklist node containers.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+/// A key as stored in a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: Bytes,
+    pub value: DataType,
+    /// Absolute expiry in milliseconds since the Unix epoch.
+    pub expire_at: Option<i64>,
+}
+
+/// Serializes `entries` as a complete RDB file, checksum included.
+pub fn encode(entries: &[Entry]) -> Vec<u8> {
+    let mut out = format!("REDIS{:04}", RDB_VERSION).into_bytes();
+
+    let ctime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    for (name, value) in [
+        ("redis-ver", crate::REDIS_VERSION.to_string()),
+        ("redis-bits", "64".to_string()),
+        ("ctime", ctime.to_string()),
+        ("used-mem", "0".to_string()),
+        ("aof-base", "0".to_string()),
+    ] {
+        out.push(OPCODE_AUX);
+        write_string(&mut out, name.as_bytes());
+        write_string(&mut out, value.as_bytes());
+    }
+
+    if !entries.is_empty() {
+        out.push(OPCODE_SELECTDB);
+        write_len(&mut out, 0);
+        out.push(OPCODE_RESIZEDB);
+        write_len(&mut out, entries.len() as u64);
+        let expires = entries.iter().filter(|e| e.expire_at.is_some()).count();
+        write_len(&mut out, expires as u64);
+    }
+
+    for entry in entries {
+        if let Some(at) = entry.expire_at {
+            out.push(OPCODE_EXPIRETIME_MS);
+            out.extend_from_slice(&at.to_le_bytes());
+        }
+        out.push(value_type(&entry.value));
+        write_string(&mut out, &entry.key);
+        write_value(&mut out, &entry.value);
+    }
+
+    out.push(OPCODE_EOF);
+    let checksum = crc64(0, &out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+fn value_type(value: &DataType) -> u8 {
+    match value {
+        DataType::String(_) => TYPE_STRING,
+        DataType::List(_) => TYPE_LIST,
+        DataType::Set(_) => TYPE_SET,
+        DataType::Hash(_) => TYPE_HASH,
+        DataType::ZSet(_) => TYPE_ZSET_2,
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &DataType) {
+    match value {
+        DataType::String(s) => write_string(out, s),
+        DataType::List(list) => {
+            write_len(out, list.len() as u64);
+            for item in list {
+                write_string(out, item);
+            }
+        }
+        DataType::Set(set) => {
+            write_len(out, set.len() as u64);
+            for member in set {
+                write_string(out, member);
+            }
+        }
+        DataType::Hash(hash) => {
+            write_len(out, hash.len() as u64);
+            for (field, value) in hash {
+                write_string(out, field);
+                write_string(out, value);
+            }
+        }
+        DataType::ZSet(zset) => {
+            write_len(out, zset.len() as u64);
+            for (member, score) in zset {
+                write_string(out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+    }
+}
+
+fn write_len(out: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0x40 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &[u8]) {
+    write_len(out, s.len() as u64);
+    out.extend_from_slice(s);
+}
+
+/// Serializes a single value as DUMP does: its RDB type and encoding,
+/// followed by the RDB version and a checksum of the whole payload.
+pub fn dump_value(value: &DataType) -> Vec<u8> {
+    let mut out = vec![value_type(value)];
+    write_value(&mut out, value);
+    out.extend_from_slice(&(RDB_VERSION as u16).to_le_bytes());
+    let checksum = crc64(0, &out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+/// Parses a payload made by [`dump_value`], or by DUMP in any Redis
+/// version whose RDB format can be loaded. No string in it may be longer
+/// than `max_string_len`.
+pub fn restore_value(payload: &[u8], max_string_len: usize) -> Result<DataType, RdbError> {
+    // Too short to even hold the version and checksum
+    let Some(body_len) = payload.len().checked_sub(10) else {
+        return Err(RdbError::BadChecksum);
+    };
+    let (body, footer) = payload.split_at(body_len);
+    let version = u16::from_le_bytes([footer[0], footer[1]]) as u32;
+    if version > MAX_RDB_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+    let checksum = u64::from_le_bytes(footer[2..].try_into().unwrap());
+    if checksum != crc64(0, &payload[..body_len + 2]) {
+        return Err(RdbError::BadChecksum);
+    }
+
+    let mut reader = Reader {
+        data: body,
+        pos: 0,
+        max_string_len,
+    };
+    let value_type = reader.byte()?;
+    let value = reader.value(value_type)?;
+    if reader.pos != body.len() {
+        return Err(RdbError::Corrupt("payload"));
+    }
+    Ok(value)
+}
+
+/// Parses a complete RDB file. Only keys of database 0 are returned, since
+/// that is the only database this server has. No string in it may be
+/// longer than `max_string_len`.
+pub fn decode(data: &[u8], max_string_len: usize) -> Result<Vec<Entry>, RdbError> {
+    if data.len() < 9 || &data[..5] != b"REDIS" {
+        return Err(RdbError::BadSignature);
+    }
+    let version = std::str::from_utf8(&data[5..9])
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or(RdbError::BadSignature)?;
+    if version == 0 || version > MAX_RDB_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+
+    let mut reader = Reader {
+        data,
+        pos: 9,
+        max_string_len,
+    };
+    let mut entries = Vec::new();
+    let mut db = 0;
+    let mut expire_at = None;
+
+    loop {
+        let opcode = reader.byte()?;
+        match opcode {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => db = reader.len()?,
+            OPCODE_RESIZEDB => {
+                reader.len()?;
+                reader.len()?;
+            }
+            OPCODE_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            OPCODE_EXPIRETIME_MS => {
+                expire_at = Some(i64::from_le_bytes(reader.array()?));
+            }
+            OPCODE_EXPIRETIME => {
+                expire_at = Some(i32::from_le_bytes(reader.array()?) as i64 * 1000);
+            }
+            // Eviction hints and functions don't apply here
+            OPCODE_IDLE => {
+                reader.len()?;
+            }
+            OPCODE_FREQ => {
+                reader.byte()?;
+            }
+            OPCODE_FUNCTION2 => {
+                reader.string()?;
+            }
+            value_type if value_type < OPCODE_FUNCTION2 => {
+                let key = reader.string()?;
+                let value = reader.value(value_type)?;
+                if db == 0 {
+                    entries.push(Entry {
+                        key,
+                        value,
+                        expire_at: expire_at.take(),
+                    });
+                }
+                expire_at = None;
+            }
+            opcode => return Err(RdbError::UnsupportedOpcode(opcode)),
+        }
+    }
+
+    // Files from version 5 on end in a checksum, where zero means disabled
+    if version >= 5 {
+        let end = reader.pos;
+        let expected = u64::from_le_bytes(reader.array()?);
+        if expected != 0 && expected != crc64(0, &data[..end]) {
+            return Err(RdbError::BadChecksum);
+        }
+    }
+    Ok(entries)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Longest string the data may expand to (`proto-max-bulk-len`).
+    max_string_len: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], RdbError> {
+        let end = self.pos.checked_add(n).ok_or(RdbError::UnexpectedEof)?;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or(RdbError::UnexpectedEof)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Result<u8, RdbError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Reads a length, also returning whether it was a special string
+    /// encoding rather than a plain length.
+    fn encoded_len(&mut self) -> Result<(u64, bool), RdbError> {
+        let first = self.byte()?;
+        Ok(match first >> 6 {
+            0 => ((first & 0x3f) as u64, false),
+            1 => ((((first & 0x3f) as u64) << 8) | self.byte()? as u64, false),
+            2 => match first {
+                0x80 => (u32::from_be_bytes(self.array()?) as u64, false),
+                0x81 => (u64::from_be_bytes(self.array()?), false),
+                _ => return Err(RdbError::Corrupt("length")),
+            },
+            _ => ((first & 0x3f) as u64, true),
+        })
+    }
+
+    fn len(&mut self) -> Result<u64, RdbError> {
+        match self.encoded_len()? {
+            (len, false) => Ok(len),
+            (_, true) => Err(RdbError::Corrupt("length")),
+        }
+    }
+
+    /// Reads a length that is about to be used as a count or size, so that
+    /// a corrupt file can't trigger a huge allocation.
+    fn count(&mut self) -> Result<usize, RdbError> {
+        let len = self.len()?;
+        if len > (self.data.len() - self.pos) as u64 {
+            return Err(RdbError::UnexpectedEof);
+        }
+        Ok(len as usize)
+    }
+
+    fn string(&mut self) -> Result<Bytes, RdbError> {
+        let (len, encoded) = self.encoded_len()?;
+        if !encoded {
+            let len = usize::try_from(len).map_err(|_| RdbError::UnexpectedEof)?;
+            return Ok(Bytes::copy_from_slice(self.bytes(len)?));
+        }
+
+        let int = match len {
+            ENC_INT8 => self.byte()? as i8 as i64,
+            ENC_INT16 => i16::from_le_bytes(self.array()?) as i64,
+            ENC_INT32 => i32::from_le_bytes(self.array()?) as i64,
+            ENC_LZF => {
+                let compressed_len = self.count()?;
+                let len = usize::try_from(self.len()?)
+                    .ok()
+                    .filter(|&len| len <= self.max_string_len)
+                    .ok_or(RdbError::Corrupt("LZF"))?;
+                let compressed = self.bytes(compressed_len)?;
+                return lzf_decompress(compressed, len).map(Bytes::from);
+            }
+            _ => return Err(RdbError::Corrupt("string")),
+        };
+        Ok(Bytes::from(int.to_string()))
+    }
+
+    /// Reads a score as stored by the original ZSET type.
+    fn string_double(&mut self) -> Result<f64, RdbError> {
+        let len = self.byte()?;
+        Ok(match len {
+            253 => f64::NAN,
+            254 => f64::INFINITY,
+            255 => f64::NEG_INFINITY,
+            len => parse_score(self.bytes(len as usize)?)?,
+        })
+    }
+
+    fn value(&mut self, value_type: u8) -> Result<DataType, RdbError> {
+        Ok(match value_type {
+            TYPE_STRING => DataType::String(self.string()?),
+            TYPE_LIST => {
+                let len = self.count()?;
+                let mut list = VecDeque::with_capacity(len);
+                for _ in 0..len {
+                    list.push_back(self.string()?);
+                }
+                DataType::List(list)
+            }
+            TYPE_SET => {
+                let len = self.count()?;
+                let mut set = HashSet::with_capacity(len);
+                for _ in 0..len {
+                    set.insert(self.string()?);
+                }
+                DataType::Set(set)
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.count()?;
+                let mut zset = HashMap::with_capacity(len);
+                for _ in 0..len {
+                    let member = self.string()?;
+                    let score = if value_type == TYPE_ZSET_2 {
+                        f64::from_le_bytes(self.array()?)
+                    } else {
+                        self.string_double()?
+                    };
+                    zset.insert(member, score);
+                }
+                DataType::ZSet(zset)
+            }
+            TYPE_HASH => {
+                let len = self.count()?;
+                let mut hash = HashMap::with_capacity(len);
+                for _ in 0..len {
+                    let field = self.string()?;
+                    hash.insert(field, self.string()?);
+                }
+                DataType::Hash(hash)
+            }
+            TYPE_LIST_ZIPLIST => DataType::List(ziplist_entries(&self.string()?)?.into()),
+            TYPE_LIST_QUICKLIST | TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.count()?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    let container = if value_type == TYPE_LIST_QUICKLIST_2 {
+                        self.len()?
+                    } else {
+                        QUICKLIST_NODE_PACKED
+                    };
+                    let node = self.string()?;
+                    match (container, value_type) {
+                        (QUICKLIST_NODE_PLAIN, _) => list.push_back(node),
+                        (QUICKLIST_NODE_PACKED, TYPE_LIST_QUICKLIST) => {
+                            list.extend(ziplist_entries(&node)?)
+                        }
+                        (QUICKLIST_NODE_PACKED, _) => list.extend(listpack_entries(&node)?),
+                        _ => return Err(RdbError::Corrupt("quicklist")),
+                    }
+                }
+                DataType::List(list)
+            }
+            TYPE_SET_INTSET => {
+                DataType::Set(intset_entries(&self.string()?)?.into_iter().collect())
+            }
+            TYPE_SET_LISTPACK => {
+                DataType::Set(listpack_entries(&self.string()?)?.into_iter().collect())
+            }
+            TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+                let blob = self.string()?;
+                let items = if value_type == TYPE_HASH_ZIPLIST {
+                    ziplist_entries(&blob)?
+                } else {
+                    listpack_entries(&blob)?
+                };
+                DataType::Hash(pairs(items)?.collect())
+            }
+            TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+                let blob = self.string()?;
+                let items = if value_type == TYPE_ZSET_ZIPLIST {
+                    ziplist_entries(&blob)?
+                } else {
+                    listpack_entries(&blob)?
+                };
+                let mut zset = HashMap::new();
+                for (member, score) in pairs(items)? {
+                    zset.insert(member, parse_score(&score)?);
+                }
+                DataType::ZSet(zset)
+            }
+            value_type => return Err(RdbError::UnsupportedType(value_type)),
+        })
+    }
+}
+
+fn parse_score(s: &[u8]) -> Result<f64, RdbError> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| match s {
+            "inf" | "+inf" => Some(f64::INFINITY),
+            "-inf" => Some(f64::NEG_INFINITY),
+            s => s.parse().ok(),
+        })
+        .ok_or(RdbError::Corrupt("score"))
+}
+
+/// Groups flattened field/value items into pairs.
+fn pairs(items: Vec<Bytes>) -> Result<impl Iterator<Item = (Bytes, Bytes)>, RdbError> {
+    if !items.len().is_multiple_of(2) {
+        return Err(RdbError::Corrupt("pairs"));
+    }
+    let mut iter = items.into_iter();
+    Ok(std::iter::from_fn(move || {
+        Some((iter.next()?, iter.next()?))
+    }))
+}
+
+/// Decodes the elements of a listpack blob.
+fn listpack_entries(blob: &[u8]) -> Result<Vec<Bytes>, RdbError> {
+    const CORRUPT: RdbError = RdbError::Corrupt("listpack");
+
+    let mut r = Reader {
+        data: blob,
+        pos: 6,
+        max_string_len: blob.len(),
+    };
+    if blob.len() < 7 {
+        return Err(CORRUPT);
+    }
+    let mut items = Vec::new();
+
+    loop {
+        let start = r.pos;
+        let b = r.byte()?;
+        let item = match b {
+            0xff => break,
+            b if b & 0x80 == 0 => int_bytes((b & 0x7f) as i64),
+            b if b & 0xc0 == 0x80 => Bytes::copy_from_slice(r.bytes((b & 0x3f) as usize)?),
+            b if b & 0xe0 == 0xc0 => {
+                let v = (((b & 0x1f) as i64) << 8) | r.byte()? as i64;
+                int_bytes(if v >= 1 << 12 { v - (1 << 13) } else { v })
+            }
+            b if b & 0xf0 == 0xe0 => {
+                let len = (((b & 0x0f) as usize) << 8) | r.byte()? as usize;
+                Bytes::copy_from_slice(r.bytes(len)?)
+            }
+            0xf0 => {
+                let len = u32::from_le_bytes(r.array()?) as usize;
+                Bytes::copy_from_slice(r.bytes(len)?)
+            }
+            0xf1 => int_bytes(i16::from_le_bytes(r.array()?) as i64),
+            0xf2 => {
+                let [a, b, c] = r.array()?;
+                int_bytes((i32::from_le_bytes([0, a, b, c]) >> 8) as i64)
+            }
+            0xf3 => int_bytes(i32::from_le_bytes(r.array()?) as i64),
+            0xf4 => int_bytes(i64::from_le_bytes(r.array()?)),
+            _ => return Err(CORRUPT),
+        };
+        items.push(item);
+
+        // Skip the back-length, whose size depends on the entry's size
+        let entry_len = r.pos - start;
+        let backlen = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        r.bytes(backlen)?;
+    }
+    Ok(items)
+}
+
+/// Decodes the elements of a ziplist blob, as written by Redis before 7.0.
+fn ziplist_entries(blob: &[u8]) -> Result<Vec<Bytes>, RdbError> {
+    const CORRUPT: RdbError = RdbError::Corrupt("ziplist");
+
+    if blob.len() < 11 {
+        return Err(CORRUPT);
+    }
+    let mut r = Reader {
+        data: blob,
+        pos: 10,
+        max_string_len: blob.len(),
+    };
+    let mut items = Vec::new();
+
+    loop {
+        let prevlen = r.byte()?;
+        if prevlen == 0xff {
+            break;
+        }
+        if prevlen == 0xfe {
+            r.bytes(4)?;
+        }
+
+        let b = r.byte()?;
+        let item = match b >> 6 {
+            0 => Bytes::copy_from_slice(r.bytes((b & 0x3f) as usize)?),
+            1 => {
+                let len = (((b & 0x3f) as usize) << 8) | r.byte()? as usize;
+                Bytes::copy_from_slice(r.bytes(len)?)
+            }
+            2 => {
+                let len = u32::from_be_bytes(r.array()?) as usize;
+                Bytes::copy_from_slice(r.bytes(len)?)
+            }
+            _ => match b {
+                0xc0 => int_bytes(i16::from_le_bytes(r.array()?) as i64),
+                0xd0 => int_bytes(i32::from_le_bytes(r.array()?) as i64),
+                0xe0 => int_bytes(i64::from_le_bytes(r.array()?)),
+                0xf0 => {
+                    let [a, b, c] = r.array()?;
+                    int_bytes((i32::from_le_bytes([0, a, b, c]) >> 8) as i64)
+                }
+                0xfe => int_bytes(r.byte()? as i8 as i64),
+                0xf1..=0xfd => int_bytes((b & 0x0f) as i64 - 1),
+                _ => return Err(CORRUPT),
+            },
+        };
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// Decodes the members of an intset blob.
+fn intset_entries(blob: &[u8]) -> Result<Vec<Bytes>, RdbError> {
+    let mut r = Reader {
+        data: blob,
+        pos: 0,
+        max_string_len: blob.len(),
+    };
+    let width = u32::from_le_bytes(r.array()?) as usize;
+    let len = u32::from_le_bytes(r.array()?) as usize;
+    if !matches!(width, 2 | 4 | 8) || blob.len() != 8 + width * len {
+        return Err(RdbError::Corrupt("intset"));
+    }
+
+    let mut members = Vec::with_capacity(len);
+    for _ in 0..len {
+        let value = match width {
+            2 => i16::from_le_bytes(r.array()?) as i64,
+            4 => i32::from_le_bytes(r.array()?) as i64,
+            _ => i64::from_le_bytes(r.array()?),
+        };
+        members.push(int_bytes(value));
+    }
+    Ok(members)
+}
+
+fn int_bytes(value: i64) -> Bytes {
+    Bytes::from(value.to_string())
+}
+
+/// Decompresses an LZF block into exactly `len` bytes.
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, RdbError> {
+    const CORRUPT: RdbError = RdbError::Corrupt("LZF");
+    // The longest back reference takes three bytes and repeats 264
+    const MAX_EXPANSION: usize = 264 / 3;
+
+    // Checked before allocating, as `len` comes from the file
+    if len > input.len().saturating_mul(MAX_EXPANSION) {
+        return Err(CORRUPT);
+    }
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes
+            let run = input.get(i..i + ctrl + 1).ok_or(CORRUPT)?;
+            out.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            // Back reference
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or(CORRUPT)? as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or(CORRUPT)? as usize + 1;
+            i += 1;
+
+            let start = out.len().checked_sub(offset).ok_or(CORRUPT)?;
+            for j in 0..run + 2 {
+                out.push(out[start + j]);
+            }
+        }
+        if out.len() > len {
+            return Err(CORRUPT);
+        }
+    }
+
+    if out.len() != len {
+        return Err(CORRUPT);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_LEN: usize = 512 * 1024 * 1024;
+
+    fn entry(key: &str, value: DataType, expire_at: Option<i64>) -> Entry {
+        Entry {
+            key: Bytes::copy_from_slice(key.as_bytes()),
+            value,
+            expire_at,
+        }
+    }
+
+    #[test]
+    fn test_round_trip_every_type() {
+        let entries = vec![
+            entry(
+                "s",
+                DataType::String(Bytes::from("v")),
+                Some(1_900_000_000_000),
+            ),
+            entry(
+                "l",
+                DataType::List(VecDeque::from([Bytes::from("a"), Bytes::from("b")])),
+                None,
+            ),
+            entry(
+                "set",
+                DataType::Set(HashSet::from([Bytes::from("m")])),
+                None,
+            ),
+            entry(
+                "h",
+                DataType::Hash(HashMap::from([(Bytes::from("f"), Bytes::from("v"))])),
+                None,
+            ),
+            entry(
+                "z",
+                DataType::ZSet(HashMap::from([(Bytes::from("m"), 1.5)])),
+                None,
+            ),
+        ];
+
+        let data = encode(&entries);
+        assert_eq!(&data[..9], b"REDIS0009");
+        assert_eq!(decode(&data, MAX_LEN).unwrap(), entries);
+    }
+
+    #[test]
+    fn test_dump_value_round_trip() {
+        let value = DataType::List(VecDeque::from([Bytes::from("a"), Bytes::from("b")]));
+        let mut payload = dump_value(&value);
+        assert_eq!(restore_value(&payload, MAX_LEN).unwrap(), value);
+
+        let string = dump_value(&DataType::String(Bytes::from("v")));
+        assert_eq!(&string[..5], b"\x00\x01v\x09\x00");
+
+        let len = payload.len();
+        payload[len - 1] ^= 0xff;
+        assert!(matches!(
+            restore_value(&payload, MAX_LEN),
+            Err(RdbError::BadChecksum)
+        ));
+        payload[len - 10] = 12;
+        assert!(matches!(
+            restore_value(&payload, MAX_LEN),
+            Err(RdbError::UnsupportedVersion(12))
+        ));
+        assert!(restore_value(b"\x00", MAX_LEN).is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_corruption() {
+        let mut data = encode(&[entry("k", DataType::String(Bytes::from("v")), None)]);
+        let len = data.len();
+        data[len - 12] ^= 0xff;
+        assert!(matches!(decode(&data, MAX_LEN), Err(RdbError::BadChecksum)));
+
+        assert!(matches!(
+            decode(b"REDIX0009", MAX_LEN),
+            Err(RdbError::BadSignature)
+        ));
+        assert!(matches!(
+            decode(b"REDIS0012\xff", MAX_LEN),
+            Err(RdbError::UnsupportedVersion(12))
+        ));
+        assert!(matches!(
+            decode(&data[..len - 9], MAX_LEN),
+            Err(RdbError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn test_decode_redis_encodings() {
+        // A Redis 7.2 dump holding an int-encoded string, a listpack hash,
+        // an intset and a quicklist, with the checksum disabled
+        let mut data = b"REDIS0011\xfe\x00".to_vec();
+        data.extend_from_slice(b"\x00\x01n\xc1\x39\x30");
+        data.extend_from_slice(b"\x10\x01h\x0c\x0c\x00\x00\x00\x02\x00\x81f\x02\x01\x01\xff");
+        data.extend_from_slice(b"\x0b\x01i\x0c\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00\x02\x00");
+        data.extend_from_slice(
+            b"\x12\x01l\x01\x02\x0e\x0e\x00\x00\x00\x02\x00\x81a\x02\x82bc\x03\xff",
+        );
+        data.extend_from_slice(b"\xff\x00\x00\x00\x00\x00\x00\x00\x00");
+
+        let entries = decode(&data, MAX_LEN).unwrap();
+        assert_eq!(entries[0].value, DataType::String(Bytes::from("12345")));
+        assert_eq!(
+            entries[1].value,
+            DataType::Hash(HashMap::from([(Bytes::from("f"), Bytes::from("1"))]))
+        );
+        assert_eq!(
+            entries[2].value,
+            DataType::Set(HashSet::from([Bytes::from("1"), Bytes::from("2")]))
+        );
+        assert_eq!(
+            entries[3].value,
+            DataType::List(VecDeque::from([Bytes::from("a"), Bytes::from("bc")]))
+        );
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        // "aaaaaaaaaa" compresses to a literal 'a' and a back reference
+        assert_eq!(
+            lzf_decompress(b"\x00a\xe0\x00\x00", 10).unwrap(),
+            b"aaaaaaaaaa"
+        );
+        assert!(lzf_decompress(b"\x00a\xe0\x00\x05", 10).is_err());
+        // More than five bytes could ever expand to
+        assert!(lzf_decompress(b"\x00a\xe0\x00\x00", 1 << 40).is_err());
+
+        // A length over the limit is refused before decompressing
+        let mut payload = b"\x00\xc3\x05\x0a\x00a\xe0\x00\x00".to_vec();
+        payload.extend_from_slice(&(RDB_VERSION as u16).to_le_bytes());
+        let checksum = crc64(0, &payload);
+        payload.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            restore_value(&payload, 10).unwrap(),
+            DataType::String(Bytes::from("aaaaaaaaaa"))
+        );
+        assert!(matches!(
+            restore_value(&payload, 9),
+            Err(RdbError::Corrupt("LZF"))
+        ));
+    }
+}
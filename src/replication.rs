@@ -0,0 +1,1087 @@
+//! Master-replica replication. A replica connects to its master, performs
+//! the handshake (PING, REPLCONF, PSYNC), loads the snapshot the master
+//! streams back and then applies every write the master propagates. After
+//! a brief disconnection it resumes from the master's backlog instead.
+
+use bytes::{Buf, Bytes, BytesMut};
+use std::{
+    collections::{VecDeque, hash_map::RandomState},
+    hash::{BuildHasher, Hasher},
+    io::Cursor,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::{
+    aof,
+    commands::Command,
+    config::Config,
+    db::{Db, Snapshot},
+    error::{CommandError, ProtocolError, ReplicationError},
+    rdb,
+    resp::{Protocol, RespValue, parse_resp},
+};
+
+/// Delay between attempts to reach an unavailable master.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How often a replica reports its offset to its master.
+const ACK_PERIOD: Duration = Duration::from_secs(1);
+
+/// How often WAIT and WAITAOF check for acknowledgements.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Length of the mark ending a snapshot of unknown length.
+const EOF_MARK_LEN: usize = 40;
+
+struct MasterLink {
+    host: String,
+    port: u16,
+    task: JoinHandle<()>,
+    state: LinkState,
+    /// When data was last received from the master.
+    last_io: Instant,
+    /// When the link was lost, or first attempted.
+    down_since: Instant,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LinkState {
+    /// Connecting to the master or going through the handshake.
+    Connecting,
+    /// Receiving the master's snapshot.
+    Sync,
+    /// Applying the master's stream.
+    Connected,
+}
+
+impl LinkState {
+    /// The name ROLE reports the state under.
+    fn name(self) -> &'static str {
+        match self {
+            LinkState::Connecting => "connecting",
+            LinkState::Sync => "sync",
+            LinkState::Connected => "connected",
+        }
+    }
+}
+
+/// A replica attached to this server.
+struct ReplicaLink {
+    id: u64,
+    stream: mpsc::UnboundedSender<Bytes>,
+    /// Address the replica accepts connections on.
+    addr: SocketAddr,
+    /// Whether the replica has been brought up to date and is following
+    /// the stream.
+    online: bool,
+    /// Offset the replica last reported having applied.
+    ack_offset: u64,
+    last_ack: Instant,
+    /// Offset the replica last reported having flushed to its append-only
+    /// file, if it keeps one.
+    aof_ack_offset: Option<u64>,
+}
+
+/// How a replica that sent PSYNC is brought up to date.
+enum Resync {
+    /// From a snapshot, followed by the writes made from `offset` on.
+    Full { replid: String, offset: u64 },
+    /// By resending the part of the stream it missed, taken from the backlog.
+    Partial { replid: String, missed: Vec<u8> },
+}
+
+struct ReplicationState {
+    /// Identifies the history of writes this server's offsets refer to.
+    replid: String,
+    /// The id of the history this server followed before its last change of
+    /// master, still valid for offsets up to `second_replid_offset`.
+    replid2: String,
+    second_replid_offset: i64,
+    /// Bytes of replication stream produced (or, on a replica, received).
+    offset: u64,
+    /// The last `repl-backlog-size` bytes of the stream, from which replicas
+    /// that lost their link can catch up without a full resync.
+    backlog: VecDeque<u8>,
+    /// Set while this server is a replica.
+    master: Option<MasterLink>,
+    replicas: Vec<ReplicaLink>,
+    next_replica_id: u64,
+}
+
+impl ReplicationState {
+    /// Switches to the history `replid`, keeping the current one as the
+    /// secondary id so that replicas which followed it can still resume.
+    fn shift_replid(&mut self, replid: String) {
+        self.replid2 = std::mem::replace(&mut self.replid, replid);
+        self.second_replid_offset = self.offset as i64 + 1;
+    }
+}
+
+/// Replication state shared by every connection.
+#[derive(Clone)]
+pub struct Replication {
+    state: Arc<Mutex<ReplicationState>>,
+    config: Config,
+}
+
+impl Replication {
+    pub fn new(config: Config) -> Replication {
+        Replication {
+            state: Arc::new(Mutex::new(ReplicationState {
+                replid: random_id(),
+                replid2: "0".repeat(40),
+                second_replid_offset: -1,
+                offset: 0,
+                backlog: VecDeque::new(),
+                master: None,
+                replicas: Vec::new(),
+                next_replica_id: 0,
+            })),
+            config,
+        }
+    }
+
+    /// Sends a write made on this server, already encoded as a command, to
+    /// every replica. A replica instead passes on its master's stream as
+    /// received, so writes applied from it are not sent again.
+    pub fn propagate(&self, data: Bytes) {
+        if self.state.lock().unwrap().master.is_none() {
+            self.feed(data);
+        }
+    }
+
+    /// Appends `data` to the replication stream.
+    pub fn feed(&self, data: Bytes) {
+        let mut lock = self.state.lock().unwrap();
+        lock.offset += data.len() as u64;
+
+        let size = self.config.repl_backlog_size();
+        lock.backlog.extend(&data[..]);
+        let excess = lock.backlog.len().saturating_sub(size);
+        lock.backlog.drain(..excess);
+
+        // Replicas whose connection has gone away are dropped here
+        lock.replicas
+            .retain(|replica| replica.stream.send(data.clone()).is_ok());
+    }
+
+    /// Registers a replica that asked to continue history `replid` from
+    /// `psync_offset`, the offset of the first byte it is missing. Returns
+    /// its id, the stream of writes made from now on, and how to bring it up
+    /// to date until then.
+    fn add_replica(
+        &self,
+        addr: SocketAddr,
+        replid: &str,
+        psync_offset: i64,
+    ) -> (u64, mpsc::UnboundedReceiver<Bytes>, Resync) {
+        let mut lock = self.state.lock().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let id = lock.next_replica_id;
+        lock.next_replica_id += 1;
+        lock.replicas.push(ReplicaLink {
+            id,
+            stream: tx,
+            addr,
+            online: false,
+            ack_offset: 0,
+            last_ack: Instant::now(),
+            aof_ack_offset: None,
+        });
+
+        let known_history = replid == lock.replid
+            || (replid == lock.replid2 && psync_offset <= lock.second_replid_offset);
+        // Offsets in PSYNC count from 1, those kept here from 0
+        let backlog_start = lock.offset - lock.backlog.len() as u64;
+        let resync = match u64::try_from(psync_offset - 1) {
+            Ok(from) if known_history && (backlog_start..=lock.offset).contains(&from) => {
+                Resync::Partial {
+                    replid: lock.replid.clone(),
+                    missed: lock
+                        .backlog
+                        .range((from - backlog_start) as usize..)
+                        .copied()
+                        .collect(),
+                }
+            }
+            _ => Resync::Full {
+                replid: lock.replid.clone(),
+                offset: lock.offset,
+            },
+        };
+        (id, rx, resync)
+    }
+
+    /// Records the offsets replica `id` reported with REPLCONF ACK.
+    fn record_ack(&self, id: u64, offset: u64, aof_offset: Option<u64>) {
+        let mut lock = self.state.lock().unwrap();
+        if let Some(replica) = lock.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.ack_offset = offset;
+            replica.aof_ack_offset = aof_offset;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    /// Asks every replica to report its offsets.
+    fn request_acks(&self) {
+        self.feed(Bytes::from(
+            "*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n",
+        ));
+    }
+
+    /// How many replicas have applied, and how many have also flushed to
+    /// disk, the stream up to `offset`. Replicas still loading the snapshot
+    /// count for neither.
+    fn acked(&self, offset: u64) -> (usize, usize) {
+        let lock = self.state.lock().unwrap();
+        let online = || lock.replicas.iter().filter(|replica| replica.online);
+        let applied = online()
+            .filter(|replica| replica.ack_offset >= offset)
+            .count();
+        let synced = online()
+            .filter(|replica| {
+                replica
+                    .aof_ack_offset
+                    .is_some_and(|synced| synced >= offset)
+            })
+            .count();
+        (applied, synced)
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.state.lock().unwrap().master.is_some()
+    }
+
+    /// Whether this is a replica that has lost, or has yet to establish,
+    /// its link to the master.
+    pub fn is_stale(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .master
+            .as_ref()
+            .is_some_and(|master| master.state != LinkState::Connected)
+    }
+
+    fn set_online(&self, id: u64) {
+        let mut lock = self.state.lock().unwrap();
+        if let Some(replica) = lock.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.online = true;
+        }
+    }
+
+    fn set_link_state(&self, state: LinkState) {
+        let mut lock = self.state.lock().unwrap();
+        if let Some(master) = &mut lock.master {
+            let now = Instant::now();
+            if master.state == LinkState::Connected && state != LinkState::Connected {
+                master.down_since = now;
+            }
+            master.last_io = now;
+            master.state = state;
+        }
+    }
+
+    /// Records that data arrived from the master.
+    fn touch_link(&self) {
+        if let Some(master) = &mut self.state.lock().unwrap().master {
+            master.last_io = Instant::now();
+        }
+    }
+
+    fn remove_replica(&self, id: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .replicas
+            .retain(|replica| replica.id != id);
+    }
+}
+
+/// Makes the server a replica of `host:port`, replacing any previous
+/// master. Returns false when it already replicates that master.
+pub fn follow(db: &Db, host: String, port: u16) -> bool {
+    let mut lock = db.replication().state.lock().unwrap();
+    if let Some(master) = &lock.master {
+        if master.host == host && master.port == port {
+            return false;
+        }
+        master.task.abort();
+    }
+
+    // Replicas of this server must resync with the new history
+    lock.replicas.clear();
+    println!("Connecting to MASTER {}:{}", host, port);
+    let task = tokio::spawn(run_replica(db.clone(), host.clone(), port));
+    let now = Instant::now();
+    lock.master = Some(MasterLink {
+        host,
+        port,
+        task,
+        state: LinkState::Connecting,
+        last_io: now,
+        down_since: now,
+    });
+    true
+}
+
+/// Stops replicating and turns the server into a master, keeping the data
+/// it has. The old replication id is kept as the secondary one, so the
+/// other replicas of the former master can resume from this server.
+pub fn promote(db: &Db) {
+    let mut lock = db.replication().state.lock().unwrap();
+    if let Some(master) = lock.master.take() {
+        master.task.abort();
+        lock.shift_replid(random_id());
+        // Replicas of this server must learn the new id
+        lock.replicas.clear();
+        println!("MASTER MODE enabled");
+    }
+}
+
+/// Keeps a replica synchronized with `host:port`, reconnecting whenever
+/// the link breaks.
+async fn run_replica(db: Db, host: String, port: u16) {
+    loop {
+        match sync_with_master(&db, &host, port).await {
+            Ok(()) => eprintln!("Connection with master lost"),
+            Err(e) => eprintln!("Error condition on socket for SYNC: {}", e),
+        }
+        db.replication().set_link_state(LinkState::Connecting);
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn sync_with_master(db: &Db, host: &str, port: u16) -> Result<(), ReplicationError> {
+    let mut link = MasterConnection {
+        stream: TcpStream::connect((host, port)).await?,
+        buf: BytesMut::with_capacity(4096),
+    };
+
+    link.request(&["PING"]).await?;
+    let listening_port = db.config().port().to_string();
+    link.request(&["REPLCONF", "listening-port", &listening_port])
+        .await?;
+    link.request(&["REPLCONF", "capa", "eof", "capa", "psync2"])
+        .await?;
+
+    // Ask to continue from where this server's own history ends, which the
+    // master accepts when that history is, or was, also its own
+    let (our_replid, psync_offset) = {
+        let lock = db.replication().state.lock().unwrap();
+        (lock.replid.clone(), (lock.offset + 1).to_string())
+    };
+    let reply = link.request(&["PSYNC", &our_replid, &psync_offset]).await?;
+    let RespValue::SimpleString(reply) = reply else {
+        return Err(ReplicationError::UnexpectedReply(format!("{:?}", reply)));
+    };
+    let mut parts = reply.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("CONTINUE"), replid, None) => {
+            println!("Successful partial resynchronization with master.");
+            let mut lock = db.replication().state.lock().unwrap();
+            if let Some(replid) = replid
+                && replid != lock.replid
+            {
+                println!("Master replication ID changed to {}", replid);
+                lock.shift_replid(replid.to_string());
+                lock.replicas.clear();
+            }
+        }
+        (Some("FULLRESYNC"), Some(replid), Some(offset)) => {
+            let offset = offset
+                .parse()
+                .map_err(|_| ReplicationError::UnexpectedReply(reply.clone()))?;
+            println!("Full resync from master: {}:{}", replid, offset);
+
+            db.replication().set_link_state(LinkState::Sync);
+            let snapshot = link.read_snapshot().await?;
+            db.load(rdb::decode(&snapshot, db.config().proto_max_bulk_len())?)?;
+            let mut lock = db.replication().state.lock().unwrap();
+            lock.replid = replid.to_string();
+            lock.replid2 = "0".repeat(40);
+            lock.second_replid_offset = -1;
+            lock.offset = offset;
+            // Neither the old backlog nor the old replicas match the new
+            // history
+            lock.backlog.clear();
+            lock.replicas.clear();
+            println!("MASTER <-> REPLICA sync: Finished with success");
+        }
+        _ => return Err(ReplicationError::UnexpectedReply(reply)),
+    }
+
+    db.replication().set_link_state(LinkState::Connected);
+    link.apply_stream(db).await
+}
+
+/// The replica's end of the connection to its master.
+struct MasterConnection {
+    stream: TcpStream,
+    buf: BytesMut,
+}
+
+impl MasterConnection {
+    /// Sends a handshake command and waits for its reply, failing on an
+    /// error reply.
+    async fn request(&mut self, args: &[&str]) -> Result<RespValue, ReplicationError> {
+        self.send(args).await?;
+        match self.next_frame().await? {
+            RespValue::SimpleError(e) => Err(ReplicationError::UnexpectedReply(e)),
+            reply => Ok(reply),
+        }
+    }
+
+    async fn send(&mut self, args: &[&str]) -> Result<(), ReplicationError> {
+        let mut out = BytesMut::new();
+        RespValue::Array(
+            args.iter()
+                .map(|arg| RespValue::BulkString(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        )
+        .write_to(&mut out, Protocol::Resp2);
+        self.stream.write_all(&out).await?;
+        Ok(())
+    }
+
+    async fn next_frame(&mut self) -> Result<RespValue, ReplicationError> {
+        loop {
+            match self.take_frame()? {
+                // Newlines sent as keepalives parse as empty commands
+                Some((RespValue::Array(items), _)) if items.is_empty() => continue,
+                Some((frame, _)) => return Ok(frame),
+                None => self.fill().await?,
+            }
+        }
+    }
+
+    fn take_frame(&mut self) -> Result<Option<(RespValue, Bytes)>, ReplicationError> {
+        Ok(take_frame(&mut self.buf)?)
+    }
+
+    async fn fill(&mut self) -> Result<(), ReplicationError> {
+        if self.stream.read_buf(&mut self.buf).await? == 0 {
+            return Err(ReplicationError::Closed);
+        }
+        Ok(())
+    }
+
+    /// Reads the snapshot, sent as `$<len>\r\n` and the file itself without
+    /// a trailing CRLF. A diskless master that doesn't know the length up
+    /// front sends `$EOF:<mark>\r\n` instead, and the 40 byte mark again
+    /// after the file.
+    async fn read_snapshot(&mut self) -> Result<Bytes, ReplicationError> {
+        let header = loop {
+            // The master may send newlines while it prepares the snapshot
+            while self.buf.first() == Some(&b'\n') {
+                self.buf.advance(1);
+            }
+            if let Some(end) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = self.buf.split_to(end + 2);
+                break String::from_utf8_lossy(&line[..end]).into_owned();
+            }
+            self.fill().await?;
+        };
+
+        if let Some(mark) = header.strip_prefix("$EOF:") {
+            if mark.len() != EOF_MARK_LEN {
+                return Err(ReplicationError::UnexpectedReply(header));
+            }
+            let mark = mark.as_bytes();
+            let mut searched = 0;
+            loop {
+                if let Some(end) = self.buf[searched..]
+                    .windows(EOF_MARK_LEN)
+                    .position(|w| w == mark)
+                {
+                    let snapshot = self.buf.split_to(searched + end).freeze();
+                    self.buf.advance(EOF_MARK_LEN);
+                    return Ok(snapshot);
+                }
+                // The mark may straddle what has arrived so far
+                searched = self.buf.len().saturating_sub(EOF_MARK_LEN - 1);
+                self.fill().await?;
+            }
+        }
+
+        let len = header
+            .strip_prefix('$')
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| ReplicationError::UnexpectedReply(header.clone()))?;
+        while self.buf.len() < len {
+            self.fill().await?;
+        }
+        Ok(self.buf.split_to(len).freeze())
+    }
+
+    /// Applies the writes the master propagates until the link breaks,
+    /// reporting the offset reached once a second and whenever the master
+    /// asks for it.
+    async fn apply_stream(&mut self, db: &Db) -> Result<(), ReplicationError> {
+        let mut ack_timer = tokio::time::interval(ACK_PERIOD);
+        loop {
+            while let Some((frame, raw)) = self.take_frame()? {
+                if is_getack(&frame) {
+                    self.send_ack(db).await?;
+                    // The request itself counts towards the offset only
+                    // once answered
+                    db.replication().feed(raw);
+                    continue;
+                }
+                match Command::from_resp(frame) {
+                    Ok(cmd) => cmd.execute_replicated(db, raw),
+                    Err(e) => {
+                        eprintln!("Error applying a command from the master: {}", e);
+                        db.replication().feed(raw);
+                    }
+                }
+            }
+
+            tokio::select! {
+                read = self.stream.read_buf(&mut self.buf) => {
+                    if read? == 0 {
+                        return Err(ReplicationError::Closed);
+                    }
+                    db.replication().touch_link();
+                }
+                _ = ack_timer.tick() => self.send_ack(db).await?,
+            }
+        }
+    }
+
+    /// Reports the offset applied and, when logging, the offset flushed to
+    /// the append-only file.
+    async fn send_ack(&mut self, db: &Db) -> Result<(), ReplicationError> {
+        let offset = db.replication().offset().to_string();
+        match aof::fsynced_offset(db) {
+            Some(synced) => {
+                let synced = synced.to_string();
+                self.send(&["REPLCONF", "ACK", &offset, "FACK", &synced])
+                    .await
+            }
+            None => self.send(&["REPLCONF", "ACK", &offset]).await,
+        }
+    }
+}
+
+/// Takes the next complete frame out of `buf`, along with the bytes it was
+/// read from.
+fn take_frame(buf: &mut BytesMut) -> Result<Option<(RespValue, Bytes)>, ProtocolError> {
+    let mut cursor = Cursor::new(&buf[..]);
+    match parse_resp(&mut cursor) {
+        Ok(frame) => {
+            let len = cursor.position() as usize;
+            Ok(Some((frame, buf.split_to(len).freeze())))
+        }
+        Err(ProtocolError::Incomplete) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The offsets in a `REPLCONF ACK <offset> [FACK <aof offset>]` report.
+fn parse_ack(frame: &RespValue) -> Option<(u64, Option<u64>)> {
+    let RespValue::Array(args) = frame else {
+        return None;
+    };
+    let args: Vec<&str> = args
+        .iter()
+        .map(|arg| match arg {
+            RespValue::BulkString(arg) => std::str::from_utf8(arg).ok(),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+
+    match args.as_slice() {
+        [name, ack, offset, rest @ ..]
+            if name.eq_ignore_ascii_case("replconf") && ack.eq_ignore_ascii_case("ack") =>
+        {
+            let aof_offset = match rest {
+                [] => None,
+                [fack, synced] if fack.eq_ignore_ascii_case("fack") => Some(synced.parse().ok()?),
+                _ => return None,
+            };
+            Some((offset.parse().ok()?, aof_offset))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `frame` is the master asking for an acknowledgement, which is
+/// answered on the link rather than applied as a command.
+fn is_getack(frame: &RespValue) -> bool {
+    let RespValue::Array(args) = frame else {
+        return false;
+    };
+    matches!(
+        args.as_slice(),
+        [RespValue::BulkString(name), RespValue::BulkString(option), ..]
+            if name.eq_ignore_ascii_case(b"replconf") && option.eq_ignore_ascii_case(b"getack")
+    )
+}
+
+/// Serves a replica that sent `PSYNC replid psync_offset` on `socket`:
+/// brings it up to date, either from the backlog or with a snapshot, and
+/// then streams it every write made on this server until it disconnects.
+pub async fn serve_replica(
+    mut socket: TcpStream,
+    db: Db,
+    replid: String,
+    psync_offset: i64,
+    listening_port: Option<u16>,
+) {
+    let Ok(peer) = socket.peer_addr() else {
+        return;
+    };
+    // Identified by the port it announced with REPLCONF, if any
+    let addr = SocketAddr::new(peer.ip(), listening_port.unwrap_or(peer.port()));
+    let name = addr.to_string();
+    println!("Replica {} asks for synchronization", name);
+
+    // The snapshot and the start of the write stream must line up exactly
+    let (id, mut writes, resync, entries) = {
+        let _guard = db.write_guard();
+        let (id, writes, resync) = db.replication().add_replica(addr, &replid, psync_offset);
+        let entries = match resync {
+            Resync::Full { .. } => db.snapshot().0,
+            Resync::Partial { .. } => Snapshot::default(),
+        };
+        (id, writes, resync, entries)
+    };
+
+    let out = match resync {
+        Resync::Partial { replid, missed } => {
+            println!(
+                "Partial resynchronization request from {} accepted, sending {} bytes of backlog",
+                name,
+                missed.len()
+            );
+            let mut out = format!("+CONTINUE {}\r\n", replid).into_bytes();
+            out.extend_from_slice(&missed);
+            out
+        }
+        Resync::Full { replid, offset } => {
+            let snapshot =
+                tokio::task::spawn_blocking(move || rdb::encode(&entries.into_entries()))
+                    .await
+                    .unwrap();
+            let mut out = format!(
+                "+FULLRESYNC {} {}\r\n${}\r\n",
+                replid,
+                offset,
+                snapshot.len()
+            )
+            .into_bytes();
+            out.extend_from_slice(&snapshot);
+            out
+        }
+    };
+
+    if socket.write_all(&out).await.is_ok() {
+        println!("Synchronization with replica {} succeeded", name);
+        db.replication().set_online(id);
+        let mut input = BytesMut::with_capacity(1024);
+        loop {
+            tokio::select! {
+                write = writes.recv() => match write {
+                    Some(data) => {
+                        if socket.write_all(&data).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+                read = socket.read_buf(&mut input) => match read {
+                    Ok(0) | Err(_) => break,
+                    // Replicas only report their offsets, which need no reply
+                    Ok(_) => loop {
+                        match take_frame(&mut input) {
+                            Ok(Some((frame, _))) => {
+                                if let Some((offset, aof_offset)) = parse_ack(&frame) {
+                                    db.replication().record_ack(id, offset, aof_offset);
+                                }
+                            }
+                            Ok(None) => break,
+                            Err(_) => {
+                                input.clear();
+                                break;
+                            }
+                        }
+                    },
+                },
+            }
+        }
+    }
+
+    println!("Connection with replica {} lost", name);
+    db.replication().remove_replica(id);
+}
+
+/// The ROLE reply: this server's role and the state of its links.
+pub fn role(db: &Db) -> RespValue {
+    let bulk = |s: String| RespValue::BulkString(Bytes::from(s));
+    let lock = db.replication().state.lock().unwrap();
+
+    match &lock.master {
+        Some(master) => {
+            let offset = match master.state {
+                LinkState::Connected => lock.offset as i64,
+                _ => -1,
+            };
+            RespValue::Array(vec![
+                bulk("slave".to_string()),
+                bulk(master.host.clone()),
+                RespValue::Integer(master.port as i64),
+                bulk(master.state.name().to_string()),
+                RespValue::Integer(offset),
+            ])
+        }
+        None => RespValue::Array(vec![
+            bulk("master".to_string()),
+            RespValue::Integer(lock.offset as i64),
+            RespValue::Array(
+                lock.replicas
+                    .iter()
+                    .filter(|replica| replica.online)
+                    .map(|replica| {
+                        RespValue::Array(vec![
+                            bulk(replica.addr.ip().to_string()),
+                            bulk(replica.addr.port().to_string()),
+                            bulk(replica.ack_offset.to_string()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ]),
+    }
+}
+
+/// The replication section of INFO.
+pub fn info(db: &Db) -> String {
+    let lock = db.replication().state.lock().unwrap();
+    let mut fields = Vec::new();
+
+    match &lock.master {
+        Some(master) => {
+            let connected = master.state == LinkState::Connected;
+            fields.extend([
+                "role:slave".to_string(),
+                format!("master_host:{}", master.host),
+                format!("master_port:{}", master.port),
+                format!(
+                    "master_link_status:{}",
+                    if connected { "up" } else { "down" }
+                ),
+                format!(
+                    "master_last_io_seconds_ago:{}",
+                    if connected {
+                        master.last_io.elapsed().as_secs() as i64
+                    } else {
+                        -1
+                    }
+                ),
+                format!(
+                    "master_sync_in_progress:{}",
+                    (master.state == LinkState::Sync) as u8
+                ),
+                format!("slave_read_repl_offset:{}", lock.offset),
+                format!("slave_repl_offset:{}", lock.offset),
+            ]);
+            if !connected {
+                fields.push(format!(
+                    "master_link_down_since_seconds:{}",
+                    master.down_since.elapsed().as_secs()
+                ));
+            }
+            fields.push(format!(
+                "slave_read_only:{}",
+                db.config().replica_read_only() as u8
+            ));
+        }
+        None => fields.push("role:master".to_string()),
+    }
+
+    fields.push(format!("connected_slaves:{}", lock.replicas.len()));
+    for (i, replica) in lock.replicas.iter().enumerate() {
+        fields.push(format!(
+            "slave{}:ip={},port={},state={},offset={},lag={}",
+            i,
+            replica.addr.ip(),
+            replica.addr.port(),
+            if replica.online {
+                "online"
+            } else {
+                "wait_bgsave"
+            },
+            replica.ack_offset,
+            replica.last_ack.elapsed().as_secs()
+        ));
+    }
+
+    let backlog_len = lock.backlog.len() as u64;
+    fields.extend([
+        format!("master_replid:{}", lock.replid),
+        format!("master_replid2:{}", lock.replid2),
+        format!("master_repl_offset:{}", lock.offset),
+        format!("second_repl_offset:{}", lock.second_replid_offset),
+        "repl_backlog_active:1".to_string(),
+        format!("repl_backlog_size:{}", db.config().repl_backlog_size()),
+        format!(
+            "repl_backlog_first_byte_offset:{}",
+            lock.offset - backlog_len + 1
+        ),
+        format!("repl_backlog_histlen:{}", backlog_len),
+    ]);
+    fields
+        .iter()
+        .map(|field| format!("{}\r\n", field))
+        .collect()
+}
+
+/// Blocks the calling connection until `numreplicas` replicas have
+/// applied every write made so far, or until `timeout` expires (zero
+/// meaning never). Returns how many replicas did.
+pub async fn wait(db: &Db, numreplicas: i64, timeout: Duration) -> Result<RespValue, CommandError> {
+    if db.replication().is_replica() {
+        return Err(CommandError::Err(
+            "WAIT cannot be used with replica instances. Please also note that since Redis 4.0 if a replica is configured to be writable (which is not the default) writes to replicas are just local and are not propagated.".to_string(),
+        ));
+    }
+
+    let target = db.replication().offset();
+    poll_acks(db, timeout, || {
+        db.replication().acked(target).0 as i64 >= numreplicas
+    })
+    .await;
+    Ok(RespValue::Integer(db.replication().acked(target).0 as i64))
+}
+
+/// Like [`wait`], but waits until every write made so far is flushed to the
+/// append-only file locally (when `numlocal` is 1) and on `numreplicas`
+/// replicas. Returns both counts.
+pub async fn wait_aof(
+    db: &Db,
+    numlocal: i64,
+    numreplicas: i64,
+    timeout: Duration,
+) -> Result<RespValue, CommandError> {
+    if db.replication().is_replica() {
+        return Err(CommandError::Err(
+            "WAITAOF cannot be used with replica instances. Please also note that writes to replicas are just local and are not propagated.".to_string(),
+        ));
+    }
+    if numlocal > 0 && !db.config().appendonly() {
+        return Err(CommandError::Err(
+            "WAITAOF cannot be used when numlocal is set but appendonly is disabled.".to_string(),
+        ));
+    }
+
+    let target = db.replication().offset();
+    let counts = || {
+        let local = aof::fsynced_offset(db).is_some_and(|synced| synced >= target);
+        (local as i64, db.replication().acked(target).1 as i64)
+    };
+    poll_acks(db, timeout, || {
+        let (local, replicas) = counts();
+        local >= numlocal && replicas >= numreplicas
+    })
+    .await;
+
+    let (local, replicas) = counts();
+    Ok(RespValue::Array(vec![
+        RespValue::Integer(local),
+        RespValue::Integer(replicas),
+    ]))
+}
+
+/// Waits until `done` holds or `timeout` expires, asking the replicas for
+/// their offsets unless it holds straight away.
+async fn poll_acks(db: &Db, timeout: Duration, done: impl Fn() -> bool) {
+    if done() {
+        return;
+    }
+    db.replication().request_acks();
+
+    let deadline = Instant::now() + timeout;
+    while !done() && (timeout.is_zero() || Instant::now() < deadline) {
+        tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+    }
+}
+
+/// A random 40 character hex identifier, as used for replication ids.
+pub fn random_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    (0..3)
+        .map(|i| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(nanos + i);
+            format!("{:016x}", hasher.finish())
+        })
+        .collect::<String>()[..40]
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const PING: &str = "*1\r\n$4\r\nPING\r\n";
+    const REPLICA: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 6380);
+
+    #[test]
+    fn test_feed_reaches_replicas_and_advances_offset() {
+        let replication = Replication::new(Config::new());
+        let (id, mut writes, resync) = replication.add_replica(REPLICA, "?", -1);
+        let Resync::Full { replid, offset } = resync else {
+            panic!("expected a full resync");
+        };
+        assert_eq!(replid.len(), 40);
+        assert_eq!(offset, 0);
+
+        replication.feed(Bytes::from(PING));
+        assert_eq!(writes.try_recv().unwrap(), PING);
+        assert_eq!(replication.state.lock().unwrap().offset, 14);
+
+        replication.remove_replica(id);
+        replication.feed(Bytes::from(PING));
+        assert!(writes.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_backlog_is_bounded() {
+        let config = Config::new();
+        config.set("repl-backlog-size", "16kb").unwrap();
+        let replication = Replication::new(config);
+        for _ in 0..2000 {
+            replication.feed(Bytes::from(PING));
+        }
+
+        let lock = replication.state.lock().unwrap();
+        assert_eq!(lock.offset, 28000);
+        assert_eq!(lock.backlog.len(), 16 * 1024);
+        assert!(
+            lock.backlog.iter().copied().eq(PING
+                .bytes()
+                .cycle()
+                .skip((28000 - 16 * 1024) % 14)
+                .take(16 * 1024))
+        );
+    }
+
+    #[test]
+    fn test_partial_resync_from_backlog() {
+        let replication = Replication::new(Config::new());
+        replication.feed(Bytes::from(PING));
+        replication.feed(Bytes::from("*1\r\n$4\r\nINFO\r\n"));
+        let replid = replication.state.lock().unwrap().replid.clone();
+
+        let (_, _, resync) = replication.add_replica(REPLICA, &replid, 15);
+        let Resync::Partial { missed, .. } = resync else {
+            panic!("expected a partial resync");
+        };
+        assert_eq!(missed, b"*1\r\n$4\r\nINFO\r\n");
+
+        // Up to date, nothing to resend
+        let (_, _, resync) = replication.add_replica(REPLICA, &replid, 29);
+        assert!(matches!(resync, Resync::Partial { missed, .. } if missed.is_empty()));
+
+        // Ahead of this server, or of another history
+        let (_, _, resync) = replication.add_replica(REPLICA, &replid, 30);
+        assert!(matches!(resync, Resync::Full { .. }));
+        let (_, _, resync) = replication.add_replica(REPLICA, &random_id(), 15);
+        assert!(matches!(resync, Resync::Full { .. }));
+
+        // After a failover the old id stays valid up to the switch
+        replication.state.lock().unwrap().shift_replid(random_id());
+        replication.feed(Bytes::from(PING));
+        let (_, _, resync) = replication.add_replica(REPLICA, &replid, 29);
+        let Resync::Partial {
+            replid: new_replid,
+            missed,
+        } = resync
+        else {
+            panic!("expected a partial resync");
+        };
+        assert_ne!(new_replid, replid);
+        assert_eq!(missed, PING.as_bytes());
+        let (_, _, resync) = replication.add_replica(REPLICA, &replid, 43);
+        assert!(matches!(resync, Resync::Full { .. }));
+    }
+
+    #[test]
+    fn test_acks_count_towards_waits() {
+        let replication = Replication::new(Config::new());
+        let (first, _writes, _) = replication.add_replica(REPLICA, "?", -1);
+        let (second, _other, _) = replication.add_replica(REPLICA, "?", -1);
+        // Replicas in the middle of a full sync have nothing to acknowledge
+        assert_eq!(replication.acked(0), (0, 0));
+        replication.set_online(first);
+        replication.set_online(second);
+        assert_eq!(replication.acked(0), (2, 0));
+        replication.feed(Bytes::from(PING));
+        assert_eq!(replication.acked(14), (0, 0));
+
+        let ack = |args: &[&str]| {
+            parse_ack(&RespValue::Array(
+                args.iter()
+                    .map(|arg| RespValue::BulkString(Bytes::copy_from_slice(arg.as_bytes())))
+                    .collect(),
+            ))
+        };
+        assert_eq!(ack(&["REPLCONF", "ACK", "14"]), Some((14, None)));
+        assert_eq!(
+            ack(&["replconf", "ack", "14", "fack", "0"]),
+            Some((14, Some(0)))
+        );
+        assert_eq!(ack(&["REPLCONF", "ACK", "x"]), None);
+        assert_eq!(ack(&["REPLCONF", "GETACK", "*"]), None);
+
+        replication.record_ack(first, 14, None);
+        replication.record_ack(second, 14, Some(14));
+        assert_eq!(replication.acked(14), (2, 1));
+        assert_eq!(replication.acked(15), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_read_snapshot_until_eof_mark() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mark = "x".repeat(EOF_MARK_LEN);
+        let master = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            // The mark arrives split across writes
+            for chunk in ["\n$EOF:", &mark, "\r\nREDIS", "0009", &mark[..10]] {
+                socket.write_all(chunk.as_bytes()).await.unwrap();
+                socket.flush().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            socket.write_all(&mark.as_bytes()[10..]).await.unwrap();
+            socket.write_all(PING.as_bytes()).await.unwrap();
+        });
+
+        let mut link = MasterConnection {
+            stream: TcpStream::connect(addr).await.unwrap(),
+            buf: BytesMut::new(),
+        };
+        assert_eq!(&link.read_snapshot().await.unwrap()[..], b"REDIS0009");
+        master.await.unwrap();
+        while link.buf.len() < PING.len() {
+            link.fill().await.unwrap();
+        }
+        assert_eq!(&link.buf[..], PING.as_bytes());
+    }
+}
@@ -1,46 +1,144 @@
+use bytes::{BufMut, Bytes, BytesMut};
 use std::io::{Cursor, Read};
 
+use crate::error::ProtocolError;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum RespValue {
-    SimpleString(String),  // +OK\r\n
-    SimpleError(String),   // -Error message\r\n
-    Integer(i64),          // :[<+|->]<value>\r\n
-    BulkString(String),    // $<length>\r\n<data>\r\n
-    Array(Vec<RespValue>), // *<number-of-elements>\r\n<element-1>...<element-n>
-    Null,
+    SimpleString(String),             // +OK\r\n
+    SimpleError(String),              // -Error message\r\n
+    Integer(i64),                     // :[<+|->]<value>\r\n
+    BulkString(Bytes),                // $<length>\r\n<data>\r\n
+    Array(Vec<RespValue>),            // *<number-of-elements>\r\n<element-1>...<element-n>
+    Null,                             // _\r\n (RESP3), $-1\r\n (RESP2)
+    Boolean(bool),                    // #<t|f>\r\n
+    Double(f64),                      // ,<floating-point-number>\r\n
+    BigNumber(String),                // ([+|-]<number>\r\n
+    Verbatim(String, String),         // =<length>\r\n<encoding>:<data>\r\n
+    Map(Vec<(RespValue, RespValue)>), // %<number-of-entries>\r\n<key-1><value-1>...
+    Set(Vec<RespValue>),              // ~<number-of-elements>\r\n<element-1>...<element-n>
+    Push(Vec<RespValue>),             // ><number-of-elements>\r\n<element-1>...<element-n>
+    // |<number-of-entries>\r\n<key-1><value-1>... followed by the reply it annotates
+    Attribute(Vec<(RespValue, RespValue)>, Box<RespValue>),
+}
+
+/// Wire protocol negotiated by a connection through HELLO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
 }
 
 const CRLF: &[u8] = b"\r\n";
 
 impl RespValue {
-    pub fn serialize(&self) -> Vec<u8> {
+    /// Appends the value encoded for a client speaking `protocol`. RESP3-only types
+    /// are downgraded to their RESP2 equivalents the same way Redis does:
+    /// maps and sets flatten to arrays, doubles and big numbers become bulk
+    /// strings, booleans become integers and attributes are dropped.
+    pub fn write_to(&self, buf: &mut BytesMut, protocol: Protocol) {
+        let resp3 = protocol == Protocol::Resp3;
         match self {
-            RespValue::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
-            RespValue::SimpleError(s) => format!("-{}\r\n", s).into_bytes(),
-            RespValue::Integer(i) => format!(":{}\r\n", i).into_bytes(),
-            RespValue::BulkString(s) => format!("${}\r\n{}\r\n", s.len(), s).into_bytes(),
-            RespValue::Null => b"$-1\r\n".to_vec(),
-            RespValue::Array(arr) => {
-                let mut buf = Vec::new();
-                buf.extend_from_slice(format!("*{}\r\n", arr.len()).as_bytes());
-                for item in arr {
-                    buf.extend_from_slice(item.serialize().as_ref());
+            RespValue::SimpleString(s) => buf.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            RespValue::SimpleError(s) => buf.extend_from_slice(format!("-{}\r\n", s).as_bytes()),
+            RespValue::Integer(i) => buf.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            RespValue::BulkString(s) => write_bulk(buf, s),
+            RespValue::Null if resp3 => buf.extend_from_slice(b"_\r\n"),
+            RespValue::Null => buf.extend_from_slice(b"$-1\r\n"),
+            RespValue::Array(arr) => write_aggregate(buf, b'*', arr, protocol),
+            RespValue::Boolean(b) if resp3 => {
+                buf.extend_from_slice(if *b { b"#t\r\n" } else { b"#f\r\n" })
+            }
+            RespValue::Boolean(b) => {
+                buf.extend_from_slice(format!(":{}\r\n", *b as i64).as_bytes())
+            }
+            RespValue::Double(d) if resp3 => {
+                buf.extend_from_slice(format!(",{}\r\n", format_double(*d)).as_bytes())
+            }
+            RespValue::Double(d) => write_bulk(buf, format_double(*d).as_bytes()),
+            RespValue::BigNumber(n) if resp3 => {
+                buf.extend_from_slice(format!("({}\r\n", n).as_bytes())
+            }
+            RespValue::BigNumber(n) => write_bulk(buf, n.as_bytes()),
+            RespValue::Verbatim(format, text) if resp3 => buf.extend_from_slice(
+                format!("={}\r\n{}:{}\r\n", text.len() + 4, format, text).as_bytes(),
+            ),
+            RespValue::Verbatim(_, text) => write_bulk(buf, text.as_bytes()),
+            RespValue::Map(entries) if resp3 => {
+                buf.extend_from_slice(format!("%{}\r\n", entries.len()).as_bytes());
+                for (key, value) in entries {
+                    key.write_to(buf, protocol);
+                    value.write_to(buf, protocol);
+                }
+            }
+            RespValue::Map(entries) => {
+                buf.extend_from_slice(format!("*{}\r\n", entries.len() * 2).as_bytes());
+                for (key, value) in entries {
+                    key.write_to(buf, protocol);
+                    value.write_to(buf, protocol);
                 }
-                buf
             }
+            RespValue::Set(items) if resp3 => write_aggregate(buf, b'~', items, protocol),
+            RespValue::Set(items) => write_aggregate(buf, b'*', items, protocol),
+            RespValue::Attribute(entries, reply) if resp3 => {
+                buf.extend_from_slice(format!("|{}\r\n", entries.len()).as_bytes());
+                for (key, value) in entries {
+                    key.write_to(buf, protocol);
+                    value.write_to(buf, protocol);
+                }
+                reply.write_to(buf, protocol);
+            }
+            RespValue::Attribute(_, reply) => reply.write_to(buf, protocol),
+            RespValue::Push(items) if resp3 => write_aggregate(buf, b'>', items, protocol),
+            RespValue::Push(items) => write_aggregate(buf, b'*', items, protocol),
         }
     }
 }
 
-pub fn parse_resp(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, String> {
+fn write_bulk(buf: &mut BytesMut, data: &[u8]) {
+    buf.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+    buf.extend_from_slice(data);
+    buf.extend_from_slice(CRLF);
+}
+
+fn write_aggregate(buf: &mut BytesMut, prefix: u8, items: &[RespValue], protocol: Protocol) {
+    buf.put_u8(prefix);
+    buf.extend_from_slice(format!("{}\r\n", items.len()).as_bytes());
+    for item in items {
+        item.write_to(buf, protocol);
+    }
+}
+
+/// Formats a double the way Redis replies with one (`inf`, `-inf`, `nan`).
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        d.to_string()
+    }
+}
+
+/// Deepest nesting of aggregates accepted by the parsers.
+pub const MAX_NESTING_DEPTH: usize = 128;
+
+pub fn parse_resp(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, ProtocolError> {
+    parse_value(cursor, 0)
+}
+
+/// Parses one value nested `depth` aggregates deep. Inline commands are only
+/// recognised at the top level, never as elements of an aggregate.
+fn parse_value(cursor: &mut Cursor<&[u8]>, depth: usize) -> Result<RespValue, ProtocolError> {
+    if depth > MAX_NESTING_DEPTH {
+        return Err(ProtocolError::NestingTooDeep);
+    }
+    let allow_inline = depth == 0;
     let mut type_byte = [0; 1];
 
-    if cursor
-        .read(&mut type_byte)
-        .map_err(|_| "Failed to read type byte")?
-        == 0
-    {
-        return Err("EOF".to_string());
+    if cursor.read(&mut type_byte).unwrap_or(0) == 0 {
+        return Err(ProtocolError::Incomplete);
     }
 
     match type_byte[0] {
@@ -48,17 +146,31 @@ pub fn parse_resp(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, String> {
         b'-' => parse_error(cursor),
         b':' => parse_integer(cursor),
         b'$' => parse_bulk_string(cursor),
-        b'*' => parse_array(cursor),
-        _ => Err(format!("Unknown RESP type: {}", type_byte[0] as char)),
+        b'*' => parse_array(cursor, depth),
+        b'_' => parse_null(cursor),
+        b'#' => parse_boolean(cursor),
+        b',' => parse_double(cursor),
+        b'(' => parse_big_number(cursor),
+        b'=' => parse_verbatim(cursor),
+        b'%' => parse_map(cursor, depth).map(RespValue::Map),
+        b'~' => parse_items(cursor, depth).map(RespValue::Set),
+        b'>' => parse_items(cursor, depth).map(RespValue::Push),
+        b'|' => parse_attribute(cursor, depth),
+        _ if allow_inline => {
+            // Not a RESP type byte, so treat the line as an inline command
+            cursor.set_position(cursor.position() - 1);
+            parse_inline(cursor)
+        }
+        _ => Err(ProtocolError::UnknownType(type_byte[0])),
     }
 }
 
-fn read_line(cursor: &mut Cursor<&[u8]>) -> Result<String, String> {
+fn read_line(cursor: &mut Cursor<&[u8]>) -> Result<String, ProtocolError> {
     let position = cursor.position() as usize;
     let inner = *cursor.get_ref();
 
     if position >= inner.len() {
-        return Err("Incomplete".to_string());
+        return Err(ProtocolError::Incomplete);
     }
 
     for i in position..inner.len() - 1 {
@@ -69,76 +181,302 @@ fn read_line(cursor: &mut Cursor<&[u8]>) -> Result<String, String> {
         }
     }
 
-    Err("Incomplete".to_string())
+    Err(ProtocolError::Incomplete)
+}
+
+/// Parses an inline command (`SET key "hello world"\r\n`) as sent by telnet
+/// or netcat sessions into the same array of bulk strings a RESP client
+/// would send. The line may end in a bare `\n`.
+fn parse_inline(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, ProtocolError> {
+    let position = cursor.position() as usize;
+    let inner = *cursor.get_ref();
+
+    let Some(newline) = inner[position..].iter().position(|&b| b == b'\n') else {
+        return Err(ProtocolError::Incomplete);
+    };
+    cursor.set_position((position + newline + 1) as u64);
+
+    let mut line = &inner[position..position + newline];
+    if let Some(stripped) = line.strip_suffix(b"\r") {
+        line = stripped;
+    }
+
+    let args = split_args(line)?;
+    Ok(RespValue::Array(
+        args.into_iter().map(RespValue::BulkString).collect(),
+    ))
+}
+
+/// Splits an inline command line into arguments following Redis'
+/// `sdssplitargs` rules: double quotes support `\n`, `\r`, `\t`, `\b`, `\a`
+/// and `\xHH` escapes, single quotes only `\'`, and a closing quote must be
+/// followed by whitespace or the end of the line.
+pub fn split_args(line: &[u8]) -> Result<Vec<Bytes>, ProtocolError> {
+    let mut args = Vec::new();
+    let mut i = 0;
+
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i >= line.len() {
+            return Ok(args);
+        }
+
+        let mut current = Vec::new();
+        let mut in_double = false;
+        let mut in_single = false;
+
+        loop {
+            if in_double {
+                let Some(&c) = line.get(i) else {
+                    return Err(ProtocolError::UnbalancedQuotes);
+                };
+                if c == b'\\' && i + 3 < line.len() && line[i + 1] == b'x' {
+                    if let (Some(hi), Some(lo)) = (hex_digit(line[i + 2]), hex_digit(line[i + 3])) {
+                        current.push(hi * 16 + lo);
+                        i += 3;
+                    } else {
+                        current.push(c);
+                    }
+                } else if c == b'\\' && i + 1 < line.len() {
+                    i += 1;
+                    current.push(match line[i] {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'b' => 0x08,
+                        b'a' => 0x07,
+                        other => other,
+                    });
+                } else if c == b'"' {
+                    if line.get(i + 1).is_some_and(|b| !b.is_ascii_whitespace()) {
+                        return Err(ProtocolError::UnbalancedQuotes);
+                    }
+                    i += 1;
+                    break;
+                } else {
+                    current.push(c);
+                }
+            } else if in_single {
+                let Some(&c) = line.get(i) else {
+                    return Err(ProtocolError::UnbalancedQuotes);
+                };
+                if c == b'\\' && line.get(i + 1) == Some(&b'\'') {
+                    i += 1;
+                    current.push(b'\'');
+                } else if c == b'\'' {
+                    if line.get(i + 1).is_some_and(|b| !b.is_ascii_whitespace()) {
+                        return Err(ProtocolError::UnbalancedQuotes);
+                    }
+                    i += 1;
+                    break;
+                } else {
+                    current.push(c);
+                }
+            } else {
+                match line.get(i) {
+                    None => break,
+                    Some(c) if c.is_ascii_whitespace() => break,
+                    Some(b'"') => in_double = true,
+                    Some(b'\'') => in_single = true,
+                    Some(&c) => current.push(c),
+                }
+            }
+            i += 1;
+        }
+
+        args.push(Bytes::from(current));
+    }
+}
+
+fn hex_digit(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
 }
 
-fn parse_simple_string(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, String> {
+fn parse_simple_string(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, ProtocolError> {
     let s = read_line(cursor)?;
     Ok(RespValue::SimpleString(s))
 }
 
-fn parse_error(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, String> {
+fn parse_error(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, ProtocolError> {
     let s = read_line(cursor)?;
     Ok(RespValue::SimpleError(s))
 }
 
-fn parse_integer(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, String> {
+fn parse_integer(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, ProtocolError> {
     let s = read_line(cursor)?;
-    let i = s.parse::<i64>().map_err(|_| "Invalid integer")?;
+    let i = s
+        .parse::<i64>()
+        .map_err(|_| ProtocolError::Invalid("integer"))?;
     Ok(RespValue::Integer(i))
 }
 
-fn parse_bulk_string(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, String> {
+fn parse_bulk_string(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, ProtocolError> {
     let len_str = read_line(cursor)?;
     let len = len_str
         .parse::<i64>()
-        .map_err(|_| "Invalid bulk string length")?;
+        .map_err(|_| ProtocolError::InvalidBulkLength)?;
 
     // Handle Null Bulk String ($-1\r\n)
     if len == -1 {
         return Ok(RespValue::Null);
     }
+    if len < 0 {
+        return Err(ProtocolError::InvalidBulkLength);
+    }
 
+    // Check the data is all there before allocating for it, so a bogus
+    // length can't trigger a huge allocation
     let len = len as usize;
+    let remaining = cursor.get_ref().len() - cursor.position() as usize;
+    if remaining < len.saturating_add(2) {
+        return Err(ProtocolError::Incomplete);
+    }
     let mut buf = vec![0; len];
 
     cursor
         .read_exact(&mut buf)
-        .map_err(|_| "Failed to read bulk string data")?;
+        .map_err(|_| ProtocolError::Incomplete)?;
 
     let mut crlf = [0; 2];
     cursor
         .read_exact(&mut crlf)
-        .map_err(|_| "Failed to read CRLF")?;
+        .map_err(|_| ProtocolError::Incomplete)?;
     if crlf != CRLF {
-        return Err("Invalid bulk string ending".to_string());
+        return Err(ProtocolError::ExpectedCrlf);
     }
 
-    let s = String::from_utf8_lossy(&buf).to_string();
-
-    Ok(RespValue::BulkString(s))
+    Ok(RespValue::BulkString(Bytes::from(buf)))
 }
 
-fn parse_array(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, String> {
+fn parse_array(cursor: &mut Cursor<&[u8]>, depth: usize) -> Result<RespValue, ProtocolError> {
     let size = read_line(cursor)?;
-    let array_len = size.parse::<i64>().map_err(|_| "Invalid array length")?;
+    let array_len = size
+        .parse::<i64>()
+        .map_err(|_| ProtocolError::InvalidMultibulkLength)?;
 
     if array_len == -1 {
         return Ok(RespValue::Null);
     }
+    if array_len < 0 {
+        return Err(ProtocolError::InvalidMultibulkLength);
+    }
 
-    let mut items = Vec::with_capacity(array_len as usize);
+    let mut items = Vec::with_capacity(capacity_for(cursor, array_len as usize));
     for _ in 0..array_len {
-        let item = parse_resp(cursor)?;
+        let item = parse_value(cursor, depth + 1)?;
         items.push(item);
     }
 
     Ok(RespValue::Array(items))
 }
 
+fn parse_null(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, ProtocolError> {
+    let s = read_line(cursor)?;
+    if !s.is_empty() {
+        return Err(ProtocolError::Invalid("null"));
+    }
+    Ok(RespValue::Null)
+}
+
+fn parse_boolean(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, ProtocolError> {
+    match read_line(cursor)?.as_str() {
+        "t" => Ok(RespValue::Boolean(true)),
+        "f" => Ok(RespValue::Boolean(false)),
+        _ => Err(ProtocolError::Invalid("boolean")),
+    }
+}
+
+fn parse_double(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, ProtocolError> {
+    let s = read_line(cursor)?;
+    let d = match s.as_str() {
+        "inf" => f64::INFINITY,
+        "-inf" => f64::NEG_INFINITY,
+        "nan" => f64::NAN,
+        _ => s
+            .parse::<f64>()
+            .map_err(|_| ProtocolError::Invalid("double"))?,
+    };
+    Ok(RespValue::Double(d))
+}
+
+fn parse_big_number(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, ProtocolError> {
+    let s = read_line(cursor)?;
+    let digits = s.strip_prefix(['+', '-']).unwrap_or(&s);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ProtocolError::Invalid("big number"));
+    }
+    Ok(RespValue::BigNumber(s))
+}
+
+fn parse_verbatim(cursor: &mut Cursor<&[u8]>) -> Result<RespValue, ProtocolError> {
+    let RespValue::BulkString(data) = parse_bulk_string(cursor)? else {
+        return Err(ProtocolError::Invalid("verbatim string"));
+    };
+    let s = String::from_utf8_lossy(&data);
+    match s.split_once(':') {
+        Some((format, text)) if format.len() == 3 => {
+            Ok(RespValue::Verbatim(format.to_string(), text.to_string()))
+        }
+        _ => Err(ProtocolError::Invalid("verbatim string")),
+    }
+}
+
+fn parse_len(cursor: &mut Cursor<&[u8]>) -> Result<usize, ProtocolError> {
+    let size = read_line(cursor)?;
+    size.parse::<usize>()
+        .map_err(|_| ProtocolError::InvalidMultibulkLength)
+}
+
+/// Bounds the capacity preallocated for `len` elements by the data left in
+/// the buffer, as every element takes at least three bytes.
+fn capacity_for(cursor: &Cursor<&[u8]>, len: usize) -> usize {
+    let remaining = cursor.get_ref().len() - cursor.position() as usize;
+    len.min(remaining / 3)
+}
+
+fn parse_items(cursor: &mut Cursor<&[u8]>, depth: usize) -> Result<Vec<RespValue>, ProtocolError> {
+    let len = parse_len(cursor)?;
+    let mut items = Vec::with_capacity(capacity_for(cursor, len));
+    for _ in 0..len {
+        items.push(parse_value(cursor, depth + 1)?);
+    }
+    Ok(items)
+}
+
+fn parse_map(
+    cursor: &mut Cursor<&[u8]>,
+    depth: usize,
+) -> Result<Vec<(RespValue, RespValue)>, ProtocolError> {
+    let len = parse_len(cursor)?;
+    let mut entries = Vec::with_capacity(capacity_for(cursor, len) / 2);
+    for _ in 0..len {
+        let key = parse_value(cursor, depth + 1)?;
+        let value = parse_value(cursor, depth + 1)?;
+        entries.push((key, value));
+    }
+    Ok(entries)
+}
+
+fn parse_attribute(cursor: &mut Cursor<&[u8]>, depth: usize) -> Result<RespValue, ProtocolError> {
+    let entries = parse_map(cursor, depth)?;
+    let reply = parse_value(cursor, depth + 1)?;
+    Ok(RespValue::Attribute(entries, Box::new(reply)))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+
+    impl RespValue {
+        fn serialize(&self, protocol: Protocol) -> Vec<u8> {
+            let mut buf = BytesMut::new();
+            self.write_to(&mut buf, protocol);
+            buf.to_vec()
+        }
+    }
+
     #[test]
     fn test_parse_array_echo_hey() {
         // "*2\r\n$4\r\nECHO\r\n$3\r\nhey\r\n"
@@ -150,8 +488,8 @@ mod test {
 
         let result = parse_resp(&mut cursor).unwrap();
         let expected = RespValue::Array(vec![
-            RespValue::BulkString("ECHO".to_string()),
-            RespValue::BulkString("hey".to_string()),
+            RespValue::BulkString(Bytes::from("ECHO")),
+            RespValue::BulkString(Bytes::from("hey")),
         ]);
 
         assert_eq!(result, expected);
@@ -177,7 +515,7 @@ mod test {
         let input = b"$5\r\nhello\r\n";
         let mut cursor = Cursor::new(&input[..]);
         let result = parse_resp(&mut cursor).unwrap();
-        assert_eq!(result, RespValue::BulkString("hello".to_string()));
+        assert_eq!(result, RespValue::BulkString(Bytes::from("hello")));
     }
 
     #[test]
@@ -201,4 +539,154 @@ mod test {
         ]);
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_parse_resp3_scalars() {
+        let input = b"*5\r\n_\r\n#t\r\n,3.5\r\n(12345678901234567890\r\n=8\r\ntxt:abcd\r\n";
+        let mut cursor = Cursor::new(&input[..]);
+        let result = parse_resp(&mut cursor).unwrap();
+
+        let expected = RespValue::Array(vec![
+            RespValue::Null,
+            RespValue::Boolean(true),
+            RespValue::Double(3.5),
+            RespValue::BigNumber("12345678901234567890".to_string()),
+            RespValue::Verbatim("txt".to_string(), "abcd".to_string()),
+        ]);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_parse_resp3_aggregates() {
+        let input = b"|1\r\n+ttl\r\n:3\r\n%1\r\n+key\r\n~1\r\n:1\r\n";
+        let mut cursor = Cursor::new(&input[..]);
+        let result = parse_resp(&mut cursor).unwrap();
+
+        let expected = RespValue::Attribute(
+            vec![(
+                RespValue::SimpleString("ttl".to_string()),
+                RespValue::Integer(3),
+            )],
+            Box::new(RespValue::Map(vec![(
+                RespValue::SimpleString("key".to_string()),
+                RespValue::Set(vec![RespValue::Integer(1)]),
+            )])),
+        );
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_serialize_round_trips_in_resp3() {
+        let value = RespValue::Push(vec![
+            RespValue::Map(vec![(
+                RespValue::BulkString(Bytes::from("a")),
+                RespValue::Double(1.5),
+            )]),
+            RespValue::Boolean(false),
+            RespValue::Null,
+        ]);
+        let bytes = value.serialize(Protocol::Resp3);
+        let mut cursor = Cursor::new(&bytes[..]);
+        assert_eq!(parse_resp(&mut cursor).unwrap(), value);
+    }
+
+    #[test]
+    fn test_serialize_downgrades_for_resp2() {
+        let value = RespValue::Map(vec![(
+            RespValue::BulkString(Bytes::from("score")),
+            RespValue::Double(1.5),
+        )]);
+        assert_eq!(
+            value.serialize(Protocol::Resp2),
+            b"*2\r\n$5\r\nscore\r\n$3\r\n1.5\r\n".to_vec()
+        );
+        assert_eq!(
+            RespValue::Boolean(true).serialize(Protocol::Resp2),
+            b":1\r\n".to_vec()
+        );
+        assert_eq!(
+            RespValue::Null.serialize(Protocol::Resp2),
+            b"$-1\r\n".to_vec()
+        );
+        assert_eq!(
+            RespValue::Null.serialize(Protocol::Resp3),
+            b"_\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_bulk_string_is_binary_safe() {
+        let payload = [0xff, 0x00, b'\r', b'\n', 0x80];
+        let mut input = b"$5\r\n".to_vec();
+        input.extend_from_slice(&payload);
+        input.extend_from_slice(b"\r\n");
+
+        let mut cursor = Cursor::new(&input[..]);
+        let result = parse_resp(&mut cursor).unwrap();
+        assert_eq!(
+            result,
+            RespValue::BulkString(Bytes::copy_from_slice(&payload))
+        );
+        assert_eq!(result.serialize(Protocol::Resp2), input);
+    }
+
+    #[test]
+    fn test_parse_inline_command() {
+        let input = b"SET key \"hello world\"\r\nPING\n";
+        let mut cursor = Cursor::new(&input[..]);
+
+        let result = parse_resp(&mut cursor).unwrap();
+        let expected = RespValue::Array(vec![
+            RespValue::BulkString(Bytes::from("SET")),
+            RespValue::BulkString(Bytes::from("key")),
+            RespValue::BulkString(Bytes::from("hello world")),
+        ]);
+        assert_eq!(result, expected);
+
+        let result = parse_resp(&mut cursor).unwrap();
+        assert_eq!(
+            result,
+            RespValue::Array(vec![RespValue::BulkString(Bytes::from("PING"))])
+        );
+    }
+
+    #[test]
+    fn test_parse_inline_incomplete() {
+        let input = b"PING";
+        let mut cursor = Cursor::new(&input[..]);
+        assert_eq!(parse_resp(&mut cursor), Err(ProtocolError::Incomplete));
+    }
+
+    #[test]
+    fn test_split_args_quoting_rules() {
+        assert_eq!(
+            split_args(b"a \"x\\x41\\n\" 'it\\'s' \"\"").unwrap(),
+            vec![
+                Bytes::from("a"),
+                Bytes::from("xA\n"),
+                Bytes::from("it's"),
+                Bytes::new(),
+            ]
+        );
+        assert!(split_args(b"\"unterminated").is_err());
+        assert!(split_args(b"\"closed\"trailing").is_err());
+        assert!(split_args(b"  ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_rejects_hostile_lengths() {
+        let mut cursor = Cursor::new(&b"$-2\r\n"[..]);
+        assert!(parse_resp(&mut cursor).is_err());
+
+        // A huge length is reported as incomplete instead of being allocated
+        let mut cursor = Cursor::new(&b"$9223372036854775807\r\nab\r\n"[..]);
+        assert_eq!(parse_resp(&mut cursor), Err(ProtocolError::Incomplete));
+
+        let mut cursor = Cursor::new(&b"*-3\r\n"[..]);
+        assert!(parse_resp(&mut cursor).is_err());
+
+        let nested = "*1\r\n".repeat(MAX_NESTING_DEPTH + 2);
+        let mut cursor = Cursor::new(nested.as_bytes());
+        assert_eq!(parse_resp(&mut cursor), Err(ProtocolError::NestingTooDeep));
+    }
 }
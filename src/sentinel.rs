@@ -0,0 +1,1701 @@
+//! Sentinel: watching masters and their replicas from the outside, and
+//! replacing a master that fails. Each monitored master is probed with PING
+//! and INFO, which also reveals its replicas. Sentinels watching the same
+//! master find each other through hello messages published on the
+//! `__sentinel__:hello` channel of the instances they watch.
+//!
+//! A master that stops answering is subjectively down (+sdown) for the
+//! sentinel that notices, and objectively down (+odown) once `quorum`
+//! sentinels agree. The sentinels then elect one of them in a new epoch to
+//! promote the best replica and point the others at it. Sentinels that
+//! weren't elected learn the new master from the winner's hello messages,
+//! which carry the epoch the configuration was decided in.
+
+use bytes::{Buf, Bytes, BytesMut};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Cursor,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    command_table::{self, CommandSpec},
+    db::{Db, unix_time_ms},
+    error::{CommandError, ProtocolError, SentinelError},
+    replication::random_id,
+    resp::{Protocol, RespValue, parse_resp},
+};
+
+/// How often the sentinel looks at the instances it monitors.
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often every instance and sentinel is pinged, in milliseconds.
+const PING_PERIOD_MS: u64 = 1000;
+
+/// How often masters and replicas are asked for INFO, in milliseconds.
+/// Replicas are asked every second while their master is down.
+const INFO_PERIOD_MS: u64 = 10000;
+
+/// How often hello messages are published, in milliseconds.
+const HELLO_PERIOD_MS: u64 = 2000;
+
+/// How often other sentinels are asked about a master that looks down, in
+/// milliseconds.
+const ASK_PERIOD_MS: u64 = 1000;
+
+/// How long an instance may answer with the wrong role before it is
+/// reconfigured, leaving time for a new configuration to spread.
+const RECONF_GRACE_MS: u64 = HELLO_PERIOD_MS * 4;
+
+/// How long a single exchange with an instance may take.
+const LINK_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// How long to wait before subscribing again to an instance's hello
+/// channel.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_millis(1000);
+
+const DEFAULT_DOWN_AFTER_MS: u64 = 30000;
+const DEFAULT_FAILOVER_TIMEOUT_MS: u64 = 180000;
+
+const HELLO_CHANNEL: &str = "__sentinel__:hello";
+
+/// The address this sentinel announces to the others.
+const ANNOUNCE_IP: &str = "127.0.0.1";
+
+/// The port a sentinel listens on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 26379;
+
+/// Commands a sentinel serves. Anything else is unknown to it.
+const COMMANDS: &[&str] = &[
+    "ping",
+    "sentinel",
+    "info",
+    "role",
+    "client",
+    "command",
+    "publish",
+    "pubsub",
+    "subscribe",
+    "unsubscribe",
+    "psubscribe",
+    "punsubscribe",
+    "hello",
+    "quit",
+];
+
+/// What this sentinel knows of a master, a replica or another sentinel.
+struct Instance {
+    ip: String,
+    port: u16,
+    run_id: Option<String>,
+    /// Unix time in ms of the last valid reply to a PING.
+    last_ok: u64,
+    /// Unix times in ms the instance was last pinged, asked for INFO and
+    /// sent a hello message at.
+    last_ping: u64,
+    last_info: u64,
+    last_hello: u64,
+    /// Whether an exchange started by a PING is still going on.
+    pending: bool,
+    /// Unix time in ms of the oldest PING not answered yet. The instance
+    /// is down once it has been waiting for too long.
+    unanswered_since: Option<u64>,
+    s_down: bool,
+    /// The role and master reported by INFO, and since when.
+    is_master: bool,
+    master_addr: Option<(String, u16)>,
+    role_since: u64,
+    link_up: bool,
+    repl_offset: u64,
+    /// For other sentinels: when they were last asked about the master,
+    /// what they answered, and who they voted for.
+    last_ask: u64,
+    master_down: bool,
+    leader: Option<String>,
+    leader_epoch: u64,
+}
+
+impl Instance {
+    fn new(ip: String, port: u16, now: u64) -> Instance {
+        Instance {
+            ip,
+            port,
+            run_id: None,
+            last_ok: now,
+            last_ping: 0,
+            last_info: 0,
+            last_hello: 0,
+            pending: false,
+            unanswered_since: None,
+            s_down: false,
+            is_master: false,
+            master_addr: None,
+            role_since: now,
+            link_up: false,
+            repl_offset: 0,
+            last_ask: 0,
+            master_down: false,
+            leader: None,
+            leader_epoch: 0,
+        }
+    }
+
+    fn addr(&self) -> String {
+        format!("{}:{}", self.ip, self.port)
+    }
+
+    fn has_addr(&self, addr: &(String, u16)) -> bool {
+        self.ip == addr.0 && self.port == addr.1
+    }
+
+    fn answered(&mut self, now: u64) {
+        self.last_ok = now;
+        self.unanswered_since = None;
+    }
+}
+
+/// A monitored master, with its replicas and the other sentinels watching
+/// it.
+struct Master {
+    name: String,
+    quorum: usize,
+    down_after: u64,
+    failover_timeout: u64,
+    /// The epoch the current master was chosen in.
+    config_epoch: u64,
+    instance: Instance,
+    /// Keyed by `ip:port`.
+    replicas: BTreeMap<String, Instance>,
+    /// Keyed by run id.
+    sentinels: BTreeMap<String, Instance>,
+    /// Unix time in ms from which a failover may start, set once the
+    /// master is objectively down.
+    o_down: Option<u64>,
+    /// The sentinel this one voted for to lead a failover, and in which
+    /// epoch.
+    leader: Option<String>,
+    leader_epoch: u64,
+    failover: Option<Failover>,
+    /// Unix time in ms of the last failover attempt, this sentinel's or one
+    /// it voted for. Attempts are spaced by twice the failover timeout.
+    failover_start: u64,
+    /// SENTINEL FAILOVER asked for one without the others' agreement.
+    forced: bool,
+    /// Instances whose hello channel this sentinel listens to.
+    subscribed: HashSet<String>,
+}
+
+impl Master {
+    fn new(name: String, ip: String, port: u16, quorum: usize, now: u64) -> Master {
+        Master {
+            name,
+            quorum,
+            down_after: DEFAULT_DOWN_AFTER_MS,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT_MS,
+            config_epoch: 0,
+            instance: Instance::new(ip, port, now),
+            replicas: BTreeMap::new(),
+            sentinels: BTreeMap::new(),
+            o_down: None,
+            leader: None,
+            leader_epoch: 0,
+            failover: None,
+            failover_start: 0,
+            forced: false,
+            subscribed: HashSet::new(),
+        }
+    }
+
+    fn instance_mut(&mut self, target: &Target) -> Option<&mut Instance> {
+        match target {
+            Target::Master => Some(&mut self.instance),
+            Target::Replica(addr) => self.replicas.get_mut(addr),
+            Target::Sentinel(id) => self.sentinels.get_mut(id),
+        }
+    }
+
+    /// The replica best placed to replace the master: one that answers and
+    /// got the furthest down the replication stream.
+    fn best_replica(&self, now: u64) -> Option<String> {
+        self.replicas
+            .iter()
+            .filter(|(_, replica)| {
+                !replica.s_down
+                    && !replica.is_master
+                    && now.saturating_sub(replica.last_ok) <= PING_PERIOD_MS * 5
+                    && now.saturating_sub(replica.last_info) <= INFO_PERIOD_MS * 3
+            })
+            .max_by(|(a, x), (b, y)| x.repl_offset.cmp(&y.repl_offset).then(b.cmp(a)))
+            .map(|(addr, _)| addr.clone())
+    }
+
+    /// Makes `addr` the master, keeping the old one as a replica for when
+    /// it comes back.
+    fn switch_to(&mut self, addr: &str, now: u64) {
+        let (ip, port) = parse_addr(addr).unwrap();
+        let mut instance = self
+            .replicas
+            .remove(addr)
+            .unwrap_or_else(|| Instance::new(ip, port, now));
+        instance.last_hello = 0;
+        let old = std::mem::replace(&mut self.instance, instance);
+        self.replicas
+            .insert(old.addr(), Instance::new(old.ip, old.port, now));
+        self.o_down = None;
+        self.failover = None;
+        self.forced = false;
+    }
+
+    fn flags(&self) -> String {
+        let mut flags = vec!["master"];
+        if self.instance.s_down {
+            flags.push("s_down");
+        }
+        if self.o_down.is_some() {
+            flags.push("o_down");
+        }
+        if self.failover.is_some() {
+            flags.push("failover_in_progress");
+        }
+        flags.join(",")
+    }
+}
+
+/// A failover this sentinel leads, or tries to.
+struct Failover {
+    epoch: u64,
+    state: FailoverState,
+    /// Unix time in ms the current state was entered at.
+    since: u64,
+    /// The replica being promoted.
+    promoted: Option<String>,
+    /// Started with SENTINEL FAILOVER, so no election is held.
+    forced: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FailoverState {
+    /// Waiting for the other sentinels' votes.
+    WaitStart,
+    SelectReplica,
+    /// REPLICAOF NO ONE was sent, waiting for the replica to report itself
+    /// as a master.
+    WaitPromotion,
+}
+
+/// The instance of a monitored master a reply came from.
+#[derive(Clone, Debug)]
+enum Target {
+    Master,
+    Replica(String),
+    Sentinel(String),
+}
+
+/// Requests sent to monitored instances and other sentinels.
+#[derive(Debug)]
+enum Request {
+    Ping,
+    Info,
+    /// Publishes a hello message.
+    Hello(String),
+    /// Asks whether the master at the address is down, and for a vote in
+    /// the epoch when a run id is given rather than `*`.
+    IsMasterDown(String, u16, u64, String),
+    ReplicaOf(Option<(String, u16)>),
+}
+
+impl Request {
+    fn args(&self) -> Vec<String> {
+        let args: Vec<&str> = match self {
+            Request::Ping => vec!["PING"],
+            Request::Info => vec!["INFO"],
+            Request::Hello(hello) => vec!["PUBLISH", HELLO_CHANNEL, hello],
+            Request::IsMasterDown(ip, port, epoch, run_id) => {
+                return vec![
+                    "SENTINEL".to_string(),
+                    "IS-MASTER-DOWN-BY-ADDR".to_string(),
+                    ip.clone(),
+                    port.to_string(),
+                    epoch.to_string(),
+                    run_id.clone(),
+                ];
+            }
+            Request::ReplicaOf(None) => vec!["REPLICAOF", "NO", "ONE"],
+            Request::ReplicaOf(Some((ip, port))) => {
+                return vec!["REPLICAOF".to_string(), ip.clone(), port.to_string()];
+            }
+        };
+        args.into_iter().map(str::to_string).collect()
+    }
+}
+
+struct SentinelState {
+    enabled: bool,
+    myid: String,
+    current_epoch: u64,
+    masters: BTreeMap<String, Master>,
+}
+
+/// The sentinel's view of the masters it monitors, shared by every
+/// connection and the background tasks.
+#[derive(Clone)]
+pub struct Sentinel {
+    state: Arc<Mutex<SentinelState>>,
+}
+
+impl Sentinel {
+    pub fn new() -> Sentinel {
+        Sentinel {
+            state: Arc::new(Mutex::new(SentinelState {
+                enabled: false,
+                myid: random_id(),
+                current_epoch: 0,
+                masters: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Turns the server into a sentinel. Only done at startup.
+    pub fn enable(&self) {
+        self.lock().enabled = true;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.lock().enabled
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SentinelState> {
+        self.state.lock().unwrap()
+    }
+}
+
+/// Starts monitoring the configured masters.
+pub fn start(db: &Db) {
+    println!("Sentinel ID is {}", db.sentinel().lock().myid);
+    let db = db.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CRON_INTERVAL);
+        loop {
+            interval.tick().await;
+            cron(&db);
+        }
+    });
+}
+
+/// Logs an event and publishes it on the channel of the same name, where
+/// clients can follow what the sentinel does.
+fn event(db: &Db, kind: &str, detail: String) {
+    println!("{} {}", kind, detail);
+    db.pubsub()
+        .publish(Bytes::copy_from_slice(kind.as_bytes()), Bytes::from(detail));
+}
+
+/// How events name an instance: `master <name> <ip> <port>`, or for the
+/// others `<kind> <id> <ip> <port> @ <master name> <ip> <port>`.
+fn describe(master: &Master, target: &Target) -> String {
+    let at = format!(
+        "@ {} {} {}",
+        master.name, master.instance.ip, master.instance.port
+    );
+    match target {
+        Target::Master => format!(
+            "master {} {} {}",
+            master.name, master.instance.ip, master.instance.port
+        ),
+        Target::Replica(addr) => {
+            let (ip, port) = parse_addr(addr).unwrap_or_default();
+            format!("slave {} {} {} {}", addr, ip, port, at)
+        }
+        Target::Sentinel(id) => match master.sentinels.get(id) {
+            Some(sentinel) => format!("sentinel {} {} {} {}", id, sentinel.ip, sentinel.port, at),
+            None => format!("sentinel {} {}", id, at),
+        },
+    }
+}
+
+fn jitter(max: u64) -> u64 {
+    u64::from_str_radix(&random_id()[..8], 16).unwrap_or(0) % max
+}
+
+fn cron(db: &Db) {
+    let mut lock = db.sentinel().lock();
+    let now = unix_time_ms() as u64;
+    let state = &mut *lock;
+    for master in state.masters.values_mut() {
+        master_cron(
+            db,
+            state.myid.as_str(),
+            &mut state.current_epoch,
+            master,
+            now,
+        );
+    }
+}
+
+fn master_cron(db: &Db, myid: &str, current_epoch: &mut u64, master: &mut Master, now: u64) {
+    probe_instances(db, myid, *current_epoch, master, now);
+    check_down(db, master, now);
+
+    // A failover is started once the master is objectively down, unless
+    // one was attempted recently
+    if master.failover.is_none()
+        && (master.forced
+            || master.o_down.is_some_and(|at| now >= at)
+                && now.saturating_sub(master.failover_start) >= master.failover_timeout * 2)
+    {
+        *current_epoch += 1;
+        let epoch = *current_epoch;
+        event(db, "+new-epoch", epoch.to_string());
+        event(db, "+try-failover", describe(master, &Target::Master));
+        master.failover = Some(Failover {
+            epoch,
+            state: FailoverState::WaitStart,
+            since: now,
+            promoted: None,
+            forced: master.forced,
+        });
+        master.forced = false;
+        master.failover_start = now;
+        vote(db, myid, current_epoch, master, myid, epoch, now);
+        // Votes are asked for right away
+        for sentinel in master.sentinels.values_mut() {
+            sentinel.last_ask = 0;
+        }
+    }
+
+    if master.failover.is_some() {
+        failover_cron(db, myid, master, now);
+    } else {
+        fix_roles(db, master, now);
+    }
+    ask_sentinels(db, myid, *current_epoch, master, now);
+}
+
+/// Pings the master, its replicas and the other sentinels, asks the
+/// instances for INFO and publishes hello messages on them when due, and
+/// listens to their hello channel.
+fn probe_instances(db: &Db, myid: &str, current_epoch: u64, master: &mut Master, now: u64) {
+    let hello = format!(
+        "{},{},{},{},{},{},{},{}",
+        ANNOUNCE_IP,
+        db.config().port(),
+        myid,
+        current_epoch,
+        master.name,
+        master.instance.ip,
+        master.instance.port,
+        master.config_epoch
+    );
+    // Replicas are followed closely while they may have to take over
+    let replica_info_period = if master.instance.s_down || master.failover.is_some() {
+        PING_PERIOD_MS
+    } else {
+        INFO_PERIOD_MS
+    };
+
+    // Instances are pinged often enough to notice in time when they're down
+    let ping_period = PING_PERIOD_MS.min(master.down_after);
+
+    let mut targets = vec![Target::Master];
+    targets.extend(master.replicas.keys().cloned().map(Target::Replica));
+    targets.extend(master.sentinels.keys().cloned().map(Target::Sentinel));
+    for target in targets {
+        let info_period = match target {
+            Target::Master => INFO_PERIOD_MS,
+            _ => replica_info_period,
+        };
+        let instance = master.instance_mut(&target).unwrap();
+        if instance.pending || now.saturating_sub(instance.last_ping) < ping_period {
+            continue;
+        }
+        instance.pending = true;
+        instance.last_ping = now;
+        instance.unanswered_since.get_or_insert(now);
+        let mut requests = vec![Request::Ping];
+        if !matches!(target, Target::Sentinel(_)) {
+            if now.saturating_sub(instance.last_info) >= info_period {
+                requests.push(Request::Info);
+            }
+            if now.saturating_sub(instance.last_hello) >= HELLO_PERIOD_MS {
+                instance.last_hello = now;
+                requests.push(Request::Hello(hello.clone()));
+            }
+        }
+        let addr = (instance.ip.clone(), instance.port);
+        send(db, &master.name, target, addr, requests, true);
+    }
+
+    let mut addrs = vec![master.instance.addr()];
+    addrs.extend(master.replicas.keys().cloned());
+    for addr in addrs {
+        if master.subscribed.insert(addr.clone()) {
+            tokio::spawn(listen_hello(db.clone(), master.name.clone(), addr));
+        }
+    }
+}
+
+/// Updates which instances are subjectively down, and whether the master
+/// is objectively down.
+fn check_down(db: &Db, master: &mut Master, now: u64) {
+    let mut targets = vec![Target::Master];
+    targets.extend(master.replicas.keys().cloned().map(Target::Replica));
+    targets.extend(master.sentinels.keys().cloned().map(Target::Sentinel));
+    for target in targets {
+        let down_after = master.down_after;
+        let instance = master.instance_mut(&target).unwrap();
+        let down = instance
+            .unanswered_since
+            .is_some_and(|since| now.saturating_sub(since) > down_after);
+        if down != instance.s_down {
+            instance.s_down = down;
+            let kind = if down { "+sdown" } else { "-sdown" };
+            event(db, kind, describe(master, &target));
+        }
+    }
+
+    if !master.instance.s_down {
+        for sentinel in master.sentinels.values_mut() {
+            sentinel.master_down = false;
+        }
+        if master.o_down.take().is_some() {
+            event(db, "-odown", describe(master, &Target::Master));
+        }
+        return;
+    }
+    let agreeing = 1 + master
+        .sentinels
+        .values()
+        .filter(|sentinel| sentinel.master_down)
+        .count();
+    if agreeing >= master.quorum && master.o_down.is_none() {
+        // Sentinels noticing at the same time start at different moments,
+        // so one of them usually gets the votes
+        master.o_down = Some(now + jitter(1000));
+        event(
+            db,
+            "+odown",
+            format!(
+                "{} #quorum {}/{}",
+                describe(master, &Target::Master),
+                agreeing,
+                master.quorum
+            ),
+        );
+    }
+}
+
+/// Asks the other sentinels whether they also see the master as down, and
+/// for their vote while this one tries to lead a failover.
+fn ask_sentinels(db: &Db, myid: &str, current_epoch: u64, master: &mut Master, now: u64) {
+    if !master.instance.s_down {
+        return;
+    }
+    let run_id = match &master.failover {
+        Some(failover) if failover.state == FailoverState::WaitStart && !failover.forced => myid,
+        _ => "*",
+    };
+    let (ip, port) = (master.instance.ip.clone(), master.instance.port);
+    let ids: Vec<String> = master.sentinels.keys().cloned().collect();
+    for id in ids {
+        let sentinel = master.sentinels.get_mut(&id).unwrap();
+        if now.saturating_sub(sentinel.last_ask) < ASK_PERIOD_MS {
+            continue;
+        }
+        sentinel.last_ask = now;
+        let addr = (sentinel.ip.clone(), sentinel.port);
+        let request = Request::IsMasterDown(ip.clone(), port, current_epoch, run_id.to_string());
+        send(
+            db,
+            &master.name,
+            Target::Sentinel(id),
+            addr,
+            vec![request],
+            false,
+        );
+    }
+}
+
+/// Records this sentinel's vote for `run_id` to lead the failover of
+/// `master` in `epoch`, unless it already voted in that epoch. Returns the
+/// vote it holds.
+fn vote(
+    db: &Db,
+    myid: &str,
+    current_epoch: &mut u64,
+    master: &mut Master,
+    run_id: &str,
+    epoch: u64,
+    now: u64,
+) -> (Option<String>, u64) {
+    if epoch > *current_epoch {
+        *current_epoch = epoch;
+        event(db, "+new-epoch", epoch.to_string());
+    }
+    if master.leader_epoch < epoch && *current_epoch <= epoch {
+        master.leader = Some(run_id.to_string());
+        master.leader_epoch = *current_epoch;
+        event(
+            db,
+            "+vote-for-leader",
+            format!("{} {}", run_id, master.leader_epoch),
+        );
+        // Leave the sentinel voted for time to do its job
+        if run_id != myid {
+            master.failover_start = now + jitter(1000);
+        }
+    }
+    (master.leader.clone(), master.leader_epoch)
+}
+
+/// Moves the failover this sentinel leads along: winning the election,
+/// promoting a replica and reconfiguring the others once it took over.
+fn failover_cron(db: &Db, myid: &str, master: &mut Master, now: u64) {
+    let failover = master.failover.as_ref().unwrap();
+    let (state, since, epoch) = (failover.state, failover.since, failover.epoch);
+    let timed_out = now.saturating_sub(since) > master.failover_timeout;
+
+    match state {
+        FailoverState::WaitStart => {
+            let elected = failover.forced || leader(myid, master, epoch) == Some(myid.to_string());
+            if elected {
+                event(db, "+elected-leader", describe(master, &Target::Master));
+                event(
+                    db,
+                    "+failover-state-select-slave",
+                    describe(master, &Target::Master),
+                );
+                let failover = master.failover.as_mut().unwrap();
+                failover.state = FailoverState::SelectReplica;
+                failover.since = now;
+            } else if timed_out {
+                event(
+                    db,
+                    "-failover-abort-not-elected",
+                    describe(master, &Target::Master),
+                );
+                master.failover = None;
+            }
+        }
+        FailoverState::SelectReplica => match master.best_replica(now) {
+            Some(addr) => {
+                let target = Target::Replica(addr.clone());
+                event(db, "+selected-slave", describe(master, &target));
+                event(
+                    db,
+                    "+failover-state-send-slaveof-noone",
+                    describe(master, &target),
+                );
+                send(
+                    db,
+                    &master.name,
+                    target.clone(),
+                    parse_addr(&addr).unwrap(),
+                    vec![Request::ReplicaOf(None)],
+                    false,
+                );
+                event(
+                    db,
+                    "+failover-state-wait-promotion",
+                    describe(master, &target),
+                );
+                let failover = master.failover.as_mut().unwrap();
+                failover.state = FailoverState::WaitPromotion;
+                failover.since = now;
+                failover.promoted = Some(addr);
+            }
+            None if timed_out => {
+                event(
+                    db,
+                    "-failover-abort-no-good-slave",
+                    describe(master, &Target::Master),
+                );
+                master.failover = None;
+            }
+            None => {}
+        },
+        FailoverState::WaitPromotion => {
+            let promoted = failover.promoted.clone().unwrap();
+            let is_master = master
+                .replicas
+                .get(&promoted)
+                .is_some_and(|replica| replica.is_master);
+            if is_master {
+                let target = Target::Replica(promoted.clone());
+                event(db, "+promoted-slave", describe(master, &target));
+                let new_master = parse_addr(&promoted).unwrap();
+                let others: Vec<String> = master
+                    .replicas
+                    .keys()
+                    .filter(|&addr| *addr != promoted)
+                    .cloned()
+                    .collect();
+                for addr in others {
+                    let target = Target::Replica(addr.clone());
+                    event(db, "+slave-reconf-sent", describe(master, &target));
+                    send(
+                        db,
+                        &master.name,
+                        target,
+                        parse_addr(&addr).unwrap(),
+                        vec![Request::ReplicaOf(Some(new_master.clone()))],
+                        false,
+                    );
+                }
+                let old = (master.instance.ip.clone(), master.instance.port);
+                master.config_epoch = epoch;
+                master.switch_to(&promoted, now);
+                event(
+                    db,
+                    "+switch-master",
+                    format!(
+                        "{} {} {} {} {}",
+                        master.name, old.0, old.1, new_master.0, new_master.1
+                    ),
+                );
+            } else if timed_out {
+                event(
+                    db,
+                    "-failover-abort-slave-timeout",
+                    describe(master, &Target::Master),
+                );
+                master.failover = None;
+            }
+        }
+    }
+}
+
+/// The sentinel voted in to lead the failover in `epoch`: the one with the
+/// most votes, if that is at least a majority of the sentinels and the
+/// quorum.
+fn leader(myid: &str, master: &Master, epoch: u64) -> Option<String> {
+    let mut votes: HashMap<&str, usize> = HashMap::new();
+    if master.leader_epoch == epoch
+        && let Some(leader) = &master.leader
+    {
+        *votes.entry(leader).or_default() += 1;
+    }
+    for sentinel in master.sentinels.values() {
+        if sentinel.leader_epoch == epoch
+            && let Some(leader) = &sentinel.leader
+        {
+            *votes.entry(leader).or_default() += 1;
+        }
+    }
+    let voters = master.sentinels.len() + 1;
+    let needed = master.quorum.max(voters / 2 + 1);
+    votes
+        .into_iter()
+        .filter(|&(_, count)| count >= needed)
+        .max_by(|(a, x), (b, y)| x.cmp(y).then((*b == myid).cmp(&(*a == myid))))
+        .map(|(leader, _)| leader.to_string())
+}
+
+/// Points replicas that report another master, or being masters
+/// themselves, back at the master. Only done while the master looks sane,
+/// and once they've kept reporting it for a while, so that a failover
+/// decided elsewhere isn't undone.
+fn fix_roles(db: &Db, master: &mut Master, now: u64) {
+    if master.instance.s_down || !master.instance.is_master || master.instance.last_info == 0 {
+        return;
+    }
+    let master_addr = (master.instance.ip.clone(), master.instance.port);
+    let addrs: Vec<String> = master.replicas.keys().cloned().collect();
+    for addr in addrs {
+        let replica = master.replicas.get_mut(&addr).unwrap();
+        let wrong = replica.is_master || replica.master_addr.as_ref() != Some(&master_addr);
+        if replica.s_down
+            || replica.last_info == 0
+            || !wrong
+            || now.saturating_sub(replica.role_since) < RECONF_GRACE_MS
+        {
+            continue;
+        }
+        let kind = if replica.is_master {
+            "+convert-to-slave"
+        } else {
+            "+fix-slave-config"
+        };
+        // Checked again after another grace period
+        replica.role_since = now;
+        let target = Target::Replica(addr.clone());
+        event(db, kind, describe(master, &target));
+        send(
+            db,
+            &master.name,
+            target,
+            parse_addr(&addr).unwrap(),
+            vec![Request::ReplicaOf(Some(master_addr.clone()))],
+            false,
+        );
+    }
+}
+
+/// Sends `requests` to an instance of master `name` in the background and
+/// applies the replies. `probe` marks the periodic PING, which the
+/// instance waits on before being pinged again.
+fn send(
+    db: &Db,
+    name: &str,
+    target: Target,
+    addr: (String, u16),
+    requests: Vec<Request>,
+    probe: bool,
+) {
+    let db = db.clone();
+    let name = name.to_string();
+    tokio::spawn(async move {
+        let args: Vec<Vec<String>> = requests.iter().map(Request::args).collect();
+        let replies = exchange(&addr, &args).await;
+
+        let mut lock = db.sentinel().lock();
+        let now = unix_time_ms() as u64;
+        let state = &mut *lock;
+        let Some(master) = state.masters.get_mut(&name) else {
+            return;
+        };
+        // The instance may have changed role in the meantime
+        let Some(instance) = master.instance_mut(&target) else {
+            return;
+        };
+        if !instance.has_addr(&addr) {
+            return;
+        }
+        if probe {
+            instance.pending = false;
+        }
+        let Ok(replies) = replies else {
+            return;
+        };
+        for (request, reply) in requests.iter().zip(replies) {
+            apply_reply(&db, master, &target, request, reply, now);
+        }
+    });
+}
+
+fn apply_reply(
+    db: &Db,
+    master: &mut Master,
+    target: &Target,
+    request: &Request,
+    reply: RespValue,
+    now: u64,
+) {
+    let Some(instance) = master.instance_mut(target) else {
+        return;
+    };
+    match (request, reply) {
+        (Request::Ping, RespValue::SimpleString(s)) if s == "PONG" => instance.answered(now),
+        // Busy instances are still alive
+        (Request::Ping, RespValue::SimpleError(e))
+            if e.starts_with("LOADING") || e.starts_with("MASTERDOWN") =>
+        {
+            instance.answered(now)
+        }
+        (Request::Info, RespValue::BulkString(info)) => {
+            let report = parse_info(&String::from_utf8_lossy(&info));
+            apply_info(db, master, target, report, now);
+        }
+        (Request::Info, RespValue::Verbatim(_, info)) => {
+            apply_info(db, master, target, parse_info(&info), now);
+        }
+        (Request::IsMasterDown(..), RespValue::Array(reply)) => {
+            if let [
+                RespValue::Integer(down),
+                RespValue::BulkString(leader),
+                RespValue::Integer(epoch),
+            ] = &reply[..]
+            {
+                instance.master_down = *down == 1;
+                if &leader[..] != b"*" {
+                    instance.leader = Some(String::from_utf8_lossy(leader).into_owned());
+                    instance.leader_epoch = *epoch as u64;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// What an instance says about itself in INFO.
+#[derive(Debug, Default, PartialEq)]
+struct InfoReport {
+    run_id: Option<String>,
+    is_master: bool,
+    master_addr: Option<(String, u16)>,
+    link_up: bool,
+    repl_offset: u64,
+    /// The replicas of a master.
+    replicas: Vec<(String, u16)>,
+}
+
+fn parse_info(info: &str) -> InfoReport {
+    let mut report = InfoReport::default();
+    let (mut master_host, mut master_port) = (None, None);
+    for line in info.lines() {
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        match field {
+            "run_id" => report.run_id = Some(value.to_string()),
+            "role" => report.is_master = value == "master",
+            "master_host" => master_host = Some(value.to_string()),
+            "master_port" => master_port = value.parse().ok(),
+            "master_link_status" => report.link_up = value == "up",
+            "slave_repl_offset" | "master_repl_offset" => {
+                report.repl_offset = value.parse().unwrap_or(0)
+            }
+            _ if field.starts_with("slave") && field[5..].parse::<usize>().is_ok() => {
+                let fields: HashMap<&str, &str> = value
+                    .split(',')
+                    .filter_map(|pair| pair.split_once('='))
+                    .collect();
+                if let (Some(ip), Some(Ok(port))) = (
+                    fields.get("ip"),
+                    fields.get("port").map(|port| port.parse()),
+                ) {
+                    report.replicas.push((ip.to_string(), port));
+                }
+            }
+            _ => {}
+        }
+    }
+    report.master_addr = master_host.zip(master_port);
+    report
+}
+
+fn apply_info(db: &Db, master: &mut Master, target: &Target, report: InfoReport, now: u64) {
+    if matches!(target, Target::Master) {
+        for (ip, port) in &report.replicas {
+            let addr = format!("{}:{}", ip, port);
+            if !master.replicas.contains_key(&addr) {
+                master
+                    .replicas
+                    .insert(addr.clone(), Instance::new(ip.clone(), *port, now));
+                event(db, "+slave", describe(master, &Target::Replica(addr)));
+            }
+        }
+    }
+
+    let instance = master.instance_mut(target).unwrap();
+    if instance.is_master != report.is_master || instance.master_addr != report.master_addr {
+        instance.role_since = now;
+    }
+    instance.last_info = now;
+    instance.run_id = report.run_id.or(instance.run_id.take());
+    instance.is_master = report.is_master;
+    instance.master_addr = report.master_addr;
+    instance.link_up = report.link_up;
+    instance.repl_offset = report.repl_offset;
+}
+
+/// Listens to the hello channel of the instance at `addr`, for as long as
+/// it belongs to master `name`.
+async fn listen_hello(db: Db, name: String, addr: String) {
+    loop {
+        {
+            let mut lock = db.sentinel().lock();
+            let Some(master) = lock.masters.get_mut(&name) else {
+                return;
+            };
+            if master.instance.addr() != addr && !master.replicas.contains_key(&addr) {
+                master.subscribed.remove(&addr);
+                return;
+            }
+        }
+        if let Some(addr) = parse_addr(&addr) {
+            let _ = subscribe(&db, &addr).await;
+        }
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
+
+async fn subscribe(db: &Db, addr: &(String, u16)) -> Result<(), SentinelError> {
+    let mut stream =
+        tokio::time::timeout(LINK_TIMEOUT, TcpStream::connect((addr.0.as_str(), addr.1)))
+            .await
+            .map_err(|_| SentinelError::Timeout)??;
+    stream
+        .write_all(&encode(&[
+            "SUBSCRIBE".to_string(),
+            HELLO_CHANNEL.to_string(),
+        ]))
+        .await?;
+    let mut input = BytesMut::new();
+    loop {
+        let (RespValue::Array(message) | RespValue::Push(message)) =
+            read_frame(&mut stream, &mut input).await?
+        else {
+            continue;
+        };
+        if let [RespValue::BulkString(kind), _, RespValue::BulkString(hello)] = &message[..]
+            && &kind[..] == b"message"
+        {
+            process_hello(db, &String::from_utf8_lossy(hello));
+        }
+    }
+}
+
+/// Applies a hello message: learns about the sentinel that sent it, and
+/// about a newer configuration of the master it describes.
+fn process_hello(db: &Db, hello: &str) {
+    let fields: Vec<&str> = hello.split(',').collect();
+    let [
+        ip,
+        port,
+        run_id,
+        epoch,
+        name,
+        master_ip,
+        master_port,
+        config_epoch,
+    ] = fields[..]
+    else {
+        return;
+    };
+    let (Ok(port), Ok(epoch), Ok(master_port), Ok(config_epoch)) = (
+        port.parse::<u16>(),
+        epoch.parse::<u64>(),
+        master_port.parse::<u16>(),
+        config_epoch.parse::<u64>(),
+    ) else {
+        return;
+    };
+
+    let mut lock = db.sentinel().lock();
+    let now = unix_time_ms() as u64;
+    let state = &mut *lock;
+    if run_id == state.myid {
+        return;
+    }
+    let Some(master) = state.masters.get_mut(name) else {
+        return;
+    };
+
+    if !master.sentinels.contains_key(run_id) {
+        // A sentinel that restarted comes back with a new id
+        master
+            .sentinels
+            .retain(|_, sentinel| !sentinel.has_addr(&(ip.to_string(), port)));
+        let mut sentinel = Instance::new(ip.to_string(), port, now);
+        sentinel.run_id = Some(run_id.to_string());
+        master.sentinels.insert(run_id.to_string(), sentinel);
+        event(
+            db,
+            "+sentinel",
+            describe(master, &Target::Sentinel(run_id.to_string())),
+        );
+    }
+    master.sentinels.get_mut(run_id).unwrap().last_hello = now;
+
+    if epoch > state.current_epoch {
+        state.current_epoch = epoch;
+        event(db, "+new-epoch", epoch.to_string());
+    }
+    if config_epoch > master.config_epoch {
+        master.config_epoch = config_epoch;
+        let new_master = (master_ip.to_string(), master_port);
+        if !master.instance.has_addr(&new_master) {
+            let old = (master.instance.ip.clone(), master.instance.port);
+            event(
+                db,
+                "+config-update-from",
+                describe(master, &Target::Sentinel(run_id.to_string())),
+            );
+            master.switch_to(&format!("{}:{}", master_ip, master_port), now);
+            event(
+                db,
+                "+switch-master",
+                format!(
+                    "{} {} {} {} {}",
+                    master.name, old.0, old.1, master_ip, master_port
+                ),
+            );
+        }
+    }
+}
+
+fn parse_addr(addr: &str) -> Option<(String, u16)> {
+    let (ip, port) = addr.rsplit_once(':')?;
+    Some((ip.to_string(), port.parse().ok()?))
+}
+
+fn encode(args: &[String]) -> BytesMut {
+    let mut out = BytesMut::new();
+    RespValue::Array(
+        args.iter()
+            .map(|arg| RespValue::BulkString(Bytes::copy_from_slice(arg.as_bytes())))
+            .collect(),
+    )
+    .write_to(&mut out, Protocol::Resp2);
+    out
+}
+
+/// Sends `requests` to the instance at `addr` in one go and reads a reply
+/// to each.
+async fn exchange(
+    addr: &(String, u16),
+    requests: &[Vec<String>],
+) -> Result<Vec<RespValue>, SentinelError> {
+    let exchange = async {
+        let mut stream = TcpStream::connect((addr.0.as_str(), addr.1)).await?;
+        let mut out = BytesMut::new();
+        for args in requests {
+            out.extend_from_slice(&encode(args));
+        }
+        stream.write_all(&out).await?;
+
+        let mut input = BytesMut::new();
+        let mut replies = Vec::with_capacity(requests.len());
+        while replies.len() < requests.len() {
+            replies.push(read_frame(&mut stream, &mut input).await?);
+        }
+        Ok(replies)
+    };
+    tokio::time::timeout(LINK_TIMEOUT, exchange)
+        .await
+        .map_err(|_| SentinelError::Timeout)?
+}
+
+async fn read_frame(
+    stream: &mut TcpStream,
+    input: &mut BytesMut,
+) -> Result<RespValue, SentinelError> {
+    loop {
+        let mut cursor = Cursor::new(&input[..]);
+        match parse_resp(&mut cursor) {
+            Ok(frame) => {
+                let len = cursor.position() as usize;
+                input.advance(len);
+                return Ok(frame);
+            }
+            Err(ProtocolError::Incomplete) => {}
+            Err(e) => return Err(e.into()),
+        }
+        if stream.read_buf(input).await? == 0 {
+            return Err(SentinelError::Closed);
+        }
+    }
+}
+
+/// Rejects, as unknown, the commands a sentinel doesn't serve, and
+/// SENTINEL on a server that isn't one.
+pub fn check_command(db: &Db, spec: &CommandSpec, args: &[RespValue]) -> Result<(), CommandError> {
+    let name = spec.name.split('|').next().unwrap_or_default();
+    let allowed = if db.sentinel().is_enabled() {
+        COMMANDS.contains(&name)
+    } else {
+        name != "sentinel"
+    };
+    if allowed {
+        Ok(())
+    } else {
+        Err(command_table::unknown_command(args))
+    }
+}
+
+fn no_such_master() -> CommandError {
+    CommandError::Err("No such master with that name".to_string())
+}
+
+fn bulk(s: impl Into<String>) -> RespValue {
+    RespValue::BulkString(Bytes::from(s.into()))
+}
+
+fn fields(fields: Vec<(&str, String)>) -> RespValue {
+    RespValue::Map(
+        fields
+            .into_iter()
+            .map(|(name, value)| (bulk(name), bulk(value)))
+            .collect(),
+    )
+}
+
+fn master_fields(master: &Master, now: u64) -> RespValue {
+    let instance = &master.instance;
+    fields(vec![
+        ("name", master.name.clone()),
+        ("ip", instance.ip.clone()),
+        ("port", instance.port.to_string()),
+        ("runid", instance.run_id.clone().unwrap_or_default()),
+        ("flags", master.flags()),
+        (
+            "last-ok-ping-reply",
+            now.saturating_sub(instance.last_ok).to_string(),
+        ),
+        ("down-after-milliseconds", master.down_after.to_string()),
+        (
+            "info-refresh",
+            now.saturating_sub(instance.last_info).to_string(),
+        ),
+        (
+            "role-reported",
+            if instance.is_master {
+                "master"
+            } else {
+                "slave"
+            }
+            .to_string(),
+        ),
+        ("config-epoch", master.config_epoch.to_string()),
+        ("num-slaves", master.replicas.len().to_string()),
+        ("num-other-sentinels", master.sentinels.len().to_string()),
+        ("quorum", master.quorum.to_string()),
+        ("failover-timeout", master.failover_timeout.to_string()),
+    ])
+}
+
+/// SENTINEL MASTERS: the state of every monitored master.
+pub fn masters(db: &Db) -> Result<RespValue, CommandError> {
+    let lock = db.sentinel().lock();
+    let now = unix_time_ms() as u64;
+    Ok(RespValue::Array(
+        lock.masters
+            .values()
+            .map(|master| master_fields(master, now))
+            .collect(),
+    ))
+}
+
+/// SENTINEL MASTER: the state of one monitored master.
+pub fn master(db: &Db, name: &str) -> Result<RespValue, CommandError> {
+    let lock = db.sentinel().lock();
+    let now = unix_time_ms() as u64;
+    let master = lock.masters.get(name).ok_or_else(no_such_master)?;
+    Ok(master_fields(master, now))
+}
+
+/// SENTINEL REPLICAS: the replicas of a monitored master.
+pub fn replicas(db: &Db, name: &str) -> Result<RespValue, CommandError> {
+    let lock = db.sentinel().lock();
+    let now = unix_time_ms() as u64;
+    let master = lock.masters.get(name).ok_or_else(no_such_master)?;
+    Ok(RespValue::Array(
+        master
+            .replicas
+            .iter()
+            .map(|(addr, replica)| {
+                let (master_host, master_port) = replica
+                    .master_addr
+                    .clone()
+                    .map_or((String::new(), String::new()), |(ip, port)| {
+                        (ip, port.to_string())
+                    });
+                fields(vec![
+                    ("name", addr.clone()),
+                    ("ip", replica.ip.clone()),
+                    ("port", replica.port.to_string()),
+                    ("runid", replica.run_id.clone().unwrap_or_default()),
+                    (
+                        "flags",
+                        if replica.s_down {
+                            "slave,s_down"
+                        } else {
+                            "slave"
+                        }
+                        .to_string(),
+                    ),
+                    (
+                        "last-ok-ping-reply",
+                        now.saturating_sub(replica.last_ok).to_string(),
+                    ),
+                    (
+                        "info-refresh",
+                        now.saturating_sub(replica.last_info).to_string(),
+                    ),
+                    (
+                        "role-reported",
+                        if replica.is_master { "master" } else { "slave" }.to_string(),
+                    ),
+                    (
+                        "master-link-status",
+                        if replica.link_up { "ok" } else { "err" }.to_string(),
+                    ),
+                    ("master-host", master_host),
+                    ("master-port", master_port),
+                    ("slave-repl-offset", replica.repl_offset.to_string()),
+                ])
+            })
+            .collect(),
+    ))
+}
+
+/// SENTINEL SENTINELS: the other sentinels watching a master.
+pub fn sentinels(db: &Db, name: &str) -> Result<RespValue, CommandError> {
+    let lock = db.sentinel().lock();
+    let now = unix_time_ms() as u64;
+    let master = lock.masters.get(name).ok_or_else(no_such_master)?;
+    Ok(RespValue::Array(
+        master
+            .sentinels
+            .iter()
+            .map(|(id, sentinel)| {
+                fields(vec![
+                    ("name", id.clone()),
+                    ("ip", sentinel.ip.clone()),
+                    ("port", sentinel.port.to_string()),
+                    ("runid", id.clone()),
+                    (
+                        "flags",
+                        if sentinel.s_down {
+                            "sentinel,s_down"
+                        } else {
+                            "sentinel"
+                        }
+                        .to_string(),
+                    ),
+                    (
+                        "last-ok-ping-reply",
+                        now.saturating_sub(sentinel.last_ok).to_string(),
+                    ),
+                    (
+                        "last-hello-message",
+                        now.saturating_sub(sentinel.last_hello).to_string(),
+                    ),
+                    (
+                        "voted-leader",
+                        sentinel.leader.clone().unwrap_or_else(|| "?".to_string()),
+                    ),
+                    ("voted-leader-epoch", sentinel.leader_epoch.to_string()),
+                ])
+            })
+            .collect(),
+    ))
+}
+
+/// SENTINEL GET-MASTER-ADDR-BY-NAME: where clients find the master.
+pub fn get_master_addr(db: &Db, name: &str) -> Result<RespValue, CommandError> {
+    let lock = db.sentinel().lock();
+    Ok(match lock.masters.get(name) {
+        Some(master) => RespValue::Array(vec![
+            bulk(master.instance.ip.clone()),
+            bulk(master.instance.port.to_string()),
+        ]),
+        None => RespValue::Null,
+    })
+}
+
+/// SENTINEL IS-MASTER-DOWN-BY-ADDR: whether this sentinel sees the master
+/// at `ip:port` as down, and its vote when asked for one by `run_id`.
+pub fn is_master_down_by_addr(
+    db: &Db,
+    ip: &str,
+    port: u16,
+    epoch: u64,
+    run_id: &str,
+) -> Result<RespValue, CommandError> {
+    let mut lock = db.sentinel().lock();
+    let now = unix_time_ms() as u64;
+    let state = &mut *lock;
+    let addr = (ip.to_string(), port);
+    let Some(master) = state
+        .masters
+        .values_mut()
+        .find(|master| master.instance.has_addr(&addr))
+    else {
+        return Ok(RespValue::Array(vec![
+            RespValue::Integer(0),
+            bulk("*"),
+            RespValue::Integer(0),
+        ]));
+    };
+
+    let down = master.instance.s_down;
+    let (leader, leader_epoch) = if run_id == "*" {
+        (None, 0)
+    } else {
+        vote(
+            db,
+            &state.myid,
+            &mut state.current_epoch,
+            master,
+            run_id,
+            epoch,
+            now,
+        )
+    };
+    Ok(RespValue::Array(vec![
+        RespValue::Integer(down as i64),
+        bulk(leader.unwrap_or_else(|| "*".to_string())),
+        RespValue::Integer(leader_epoch as i64),
+    ]))
+}
+
+/// SENTINEL MONITOR: starts watching a master.
+pub fn monitor(
+    db: &Db,
+    name: String,
+    ip: String,
+    port: u16,
+    quorum: usize,
+) -> Result<RespValue, CommandError> {
+    if quorum == 0 {
+        return Err(CommandError::Err(
+            "Quorum must be 1 or greater.".to_string(),
+        ));
+    }
+    let mut lock = db.sentinel().lock();
+    if lock.masters.contains_key(&name) {
+        return Err(CommandError::Err("Duplicated master name.".to_string()));
+    }
+    let master = Master::new(name.clone(), ip, port, quorum, unix_time_ms() as u64);
+    event(
+        db,
+        "+monitor",
+        format!("{} quorum {}", describe(&master, &Target::Master), quorum),
+    );
+    lock.masters.insert(name, master);
+    Ok(RespValue::SimpleString("OK".to_string()))
+}
+
+/// SENTINEL REMOVE: stops watching a master.
+pub fn remove(db: &Db, name: &str) -> Result<RespValue, CommandError> {
+    let master = db
+        .sentinel()
+        .lock()
+        .masters
+        .remove(name)
+        .ok_or_else(no_such_master)?;
+    event(db, "-monitor", describe(&master, &Target::Master));
+    Ok(RespValue::SimpleString("OK".to_string()))
+}
+
+/// SENTINEL SET: changes how a master is watched.
+pub fn set(db: &Db, name: &str, options: &[(String, String)]) -> Result<RespValue, CommandError> {
+    let mut lock = db.sentinel().lock();
+    let master = lock.masters.get_mut(name).ok_or_else(no_such_master)?;
+    for (option, value) in options {
+        let invalid = || {
+            CommandError::Err(format!(
+                "Invalid argument '{}' for SENTINEL SET '{}'",
+                value, option
+            ))
+        };
+        let number = value.parse::<u64>().ok().filter(|&n| n > 0);
+        match option.to_lowercase().as_str() {
+            "down-after-milliseconds" => master.down_after = number.ok_or_else(invalid)?,
+            "failover-timeout" => master.failover_timeout = number.ok_or_else(invalid)?,
+            "quorum" => master.quorum = number.ok_or_else(invalid)? as usize,
+            _ => {
+                return Err(CommandError::Err(format!(
+                    "Invalid argument '{}' for SENTINEL SET '{}'",
+                    option, name
+                )));
+            }
+        }
+    }
+    Ok(RespValue::SimpleString("OK".to_string()))
+}
+
+pub fn myid(db: &Db) -> Result<RespValue, CommandError> {
+    Ok(bulk(db.sentinel().lock().myid.clone()))
+}
+
+/// SENTINEL FAILOVER: fails a master over without asking the other
+/// sentinels.
+pub fn failover(db: &Db, name: &str) -> Result<RespValue, CommandError> {
+    let mut lock = db.sentinel().lock();
+    let now = unix_time_ms() as u64;
+    let master = lock.masters.get_mut(name).ok_or_else(no_such_master)?;
+    if master.failover.is_some() {
+        return Err(CommandError::InProgress);
+    }
+    if master.best_replica(now).is_none() {
+        return Err(CommandError::NoGoodReplica);
+    }
+    master.forced = true;
+    Ok(RespValue::SimpleString("OK".to_string()))
+}
+
+/// ROLE on a sentinel: the masters it watches.
+pub fn role(db: &Db) -> RespValue {
+    let lock = db.sentinel().lock();
+    RespValue::Array(vec![
+        bulk("sentinel"),
+        RespValue::Array(lock.masters.keys().cloned().map(bulk).collect()),
+    ])
+}
+
+/// The `sentinel` section of INFO, empty unless this is a sentinel.
+pub fn info_section(db: &Db) -> String {
+    let lock = db.sentinel().lock();
+    if !lock.enabled {
+        return String::new();
+    }
+    let mut fields = vec![
+        format!("sentinel_masters:{}", lock.masters.len()),
+        "sentinel_tilt:0".to_string(),
+        "sentinel_running_scripts:0".to_string(),
+    ];
+    for (i, master) in lock.masters.values().enumerate() {
+        fields.push(format!(
+            "master{}:name={},status={},address={},slaves={},sentinels={}",
+            i,
+            master.name,
+            if master.o_down.is_some() {
+                "odown"
+            } else {
+                "ok"
+            },
+            master.instance.addr(),
+            master.replicas.len(),
+            master.sentinels.len() + 1
+        ));
+    }
+    fields
+        .iter()
+        .map(|field| format!("{}\r\n", field))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::Command;
+
+    fn watched(db: &Db) {
+        db.sentinel().enable();
+        monitor(db, "mymaster".to_string(), "127.0.0.1".to_string(), 6379, 2).unwrap();
+    }
+
+    #[test]
+    fn test_parse_info() {
+        let master = "# Replication\r\nrole:master\r\nconnected_slaves:2\r\n\
+            slave0:ip=127.0.0.1,port=6380,state=online,offset=42,lag=0\r\n\
+            slave1:ip=127.0.0.1,port=6381,state=online,offset=40,lag=1\r\n";
+        let report = parse_info(master);
+        assert!(report.is_master);
+        assert_eq!(
+            report.replicas,
+            vec![
+                ("127.0.0.1".to_string(), 6380),
+                ("127.0.0.1".to_string(), 6381)
+            ]
+        );
+
+        let replica = "role:slave\r\nmaster_host:127.0.0.1\r\nmaster_port:6379\r\n\
+            master_link_status:up\r\nslave_repl_offset:42\r\n";
+        assert_eq!(
+            parse_info(replica),
+            InfoReport {
+                master_addr: Some(("127.0.0.1".to_string(), 6379)),
+                link_up: true,
+                repl_offset: 42,
+                ..InfoReport::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_one_vote_per_epoch() {
+        let db = Db::new();
+        watched(&db);
+        let ask = |run_id: &str, epoch| {
+            is_master_down_by_addr(&db, "127.0.0.1", 6379, epoch, run_id).unwrap()
+        };
+        let reply = |leader: &str, epoch| {
+            RespValue::Array(vec![
+                RespValue::Integer(0),
+                bulk(leader),
+                RespValue::Integer(epoch),
+            ])
+        };
+
+        assert_eq!(ask("*", 1), reply("*", 0));
+        assert_eq!(ask("a", 1), reply("a", 1));
+        assert_eq!(ask("b", 1), reply("a", 1));
+        assert_eq!(ask("b", 2), reply("b", 2));
+        assert_eq!(db.sentinel().lock().current_epoch, 2);
+        assert_eq!(
+            is_master_down_by_addr(&db, "127.0.0.1", 6380, 3, "c").unwrap(),
+            reply("*", 0)
+        );
+    }
+
+    #[test]
+    fn test_leader_needs_a_majority() {
+        let now = unix_time_ms() as u64;
+        let mut master = Master::new("m".to_string(), "127.0.0.1".to_string(), 6379, 1, now);
+        for id in ["b", "c", "d", "e"] {
+            let mut sentinel = Instance::new("127.0.0.1".to_string(), 26379, now);
+            sentinel.leader = Some("a".to_string());
+            sentinel.leader_epoch = if id == "b" { 3 } else { 2 };
+            master.sentinels.insert(id.to_string(), sentinel);
+        }
+        master.leader = Some("a".to_string());
+        master.leader_epoch = 3;
+
+        // Two votes out of five sentinels
+        assert_eq!(leader("a", &master, 3), None);
+        master.sentinels.get_mut("c").unwrap().leader_epoch = 3;
+        assert_eq!(leader("a", &master, 3), Some("a".to_string()));
+    }
+
+    #[test]
+    fn test_timestamps_ahead_of_now() {
+        // Replies stamped by another task, or a clock stepping back, can
+        // leave an instance's timestamps ahead of the time being compared
+        let now = unix_time_ms() as u64;
+        let mut master = Master::new("m".to_string(), "127.0.0.1".to_string(), 6379, 1, now);
+        master.instance.last_ok = now + 1000;
+        master.instance.last_info = now + 1000;
+        master_fields(&master, now);
+        let mut replica = Instance::new("127.0.0.1".to_string(), 6380, now + 1000);
+        replica.last_info = now + 1000;
+        master
+            .replicas
+            .insert("127.0.0.1:6380".to_string(), replica);
+        assert_eq!(master.best_replica(now), Some("127.0.0.1:6380".to_string()));
+    }
+
+    #[test]
+    fn test_hello_switches_to_newer_master() {
+        let db = Db::new();
+        watched(&db);
+        process_hello(&db, "127.0.0.1,26380,other,5,mymaster,127.0.0.1,6380,0");
+        {
+            let lock = db.sentinel().lock();
+            assert_eq!(lock.current_epoch, 5);
+            assert!(lock.masters["mymaster"].sentinels.contains_key("other"));
+        }
+        assert_eq!(
+            get_master_addr(&db, "mymaster").unwrap(),
+            RespValue::Array(vec![bulk("127.0.0.1"), bulk("6379")])
+        );
+
+        process_hello(&db, "127.0.0.1,26380,other,5,mymaster,127.0.0.1,6380,5");
+        assert_eq!(
+            get_master_addr(&db, "mymaster").unwrap(),
+            RespValue::Array(vec![bulk("127.0.0.1"), bulk("6380")])
+        );
+        let lock = db.sentinel().lock();
+        assert!(
+            lock.masters["mymaster"]
+                .replicas
+                .contains_key("127.0.0.1:6379")
+        );
+    }
+
+    #[test]
+    fn test_only_sentinel_commands_on_a_sentinel() {
+        let check = |db: &Db, args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|arg| RespValue::BulkString(Bytes::copy_from_slice(arg.as_bytes())))
+                    .collect(),
+            );
+            let (spec, args) = Command::resolve(frame).unwrap();
+            check_command(db, spec, &args)
+        };
+        let db = Db::new();
+        assert!(check(&db, &["SENTINEL", "MYID"]).is_err());
+        assert!(check(&db, &["GET", "foo"]).is_ok());
+        db.sentinel().enable();
+        assert!(check(&db, &["SENTINEL", "MYID"]).is_ok());
+        assert!(check(&db, &["GET", "foo"]).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+//! What INFO reports about the server as a whole: its identity, the clients
+//! connected to it and activity counters, which CONFIG RESETSTAT clears.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{db::Db, replication};
+
+/// Calls made to a single command.
+#[derive(Default)]
+struct CommandStat {
+    calls: u64,
+    usec: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    connections_received: u64,
+    commands_processed: u64,
+    keyspace_hits: u64,
+    keyspace_misses: u64,
+    /// Keyed by full command name, such as `config|get`.
+    commands: BTreeMap<&'static str, CommandStat>,
+}
+
+struct StatsState {
+    run_id: String,
+    started: Instant,
+    connected_clients: u64,
+    /// Clients waiting in a blocking command.
+    blocked_clients: u64,
+    counters: Counters,
+}
+
+/// Server statistics shared by every connection.
+#[derive(Clone)]
+pub struct Stats {
+    state: Arc<Mutex<StatsState>>,
+}
+
+/// Keeps a client counted in one of the gauges until dropped.
+pub struct Counted {
+    stats: Stats,
+    gauge: fn(&mut StatsState) -> &mut u64,
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        *(self.gauge)(&mut self.stats.state.lock().unwrap()) -= 1;
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            state: Arc::new(Mutex::new(StatsState {
+                run_id: replication::random_id(),
+                started: Instant::now(),
+                connected_clients: 0,
+                blocked_clients: 0,
+                counters: Counters::default(),
+            })),
+        }
+    }
+
+    /// Identifies this run of the server, changing on every restart.
+    pub fn run_id(&self) -> String {
+        self.state.lock().unwrap().run_id.clone()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.state.lock().unwrap().started.elapsed()
+    }
+
+    /// Counts a new client connection, which stays connected until the
+    /// returned guard is dropped.
+    pub fn client_connected(&self) -> Counted {
+        let mut lock = self.state.lock().unwrap();
+        lock.counters.connections_received += 1;
+        lock.connected_clients += 1;
+        self.counted(|state| &mut state.connected_clients)
+    }
+
+    /// Counts a client as blocked until the returned guard is dropped.
+    pub fn client_blocked(&self) -> Counted {
+        self.state.lock().unwrap().blocked_clients += 1;
+        self.counted(|state| &mut state.blocked_clients)
+    }
+
+    fn counted(&self, gauge: fn(&mut StatsState) -> &mut u64) -> Counted {
+        Counted {
+            stats: self.clone(),
+            gauge,
+        }
+    }
+
+    /// Records a call to the command named `name` that took `duration`.
+    pub fn command_processed(&self, name: &'static str, duration: Duration) {
+        let mut lock = self.state.lock().unwrap();
+        lock.counters.commands_processed += 1;
+        let stat = lock.counters.commands.entry(name).or_default();
+        stat.calls += 1;
+        stat.usec += duration.as_micros() as u64;
+    }
+
+    /// Records a key lookup that found the key, or didn't.
+    pub fn keyspace_lookup(&self, hit: bool) {
+        let mut lock = self.state.lock().unwrap();
+        if hit {
+            lock.counters.keyspace_hits += 1;
+        } else {
+            lock.counters.keyspace_misses += 1;
+        }
+    }
+
+    /// Starts every counter over from zero.
+    pub fn reset(&self) {
+        self.state.lock().unwrap().counters = Counters::default();
+    }
+}
+
+/// The `clients` section of INFO.
+pub fn clients_section(db: &Db) -> String {
+    let lock = db.stats().state.lock().unwrap();
+    [
+        format!("connected_clients:{}", lock.connected_clients),
+        format!("blocked_clients:{}", lock.blocked_clients),
+    ]
+    .iter()
+    .map(|line| format!("{}\r\n", line))
+    .collect()
+}
+
+/// The `stats` section of INFO.
+pub fn info_section(db: &Db) -> String {
+    let lock = db.stats().state.lock().unwrap();
+    let counters = &lock.counters;
+    [
+        format!(
+            "total_connections_received:{}",
+            counters.connections_received
+        ),
+        format!("total_commands_processed:{}", counters.commands_processed),
+        format!("keyspace_hits:{}", counters.keyspace_hits),
+        format!("keyspace_misses:{}", counters.keyspace_misses),
+    ]
+    .iter()
+    .map(|line| format!("{}\r\n", line))
+    .collect()
+}
+
+/// The `commandstats` section of INFO: calls to each command made so far
+/// and the time spent in them.
+pub fn commandstats_section(db: &Db) -> String {
+    let lock = db.stats().state.lock().unwrap();
+    lock.counters
+        .commands
+        .iter()
+        .map(|(name, stat)| {
+            format!(
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2}\r\n",
+                name,
+                stat.calls,
+                stat.usec,
+                stat.usec as f64 / stat.calls as f64
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset() {
+        let db = Db::new();
+        let client = db.stats().client_connected();
+        db.stats()
+            .command_processed("get", Duration::from_micros(3));
+        db.stats()
+            .command_processed("get", Duration::from_micros(2));
+        db.stats().keyspace_lookup(false);
+        assert_eq!(
+            info_section(&db),
+            "total_connections_received:1\r\ntotal_commands_processed:2\r\n\
+             keyspace_hits:0\r\nkeyspace_misses:1\r\n"
+        );
+        assert_eq!(
+            commandstats_section(&db),
+            "cmdstat_get:calls=2,usec=5,usec_per_call=2.50\r\n"
+        );
+
+        // Gauges describe the present, so they survive a reset
+        db.stats().reset();
+        assert_eq!(
+            info_section(&db),
+            "total_connections_received:0\r\ntotal_commands_processed:0\r\n\
+             keyspace_hits:0\r\nkeyspace_misses:0\r\n"
+        );
+        assert_eq!(commandstats_section(&db), "");
+        assert_eq!(
+            clients_section(&db),
+            "connected_clients:1\r\nblocked_clients:0\r\n"
+        );
+
+        drop(client);
+        assert!(clients_section(&db).starts_with("connected_clients:0\r\n"));
+    }
+}